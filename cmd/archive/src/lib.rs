@@ -0,0 +1,661 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility archive`
+//!
+//! `humility archive check` checks a Hubris archive for completeness and
+//! compatibility with this version of Humility, without connecting to a
+//! target.  Unlike other commands (which fail as soon as an archive can't
+//! be loaded), `humility archive check` examines the archive piece by piece,
+//! checking:
+//!
+//! - that the archive version is supported;
+//! - that the expected files (`app.toml`, `elf/kernel`, the task ELF objects
+//!   in `elf/task`, the final image in `img`, and the flash metadata) are
+//!   present;
+//! - that each task's ELF object contains DWARF and (if it has an Idol
+//!   interface) that its Idol metadata can be parsed;
+//! - that the archive can be loaded, and that the image ID in the kernel
+//!   matches the image ID in the final image;
+//! - that the task and region descriptors are present; and
+//! - that the external variables (and definitions) used by hiffy and by jefe
+//!   are present.
+//!
+//! It then reports which commands that use an archive will and won't work
+//! with it:
+//!
+//! ```console
+//! % humility -a ./build-gimlet.zip archive check
+//! humility: checking ./build-gimlet.zip
+//! CHECK                    STATUS DETAIL
+//! version                  ok     hubris build archive v3
+//! app.toml                 ok     -
+//! elf/kernel               ok     -
+//! elf/task                 ok     22 tasks
+//! img/final.elf            ok     -
+//! img/flash.ron            ok     -
+//! git-rev                  ok     -
+//! dwarf                    ok     present for kernel and 22 tasks
+//! idol                     ok     14 interfaces
+//! load                     ok     -
+//! image id                 ok     [52, 1c, 0e, 9f, 4d, 76, 2f, 4e]
+//! task descriptors         ok     -
+//! hiffy                    ok     -
+//! jefe                     fail   missing JEFE_EXTERNAL_TASKINDEX
+//!
+//! COMMAND      STATUS     REASON
+//! apptable     ok         -
+//! auxflash     ok         -
+//! dump         ok         -
+//! ...
+//! jefe         won't work missing jefe external variables
+//! ...
+//! ```
+//!
+//! If any check fails, `humility archive check` exits with a non-zero
+//! status.  The archive embedded in a dump can be checked by specifying the
+//! dump (with `-d`) rather than an archive.
+//!
+
+use anyhow::{bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use colored::Colorize;
+use goblin::elf::Elf;
+use humility::hubris::*;
+use humility_cmd::{Archive, Args, Command, RunUnattached};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::str::FromStr;
+
+#[derive(Parser, Debug)]
+#[clap(name = "archive", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct ArchiveArgs {
+    #[clap(subcommand)]
+    cmd: ArchiveCommand,
+}
+
+#[derive(Parser, Debug)]
+enum ArchiveCommand {
+    /// Checks an archive, reporting which commands will work with it
+    Check,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Status {
+    Ok,
+    Warn,
+    Fail,
+}
+
+impl Status {
+    fn print(&self) -> colored::ColoredString {
+        match self {
+            Status::Ok => "ok".green(),
+            Status::Warn => "warn".yellow(),
+            Status::Fail => "fail".red(),
+        }
+    }
+}
+
+//
+// The aspects of an archive that commands depend on.
+//
+#[derive(Copy, Clone, Debug)]
+enum Requirement {
+    Load,
+    Flash,
+    Dwarf,
+    Descriptors,
+    Hiffy,
+    Jefe,
+    Interface(&'static str),
+}
+
+//
+// The requirements of every command that uses an archive.  (That this covers
+// every command is checked by `validate_commands`, below.)
+//
+const COMMANDS: &[(&str, &[Requirement])] = &[
+    ("apptable", &[Requirement::Load]),
+    ("auxflash", &[Requirement::Hiffy, Requirement::Interface("AuxFlash")]),
+    ("dashboard", &[Requirement::Hiffy, Requirement::Interface("Sensor")]),
+    ("diagnose", &[Requirement::Dwarf, Requirement::Jefe]),
+    ("dump", &[Requirement::Load, Requirement::Descriptors]),
+    ("etm", &[Requirement::Load]),
+    ("extract", &[]),
+    ("flash", &[Requirement::Load, Requirement::Flash]),
+    ("gdb", &[Requirement::Load, Requirement::Flash]),
+    ("gpio", &[Requirement::Hiffy]),
+    ("hash", &[Requirement::Hiffy]),
+    ("hiffy", &[Requirement::Hiffy]),
+    ("i2c", &[Requirement::Hiffy]),
+    ("itm", &[Requirement::Load]),
+    ("jefe", &[Requirement::Jefe]),
+    ("lpc55gpio", &[Requirement::Hiffy]),
+    ("manifest", &[Requirement::Load]),
+    ("map", &[Requirement::Load, Requirement::Descriptors]),
+    ("monorail", &[Requirement::Hiffy, Requirement::Interface("Monorail")]),
+    ("net", &[Requirement::Hiffy, Requirement::Interface("Net")]),
+    ("openocd", &[Requirement::Load]),
+    ("pmbus", &[Requirement::Hiffy]),
+    ("probe", &[Requirement::Load]),
+    ("profile", &[Requirement::Load, Requirement::Dwarf]),
+    ("qspi", &[Requirement::Hiffy]),
+    ("readmem", &[Requirement::Load]),
+    ("readvar", &[Requirement::Load, Requirement::Dwarf]),
+    ("registers", &[Requirement::Load]),
+    ("rencm", &[Requirement::Hiffy]),
+    ("rendmp", &[Requirement::Hiffy]),
+    ("ringbuf", &[Requirement::Load, Requirement::Dwarf]),
+    ("rpc", &[Requirement::Load]),
+    ("sensors", &[Requirement::Hiffy, Requirement::Interface("Sensor")]),
+    ("size", &[Requirement::Load]),
+    ("spctrl", &[Requirement::Hiffy]),
+    ("spd", &[Requirement::Hiffy]),
+    ("spi", &[Requirement::Hiffy]),
+    ("stackmargin", &[Requirement::Load, Requirement::Dwarf]),
+    ("stmsecure", &[Requirement::Load]),
+    ("tasks", &[Requirement::Load, Requirement::Dwarf]),
+    ("test", &[Requirement::Load]),
+    ("trace", &[Requirement::Load, Requirement::Dwarf]),
+    ("validate", &[Requirement::Hiffy, Requirement::Interface("Validate")]),
+    ("vpd", &[Requirement::Hiffy, Requirement::Interface("Vpd")]),
+    ("vsc7448", &[Requirement::Hiffy]),
+    ("watch", &[Requirement::Load, Requirement::Dwarf]),
+];
+
+//
+// Commands that don't use an archive at all (and so aren't reported).
+//
+const UNARCHIVED: &[&str] =
+    &["archive", "archivediff", "debugmailbox", "doc", "exec", "isp", "reset"];
+
+//
+// The variables and definitions that HiffyContext::new() requires.
+//
+const HIFFY_VARIABLES: &[&str] = &[
+    "HIFFY_VERSION_MAJOR",
+    "HIFFY_VERSION_MINOR",
+    "HIFFY_READY",
+    "HIFFY_KICK",
+    "HIFFY_TEXT",
+    "HIFFY_DATA",
+    "HIFFY_RSTACK",
+    "HIFFY_REQUESTS",
+    "HIFFY_ERRORS",
+    "HIFFY_FAILURE",
+];
+
+const HIFFY_DEFINITIONS: &[&str] = &["HIFFY_FUNCTIONS"];
+
+const JEFE_VARIABLES: &[&str] = &[
+    "JEFE_EXTERNAL_READY",
+    "JEFE_EXTERNAL_KICK",
+    "JEFE_EXTERNAL_REQUEST",
+    "JEFE_EXTERNAL_REQUESTS",
+    "JEFE_EXTERNAL_ERRORS",
+    "JEFE_EXTERNAL_TASKINDEX",
+];
+
+#[derive(Default)]
+struct Checker {
+    checks: Vec<(String, Status, String)>,
+
+    // what we have learned about the archive
+    loaded: Option<HubrisArchive>,
+    flash: bool,
+    dwarf: bool,
+    descriptors: bool,
+    hiffy: bool,
+    jefe: bool,
+    interfaces: BTreeSet<String>,
+}
+
+impl Checker {
+    fn check(&mut self, what: &str, status: Status, detail: &str) {
+        self.checks.push((what.to_string(), status, detail.to_string()));
+    }
+
+    fn result(&mut self, what: &str, result: Result<String>) -> bool {
+        match result {
+            Ok(detail) => {
+                self.check(what, Status::Ok, &detail);
+                true
+            }
+            Err(err) => {
+                self.check(what, Status::Fail, &format!("{:#}", err));
+                false
+            }
+        }
+    }
+
+    //
+    // Checks that the ELF object for each task has DWARF, and that its Idol
+    // metadata (if any) can be parsed.
+    //
+    fn check_objects(&mut self, objects: &BTreeMap<String, Vec<u8>>) {
+        let mut nodwarf = vec![];
+        let mut badidol = vec![];
+        let mut ninterfaces = 0;
+
+        for (name, buffer) in objects {
+            let elf = match Elf::parse(buffer) {
+                Ok(elf) => elf,
+                Err(e) => {
+                    self.check(name, Status::Fail, &e.to_string());
+                    continue;
+                }
+            };
+
+            let section = |which: &str| {
+                elf.section_headers.iter().find(|sh| {
+                    matches!(
+                        elf.shdr_strtab.get(sh.sh_name),
+                        Some(Ok(n)) if n == which
+                    )
+                })
+            };
+
+            if !section(".debug_info").map_or(false, |sh| sh.sh_size != 0) {
+                nodwarf.push(name.clone());
+            }
+
+            //
+            // As when loading an archive, an empty .idolatry section denotes
+            // a task without an interface.
+            //
+            if let Some(sh) = section(".idolatry").filter(|sh| sh.sh_size != 0)
+            {
+                let offset = sh.sh_offset as usize;
+                let contents = buffer
+                    .get(offset..offset + sh.sh_size as usize)
+                    .and_then(|c| std::str::from_utf8(c).ok());
+
+                match contents.map(idol::syntax::Interface::from_str) {
+                    Some(Ok(_)) => ninterfaces += 1,
+                    Some(Err(e)) => badidol.push(format!("{}: {}", name, e)),
+                    None => badidol.push(format!("{}: bad section", name)),
+                }
+            }
+        }
+
+        let ntasks = objects.len().saturating_sub(1);
+
+        if nodwarf.is_empty() {
+            let detail = format!("present for kernel and {} tasks", ntasks);
+            self.check("dwarf", Status::Ok, &detail);
+            self.dwarf = true;
+        } else {
+            let detail = format!("missing for {}", nodwarf.join(", "));
+            self.check("dwarf", Status::Fail, &detail);
+        }
+
+        if badidol.is_empty() {
+            let detail = format!("{} interfaces", ninterfaces);
+            self.check("idol", Status::Ok, &detail);
+        } else {
+            self.check("idol", Status::Fail, &badidol.join("; "));
+        }
+    }
+
+    //
+    // Checks the files in the archive, returning the ELF objects for the
+    // kernel and tasks.
+    //
+    fn check_files(
+        &mut self,
+        contents: &[u8],
+    ) -> Result<BTreeMap<String, Vec<u8>>> {
+        let mut archive = zip::ZipArchive::new(Cursor::new(contents))?;
+        let mut objects = BTreeMap::new();
+        let mut ntasks = 0;
+
+        let comment = String::from_utf8_lossy(archive.comment()).to_string();
+        let version = HubrisArchive::check_version(&comment).map(|_| comment);
+        self.result("version", version);
+
+        let mut files = BTreeSet::new();
+
+        for i in 0..archive.len() {
+            let mut file = archive.by_index(i)?;
+            let name = file.name().to_string();
+            let path = Path::new(&name);
+
+            let object = if name == "elf/kernel" {
+                Some("kernel".to_string())
+            } else if path.parent() == Some(Path::new("elf/task")) {
+                ntasks += 1;
+                path.file_name().map(|f| f.to_string_lossy().to_string())
+            } else {
+                None
+            };
+
+            if let Some(object) = object {
+                let mut buffer = vec![];
+                file.read_to_end(&mut buffer)?;
+                objects.insert(object, buffer);
+            }
+
+            files.insert(name);
+        }
+
+        let mut expect = |name: &str, status: Status| {
+            if files.contains(name) {
+                self.check(name, Status::Ok, "-");
+                true
+            } else {
+                self.check(name, status, "missing");
+                false
+            }
+        };
+
+        expect("app.toml", Status::Fail);
+        expect("elf/kernel", Status::Fail);
+
+        let elf = expect("img/final.elf", Status::Fail);
+        let ron = expect("img/flash.ron", Status::Fail);
+        expect("git-rev", Status::Warn);
+
+        self.flash = elf && ron;
+
+        if ntasks == 0 {
+            self.check("elf/task", Status::Fail, "no tasks found");
+        } else {
+            self.check("elf/task", Status::Ok, &format!("{} tasks", ntasks));
+        }
+
+        Ok(objects)
+    }
+
+    //
+    // Loads the archive, and checks those aspects of it that require it to
+    // be loaded.
+    //
+    fn check_loaded(&mut self, source: &Source) {
+        let mut hubris = match HubrisArchive::new() {
+            Ok(hubris) => hubris,
+            Err(err) => {
+                self.result("load", Err(err));
+                return;
+            }
+        };
+
+        let loaded = source.load(&mut hubris, HubrisArchiveDoneness::Cook);
+
+        if !self.result("load", loaded.map(|_| "-".to_string())) {
+            return;
+        }
+
+        let id = match (hubris.image_id_addr(), hubris.image_id()) {
+            (Some(addr), Some(id)) => {
+                let mut image = vec![0u8; id.len()];
+
+                let read = humility::core::attach_archive(&hubris)
+                    .and_then(|mut core| core.read_8(addr, &mut image));
+
+                match read {
+                    Ok(_) if image == id => Ok(format!("{:x?}", id)),
+                    Ok(_) => Err(anyhow::anyhow!(
+                        "kernel has {:x?}, final image has {:x?}",
+                        id,
+                        image
+                    )),
+                    Err(err) => Err(err),
+                }
+            }
+            _ => Err(anyhow::anyhow!("kernel has no image ID")),
+        };
+
+        self.result("image id", id);
+
+        let descs = ["HUBRIS_TASK_DESCS", "HUBRIS_REGION_DESCS"];
+        self.descriptors =
+            self.variables("task descriptors", &hubris, &descs, &[]);
+        self.hiffy = self.variables(
+            "hiffy",
+            &hubris,
+            HIFFY_VARIABLES,
+            HIFFY_DEFINITIONS,
+        );
+        self.jefe = self.variables("jefe", &hubris, JEFE_VARIABLES, &[]);
+
+        for i in 0..hubris.ntasks() {
+            if let Ok(module) = hubris.lookup_module(HubrisTask::Task(i as u32))
+            {
+                if let Some(iface) = &module.iface {
+                    self.interfaces.insert(iface.name.clone());
+                }
+            }
+        }
+
+        self.loaded = Some(hubris);
+    }
+
+    fn variables(
+        &mut self,
+        what: &str,
+        hubris: &HubrisArchive,
+        names: &[&str],
+        definitions: &[&str],
+    ) -> bool {
+        let missing = names
+            .iter()
+            .filter(|name| hubris.lookup_variable(name).is_err())
+            .chain(
+                definitions
+                    .iter()
+                    .filter(|name| hubris.lookup_definition(name).is_err()),
+            )
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if missing.is_empty() {
+            self.check(what, Status::Ok, "-");
+            true
+        } else {
+            let detail = format!("missing {}", missing.join(", "));
+            self.check(what, Status::Fail, &detail);
+            false
+        }
+    }
+
+    //
+    // Returns the reason that a requirement isn't met, if it isn't.
+    //
+    fn unmet(&self, requirement: &Requirement) -> Option<String> {
+        let loaded = self.loaded.is_some();
+
+        let met = match requirement {
+            Requirement::Load => loaded,
+            Requirement::Flash => self.flash,
+            Requirement::Dwarf => loaded && self.dwarf,
+            Requirement::Descriptors => loaded && self.descriptors,
+            Requirement::Hiffy => loaded && self.hiffy,
+            Requirement::Jefe => loaded && self.jefe,
+            Requirement::Interface(name) => self.interfaces.contains(*name),
+        };
+
+        if met {
+            None
+        } else if !loaded && !matches!(requirement, Requirement::Flash) {
+            Some("archive fails to load".to_string())
+        } else {
+            Some(match requirement {
+                Requirement::Load => unreachable!(),
+                Requirement::Flash => "missing flash image or metadata".into(),
+                Requirement::Dwarf => "missing DWARF".into(),
+                Requirement::Descriptors => "missing task descriptors".into(),
+                Requirement::Hiffy => "missing hiffy variables".into(),
+                Requirement::Jefe => "missing jefe external variables".into(),
+                Requirement::Interface(name) => {
+                    format!("no task provides {} interface", name)
+                }
+            })
+        }
+    }
+
+    fn print(&self) {
+        println!("{:24} {:6} DETAIL", "CHECK", "STATUS");
+
+        for (what, status, detail) in &self.checks {
+            println!("{:24} {:6} {}", what, status.print(), detail);
+        }
+
+        println!();
+        println!("{:12} {:10} REASON", "COMMAND", "STATUS");
+
+        for (cmd, requirements) in COMMANDS {
+            let unmet = requirements.iter().find_map(|r| self.unmet(r));
+
+            match unmet {
+                None => println!("{:12} {:10} -", cmd, "ok".green()),
+                Some(reason) => {
+                    println!("{:12} {:10} {}", cmd, "won't work".red(), reason)
+                }
+            }
+        }
+    }
+}
+
+//
+// What we are checking:  an archive, or the archive embedded in a dump.
+//
+enum Source<'a> {
+    Archive(&'a str),
+    Dump(&'a str),
+}
+
+impl Source<'_> {
+    fn load(
+        &self,
+        hubris: &mut HubrisArchive,
+        doneness: HubrisArchiveDoneness,
+    ) -> Result<()> {
+        match self {
+            Source::Archive(filename) => hubris.load(filename, doneness),
+            Source::Dump(filename) => hubris.load_dump(filename, doneness),
+        }
+    }
+
+    fn contents(&self) -> Result<Vec<u8>> {
+        match self {
+            Source::Archive(filename) => Ok(std::fs::read(filename)?),
+            Source::Dump(filename) => {
+                let mut hubris = HubrisArchive::new()?;
+                self.load(&mut hubris, HubrisArchiveDoneness::Raw)?;
+
+                if hubris.archive().is_empty() {
+                    bail!("dump {} does not contain an archive", filename);
+                }
+
+                Ok(hubris.archive().to_vec())
+            }
+        }
+    }
+}
+
+fn check(source: Source) -> Result<()> {
+    let contents = source.contents()?;
+    let mut checker = Checker::default();
+
+    match source {
+        Source::Archive(filename) => humility::msg!("checking {}", filename),
+        Source::Dump(filename) => {
+            humility::msg!("checking archive in dump {}", filename)
+        }
+    }
+
+    match checker.check_files(&contents) {
+        Ok(objects) => {
+            checker.check_objects(&objects);
+            checker.check_loaded(&source);
+        }
+        Err(err) => {
+            checker.result("zip", Err(err));
+        }
+    }
+
+    checker.print();
+
+    let nfailed = checker
+        .checks
+        .iter()
+        .filter(|(_, status, _)| *status == Status::Fail)
+        .count();
+
+    if nfailed != 0 {
+        bail!(
+            "{} check{} failed",
+            nfailed,
+            if nfailed != 1 { "s" } else { "" }
+        );
+    }
+
+    Ok(())
+}
+
+fn archivecmd(
+    _hubris: &mut HubrisArchive,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ArchiveArgs::try_parse_from(subargs)?;
+
+    //
+    // We explicitly don't have the archive loaded for us, as we want to be
+    // able to check archives that fail to load.
+    //
+    let source = match (&args.archive, &args.dump) {
+        (Some(filename), _) => Source::Archive(filename),
+        (None, Some(filename)) => Source::Dump(filename),
+        (None, None) => bail!("must provide a Hubris archive or dump"),
+    };
+
+    match subargs.cmd {
+        ArchiveCommand::Check => check(source),
+    }
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Unattached {
+            name: "archive",
+            archive: Archive::Ignored,
+            run: RunUnattached::Args(archivecmd),
+        },
+        ArchiveArgs::command(),
+    )
+}
+
+#[test]
+fn validate_commands() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/..");
+    let mut missing = vec![];
+
+    for entry in std::fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+
+        if !path.join("Cargo.toml").exists() {
+            continue;
+        }
+
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+
+        if !COMMANDS.iter().any(|(cmd, _)| *cmd == name)
+            && !UNARCHIVED.contains(&name.as_str())
+        {
+            missing.push(name);
+        }
+    }
+
+    assert!(
+        missing.is_empty(),
+        "commands missing from COMMANDS: {:?}",
+        missing
+    );
+
+    assert!(COMMANDS.windows(2).all(|w| w[0].0 < w[1].0));
+}
@@ -0,0 +1,509 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility archivediff`
+//!
+//! `humility archivediff` summarizes the differences between two Hubris
+//! archives.  It does not connect to a Hubris target; the archives to be
+//! compared are specified as arguments.  The archives are compared by
+//! archive-wide attributes (version, git revision, image ID, board, target,
+//! and kernel features), by tasks and their sizes and features, by the memory
+//! regions of each task (as determined from the region descriptors in each
+//! archive's flash image), by peripherals, I2C devices and sensors, and by
+//! the Idol interfaces of each task.  Finally, any differences in `app.toml`
+//! are shown as a unified diff.
+//!
+//! Lines beginning with `-` are present only in the first archive, lines
+//! beginning with `+` are present only in the second archive, and lines
+//! beginning with `~` differ between the two:
+//!
+//! ```console
+//! % humility archivediff build-a.zip build-b.zip
+//! humility: comparing build-a.zip to build-b.zip
+//! archive:
+//!   ~ git rev: 753a57169eba699e73ee59e0cf5345eb1d6e1ae2 -> e3c7ba5ba6e0fb43b8fa1bfb84aea8dd3e4a8a3c
+//!   ~ image id: [b8, 7a, 50, 41, 1f, 3e, 42, c4] -> [e7, 2b, 8b, 2f, 59, 9c, 7b, 28]
+//! tasks:
+//!   ~ hiffy: 42.9K -> 44.1K (+1264)
+//!   + thermal: 12.2K
+//! regions:
+//!   ~ hiffy
+//!     - 0x24010000 - 0x24013fff   16KiB rw---
+//!     + 0x24010000 - 0x24017fff   32KiB rw---
+//!   + thermal: 0x08060000 - 0x08063fff   16KiB r-x--, ...
+//! idol interfaces:
+//!   + Thermal.get_mode
+//!   ~ Sensor.get: signature changed
+//! app.toml:
+//!   --- build-a.zip
+//!   +++ build-b.zip
+//!   @@ -120,6 +120,15 @@
+//!   ...
+//! ```
+//!
+//! If the archives do not differ in any of these respects, `humility
+//! archivediff` will indicate as much.
+//!
+
+use anyhow::{Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::hubris::*;
+use humility_cmd::{Archive, Command, RunUnattached};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Cursor, Read};
+
+#[derive(Parser, Debug)]
+#[clap(name = "archivediff", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct ArchiveDiffArgs {
+    /// number of lines of context in the diff of app.toml
+    #[clap(long, short, default_value = "3", value_name = "lines")]
+    context: usize,
+
+    /// first archive
+    a: String,
+
+    /// second archive
+    b: String,
+}
+
+//
+// Each section of our comparison is represented as a map of a name to the
+// lines that describe it.
+//
+type Section = BTreeMap<String, Vec<String>>;
+
+fn load(filename: &str) -> Result<HubrisArchive> {
+    let mut hubris = HubrisArchive::new()?;
+
+    hubris
+        .load(filename, HubrisArchiveDoneness::Cook)
+        .with_context(|| format!("failed to load archive {}", filename))?;
+
+    Ok(hubris)
+}
+
+fn size(memsize: u32) -> String {
+    format!("{:.1}K", memsize as f64 / 1024_f64)
+}
+
+fn attributes(hubris: &HubrisArchive) -> Section {
+    let mut section = Section::new();
+
+    let mut add = |what: &str, val: &Option<String>| {
+        if let Some(val) = val {
+            section.insert(what.to_string(), vec![val.trim().to_string()]);
+        }
+    };
+
+    add("version", &hubris.manifest.version);
+    add("git rev", &hubris.manifest.gitrev);
+    add("image id", &hubris.image_id().map(|id| format!("{:x?}", id)));
+    add("name", &hubris.manifest.name);
+    add("board", &hubris.manifest.board);
+    add("target", &hubris.manifest.target);
+
+    section.insert(
+        "features".to_string(),
+        vec![hubris.manifest.features.join(", ")],
+    );
+
+    section
+}
+
+fn modules(hubris: &HubrisArchive) -> Result<Vec<&HubrisModule>> {
+    let mut modules = vec![hubris.lookup_module(HubrisTask::Kernel)?];
+
+    for i in 0..hubris.ntasks() {
+        modules.push(hubris.lookup_module(HubrisTask::Task(i as u32))?);
+    }
+
+    Ok(modules)
+}
+
+fn tasks(hubris: &HubrisArchive) -> Result<(Section, Section)> {
+    let mut sizes = Section::new();
+    let mut features = Section::new();
+
+    for module in modules(hubris)? {
+        sizes.insert(module.name.clone(), vec![size(module.memsize)]);
+
+        if let Some(f) = hubris.manifest.task_features.get(&module.name) {
+            features.insert(module.name.clone(), vec![f.join(", ")]);
+        }
+    }
+
+    Ok((sizes, features))
+}
+
+fn regions(hubris: &HubrisArchive) -> Result<Section> {
+    let mut core = humility::core::attach_archive(hubris)?;
+    let mut section = Section::new();
+
+    for region in hubris.regions(core.as_mut())?.values() {
+        let line = format!(
+            "0x{:08x} - 0x{:08x} {:>7} {}{}{}{}{}",
+            region.base,
+            (region.base + region.mapsize).wrapping_sub(1),
+            if region.mapsize >= 1024 {
+                format!("{}KiB", region.mapsize >> 10)
+            } else {
+                format!("{}", region.mapsize)
+            },
+            if region.attr.read { "r" } else { "-" },
+            if region.attr.write { "w" } else { "-" },
+            if region.attr.execute { "x" } else { "-" },
+            if region.attr.device { "d" } else { "-" },
+            if region.attr.dma { "m" } else { "-" },
+        );
+
+        for task in &region.tasks {
+            let name = &hubris.lookup_module(*task)?.name;
+            section.entry(name.clone()).or_default().push(line.clone());
+        }
+    }
+
+    Ok(section)
+}
+
+fn peripherals(hubris: &HubrisArchive) -> Section {
+    hubris
+        .manifest
+        .peripherals
+        .iter()
+        .map(|(name, addr)| (name.clone(), vec![format!("0x{:08x}", addr)]))
+        .collect()
+}
+
+fn i2c_device(device: &HubrisI2cDevice) -> String {
+    let mux = match (device.mux, device.segment) {
+        (Some(m), Some(s)) => format!("{}:{}", m, s),
+        (None, None) => "-".to_string(),
+        (_, _) => "?:?".to_string(),
+    };
+
+    format!(
+        "{} {} {} 0x{:02x}",
+        device.controller, device.port.name, mux, device.address
+    )
+}
+
+fn i2c_devices(hubris: &HubrisArchive) -> Section {
+    hubris
+        .manifest
+        .i2c_devices
+        .iter()
+        .map(|device| {
+            (
+                i2c_device(device),
+                vec![format!("{} ({})", device.device, device.description)],
+            )
+        })
+        .collect()
+}
+
+fn sensors(hubris: &HubrisArchive) -> Section {
+    let mut section = Section::new();
+
+    for sensor in &hubris.manifest.sensors {
+        let device = match hubris.manifest.i2c_devices.get(sensor.device) {
+            Some(device) => format!("{} {}", device.device, i2c_device(device)),
+            None => "<unknown>".to_string(),
+        };
+
+        section
+            .entry(format!("{} {}", sensor.name, sensor.kind.to_string()))
+            .or_default()
+            .push(device);
+    }
+
+    section
+}
+
+//
+// For Idol interfaces, we want to know if operations have been added or
+// removed, or if an operation's signature has changed; rather than attempt
+// to describe the change in signature, we reduce each signature to its
+// debug representation, and only indicate that it has changed.
+//
+fn idol(hubris: &HubrisArchive) -> Result<BTreeMap<String, String>> {
+    let mut ops = BTreeMap::new();
+
+    for module in modules(hubris)? {
+        if let Some(iface) = &module.iface {
+            for (name, op) in iface.ops.iter() {
+                ops.insert(
+                    format!("{}.{}", iface.name, name),
+                    format!("{:?}", op),
+                );
+            }
+        }
+    }
+
+    Ok(ops)
+}
+
+fn app_toml(hubris: &HubrisArchive) -> Result<String> {
+    let cursor = Cursor::new(hubris.archive());
+    let mut archive = zip::ZipArchive::new(cursor)?;
+    let mut app = String::new();
+
+    archive.by_name("app.toml")?.read_to_string(&mut app)?;
+    Ok(app)
+}
+
+//
+// Returns the lines describing the differences between two sections:  a
+// key that is in only one is prefixed with `-` or `+`, and a key whose
+// values differ is prefixed with `~` (followed by the removed and added
+// values, if there is more than one value on either side).
+//
+fn section_diff(a: &Section, b: &Section) -> Vec<String> {
+    let mut lines = vec![];
+    let keys = a.keys().chain(b.keys()).collect::<BTreeSet<_>>();
+
+    for key in keys {
+        match (a.get(key), b.get(key)) {
+            (Some(a), Some(b)) if a == b => {}
+            (Some(a), Some(b)) if a.len() == 1 && b.len() == 1 => {
+                lines.push(format!("~ {}: {} -> {}", key, a[0], b[0]));
+            }
+            (Some(a), Some(b)) => {
+                lines.push(format!("~ {}", key));
+
+                for line in a.iter().filter(|line| !b.contains(line)) {
+                    lines.push(format!("  - {}", line));
+                }
+
+                for line in b.iter().filter(|line| !a.contains(line)) {
+                    lines.push(format!("  + {}", line));
+                }
+            }
+            (Some(a), None) => {
+                lines.push(format!("- {}: {}", key, a.join(", ")));
+            }
+            (None, Some(b)) => {
+                lines.push(format!("+ {}: {}", key, b.join(", ")));
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+
+    lines
+}
+
+//
+// Prints the differences in a section, returning true if there were any.
+//
+fn diff_section(title: &str, a: &Section, b: &Section) -> bool {
+    let lines = section_diff(a, b);
+
+    if lines.is_empty() {
+        return false;
+    }
+
+    println!("{}:", title);
+
+    for line in lines {
+        println!("  {}", line);
+    }
+
+    true
+}
+
+fn diff_tasks(a: &HubrisArchive, b: &HubrisArchive) -> Result<bool> {
+    let (asizes, afeatures) = tasks(a)?;
+    let (mut bsizes, bfeatures) = tasks(b)?;
+
+    //
+    // For tasks whose size has changed, we want to indicate the change in
+    // bytes, which we can't get from our string representation.
+    //
+    let memsize = |hubris: &HubrisArchive| -> Result<BTreeMap<String, u32>> {
+        Ok(modules(hubris)?
+            .iter()
+            .map(|m| (m.name.clone(), m.memsize))
+            .collect())
+    };
+
+    let (amem, bmem) = (memsize(a)?, memsize(b)?);
+
+    for (name, size) in bsizes.iter_mut() {
+        if let (Some(asize), Some(bsize)) = (amem.get(name), bmem.get(name)) {
+            if asize != bsize {
+                size[0] = format!(
+                    "{} ({:+})",
+                    size[0],
+                    *bsize as i64 - *asize as i64
+                );
+            }
+        }
+    }
+
+    let sizes = diff_section("tasks", &asizes, &bsizes);
+    let features = diff_section("task features", &afeatures, &bfeatures);
+
+    Ok(sizes || features)
+}
+
+fn diff_idol(a: &HubrisArchive, b: &HubrisArchive) -> Result<bool> {
+    let (a, b) = (idol(a)?, idol(b)?);
+    let mut header = false;
+
+    for key in a.keys().chain(b.keys()).collect::<BTreeSet<_>>() {
+        let line = match (a.get(key), b.get(key)) {
+            (Some(a), Some(b)) if a == b => continue,
+            (Some(_), Some(_)) => format!("~ {}: signature changed", key),
+            (Some(_), None) => format!("- {}", key),
+            (None, Some(_)) => format!("+ {}", key),
+            (None, None) => unreachable!(),
+        };
+
+        if !header {
+            println!("idol interfaces:");
+            header = true;
+        }
+
+        println!("  {}", line);
+    }
+
+    Ok(header)
+}
+
+fn diff_app_toml(
+    a: &HubrisArchive,
+    b: &HubrisArchive,
+    subargs: &ArchiveDiffArgs,
+) -> Result<bool> {
+    let (atoml, btoml) = (app_toml(a)?, app_toml(b)?);
+
+    if atoml == btoml {
+        return Ok(false);
+    }
+
+    let diff = similar::TextDiff::from_lines(&atoml, &btoml);
+
+    println!("app.toml:");
+
+    for line in diff
+        .unified_diff()
+        .context_radius(subargs.context)
+        .header(&subargs.a, &subargs.b)
+        .to_string()
+        .lines()
+    {
+        println!("  {}", line);
+    }
+
+    Ok(true)
+}
+
+fn archivediff(_hubris: &mut HubrisArchive, subargs: &[String]) -> Result<()> {
+    let subargs = ArchiveDiffArgs::try_parse_from(subargs)?;
+
+    let a = load(&subargs.a)?;
+    let b = load(&subargs.b)?;
+
+    humility::msg!("comparing {} to {}", subargs.a, subargs.b);
+
+    let mut differ = diff_section("archive", &attributes(&a), &attributes(&b));
+
+    differ |= diff_tasks(&a, &b)?;
+
+    //
+    // Older archives may lack the region descriptors that we need to
+    // determine the memory layout; if either archive lacks them, we
+    // note it and move on.
+    //
+    match (regions(&a), regions(&b)) {
+        (Ok(aregions), Ok(bregions)) => {
+            differ |= diff_section("regions", &aregions, &bregions);
+        }
+        (Err(err), _) | (_, Err(err)) => {
+            humility::msg!("can't compare regions: {}", err);
+        }
+    }
+
+    differ |= diff_section("peripherals", &peripherals(&a), &peripherals(&b));
+    differ |= diff_section("i2c devices", &i2c_devices(&a), &i2c_devices(&b));
+    differ |= diff_section("sensors", &sensors(&a), &sensors(&b));
+    differ |= diff_idol(&a, &b)?;
+    differ |= diff_app_toml(&a, &b, &subargs)?;
+
+    if !differ {
+        humility::msg!("archives do not differ");
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Unattached {
+            name: "archivediff",
+            archive: Archive::Ignored,
+            run: RunUnattached::Subargs(archivediff),
+        },
+        ArchiveDiffArgs::command(),
+    )
+}
+
+//
+// Builds a section from keys and their comma-separated values.
+//
+#[cfg(test)]
+fn section(entries: &[(&str, &str)]) -> Section {
+    entries
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.split(',').map(String::from).collect()))
+        .collect()
+}
+
+#[test]
+fn validate_section_diff_same() {
+    let a = section(&[("jefe", "1024 bytes"), ("net", "a,b")]);
+    assert!(section_diff(&a, &a).is_empty());
+    assert!(section_diff(&section(&[]), &section(&[])).is_empty());
+}
+
+#[test]
+fn validate_section_diff() {
+    let a = section(&[
+        ("jefe", "1024 bytes"),
+        ("net", "a,b,c"),
+        ("ping", "x,y"),
+        ("spi", "old"),
+    ]);
+
+    let b = section(&[
+        ("i2c", "new"),
+        ("jefe", "2048 bytes"),
+        ("net", "b,c,d"),
+        ("ping", "x,y"),
+    ]);
+
+    assert_eq!(
+        section_diff(&a, &b),
+        vec![
+            "+ i2c: new",
+            "~ jefe: 1024 bytes -> 2048 bytes",
+            "~ net",
+            "  - a",
+            "  + d",
+            "- spi: old",
+        ]
+    );
+}
+
+#[test]
+fn validate_section_diff_multiple() {
+    //
+    // A change from a single value to several is shown as removals and
+    // additions, and a key present on only one side shows all its values.
+    //
+    let a = section(&[("net", "a"), ("spi", "x,y")]);
+    let b = section(&[("net", "a,b")]);
+
+    assert_eq!(section_diff(&a, &b), vec!["~ net", "  + b", "- spi: x, y"]);
+}
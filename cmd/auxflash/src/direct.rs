@@ -0,0 +1,481 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Direct programming of auxiliary flash from the debugger.  If the running
+// image doesn't have an AuxFlash task (e.g., because it predates auxiliary
+// flash, or because it is an image other than the one in the archive), we
+// can't program auxiliary flash via hiffy -- but we can drive the STM32H7
+// QUADSPI peripheral to which auxiliary flash is attached ourselves.  We
+// halt the target while we do this to assure that nothing else is using the
+// peripheral, use indirect mode for commands and programming, and
+// memory-mapped mode for reading back.  Because the image will resume where
+// it left off, we save the state of the peripheral (and of its clock) before
+// we touch it, and restore it before we let the target run again.  Note that
+// every word written goes over the debug link, so this is much slower than
+// programming via hiffy.
+//
+
+use super::SLOT_SIZE_BYTES;
+use anyhow::{bail, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use indicatif::{ProgressBar, ProgressStyle};
+use std::time::{Duration, Instant};
+
+//
+// QUADSPI register offsets and fields
+//
+const QUADSPI_CR: u32 = 0x00;
+const QUADSPI_DCR: u32 = 0x04;
+const QUADSPI_SR: u32 = 0x08;
+const QUADSPI_FCR: u32 = 0x0c;
+const QUADSPI_DLR: u32 = 0x10;
+const QUADSPI_CCR: u32 = 0x14;
+const QUADSPI_AR: u32 = 0x18;
+const QUADSPI_DR: u32 = 0x20;
+
+const CR_EN: u32 = 1 << 0;
+const CR_ABORT: u32 = 1 << 1;
+const CR_PRESCALER_SHIFT: u32 = 24;
+const DCR_FSIZE_SHIFT: u32 = 16;
+const DCR_CSHT_SHIFT: u32 = 8;
+const SR_TCF: u32 = 1 << 1;
+const SR_BUSY: u32 = 1 << 5;
+const FCR_CTCF: u32 = 1 << 1;
+
+const CCR_IMODE_SINGLE: u32 = 1 << 8;
+const CCR_ADMODE_SINGLE: u32 = 1 << 10;
+const CCR_ADSIZE_24: u32 = 2 << 12;
+const CCR_DMODE_SINGLE: u32 = 1 << 24;
+const CCR_FMODE_READ: u32 = 1 << 26;
+const CCR_FMODE_MAPPED: u32 = 3 << 26;
+
+//
+// The prescaler that we use if the peripheral hasn't been configured; this
+// is deliberately conservative.
+//
+const PRESCALER: u32 = 7;
+
+//
+// The base of QUADSPI memory-mapped mode
+//
+const QUADSPI_MAPPED: u32 = 0x9000_0000;
+
+//
+// The QUADSPI enable in RCC_AHB3ENR
+//
+const RCC_AHB3ENR: u32 = 0xd4;
+const RCC_AHB3ENR_QSPIEN: u32 = 1 << 14;
+
+//
+// JEDEC-standard SPI NOR flash commands
+//
+const CMD_WRITE_ENABLE: u8 = 0x06;
+const CMD_READ_STATUS: u8 = 0x05;
+const CMD_READ_ID: u8 = 0x9f;
+const CMD_PAGE_PROGRAM: u8 = 0x02;
+const CMD_READ: u8 = 0x03;
+const CMD_SECTOR_ERASE: u8 = 0xd8;
+
+const STATUS_WIP: u8 = 1 << 0;
+const SECTOR_SIZE: usize = 64 * 1024;
+const PAGE_SIZE: usize = 256;
+
+//
+// The largest indirect read that we will perform:  the size of the FIFO.
+//
+const MAX_READ: usize = 32;
+
+//
+// The state of the peripheral (and its clock) as we found it
+//
+struct Saved {
+    enr: u32,
+    cr: u32,
+    dcr: u32,
+    ccr: u32,
+}
+
+struct DirectFlash<'a> {
+    core: &'a mut dyn Core,
+    base: u32,
+    rcc: u32,
+    saved: Saved,
+}
+
+impl<'a> DirectFlash<'a> {
+    fn new(hubris: &HubrisArchive, core: &'a mut dyn Core) -> Result<Self> {
+        let base = hubris.lookup_peripheral("quadspi")?;
+        let rcc = hubris.lookup_peripheral("rcc")?;
+
+        //
+        // We need the peripheral to be clocked to read its registers; if it
+        // isn't, we enable its clock (and will disable it when we restore).
+        //
+        let enr = core.read_word_32(rcc + RCC_AHB3ENR)?;
+
+        if enr & RCC_AHB3ENR_QSPIEN == 0 {
+            core.write_word_32(rcc + RCC_AHB3ENR, enr | RCC_AHB3ENR_QSPIEN)?;
+        }
+
+        let saved = Saved {
+            enr,
+            cr: core.read_word_32(base + QUADSPI_CR)?,
+            dcr: core.read_word_32(base + QUADSPI_DCR)?,
+            ccr: core.read_word_32(base + QUADSPI_CCR)?,
+        };
+
+        Ok(Self { core, base, rcc, saved })
+    }
+
+    fn configure(&mut self) -> Result<()> {
+        if self.saved.cr & CR_EN == 0 {
+            self.core.write_word_32(
+                self.base + QUADSPI_CR,
+                (PRESCALER << CR_PRESCALER_SHIFT) | CR_EN,
+            )?;
+        } else {
+            //
+            // The image may have left the peripheral in memory-mapped mode,
+            // in which case it will remain busy until we abort it.
+            //
+            self.abort()?;
+        }
+
+        //
+        // Until we know the size of the part, set the flash size to be its
+        // maximum.
+        //
+        self.core.write_word_32(
+            self.base + QUADSPI_DCR,
+            (31 << DCR_FSIZE_SHIFT) | (2 << DCR_CSHT_SHIFT),
+        )
+    }
+
+    fn restore(&mut self) -> Result<()> {
+        let cr = self.core.read_word_32(self.base + QUADSPI_CR)?;
+
+        if cr & CR_EN != 0 {
+            self.abort()?;
+        }
+
+        //
+        // We restore DCR and CCR with the peripheral disabled, lest restoring
+        // CCR start a command; restoring CR then re-enables the peripheral
+        // (and, if the image was using it, memory-mapped mode) as we found
+        // it.
+        //
+        self.core.write_word_32(self.base + QUADSPI_CR, cr & !CR_EN)?;
+        self.core.write_word_32(self.base + QUADSPI_DCR, self.saved.dcr)?;
+        self.core.write_word_32(self.base + QUADSPI_CCR, self.saved.ccr)?;
+        self.core.write_word_32(self.base + QUADSPI_CR, self.saved.cr)?;
+        self.core.write_word_32(self.rcc + RCC_AHB3ENR, self.saved.enr)?;
+
+        Ok(())
+    }
+
+    fn abort(&mut self) -> Result<()> {
+        let cr = self.core.read_word_32(self.base + QUADSPI_CR)?;
+        self.core.write_word_32(self.base + QUADSPI_CR, cr | CR_ABORT)?;
+
+        let started = Instant::now();
+
+        while self.core.read_word_32(self.base + QUADSPI_CR)? & CR_ABORT != 0 {
+            if started.elapsed() > Duration::from_secs(1) {
+                bail!("timed out aborting QUADSPI operation");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn wait(&mut self, mask: u32, set: bool, what: &str) -> Result<()> {
+        let started = Instant::now();
+
+        loop {
+            let sr = self.core.read_word_32(self.base + QUADSPI_SR)?;
+
+            if (sr & mask != 0) == set {
+                return Ok(());
+            }
+
+            if started.elapsed() > Duration::from_secs(1) {
+                bail!("timed out waiting for {} (SR 0x{:x})", what, sr);
+            }
+        }
+    }
+
+    fn complete(&mut self) -> Result<()> {
+        self.wait(SR_TCF, true, "transfer completion")?;
+        self.core.write_word_32(self.base + QUADSPI_FCR, FCR_CTCF)?;
+        self.wait(SR_BUSY, false, "idle")
+    }
+
+    fn start(&mut self, ccr: u32, addr: Option<u32>, len: usize) -> Result<()> {
+        self.wait(SR_BUSY, false, "idle")?;
+
+        if len != 0 {
+            self.core.write_word_32(self.base + QUADSPI_DLR, len as u32 - 1)?;
+        }
+
+        self.core.write_word_32(self.base + QUADSPI_CCR, ccr)?;
+
+        if let Some(addr) = addr {
+            self.core.write_word_32(self.base + QUADSPI_AR, addr)?;
+        }
+
+        Ok(())
+    }
+
+    fn ccr(cmd: u8, addr: Option<u32>, data: bool) -> u32 {
+        let mut ccr = CCR_IMODE_SINGLE | cmd as u32;
+
+        if addr.is_some() {
+            ccr |= CCR_ADMODE_SINGLE | CCR_ADSIZE_24;
+        }
+
+        if data {
+            ccr |= CCR_DMODE_SINGLE;
+        }
+
+        ccr
+    }
+
+    fn read(
+        &mut self,
+        cmd: u8,
+        addr: Option<u32>,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        assert!(!buf.is_empty() && buf.len() <= MAX_READ);
+
+        let ccr = Self::ccr(cmd, addr, true) | CCR_FMODE_READ;
+        self.start(ccr, addr, buf.len())?;
+
+        //
+        // Our read fits in the FIFO, so we can wait for the transfer to
+        // complete before draining it.
+        //
+        self.wait(SR_TCF, true, "read completion")?;
+
+        for chunk in buf.chunks_mut(4) {
+            let val = self.core.read_word_32(self.base + QUADSPI_DR)?;
+            chunk.copy_from_slice(&val.to_le_bytes()[..chunk.len()]);
+        }
+
+        self.complete()
+    }
+
+    fn write(&mut self, cmd: u8, addr: Option<u32>, data: &[u8]) -> Result<()> {
+        assert!(data.len() % 4 == 0);
+
+        let ccr = Self::ccr(cmd, addr, !data.is_empty());
+        self.start(ccr, addr, data.len())?;
+
+        //
+        // Every write goes over the debug link, which is much slower than
+        // the QUADSPI drains the FIFO; we don't need to check its level.
+        //
+        for chunk in data.chunks(4) {
+            let val = u32::from_le_bytes(chunk.try_into().unwrap());
+            self.core.write_word_32(self.base + QUADSPI_DR, val)?;
+        }
+
+        self.complete()
+    }
+
+    fn read_id(&mut self) -> Result<[u8; 3]> {
+        let mut id = [0u8; 3];
+        self.read(CMD_READ_ID, None, &mut id)?;
+        Ok(id)
+    }
+
+    fn wait_ready(&mut self, timeout: Duration) -> Result<()> {
+        let started = Instant::now();
+        let mut status = [0u8; 1];
+
+        loop {
+            self.read(CMD_READ_STATUS, None, &mut status)?;
+
+            if status[0] & STATUS_WIP == 0 {
+                return Ok(());
+            }
+
+            if started.elapsed() > timeout {
+                bail!("timed out waiting for flash operation to complete");
+            }
+        }
+    }
+
+    fn erase(&mut self, addr: u32) -> Result<()> {
+        self.write(CMD_WRITE_ENABLE, None, &[])?;
+        self.write(CMD_SECTOR_ERASE, Some(addr), &[])?;
+        self.wait_ready(Duration::from_secs(5))
+    }
+
+    fn program(&mut self, addr: u32, page: &[u8]) -> Result<()> {
+        self.write(CMD_WRITE_ENABLE, None, &[])?;
+        self.write(CMD_PAGE_PROGRAM, Some(addr), page)?;
+        self.wait_ready(Duration::from_secs(1))
+    }
+
+    fn read_mapped(&mut self, addr: u32, buf: &mut [u8]) -> Result<()> {
+        let ccr = Self::ccr(CMD_READ, Some(addr), true) | CCR_FMODE_MAPPED;
+        self.start(ccr, None, 0)?;
+
+        let rval = self.core.read_8(QUADSPI_MAPPED + addr, buf);
+
+        //
+        // Abort memory-mapped mode (regardless of the success of our read)
+        // so we can issue commands again.
+        //
+        self.abort()?;
+
+        rval
+    }
+}
+
+fn write_slot_configured(
+    flash: &mut DirectFlash,
+    slot: u32,
+    data: &[u8],
+    force: bool,
+) -> Result<()> {
+    flash.configure()?;
+    let id = flash.read_id()?;
+
+    if id.iter().all(|&b| b == 0) || id.iter().all(|&b| b == 0xff) {
+        bail!(
+            "no response from auxiliary flash (JEDEC ID {:x?}); \
+            are the QUADSPI pins configured?",
+            id
+        );
+    }
+
+    //
+    // The third byte of the JEDEC ID is (by convention) the base-2 log of
+    // the capacity of the part.
+    //
+    if !(16..=24).contains(&id[2]) {
+        bail!("unexpected capacity in JEDEC ID {:x?}", id);
+    }
+
+    let size = 1usize << id[2];
+    let nslots = size / SLOT_SIZE_BYTES;
+
+    humility::msg!(
+        "found auxiliary flash with JEDEC ID {:x?} ({} slots)",
+        id,
+        nslots
+    );
+
+    if slot as usize >= nslots {
+        bail!("slot {} is out of range (there are {} slots)", slot, nslots);
+    }
+
+    if data.len() > SLOT_SIZE_BYTES {
+        bail!(
+            "Data is too large ({} bytes, slot size is {} bytes)",
+            data.len(),
+            SLOT_SIZE_BYTES
+        );
+    }
+
+    flash.core.write_word_32(
+        flash.base + QUADSPI_DCR,
+        ((id[2] as u32 - 1) << DCR_FSIZE_SHIFT) | (2 << DCR_CSHT_SHIFT),
+    )?;
+
+    //
+    // Pad our data to a word boundary; erased flash is all 1s, so padding
+    // with 0xff leaves the flash as we found it.
+    //
+    let mut data = data.to_vec();
+    data.resize((data.len() + 3) & !3, 0xff);
+
+    let base = slot * SLOT_SIZE_BYTES as u32;
+    let mut current = vec![0u8; data.len()];
+    flash.read_mapped(base, &mut current)?;
+
+    if current == data {
+        humility::msg!("Slot {} is already programmed with our data", slot);
+
+        if force {
+            humility::msg!("Reprogramming it anyways!");
+        } else {
+            humility::msg!("Skipping reprogramming.");
+            return Ok(());
+        }
+    }
+
+    humility::msg!("erasing slot {}", slot);
+
+    let bar = ProgressBar::new(SLOT_SIZE_BYTES as u64);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("humility: erasing [{bar:30}] {bytes}/{total_bytes}"),
+    );
+
+    for offset in (0..SLOT_SIZE_BYTES).step_by(SECTOR_SIZE) {
+        flash.erase(base + offset as u32)?;
+        bar.set_position((offset + SECTOR_SIZE) as u64);
+    }
+
+    bar.finish_and_clear();
+
+    let bar = ProgressBar::new(data.len() as u64);
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("humility: writing [{bar:30}] {bytes}/{total_bytes}"),
+    );
+
+    for (i, page) in data.chunks(PAGE_SIZE).enumerate() {
+        let offset = i * PAGE_SIZE;
+        flash.program(base + offset as u32, page)?;
+        bar.set_position((offset + page.len()) as u64);
+    }
+
+    bar.finish_and_clear();
+
+    flash.read_mapped(base, &mut current)?;
+
+    if current != data {
+        bail!("auxiliary flash verification failed after programming");
+    }
+
+    humility::msg!("done");
+    Ok(())
+}
+
+///
+/// Programs the specified slot of auxiliary flash directly, halting the
+/// target while doing so.
+///
+pub fn write_slot(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    slot: u32,
+    data: &[u8],
+    force: bool,
+) -> Result<()> {
+    core.halt()?;
+
+    let rval = match DirectFlash::new(hubris, core) {
+        Ok(mut flash) => {
+            let rval = write_slot_configured(&mut flash, slot, data, force);
+
+            //
+            // Restore the peripheral regardless of our success -- but report
+            // the first error that we hit.
+            //
+            let restored = flash.restore();
+            rval.and(restored)
+        }
+        Err(err) => Err(err),
+    };
+
+    core.run()?;
+    rval
+}
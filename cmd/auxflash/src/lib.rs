@@ -8,6 +8,60 @@
 //!
 //! This subcommand should be rarely used; `humility flash` will automatically
 //! program auxiliary flash when needed.
+//!
+//! Auxiliary flash is normally programmed via the AuxFlash task, which
+//! requires that the archive match the running image.  If that's not the
+//! case (e.g., when the running image predates auxiliary flash, or is not
+//! the image in the archive), `humility auxflash write --direct` will instead
+//! program the auxiliary flash directly by driving the QUADSPI peripheral
+//! from the debugger.  The target is halted while this is done, the state of
+//! the QUADSPI peripheral is restored before the target is resumed, and the
+//! auxiliary flash is verified after it is written.  Note that direct
+//! programming is much slower than programming via the AuxFlash task, and
+//! that it requires that the QUADSPI pins have been configured by the
+//! running image (and that auxiliary flash is in fact what is attached to
+//! the QUADSPI peripheral).
+//!
+//! ```console
+//! % humility auxflash write --slot 0 --direct
+//! humility: attached via ST-Link V3
+//! humility: found auxiliary flash with JEDEC ID [20, ba, 18] (16 slots)
+//! humility: erasing slot 0
+//! humility: done
+//! ```
+//!
+//! To check the contents of auxiliary flash against the archive, use
+//! `humility auxflash inspect`.  Each slot's TLV-C contents are read and
+//! checked, and the slot is flagged as matching the archive, stale (that is,
+//! valid but not matching the archive), corrupt or empty:
+//!
+//! ```console
+//! % humility auxflash inspect
+//! humility: attached via ST-Link V3
+//!  slot | status
+//! ------|----------------------------
+//!     0 | Active, matches archive
+//!     1 | matches archive
+//!     2 | stale
+//!     3 | corrupt: body checksum mismatch in chunk SPA3
+//!     4 | empty
+//! ...
+//! ```
+//!
+//! To see the chunks within a slot, along with their sizes and checksums,
+//! specify the slot with `--slot`:
+//!
+//! ```console
+//! % humility auxflash inspect --slot 0
+//! humility: attached via ST-Link V3
+//! humility: slot 0 is active and matches archive
+//! TAG        OFFSET   LENGTH   CHECKSUM STATUS
+//! AUXI     0x000000   176452 0x7c5e9a01 ok
+//!   SPA3   0x00000c   176424 0x0e1b6f3d ok
+//! CHCK     0x02b150       32 0x5d8e44a2 ok
+//! humility: slot checksum:    8b2a03f1c2...
+//! humility: archive checksum: 8b2a03f1c2...
+//! ```
 
 use anyhow::{anyhow, bail, Context, Result};
 use clap::{Command as ClapCommand, CommandFactory, Parser};
@@ -25,6 +79,7 @@ use humility_cmd_hiffy::HiffyLease;
 const SLOT_SIZE_BYTES: usize = 1024 * 1024;
 const READ_CHUNK_SIZE: usize = 256; // limited by HIFFY_SCRATCH_SIZE
 const WRITE_CHUNK_SIZE: usize = 2048; // limited by HIFFY_DATA_SIZE
+const TLVC_HEADER_SIZE: usize = 12; // tag, length and header checksum
 
 #[derive(Parser, Debug)]
 #[clap(name = "auxflash", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -65,10 +120,131 @@ enum AuxFlashCommand {
         slot: u32,
         #[clap(long, short = 'F')]
         force: bool,
+        /// program the auxiliary flash directly rather than via hiffy
+        #[clap(long, short)]
+        direct: bool,
         input: Option<String>,
     },
+    /// Decodes slot contents and compares them against the archive
+    Inspect {
+        /// slot to display in detail (defaults to summarizing all slots)
+        #[clap(long, short)]
+        slot: Option<u32>,
+    },
+}
+
+/// A chunk found when walking the TLV-C contents of a slot
+struct TlvcChunk {
+    depth: usize,
+    tag: [u8; 4],
+    offset: usize,
+    len: usize,
+    checksum: u32,
+    valid: bool,
+}
+
+/// The disposition of a slot relative to the archive
+enum SlotContents {
+    Empty,
+    Corrupt { reason: String, chunks: Vec<TlvcChunk> },
+    Valid { chunks: Vec<TlvcChunk>, chck: [u8; 32] },
+}
+
+fn tag_str(tag: &[u8; 4]) -> String {
+    tag.iter()
+        .map(|&c| if c.is_ascii_graphic() { c as char } else { '.' })
+        .collect()
+}
+
+///
+/// Finds the CHCK chunk in TLV-C data, returning its contents.
+///
+fn read_chck(data: &[u8]) -> Result<Option<[u8; 32]>> {
+    let mut reader = tlvc::TlvcReader::begin(data)
+        .map_err(|e| anyhow!("TlvcReader::begin failed: {:?}", e))?;
+
+    loop {
+        match reader.next() {
+            Ok(Some(chunk)) => {
+                if &chunk.header().tag == b"CHCK" {
+                    if chunk.len() != 32 {
+                        bail!("CHCK chunk has bad length {}", chunk.len());
+                    }
+
+                    let mut out = [0; 32];
+                    chunk.read_exact(0, &mut out).map_err(|e| {
+                        anyhow!("Failed to read chunk: {:?}", e)
+                    })?;
+                    return Ok(Some(out));
+                }
+            }
+            Ok(None) => return Ok(None),
+            Err(e) => bail!("{:?}", e),
+        }
+    }
+}
+
+///
+/// Walks the chunks in TLV-C data, recording each chunk and its offset.  A
+/// chunk whose body itself parses as TLV-C (as the AUXI chunk does) is
+/// walked in turn.
+///
+fn tlvc_walk(
+    mut reader: tlvc::TlvcReader<&[u8]>,
+    data: &[u8],
+    mut offset: usize,
+    depth: usize,
+    chunks: &mut Vec<TlvcChunk>,
+) -> Result<()> {
+    let mut scratch = [0u8; 256];
+
+    loop {
+        let chunk = match reader.next() {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(e) => {
+                bail!("bad chunk at offset 0x{:x}: {:?}", offset, e);
+            }
+        };
+
+        let len = chunk.len() as usize;
+        let body = offset + TLVC_HEADER_SIZE;
+        let end = body + ((len + 3) & !3);
+
+        let checksum = match data.get(end..end + 4) {
+            Some(c) => u32::from_le_bytes(c.try_into().unwrap()),
+            None => bail!("chunk at offset 0x{:x} is truncated", offset),
+        };
+
+        let valid = chunk.check_body_checksum(&mut scratch).is_ok();
+        let tag = chunk.header().tag;
+
+        chunks.push(TlvcChunk { depth, tag, offset, len, checksum, valid });
+
+        if valid {
+            let mut nested = vec![];
+
+            if tlvc_walk(
+                chunk.read_as_chunks(),
+                data,
+                body,
+                depth + 1,
+                &mut nested,
+            )
+            .is_ok()
+            {
+                chunks.extend(nested);
+            }
+        }
+
+        offset = end + 4;
+    }
+
+    Ok(())
 }
 
+mod direct;
+
 pub struct AuxFlashHandler<'a> {
     hubris: &'a HubrisArchive,
     core: &'a mut dyn Core,
@@ -216,6 +392,224 @@ impl<'a> AuxFlashHandler<'a> {
         Ok(())
     }
 
+    fn slot_read_chunk(
+        &mut self,
+        op: &IdolOperation,
+        slot: u32,
+        offset: usize,
+        chunk: &mut [u8],
+    ) -> Result<()> {
+        let value = humility_cmd_hiffy::hiffy_call(
+            self.hubris,
+            self.core,
+            &mut self.context,
+            op,
+            &[
+                ("slot", IdolArgument::Scalar(slot as u64)),
+                ("offset", IdolArgument::Scalar(offset as u64)),
+            ],
+            Some(HiffyLease::Read(chunk)),
+        )?;
+        if let Err(e) = value {
+            bail!("Got Hubris error: {:?}", e);
+        }
+        Ok(())
+    }
+
+    fn slot_read_at(
+        &mut self,
+        slot: u32,
+        offset: usize,
+        out: &mut [u8],
+    ) -> Result<()> {
+        let op = self.get_idol_command("read_slot_with_offset")?;
+
+        for (i, chunk) in out.chunks_mut(READ_CHUNK_SIZE).enumerate() {
+            let offset = offset + i * READ_CHUNK_SIZE;
+            self.slot_read_chunk(&op, slot, offset, chunk)?;
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Reads and decodes the TLV-C contents of a slot.  Rather than read the
+    /// entire slot, we walk the top-level chunk headers to determine the
+    /// extent of the data, and then read only that.
+    ///
+    fn slot_contents(&mut self, slot: u32) -> Result<SlotContents> {
+        let mut extent = 0;
+
+        loop {
+            let mut header = [0u8; TLVC_HEADER_SIZE];
+
+            if extent + TLVC_HEADER_SIZE > SLOT_SIZE_BYTES {
+                break;
+            }
+
+            self.slot_read_at(slot, extent, &mut header)?;
+
+            //
+            // An erased header denotes the end of the data.
+            //
+            if header.iter().all(|&b| b == 0xff) {
+                break;
+            }
+
+            let len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            let len = TLVC_HEADER_SIZE + ((len as usize + 3) & !3) + 4;
+
+            if extent + len > SLOT_SIZE_BYTES {
+                return Ok(SlotContents::Corrupt {
+                    reason: format!(
+                        "chunk at offset 0x{:x} exceeds slot",
+                        extent
+                    ),
+                    chunks: vec![],
+                });
+            }
+
+            extent += len;
+        }
+
+        if extent == 0 {
+            return Ok(SlotContents::Empty);
+        }
+
+        let mut data = vec![0u8; extent];
+        self.slot_read_at(slot, 0, &mut data)?;
+
+        let reader = match tlvc::TlvcReader::begin(&data[..]) {
+            Ok(reader) => reader,
+            Err(e) => bail!("TlvcReader::begin failed: {:?}", e),
+        };
+
+        let mut chunks = vec![];
+
+        let reason = if let Err(e) = tlvc_walk(reader, &data, 0, 0, &mut chunks)
+        {
+            e.to_string()
+        } else if let Some(c) = chunks.iter().find(|c| !c.valid) {
+            format!("body checksum mismatch in chunk {}", tag_str(&c.tag))
+        } else {
+            match read_chck(&data) {
+                Ok(Some(chck)) => {
+                    return Ok(SlotContents::Valid { chunks, chck });
+                }
+                Ok(None) => "missing CHCK".to_string(),
+                Err(e) => e.to_string(),
+            }
+        };
+
+        Ok(SlotContents::Corrupt { reason, chunks })
+    }
+
+    fn auxflash_inspect(&mut self, slot: Option<u32>) -> Result<()> {
+        let archive = match self.hubris.read_auxflash_data()? {
+            Some(data) => read_chck(&data)?,
+            None => None,
+        };
+
+        let active_slot = self.active_slot()?;
+
+        let disposition = |chck: &[u8; 32]| match archive {
+            Some(archive) if archive == *chck => "matches archive".green(),
+            Some(_) => "stale".yellow(),
+            None => "valid".blue(),
+        };
+
+        let slot = match slot {
+            Some(slot) => slot,
+            None => {
+                let slot_count = self.slot_count()?;
+                println!(" {} | {}", "slot".bold(), "status".bold());
+                println!("------|----------------------------");
+
+                for i in 0..slot_count {
+                    print!("  {:>3} | ", i);
+
+                    if active_slot == Some(i) {
+                        print!("{}, ", "Active".green());
+                    }
+
+                    match self.slot_contents(i) {
+                        Err(e) => println!("Error: {}", e.to_string().red()),
+                        Ok(SlotContents::Empty) => println!("empty"),
+                        Ok(SlotContents::Corrupt { reason, .. }) => {
+                            println!("{}: {}", "corrupt".red(), reason)
+                        }
+                        Ok(SlotContents::Valid { chck, .. }) => {
+                            println!("{}", disposition(&chck))
+                        }
+                    }
+                }
+
+                return Ok(());
+            }
+        };
+
+        let active =
+            if active_slot == Some(slot) { "is active and" } else { "is" };
+
+        let (chunks, chck) = match self.slot_contents(slot)? {
+            SlotContents::Empty => {
+                humility::msg!("slot {} is empty", slot);
+                return Ok(());
+            }
+            SlotContents::Corrupt { reason, chunks } => {
+                humility::msg!("slot {} {} {}", slot, active, "corrupt".red());
+                (chunks, Err(reason))
+            }
+            SlotContents::Valid { chunks, chck } => {
+                humility::msg!(
+                    "slot {} {} {}",
+                    slot,
+                    active,
+                    disposition(&chck)
+                );
+                (chunks, Ok(chck))
+            }
+        };
+
+        println!(
+            "{:8} {:>8} {:>8} {:>10} STATUS",
+            "TAG", "OFFSET", "LENGTH", "CHECKSUM"
+        );
+
+        for c in &chunks {
+            println!(
+                "{:8} {:>#8x} {:>8} {:>#10x} {}",
+                format!(
+                    "{:indent$}{}",
+                    "",
+                    tag_str(&c.tag),
+                    indent = c.depth * 2
+                ),
+                c.offset,
+                c.len,
+                c.checksum,
+                if c.valid { "ok".normal() } else { "corrupt".red() }
+            );
+        }
+
+        let hex = |chck: &[u8; 32]| {
+            chck.iter().map(|b| format!("{:02x}", b)).collect::<String>()
+        };
+
+        let chck = match chck {
+            Ok(chck) => chck,
+            Err(reason) => bail!("slot {} is corrupt: {}", slot, reason),
+        };
+
+        humility::msg!("slot checksum:    {}", hex(&chck));
+
+        if let Some(archive) = archive {
+            humility::msg!("archive checksum: {}", hex(&archive));
+        }
+
+        Ok(())
+    }
+
     fn auxflash_read(
         &mut self,
         slot: u32,
@@ -232,20 +626,7 @@ impl<'a> AuxFlashHandler<'a> {
         bar.set_length(out.len() as u64);
         for (i, chunk) in out.chunks_mut(READ_CHUNK_SIZE).enumerate() {
             let offset = i * READ_CHUNK_SIZE;
-            let value = humility_cmd_hiffy::hiffy_call(
-                self.hubris,
-                self.core,
-                &mut self.context,
-                &op,
-                &[
-                    ("slot", IdolArgument::Scalar(slot as u64)),
-                    ("offset", IdolArgument::Scalar(offset as u64)),
-                ],
-                Some(HiffyLease::Read(chunk)),
-            )?;
-            if let Err(e) = value {
-                bail!("Got Hubris error: {:?}", e);
-            }
+            self.slot_read_chunk(&op, slot, offset, chunk)?;
             bar.set_position(offset as u64);
         }
 
@@ -261,33 +642,17 @@ impl<'a> AuxFlashHandler<'a> {
         // If the input data can be parsed as TLV-C, then check against
         // the slot checksum currently loaded in auxiliary flash, skipping
         // flashing if they match.
-        let mut reader = tlvc::TlvcReader::begin(data)
-            .map_err(|e| anyhow!("TlvcReader::begin failed: {:?}", e))?;
-        let mut chck_data = None;
-        loop {
-            match reader.next() {
-                Ok(Some(chunk)) => {
-                    if &chunk.header().tag == b"CHCK" {
-                        assert_eq!(chunk.len(), 32);
-                        let mut out = [0; 32];
-                        chunk.read_exact(0, &mut out).map_err(|e| {
-                            anyhow!("Failed to read chunk: {:?}", e)
-                        })?;
-                        chck_data = Some(out);
-                        break;
-                    }
-                }
-                Ok(None) => break,
-                Err(e) => {
-                    humility::msg!(
-                        "Failed to load data as TLV-C ({:?}); \
-                         skipping reflash check",
-                        e
-                    );
-                    break;
-                }
+        let chck_data = match read_chck(data) {
+            Ok(chck) => chck,
+            Err(e) => {
+                humility::msg!(
+                    "Failed to load data as TLV-C ({:?}); \
+                     skipping reflash check",
+                    e
+                );
+                None
             }
-        }
+        };
         if let Some(chck_data) = chck_data {
             if let Ok(Some(chck_slot)) = self.slot_status(slot) {
                 if chck_data == chck_slot {
@@ -373,6 +738,47 @@ fn auxflash(
     subargs: &[String],
 ) -> Result<()> {
     let subargs = AuxFlashArgs::try_parse_from(subargs)?;
+
+    //
+    // We can only use hiffy if the archive matches the running image and
+    // the image has an AuxFlash task.  If that's not the case, we can
+    // program the auxiliary flash directly -- but because that means taking
+    // over the QUADSPI peripheral from underneath the running image (which
+    // may be using it for something else entirely), we only do so when
+    // explicitly asked.
+    //
+    let hiffy = hubris.validate(core, HubrisValidate::Booted).is_ok()
+        && IdolOperation::new(hubris, "AuxFlash", "slot_count", None).is_ok();
+
+    if let AuxFlashCommand::Write { slot, force, direct, ref input } =
+        subargs.cmd
+    {
+        if !hiffy && !direct {
+            bail!(
+                "AuxFlash task not available; use --direct to program \
+                auxiliary flash directly"
+            );
+        }
+
+        if direct {
+            let data = match input {
+                Some(input) => std::fs::read(input)?,
+                None => hubris.read_auxflash_data()?.ok_or_else(|| {
+                    anyhow!(
+                        "Could not find auxiliary data in the archive. \
+                         Does this Hubris app include auxiliary blobs?"
+                    )
+                })?,
+            };
+
+            return direct::write_slot(hubris, core, slot, &data, force);
+        }
+    }
+
+    if !hiffy {
+        hubris.validate(core, HubrisValidate::Booted)?;
+    }
+
     let mut worker = AuxFlashHandler::new(hubris, core, subargs.timeout)?;
     match subargs.cmd {
         AuxFlashCommand::Status { verbose } => {
@@ -385,7 +791,7 @@ fn auxflash(
             let data = worker.auxflash_read(slot, count)?;
             std::fs::write(&output, &data)?;
         }
-        AuxFlashCommand::Write { slot, input, force } => match input {
+        AuxFlashCommand::Write { slot, input, force, .. } => match input {
             Some(input) => {
                 let data = std::fs::read(&input)?;
                 worker.auxflash_write(slot, &data, force)?;
@@ -396,6 +802,9 @@ fn auxflash(
                 worker.auxflash_write_from_archive(slot, force)?;
             }
         },
+        AuxFlashCommand::Inspect { slot } => {
+            worker.auxflash_inspect(slot)?;
+        }
     }
     Ok(())
 }
@@ -406,7 +815,11 @@ pub fn init() -> (Command, ClapCommand<'static>) {
             name: "auxflash",
             archive: Archive::Required,
             attach: Attach::LiveOnly,
-            validate: Validate::Booted,
+            //
+            // We validate the image ourselves, as programming auxiliary flash
+            // directly doesn't require the archive to match.
+            //
+            validate: Validate::None,
             run: Run::Subargs(auxflash),
         },
         AuxFlashArgs::command(),
@@ -0,0 +1,612 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Dashboard layouts, as specified with `humility dashboard --layout`.  A
+// layout is a TOML file of panels, each of which has a title, optional
+// units, and a list of series; each series is drawn from exactly one
+// source:  a sensor (or all sensors of a kind), a PMBus command on a rail,
+// a global variable (or a field within one), or the number of times a task
+// has restarted.
+//
+
+use anyhow::{bail, Context, Result};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility::reflect::{self, Base};
+use humility_cmd::doppel::{GenOrRestartCount, Task};
+use humility_cmd::hiffy::*;
+use humility_cmd::i2c::I2cArgs;
+use humility_cmd::idol;
+use humility_cmd::tasks::read_tasks;
+use pmbus::commands::*;
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayoutFile {
+    panel: Vec<PanelSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PanelSpec {
+    title: String,
+    units: Option<String>,
+    series: Vec<SeriesSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SeriesSpec {
+    name: Option<String>,
+    sensor: Option<String>,
+    kind: Option<String>,
+    rail: Option<String>,
+    command: Option<String>,
+    variable: Option<String>,
+    task: Option<String>,
+}
+
+pub enum Source<'a> {
+    Sensor(usize),
+    Pmbus {
+        device: &'a HubrisI2cDevice,
+        driver: pmbus::Device,
+        page: Option<u8>,
+        code: u8,
+    },
+    Variable {
+        addr: u32,
+        ty: &'a HubrisBasetype,
+    },
+    Restarts {
+        ndx: usize,
+        last: Option<GenOrRestartCount>,
+        count: u32,
+    },
+}
+
+pub struct Panel<'a> {
+    pub title: String,
+    pub units: Option<String>,
+    pub series: Vec<(String, Source<'a>)>,
+}
+
+///
+/// Returns all sensors that match the specified criteria as named sources.
+///
+pub fn sensors(
+    hubris: &HubrisArchive,
+    capture: impl Fn(&HubrisSensor) -> bool,
+) -> Vec<(String, Source<'_>)> {
+    hubris
+        .manifest
+        .sensors
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| capture(s))
+        .map(|(i, s)| (s.name.clone(), Source::Sensor(i)))
+        .collect()
+}
+
+fn find_rail<'a>(
+    hubris: &'a HubrisArchive,
+    rail: &str,
+) -> Result<(&'a HubrisI2cDevice, Option<u8>)> {
+    let mut found = None;
+
+    for device in &hubris.manifest.i2c_devices {
+        if let HubrisI2cDeviceClass::Pmbus { rails } = &device.class {
+            for (rnum, r) in rails.iter().enumerate() {
+                if rail != r {
+                    continue;
+                }
+
+                if found.is_some() {
+                    bail!("multiple devices match rail {}", rail);
+                }
+
+                let page =
+                    if rails.len() > 1 { Some(rnum as u8) } else { None };
+                found = Some((device, page));
+            }
+        }
+    }
+
+    match found {
+        Some(found) => Ok(found),
+        None => bail!("rail {} not found", rail),
+    }
+}
+
+fn pmbus_source<'a>(
+    hubris: &'a HubrisArchive,
+    rail: &str,
+    command: &str,
+) -> Result<Source<'a>> {
+    let (device, page) = find_rail(hubris, rail)?;
+
+    let driver = match pmbus::Device::from_str(&device.device) {
+        Some(driver) => driver,
+        None => pmbus::Device::Common,
+    };
+
+    let mut found = None;
+
+    for code in 0..=255u8 {
+        driver.command(code, |cmd| {
+            let readable = matches!(
+                cmd.read_op(),
+                pmbus::Operation::ReadByte
+                    | pmbus::Operation::ReadWord
+                    | pmbus::Operation::ReadWord32
+            );
+
+            if cmd.name() == command && readable {
+                found = Some(code);
+            }
+        });
+    }
+
+    match found {
+        Some(code) => Ok(Source::Pmbus { device, driver, page, code }),
+        None => bail!("{} is not a readable command on {}", command, rail),
+    }
+}
+
+///
+/// Resolves a variable (and any fields) to the address and type of the base
+/// type that we are to graph.
+///
+fn variable_source<'a>(
+    hubris: &'a HubrisArchive,
+    spec: &str,
+) -> Result<Source<'a>> {
+    let mut fields = spec.split('.');
+    let name = fields.next().unwrap();
+    let var = hubris.lookup_variable(name)?;
+
+    let mut addr = var.addr;
+    let mut goff = var.goff;
+    let mut path = name.to_string();
+
+    for field in fields {
+        let s = hubris
+            .lookup_struct(goff)
+            .with_context(|| format!("{} is not a structure", path))?;
+
+        let m = s.lookup_member(field)?;
+
+        addr += m.offset as u32;
+        goff = m.goff;
+        path = format!("{}.{}", path, field);
+    }
+
+    let ty = hubris
+        .lookup_basetype(goff)
+        .with_context(|| format!("{} is not a numeric value", spec))?;
+
+    if ty.encoding == HubrisEncoding::Unknown || ty.size == 0 {
+        bail!("{} is not a numeric value", spec);
+    }
+
+    Ok(Source::Variable { addr, ty })
+}
+
+fn series<'a>(
+    hubris: &'a HubrisArchive,
+    spec: &SeriesSpec,
+) -> Result<Vec<(String, Source<'a>)>> {
+    let name = |default: &str| match spec.name {
+        Some(ref name) => name.clone(),
+        None => default.to_string(),
+    };
+
+    if spec.command.is_some() && spec.rail.is_none() {
+        bail!("command specified without a rail");
+    }
+
+    let source = match (
+        &spec.sensor,
+        &spec.kind,
+        &spec.rail,
+        &spec.variable,
+        &spec.task,
+    ) {
+        (Some(sensor), kind, None, None, None) => {
+            let kind = match kind {
+                Some(kind) => match HubrisSensorKind::from_string(kind) {
+                    Some(kind) => Some(kind),
+                    None => bail!("unrecognized sensor kind \"{}\"", kind),
+                },
+                None => None,
+            };
+
+            //
+            // A device can have several sensors with the same name (e.g.,
+            // the voltage, current and power of a rail), so a sensor may
+            // need to be qualified with its kind.
+            //
+            let matches = hubris
+                .manifest
+                .sensors
+                .iter()
+                .enumerate()
+                .filter(|(_, s)| &s.name == sensor)
+                .filter(|(_, s)| kind.map_or(true, |k| s.kind == k))
+                .collect::<Vec<_>>();
+
+            match matches.as_slice() {
+                [(ndx, _)] => (name(sensor), Source::Sensor(*ndx)),
+                [] => match kind {
+                    Some(kind) => {
+                        bail!("unknown {} sensor {}", kind.to_string(), sensor)
+                    }
+                    None => bail!("unknown sensor {}", sensor),
+                },
+                [..] => {
+                    let mut kinds = vec![];
+
+                    for (_, s) in &matches {
+                        if !kinds.contains(&s.kind.to_string()) {
+                            kinds.push(s.kind.to_string());
+                        }
+                    }
+
+                    if kinds.len() > 1 {
+                        bail!(
+                            "sensor {} is ambiguous; specify its kind \
+                            (one of {})",
+                            sensor,
+                            kinds.join(", ")
+                        );
+                    }
+
+                    bail!("multiple {} sensors are named {}", kinds[0], sensor);
+                }
+            }
+        }
+
+        (None, Some(kind), None, None, None) => {
+            if spec.name.is_some() {
+                bail!("a name cannot be specified for a kind of sensor");
+            }
+
+            return match HubrisSensorKind::from_string(kind) {
+                Some(kind) => Ok(sensors(hubris, |s| s.kind == kind)),
+                None => bail!("unrecognized sensor kind \"{}\"", kind),
+            };
+        }
+
+        (None, None, Some(rail), None, None) => match &spec.command {
+            Some(command) => (
+                name(&format!("{} {}", rail, command)),
+                pmbus_source(hubris, rail, command)?,
+            ),
+            None => bail!("rail {} requires a command", rail),
+        },
+
+        (None, None, None, Some(variable), None) => {
+            (name(variable), variable_source(hubris, variable)?)
+        }
+
+        (None, None, None, None, Some(task)) => {
+            match hubris.lookup_task(task) {
+                Some(HubrisTask::Task(ndx)) => (
+                    name(task),
+                    Source::Restarts {
+                        ndx: *ndx as usize,
+                        last: None,
+                        count: 0,
+                    },
+                ),
+                _ => bail!("unknown task {}", task),
+            }
+        }
+
+        _ => bail!(
+            "series must have exactly one of sensor, kind, rail, \
+            variable, or task"
+        ),
+    };
+
+    Ok(vec![source])
+}
+
+pub fn load<'a>(
+    hubris: &'a HubrisArchive,
+    filename: &str,
+) -> Result<Vec<Panel<'a>>> {
+    let contents = std::fs::read_to_string(filename)
+        .with_context(|| format!("failed to read {}", filename))?;
+
+    let file: LayoutFile = toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", filename))?;
+
+    let mut panels = vec![];
+
+    for p in file.panel {
+        let mut all = vec![];
+
+        for spec in &p.series {
+            all.extend(series(hubris, spec).with_context(|| {
+                format!("{}: panel \"{}\"", filename, p.title)
+            })?);
+        }
+
+        if all.is_empty() {
+            bail!("{}: panel \"{}\" has no series", filename, p.title);
+        }
+
+        panels.push(Panel { title: p.title, units: p.units, series: all });
+    }
+
+    if panels.is_empty() {
+        bail!("{}: no panels specified", filename);
+    }
+
+    Ok(panels)
+}
+
+impl<'a> Source<'a> {
+    ///
+    /// Returns the number of HIF results that this source's ops generate.
+    ///
+    fn nresults(&self) -> usize {
+        match self {
+            Source::Sensor(_) => 1,
+            Source::Pmbus { page, .. } => 2 + page.map_or(0, |_| 1),
+            Source::Variable { .. } | Source::Restarts { .. } => 0,
+        }
+    }
+
+    ///
+    /// Appends the HIF ops (if any) needed to read this source.
+    ///
+    pub fn ops(
+        &self,
+        hubris: &HubrisArchive,
+        context: &mut HiffyContext,
+        ops: &mut Vec<Op>,
+    ) -> Result<()> {
+        let funcs = context.functions()?;
+
+        match self {
+            Source::Sensor(ndx) => {
+                let op =
+                    idol::IdolOperation::new(hubris, "Sensor", "get", None)?;
+
+                let ok = hubris.lookup_basetype(op.ok)?;
+
+                if ok.encoding != HubrisEncoding::Float || ok.size != 4 {
+                    bail!(
+                        "expected return value of read_sensor() to be an f32"
+                    );
+                }
+
+                let payload = op.payload(&[(
+                    "id",
+                    idol::IdolArgument::Scalar(*ndx as u64),
+                )])?;
+                context.idol_call_ops(&funcs, &op, &payload, ops)?;
+            }
+
+            Source::Pmbus { device, driver, page, code } => {
+                let func = funcs.get("I2cRead", 7)?;
+                let write_func = funcs.get("I2cWrite", 8)?;
+                let harg = I2cArgs::from_device(device);
+
+                ops.push(Op::Push(harg.controller));
+                ops.push(Op::Push(harg.port.index));
+
+                if let Some(mux) = harg.mux {
+                    ops.push(Op::Push(mux.0));
+                    ops.push(Op::Push(mux.1));
+                } else {
+                    ops.push(Op::PushNone);
+                    ops.push(Op::PushNone);
+                }
+
+                ops.push(Op::Push(harg.address.unwrap()));
+
+                if let Some(page) = page {
+                    ops.push(Op::Push(CommandCode::PAGE as u8));
+                    ops.push(Op::Push(*page));
+                    ops.push(Op::Push(1));
+                    ops.push(Op::Call(write_func.id));
+                    ops.push(Op::DropN(3));
+                }
+
+                for code in [CommandCode::VOUT_MODE as u8, *code] {
+                    let mut len = None;
+
+                    driver.command(code, |cmd| {
+                        len = match cmd.read_op() {
+                            pmbus::Operation::ReadByte => Some(1),
+                            pmbus::Operation::ReadWord => Some(2),
+                            pmbus::Operation::ReadWord32 => Some(4),
+                            _ => None,
+                        };
+                    });
+
+                    match len {
+                        Some(len) => {
+                            ops.push(Op::Push(code));
+                            ops.push(Op::Push(len));
+                            ops.push(Op::Call(func.id));
+                            ops.push(Op::DropN(2));
+                        }
+                        None => bail!("can't read command 0x{:02x}", code),
+                    }
+                }
+
+                ops.push(Op::DropN(5));
+            }
+
+            Source::Variable { .. } | Source::Restarts { .. } => {}
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Returns the value of this source.  This is called with the core
+    /// halted if there are any variables to be read from it.
+    ///
+    fn value(
+        &mut self,
+        core: &mut dyn Core,
+        results: &[Result<Vec<u8>, u32>],
+        tasks: Option<&[Task]>,
+    ) -> Result<Option<f32>> {
+        match self {
+            Source::Sensor(_) => Ok(match &results[0] {
+                Ok(val) => Some(f32::from_le_bytes(val[0..4].try_into()?)),
+                Err(_) => None,
+            }),
+
+            Source::Pmbus { driver, page, code, .. } => {
+                let results = &results[page.map_or(0, |_| 1)..];
+
+                let (mode, val) = match (&results[0], &results[1]) {
+                    (Ok(mode), Ok(val)) => {
+                        match VOUT_MODE::CommandData::from_slice(mode) {
+                            Some(mode) => (mode, val),
+                            None => return Ok(None),
+                        }
+                    }
+                    _ => return Ok(None),
+                };
+
+                //
+                // We graph the first field of the command that isn't a
+                // bitfield, taking the numeric portion of its interpreted
+                // value (e.g., "11.987V") as our value.
+                //
+                let mut rval = None;
+
+                let _ = driver.interpret(
+                    *code,
+                    val,
+                    || mode,
+                    |field, value| {
+                        if !field.bitfield() && rval.is_none() {
+                            rval = numeric(&value.to_string());
+                        }
+                    },
+                );
+
+                Ok(rval)
+            }
+
+            Source::Variable { addr, ty } => {
+                let mut buf = vec![0u8; ty.size];
+                core.read_8(*addr, &mut buf)?;
+
+                Ok(match reflect::load_base(&buf, ty, 0)? {
+                    Base::I8(v) => Some(v as f32),
+                    Base::I16(v) => Some(v as f32),
+                    Base::I32(v) => Some(v as f32),
+                    Base::I64(v) => Some(v as f32),
+                    Base::I128(v) => Some(v as f32),
+                    Base::U8(v) => Some(v as f32),
+                    Base::U16(v) => Some(v as f32),
+                    Base::U32(v) => Some(v as f32),
+                    Base::U64(v) => Some(v as f32),
+                    Base::U128(v) => Some(v as f32),
+                    Base::Bool(v) => Some(if v { 1.0 } else { 0.0 }),
+                    Base::F32(v) => Some(v),
+                    Base::F64(v) => Some(v as f32),
+                    Base::U0 => None,
+                })
+            }
+
+            Source::Restarts { ndx, last, count } => {
+                let current = match tasks.and_then(|tasks| tasks.get(*ndx)) {
+                    Some(task) => task.generation,
+                    None => return Ok(None),
+                };
+
+                //
+                // Older kernels only have a 6-bit generation that wraps;
+                // newer kernels have a restart count.  Either way, we count
+                // the restarts since we started watching.
+                //
+                *count += last
+                    .and_then(|last| current.restarts_since(last))
+                    .unwrap_or(0);
+
+                *last = Some(current);
+
+                Ok(Some(*count as f32))
+            }
+        }
+    }
+}
+
+///
+/// Returns the numeric prefix of an interpreted value, if any.
+///
+fn numeric(val: &str) -> Option<f32> {
+    let val = val.trim();
+    let end = val
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-'))
+        .unwrap_or(val.len());
+
+    val[..end].parse::<f32>().ok()
+}
+
+///
+/// Given the results of running the ops for the specified sources, returns
+/// the value of each source.  Sources that aren't read via HIF are read
+/// directly from the target, which is halted once for all of them.
+///
+pub fn values(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    sources: &mut [Source],
+    results: &[Result<Vec<u8>, u32>],
+) -> Result<Vec<Option<f32>>> {
+    let restarts = sources.iter().any(|s| matches!(s, Source::Restarts { .. }));
+    let direct = sources.iter().any(|s| matches!(s, Source::Variable { .. }));
+
+    //
+    // The task table is only read if there's a restart count to graph.
+    // (Reading it halts and resumes the core, so we do it before halting
+    // the core to read any variables.)
+    //
+    let tasks = if restarts { Some(read_tasks(hubris, core)?) } else { None };
+
+    if direct {
+        core.halt()?;
+    }
+
+    let mut offs = 0;
+    let mut rval = vec![];
+
+    for source in sources.iter_mut() {
+        let n = source.nresults();
+
+        match source.value(core, &results[offs..offs + n], tasks.as_deref()) {
+            Ok(val) => rval.push(val),
+            Err(err) => {
+                if direct {
+                    core.run()?;
+                }
+
+                return Err(err);
+            }
+        }
+
+        offs += n;
+    }
+
+    if direct {
+        core.run()?;
+    }
+
+    Ok(rval)
+}
@@ -9,7 +9,59 @@
 //! `humility sensors` for more details.)
 //!
 //! If `-o` is provided, it specifies an output file for any raw sensor data
-//! graphed by the dashboard.
+//! graphed by the dashboard.  This file can later be replayed without an
+//! attached system (or an archive) by specifying it with `--replay`:
+//!
+//! ```console
+//! % humility dashboard --replay overnight.csv
+//! ```
+//!
+//! When replaying, the graphs can be scrubbed through time with the left and
+//! right arrow keys (or to the beginning and end with Home and End), and
+//! zoomed with `+` and `-`, as with a live dashboard.
+//!
+//! By default, the dashboard displays temperature, fan speed and current
+//! sensors.  To display other data, a layout file may be specified with
+//! `--layout`.  A layout consists of panels, each with a title, optional
+//! units, and a list of series.  Each series is one of a sensor (`sensor`),
+//! all sensors of a kind (`kind`), a PMBus command on a rail (`rail` and
+//! `command`), a numeric global variable or a field within one (`variable`),
+//! or the number of times a task has restarted since the dashboard was
+//! started (`task`); a series other than `kind` can be given a `name` to
+//! display in the legend.  As a device can have several sensors with the
+//! same name (e.g., the voltage, current and power of a rail), a `sensor`
+//! can be qualified with a `kind` -- and must be if the name is otherwise
+//! ambiguous:
+//!
+//! ```toml
+//! [[panel]]
+//! title = "Temperature"
+//! units = "°C"
+//! series = [ { kind = "temp" } ]
+//!
+//! [[panel]]
+//! title = "VDD_VCORE"
+//! units = "A"
+//! series = [ { rail = "VDD_VCORE", command = "READ_IOUT", name = "IOUT" } ]
+//!
+//! [[panel]]
+//! title = "V3P3_SYS_A0"
+//! units = "V"
+//! series = [ { sensor = "V3P3_SYS_A0", kind = "voltage" } ]
+//!
+//! [[panel]]
+//! title = "Restarts"
+//! series = [ { task = "net" }, { task = "thermal" } ]
+//! ```
+//!
+//! Variables and the task table are read directly from the target, which is
+//! briefly halted to do so (once to read the task table, and once to read
+//! all variables).
+//!
+//! If `--thresholds` is provided, it specifies a file of sensor thresholds
+//! (see `humility sensors` for the format).  Thresholds are drawn as lines on
+//! the graph of each sensor that has them (the critical threshold in red),
+//! and any sensor that is out of range has its value flagged in the legend.
 //!
 
 use anyhow::{bail, Result};
@@ -28,7 +80,8 @@ use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
 use humility_cmd::idol;
-use humility_cmd::{Archive, Attach, Command, Run, Validate};
+use humility_cmd::thresholds::{Threshold, Thresholds};
+use humility_cmd::{Archive, Args, Attach, Command, RunUnattached, Validate};
 use std::fs::File;
 use std::io;
 use std::io::Write;
@@ -40,8 +93,8 @@ use tui::{
     symbols,
     text::{Span, Spans},
     widgets::{
-        Axis, Block, Borders, Chart, Dataset, List, ListItem, ListState,
-        Paragraph,
+        Axis, Block, Borders, Chart, Dataset, GraphType, List, ListItem,
+        ListState, Paragraph,
     },
     Frame, Terminal,
 };
@@ -59,8 +112,26 @@ struct DashboardArgs {
     /// CSV output file
     #[clap(long, short)]
     output: Option<String>,
+
+    /// file of sensor thresholds to draw and check
+    #[clap(long, value_name = "file")]
+    thresholds: Option<String>,
+
+    /// file specifying the panels to display
+    #[clap(long, value_name = "file")]
+    layout: Option<String>,
+
+    /// replay a CSV file previously written with -o
+    #[clap(
+        long, value_name = "file",
+        conflicts_with_all = &["output", "thresholds", "layout"]
+    )]
+    replay: Option<String>,
 }
 
+mod layout;
+mod replay;
+
 struct StatefulList {
     state: ListState,
     n: usize,
@@ -92,6 +163,8 @@ struct Series {
     color: Color,
     data: Vec<(f64, f64)>,
     raw: Vec<Option<f32>>,
+    threshold: Option<Threshold>,
+    lines: Vec<(String, Color, Vec<(f64, f64)>)>,
 }
 
 trait Attributes {
@@ -213,6 +286,49 @@ impl Attributes for CurrentGraph {
     }
 }
 
+///
+/// Attributes for a graph for which we know nothing beyond its label and
+/// (possibly) its units -- e.g., a panel from a layout file, or a graph
+/// from a replayed file that doesn't identify its graphs.
+///
+struct GenericGraph {
+    label: String,
+    units: Option<String>,
+}
+
+impl GenericGraph {
+    fn new(label: &str, units: Option<&str>) -> Self {
+        Self { label: label.to_string(), units: units.map(str::to_string) }
+    }
+}
+
+impl Attributes for GenericGraph {
+    fn label(&self) -> String {
+        self.label.clone()
+    }
+    fn legend_label(&self) -> String {
+        "Series".to_string()
+    }
+
+    fn y_axis_label(&self) -> String {
+        match self.units {
+            Some(ref units) => units.clone(),
+            None => "Value".to_string(),
+        }
+    }
+
+    fn axis_value(&self, val: f64) -> String {
+        format!("{:2.2}", val)
+    }
+
+    fn legend_value(&self, val: f64) -> String {
+        match self.units {
+            Some(ref units) => format!("{:3.2}{}", val, units),
+            None => format!("{:3.2}", val),
+        }
+    }
+}
+
 struct Graph {
     series: Vec<Series>,
     legend: StatefulList,
@@ -221,6 +337,7 @@ struct Graph {
     interpolate: usize,
     bounds: [f64; 2],
     attributes: Box<dyn Attributes>,
+    end: Option<usize>,
 }
 
 impl Graph {
@@ -249,6 +366,8 @@ impl Graph {
                 color: colors[ndx % colors.len()],
                 data: Vec::new(),
                 raw: Vec::new(),
+                threshold: None,
+                lines: Vec::new(),
             })
         }
 
@@ -260,9 +379,16 @@ impl Graph {
             interpolate: 0,
             bounds: [20.0, 120.0],
             attributes: attr,
+            end: None,
         })
     }
 
+    fn thresholds(&mut self, thresholds: &[Option<Threshold>]) {
+        for (s, threshold) in self.series.iter_mut().zip(thresholds.iter()) {
+            s.threshold = *threshold;
+        }
+    }
+
     fn data(&mut self, data: &[Option<f32>]) {
         for (ndx, s) in self.series.iter_mut().enumerate() {
             s.raw.push(data[ndx]);
@@ -276,12 +402,18 @@ impl Graph {
             s.data = Vec::new();
         }
 
+        //
+        // We display the samples that precede our end -- which is the latest
+        // sample unless we have been explicitly positioned elsewhere.
+        //
+        let time = self.end.unwrap_or(self.time);
+
         for i in 0..self.width {
-            if self.time < self.width - i {
+            if time < self.width - i {
                 continue;
             }
 
-            let offs = (self.time - (self.width - i)) as usize;
+            let offs = (time - (self.width - i)) as usize;
 
             for (_ndx, s) in &mut self.series.iter_mut().enumerate() {
                 if let Some(datum) = s.raw[offs] {
@@ -307,6 +439,35 @@ impl Graph {
             }
         }
 
+        //
+        // Each threshold is drawn as a line across the width of the graph.
+        //
+        for s in &mut self.series {
+            s.lines = Vec::new();
+
+            if let Some(t) = s.threshold {
+                for (label, limit, color) in [
+                    ("min", t.min, Color::DarkGray),
+                    ("max", t.max, Color::DarkGray),
+                    ("critical", t.critical, Color::Red),
+                ] {
+                    if let Some(limit) = limit {
+                        let limit = limit as f64;
+
+                        s.lines.push((
+                            format!(
+                                "{} {}",
+                                label,
+                                self.attributes.legend_value(limit)
+                            ),
+                            color,
+                            vec![(0.0, limit), (self.width as f64, limit)],
+                        ));
+                    }
+                }
+            }
+        }
+
         self.update_bounds();
     }
 
@@ -322,7 +483,9 @@ impl Graph {
                 }
             }
 
-            for (_, datum) in &s.data {
+            let lines = s.lines.iter().flat_map(|(_, _, line)| line.iter());
+
+            for (_, datum) in s.data.iter().chain(lines) {
                 min = match min {
                     Some(min) if datum < min => Some(datum),
                     None => Some(datum),
@@ -388,6 +551,7 @@ struct Dashboard<'a> {
     context: HiffyContext<'a>,
     ops: Vec<Op>,
     status_ops: Vec<idol::IdolOperation<'a>>,
+    sources: Vec<layout::Source<'a>>,
     graphs: Vec<Graph>,
     current: usize,
     work: Vec<Vec<Op>>,
@@ -413,41 +577,95 @@ impl<'a> Dashboard<'a> {
         status_ops.push(sequencer_state_ops(hubris, &mut context, &mut ops)?);
         status.push("".to_string());
 
-        let temps = sensor_ops(hubris, &mut context, &mut ops, |s| {
-            s.kind == HubrisSensorKind::Temperature
-        })?;
+        //
+        // Absent a layout, we display a panel for each of temperature, fan
+        // speed and current.
+        //
+        let panels = match subargs.layout {
+            Some(ref filename) => layout::load(hubris, filename)?
+                .into_iter()
+                .map(|p| {
+                    let attr = GenericGraph::new(&p.title, p.units.as_deref());
+                    (Box::new(attr) as Box<dyn Attributes>, p.series)
+                })
+                .collect::<Vec<_>>(),
+            None => {
+                let kind = |kind: HubrisSensorKind| {
+                    layout::sensors(hubris, move |s| s.kind == kind)
+                };
+
+                let temps = kind(HubrisSensorKind::Temperature);
+                let fans = kind(HubrisSensorKind::Speed);
+                let current = kind(HubrisSensorKind::Current);
+                let fan = FanGraph::new(fans.len());
+
+                vec![
+                    (Box::new(TempGraph) as Box<dyn Attributes>, temps),
+                    (Box::new(fan) as Box<dyn Attributes>, fans),
+                    (Box::new(CurrentGraph) as Box<dyn Attributes>, current),
+                ]
+            }
+        };
 
-        let fans = sensor_ops(hubris, &mut context, &mut ops, |s| {
-            s.kind == HubrisSensorKind::Speed
-        })?;
+        let mut graphs = vec![];
+        let mut sources = vec![];
 
-        let current = sensor_ops(hubris, &mut context, &mut ops, |s| {
-            s.kind == HubrisSensorKind::Current
-        })?;
+        for (attr, series) in panels {
+            let names =
+                series.iter().map(|(n, _)| n.clone()).collect::<Vec<_>>();
+
+            graphs.push(Graph::new(&names, attr)?);
+
+            for (_, source) in series {
+                source.ops(hubris, &mut context, &mut ops)?;
+                sources.push(source);
+            }
+        }
 
         ops.push(Op::Done);
 
         context.start(core, ops.as_slice(), None)?;
 
+        //
+        // Our output file has a header that names each column with both its
+        // graph and its series, allowing it to be replayed.
+        //
         let output = if let Some(output) = &subargs.output {
             let mut f = File::create(output)?;
-            writeln!(&mut f, "{}", temps.join(","))?;
+            writeln!(&mut f, "{}", replay::header(&graphs))?;
             Some(f)
         } else {
             None
         };
 
-        let graphs = vec![
-            Graph::new(&temps, Box::new(TempGraph))?,
-            Graph::new(&fans, Box::new(FanGraph::new(fans.len())))?,
-            Graph::new(&current, Box::new(CurrentGraph))?,
-        ];
+        if let Some(ref filename) = subargs.thresholds {
+            let thresholds = Thresholds::load(hubris, filename)?;
+            let mut offs = 0;
+
+            for graph in graphs.iter_mut() {
+                let n = graph.series.len();
+
+                let t = sources[offs..offs + n]
+                    .iter()
+                    .map(|source| match source {
+                        layout::Source::Sensor(ndx) => {
+                            thresholds.lookup(&hubris.manifest.sensors[*ndx])
+                        }
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>();
+
+                graph.thresholds(&t);
+                offs += n;
+            }
+        }
 
         Ok(Dashboard {
             hubris,
             context,
             ops,
             status_ops,
+            sources,
             graphs,
             current: 0,
             outstanding: true,
@@ -510,15 +728,14 @@ impl<'a> Dashboard<'a> {
         Ok(())
     }
 
-    fn status(&self) -> Vec<(&str, &str)> {
-        vec![("Power state", &self.status[0])]
+    fn status(&self) -> Vec<(&'static str, String)> {
+        vec![("Power state", self.status[0].clone())]
     }
 
     fn need_update(&mut self, core: &mut dyn Core) -> Result<bool> {
         if self.outstanding {
             if self.context.done(core)? {
                 let results = self.context.results(core)?;
-                let mut raw = vec![];
 
                 for (i, r) in results[0..self.status.len()].iter().enumerate() {
                     if let Ok(val) = r {
@@ -526,13 +743,12 @@ impl<'a> Dashboard<'a> {
                     }
                 }
 
-                for r in &results[self.status.len()..] {
-                    raw.push(if let Ok(val) = r {
-                        Some(f32::from_le_bytes(val[0..4].try_into()?))
-                    } else {
-                        None
-                    });
-                }
+                let raw = layout::values(
+                    self.hubris,
+                    core,
+                    &mut self.sources,
+                    &results[self.status.len()..],
+                )?;
 
                 let mut offs = 0;
 
@@ -542,14 +758,15 @@ impl<'a> Dashboard<'a> {
                 }
 
                 if let Some(output) = &mut self.output {
-                    for val in raw {
-                        if let Some(val) = val {
-                            write!(output, "{:.2},", val)?;
-                        } else {
-                            write!(output, ",")?;
-                        }
-                    }
-                    writeln!(output)?;
+                    let row = raw
+                        .iter()
+                        .map(|val| match val {
+                            Some(val) => format!("{:.2}", val),
+                            None => String::new(),
+                        })
+                        .collect::<Vec<_>>();
+
+                    writeln!(output, "{}", row.join(","))?;
                 }
 
                 self.outstanding = false;
@@ -701,7 +918,10 @@ fn run_dashboard<B: Backend>(
 
         if update {
             dashboard.update_data();
-            terminal.draw(|f| draw(f, &mut dashboard))?;
+            terminal.draw(|f| {
+                let status = dashboard.status();
+                draw(f, &mut dashboard.graphs, &status)
+            })?;
         }
 
         last_tick = Instant::now();
@@ -711,10 +931,9 @@ fn run_dashboard<B: Backend>(
 fn dashboard(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    subargs: &[String],
+    subargs: &DashboardArgs,
 ) -> Result<()> {
-    let subargs = DashboardArgs::try_parse_from(subargs)?;
-    let dashboard = Dashboard::new(hubris, core, &subargs)?;
+    let dashboard = Dashboard::new(hubris, core, subargs)?;
 
     // setup terminal
     enable_raw_mode()?;
@@ -739,51 +958,42 @@ fn dashboard(
     Ok(())
 }
 
-pub fn init() -> (Command, ClapCommand<'static>) {
-    (
-        Command::Attached {
-            name: "dashboard",
-            archive: Archive::Required,
-            attach: Attach::LiveOnly,
-            validate: Validate::Booted,
-            run: Run::Subargs(dashboard),
-        },
-        DashboardArgs::command(),
-    )
-}
-
-fn sensor_ops(
-    hubris: &HubrisArchive,
-    context: &mut HiffyContext,
-    ops: &mut Vec<Op>,
-    capture: impl Fn(&HubrisSensor) -> bool,
-) -> Result<Vec<String>> {
-    let mut sensors = vec![];
-    let funcs = context.functions()?;
-    let op = idol::IdolOperation::new(hubris, "Sensor", "get", None)?;
-
-    let ok = hubris.lookup_basetype(op.ok)?;
+fn dashboardcmd(
+    hubris: &mut HubrisArchive,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = DashboardArgs::try_parse_from(subargs)?;
 
-    if ok.encoding != HubrisEncoding::Float {
-        bail!("expected return value of read_sensor() to be a float");
+    //
+    // A replay requires neither an archive nor an attached system.
+    //
+    if let Some(ref filename) = subargs.replay {
+        return replay::replay(filename);
     }
 
-    if ok.size != 4 {
-        bail!("expected return value of read_sensor() to be an f32");
+    if !hubris.loaded() {
+        bail!("must provide a Hubris archive");
     }
 
-    for (i, s) in hubris.manifest.sensors.iter().enumerate() {
-        if !capture(s) {
-            continue;
-        }
-
-        let payload =
-            op.payload(&[("id", idol::IdolArgument::Scalar(i as u64))])?;
-        context.idol_call_ops(&funcs, &op, &payload, ops)?;
-        sensors.push(s.name.clone());
-    }
+    humility_cmd::attach(
+        hubris,
+        args,
+        Attach::LiveOnly,
+        Validate::Booted,
+        |hubris, core| dashboard(hubris, core, &subargs),
+    )
+}
 
-    Ok(sensors)
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Unattached {
+            name: "dashboard",
+            archive: Archive::Optional,
+            run: RunUnattached::Args(dashboardcmd),
+        },
+        DashboardArgs::command(),
+    )
 }
 
 fn sequencer_state_ops<'a>(
@@ -872,15 +1082,21 @@ fn draw_graph<B: Backend>(f: &mut Frame<B>, parent: Rect, graph: &mut Graph) {
         )
         .split(parent);
 
-    let x_labels = vec![
-        Span::styled(
-            format!("t-{}", graph.width),
-            Style::default().add_modifier(Modifier::BOLD),
-        ),
-        Span::styled(
-            format!("t-{}", 1),
-            Style::default().add_modifier(Modifier::BOLD),
+    //
+    // If we have been positioned at a particular time, we label the x axis
+    // with absolute times rather than times relative to the latest sample.
+    //
+    let (first, last) = match graph.end {
+        Some(end) => (
+            format!("t={}", end.saturating_sub(graph.width)),
+            format!("t={}", end.saturating_sub(1)),
         ),
+        None => (format!("t-{}", graph.width), format!("t-{}", 1)),
+    };
+
+    let x_labels = vec![
+        Span::styled(first, Style::default().add_modifier(Modifier::BOLD)),
+        Span::styled(last, Style::default().add_modifier(Modifier::BOLD)),
     ];
 
     let mut datasets = vec![];
@@ -900,6 +1116,17 @@ fn draw_graph<B: Backend>(f: &mut Frame<B>, parent: Rect, graph: &mut Graph) {
                 .style(Style::default().fg(s.color))
                 .data(&s.data),
         );
+
+        for (label, color, line) in &s.lines {
+            datasets.push(
+                Dataset::default()
+                    .name(label.as_str())
+                    .marker(symbols::Marker::Braille)
+                    .graph_type(GraphType::Line)
+                    .style(Style::default().fg(*color))
+                    .data(line),
+            );
+        }
     }
 
     let chart = Chart::new(datasets)
@@ -942,17 +1169,40 @@ fn draw_graph<B: Backend>(f: &mut Frame<B>, parent: Rect, graph: &mut Graph) {
     let mut rows = vec![];
 
     for s in &graph.series {
-        let val = match s.raw.last() {
+        let last = match graph.end {
+            Some(end) if end > 0 => s.raw.get(end - 1),
+            Some(_) => None,
+            None => s.raw.last(),
+        };
+
+        let val = match last {
             None | Some(None) => "-".to_string(),
             Some(Some(val)) => graph.attributes.legend_value((*val).into()),
         };
 
+        //
+        // If this sensor has a threshold and is out of range, flag it.
+        //
+        let style = match (s.threshold, last) {
+            (Some(t), Some(last)) => match t.check(*last) {
+                Some(v) if v.is_critical() => Style::default()
+                    .fg(Color::White)
+                    .bg(Color::Red)
+                    .add_modifier(Modifier::BOLD),
+                Some(_) => {
+                    Style::default().fg(Color::Black).bg(Color::LightRed)
+                }
+                None => Style::default().fg(s.color),
+            },
+            _ => Style::default().fg(s.color),
+        };
+
         rows.push(ListItem::new(Spans::from(vec![
             Span::styled(
                 format!("{:<20}", s.name),
                 Style::default().fg(s.color),
             ),
-            Span::styled(format!("{:>8}", val), Style::default().fg(s.color)),
+            Span::styled(format!("{:>8}", val), style),
         ])));
     }
 
@@ -976,29 +1226,31 @@ fn draw_graph<B: Backend>(f: &mut Frame<B>, parent: Rect, graph: &mut Graph) {
 fn draw_graphs<B: Backend>(
     f: &mut Frame<B>,
     parent: Rect,
-    dashboard: &mut Dashboard,
+    graphs: &mut [Graph],
 ) {
+    //
+    // The first graph gets twice the space of each of the others.
+    //
+    let total = graphs.len() as u32 + 1;
+
+    let constraints = (0..graphs.len())
+        .map(|i| Constraint::Ratio(if i == 0 { 2 } else { 1 }, total))
+        .collect::<Vec<_>>();
+
     let screen = Layout::default()
         .direction(Direction::Vertical)
-        .constraints(
-            [
-                Constraint::Ratio(1, 2),
-                Constraint::Ratio(1, 4),
-                Constraint::Ratio(1, 4),
-            ]
-            .as_ref(),
-        )
+        .constraints(constraints)
         .split(parent);
 
-    draw_graph(f, screen[0], &mut dashboard.graphs[0]);
-    draw_graph(f, screen[1], &mut dashboard.graphs[1]);
-    draw_graph(f, screen[2], &mut dashboard.graphs[2]);
+    for (graph, area) in graphs.iter_mut().zip(screen.into_iter()) {
+        draw_graph(f, area, graph);
+    }
 }
 
 fn draw_status<B: Backend>(
     f: &mut Frame<B>,
     parent: Rect,
-    status: &[(&str, &str)],
+    status: &[(&str, String)],
 ) {
     let mut bar = vec![];
 
@@ -1015,7 +1267,7 @@ fn draw_status<B: Backend>(
             Style::default().add_modifier(Modifier::BOLD),
         ));
 
-        bar.push(Span::raw(s.1));
+        bar.push(Span::raw(s.1.as_str()));
 
         if i < status.len() - 1 {
             bar.push(Span::raw(" | "));
@@ -1031,7 +1283,11 @@ fn draw_status<B: Backend>(
     f.render_widget(para, parent);
 }
 
-fn draw<B: Backend>(f: &mut Frame<B>, dashboard: &mut Dashboard) {
+fn draw<B: Backend>(
+    f: &mut Frame<B>,
+    graphs: &mut [Graph],
+    status: &[(&str, String)],
+) {
     let size = f.size();
 
     let screen = Layout::default()
@@ -1039,6 +1295,6 @@ fn draw<B: Backend>(f: &mut Frame<B>, dashboard: &mut Dashboard) {
         .constraints([Constraint::Min(1), Constraint::Length(1)].as_ref())
         .split(size);
 
-    draw_graphs(f, screen[0], dashboard);
-    draw_status(f, screen[1], &dashboard.status());
+    draw_graphs(f, screen[0], graphs);
+    draw_status(f, screen[1], status);
 }
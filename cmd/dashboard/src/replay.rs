@@ -0,0 +1,395 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Replay of the raw sensor data written by `humility dashboard -o`.  The
+// header of such a file names each column as `<graph>/<series>`, which
+// allows us to reconstruct the graphs without an archive; as either can be
+// an arbitrary name from a layout file, any that contains a comma, slash or
+// double quote is itself double-quoted (with embedded quotes doubled).
+// (Files written before the header carried the graph name named only the
+// temperature sensors; we graph those as temperatures and ignore any other
+// columns.)
+//
+
+use super::{
+    draw, Attributes, CurrentGraph, FanGraph, GenericGraph, Graph, TempGraph,
+};
+use anyhow::{bail, Context, Result};
+use crossterm::{
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    execute,
+    terminal::{
+        disable_raw_mode, enable_raw_mode, EnterAlternateScreen,
+        LeaveAlternateScreen,
+    },
+};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use tui::{
+    backend::{Backend, CrosstermBackend},
+    Terminal,
+};
+
+const LEGACY_GRAPH: &str = "Temperature";
+
+///
+/// Returns the header line for an output file for the specified graphs.
+///
+pub fn header(graphs: &[Graph]) -> String {
+    graphs
+        .iter()
+        .flat_map(|g| {
+            let label = g.attributes.label();
+            g.series
+                .iter()
+                .map(move |s| format!("{}/{}", quote(label), quote(&s.name)))
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn quote(name: &str) -> String {
+    if name.contains(|c| matches!(c, ',' | '/' | '"')) {
+        format!("\"{}\"", name.replace('"', "\"\""))
+    } else {
+        name.to_string()
+    }
+}
+
+///
+/// Parses a header into the graph label (if any) and series name of each
+/// column.  Outside of quotes, only the first slash in a column separates
+/// the label from the name.
+///
+fn parse_header(header: &str) -> Result<Vec<(Option<String>, String)>> {
+    let mut columns = vec![];
+    let mut parts = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = header.chars().peekable();
+
+    let mut column = |parts: Vec<String>| match &parts[..] {
+        [name] if name.trim().is_empty() => {}
+        [name] => columns.push((None, name.trim().to_string())),
+        [label, name] => columns
+            .push((Some(label.trim().to_string()), name.trim().to_string())),
+        _ => unreachable!(),
+    };
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                parts.last_mut().unwrap().push(c);
+            }
+            '"' => quoted = !quoted,
+            '/' if !quoted && parts.len() == 1 => parts.push(String::new()),
+            ',' if !quoted => {
+                column(std::mem::replace(&mut parts, vec![String::new()]))
+            }
+            _ => parts.last_mut().unwrap().push(c),
+        }
+    }
+
+    if quoted {
+        bail!("unterminated quote");
+    }
+
+    column(parts);
+
+    Ok(columns)
+}
+
+fn attributes(label: &str, nseries: usize) -> Box<dyn Attributes> {
+    match label {
+        "Temperature" => Box::new(TempGraph),
+        "Fan speed" => Box::new(FanGraph::new(nseries)),
+        "Output current" => Box::new(CurrentGraph),
+        _ => Box::new(GenericGraph::new(label, None)),
+    }
+}
+
+fn load(filename: &str) -> Result<Vec<Graph>> {
+    let file = File::open(filename)
+        .with_context(|| format!("failed to open {}", filename))?;
+    let mut lines = BufReader::new(file).lines();
+
+    let header = match lines.next() {
+        Some(header) => header?,
+        None => bail!("{}: file is empty", filename),
+    };
+
+    //
+    // Determine the graph (and position within that graph) of each column.
+    //
+    let mut graphs: Vec<(String, Vec<String>)> = vec![];
+    let mut columns = vec![];
+
+    let header = parse_header(&header)
+        .with_context(|| format!("{}: invalid header", filename))?;
+
+    for (label, name) in header {
+        let label = label.unwrap_or_else(|| LEGACY_GRAPH.to_string());
+
+        let ndx = match graphs.iter().position(|(l, _)| *l == label) {
+            Some(ndx) => ndx,
+            None => {
+                graphs.push((label, vec![]));
+                graphs.len() - 1
+            }
+        };
+
+        graphs[ndx].1.push(name);
+        columns.push(ndx);
+    }
+
+    if columns.is_empty() {
+        bail!("{}: header names no sensors", filename);
+    }
+
+    let mut rval = graphs
+        .iter()
+        .map(|(label, names)| Graph::new(names, attributes(label, names.len())))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut samples = 0;
+
+    for (lineno, line) in lines.enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields = line.split(',').map(str::trim).collect::<Vec<_>>();
+        let mut values = vec![vec![]; rval.len()];
+
+        for (i, ndx) in columns.iter().enumerate() {
+            let val = match fields.get(i) {
+                None | Some(&"") => None,
+                Some(field) => {
+                    Some(field.parse::<f32>().with_context(|| {
+                        format!(
+                            "{}: line {}: invalid value \"{}\"",
+                            filename,
+                            lineno + 2,
+                            field
+                        )
+                    })?)
+                }
+            };
+
+            values[*ndx].push(val);
+        }
+
+        for (graph, values) in rval.iter_mut().zip(values.iter()) {
+            graph.data(values);
+        }
+
+        samples += 1;
+    }
+
+    if samples == 0 {
+        bail!("{}: file contains no samples", filename);
+    }
+
+    Ok(rval)
+}
+
+struct Replay<'a> {
+    filename: &'a str,
+    graphs: Vec<Graph>,
+    current: usize,
+    end: usize,
+    len: usize,
+}
+
+impl<'a> Replay<'a> {
+    fn new(filename: &'a str) -> Result<Self> {
+        let graphs = load(filename)?;
+        let len = graphs[0].time;
+
+        let mut replay = Replay { filename, graphs, current: 0, end: 0, len };
+        replay.seek(0);
+
+        Ok(replay)
+    }
+
+    fn width(&self) -> usize {
+        self.graphs[0].width
+    }
+
+    //
+    // Positions the end of the displayed window, clamping it such that we
+    // display a full window of samples wherever possible.
+    //
+    fn seek(&mut self, end: usize) {
+        let min = std::cmp::min(self.width(), self.len);
+        self.end = end.clamp(min, self.len);
+
+        for graph in self.graphs.iter_mut() {
+            graph.end = Some(self.end);
+        }
+    }
+
+    fn forward(&mut self) {
+        let step = std::cmp::max(self.width() / 10, 1);
+        self.seek(self.end + step);
+    }
+
+    fn back(&mut self) {
+        let step = std::cmp::max(self.width() / 10, 1);
+        self.seek(self.end.saturating_sub(step));
+    }
+
+    fn zoom_in(&mut self) {
+        for graph in self.graphs.iter_mut() {
+            graph.zoom_in();
+        }
+
+        self.seek(self.end);
+    }
+
+    fn zoom_out(&mut self) {
+        for graph in self.graphs.iter_mut() {
+            graph.zoom_out();
+        }
+
+        self.seek(self.end);
+    }
+
+    fn up(&mut self) {
+        self.graphs[self.current].previous();
+    }
+
+    fn down(&mut self) {
+        self.graphs[self.current].next();
+    }
+
+    fn esc(&mut self) {
+        self.graphs[self.current].unselect();
+    }
+
+    fn tab(&mut self) {
+        self.current = (self.current + 1) % self.graphs.len();
+    }
+
+    fn update_data(&mut self) {
+        for graph in self.graphs.iter_mut() {
+            graph.update_data();
+        }
+    }
+
+    fn status(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("File", self.filename.to_string()),
+            ("Samples", format!("{} of {}", self.end, self.len)),
+        ]
+    }
+}
+
+fn run_replay<B: Backend>(
+    terminal: &mut Terminal<B>,
+    mut replay: Replay,
+) -> Result<()> {
+    loop {
+        replay.update_data();
+        terminal.draw(|f| {
+            let status = replay.status();
+            draw(f, &mut replay.graphs, &status)
+        })?;
+
+        //
+        // Unlike a live dashboard, there is nothing to update in the absence
+        // of input, so we simply block for the next event (which may be a
+        // resize that requires us to redraw).
+        //
+        if let Event::Key(key) = event::read()? {
+            match key.code {
+                KeyCode::Char('q') => return Ok(()),
+                KeyCode::Char('+') => replay.zoom_in(),
+                KeyCode::Char('-') => replay.zoom_out(),
+                KeyCode::Left => replay.back(),
+                KeyCode::Right => replay.forward(),
+                KeyCode::Home => replay.seek(0),
+                KeyCode::End => replay.seek(replay.len),
+                KeyCode::Up => replay.up(),
+                KeyCode::Down => replay.down(),
+                KeyCode::Esc => replay.esc(),
+                KeyCode::Tab => replay.tab(),
+                _ => {}
+            }
+        }
+    }
+}
+
+pub fn replay(filename: &str) -> Result<()> {
+    let replay = Replay::new(filename)?;
+
+    // setup terminal
+    enable_raw_mode()?;
+    let mut stdout = io::stdout();
+    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    let backend = CrosstermBackend::new(stdout);
+    let mut terminal = Terminal::new(backend)?;
+
+    let res = run_replay(&mut terminal, replay);
+
+    // restore terminal
+    disable_raw_mode()?;
+    execute!(
+        terminal.backend_mut(),
+        LeaveAlternateScreen,
+        DisableMouseCapture
+    )?;
+    terminal.show_cursor()?;
+
+    res
+}
+
+#[test]
+fn validate_header() {
+    let names = [
+        ("Temperature", "CPU"),
+        ("In/Out", "a,b"),
+        ("Power", "say \"hi\""),
+        ("Rails", "V3P3/V1P8"),
+    ];
+
+    let header = names
+        .iter()
+        .map(|(l, n)| format!("{}/{}", quote(l), quote(n)))
+        .collect::<Vec<_>>()
+        .join(",");
+
+    assert_eq!(
+        header,
+        "Temperature/CPU,\"In/Out\"/\"a,b\",Power/\"say \"\"hi\"\"\",\
+        Rails/\"V3P3/V1P8\""
+    );
+
+    let parsed = parse_header(&header).unwrap();
+
+    assert_eq!(
+        parsed,
+        names
+            .iter()
+            .map(|(l, n)| (Some(l.to_string()), n.to_string()))
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn validate_header_legacy() {
+    assert_eq!(
+        parse_header("CPU, Southeast ,,Fan/0/1").unwrap(),
+        vec![
+            (None, "CPU".to_string()),
+            (None, "Southeast".to_string()),
+            (Some("Fan".to_string()), "0/1".to_string()),
+        ]
+    );
+
+    assert!(parse_header("\"CPU").is_err());
+}
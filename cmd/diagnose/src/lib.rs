@@ -254,15 +254,10 @@ fn diagnose(
         if before.generation != after.generation {
             // Estimate restart count. Since kernel generation bits are
             // currently limited, this is best-effort.
-            let count = match (before.generation, after.generation) {
-                (
-                    GenOrRestartCount::RestartCount(b),
-                    GenOrRestartCount::RestartCount(a),
-                ) => a.wrapping_sub(b),
-                (GenOrRestartCount::Gen(b), GenOrRestartCount::Gen(a)) => {
-                    u32::from(a.0.wrapping_sub(b.0) & 0x3F)
-                }
-                _ => bail!("generation changed shape between reads?"),
+            let count = match after.generation.restarts_since(before.generation)
+            {
+                Some(count) => count,
+                None => bail!("generation changed shape between reads?"),
             };
             println!(
                 "{}: Task {} (#{}) has restarted {}{} more times.",
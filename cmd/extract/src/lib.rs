@@ -128,16 +128,47 @@
 //! extracting the entire archive requires the specification of an output file
 //! to prevent accidental blasts of binary content to the console.)
 //!
+//! To extract the image that `humility flash` would program in a form
+//! suitable for an external programmer, use the `--image` option to specify
+//! the format: `srec` (Motorola S-record), `ihex` (Intel HEX), or `bin` (a
+//! flat binary).  An output file must be specified, and a JSON manifest
+//! describing the image -- its contiguous regions and their SHA-256 sums, as
+//! well as the SHA-256 sum of the output file itself -- is written alongside
+//! it (or to the file specified with `--manifest`):
+//!
+//! ```console
+//! % humility -a /path/to/my/hubris-archive.zip extract --image ihex -o image.ihex
+//! humility: extracted ihex image (4 regions, 307200 bytes) to image.ihex
+//! humility: wrote manifest to image.ihex.json
+//! ```
+//!
+//! For a binary image, gaps between regions are filled with 0xff by default;
+//! a different value can be specified with `--fill`.  The binary image begins
+//! at the lowest address in the image unless a base address is specified
+//! with `--base`, in which case the image is padded from the base address.
+//!
 
 use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::hubris::HubrisArchive;
+use humility::image::{
+    generate_ihex_from_elf, generate_srec_from_elf, image_regions,
+};
 use humility_cmd::{Command, RunUnattached};
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::fs::File;
 use std::io::Cursor;
 use std::io::{self, Read, Write};
 
+//
+// The largest binary image that we are willing to generate; an image that
+// spans more than this likely has regions in disparate memories, and should
+// be extracted as SREC or IHEX instead.
+//
+const MAX_BIN_SIZE: u32 = 64 * 1024 * 1024;
+
 #[derive(Parser, Debug)]
 #[clap(name = "extract", about = env!("CARGO_PKG_DESCRIPTION"))]
 struct ExtractArgs {
@@ -149,14 +180,160 @@ struct ExtractArgs {
     #[clap(long, short)]
     output: Option<String>,
 
+    /// extract flash image in specified format (srec, ihex, or bin)
+    #[clap(
+        long, value_name = "format",
+        conflicts_with_all = &["list", "file"],
+        requires = "output",
+    )]
+    image: Option<String>,
+
+    /// fill value for gaps in a binary image
+    #[clap(
+        long, value_name = "byte", requires = "image",
+        parse(try_from_str = parse_int::parse)
+    )]
+    fill: Option<u8>,
+
+    /// base address of a binary image
+    #[clap(
+        long, value_name = "address", requires = "image",
+        parse(try_from_str = parse_int::parse)
+    )]
+    base: Option<u32>,
+
+    /// file for image manifest (defaults to output file with .json appended)
+    #[clap(long, value_name = "file", requires = "image")]
+    manifest: Option<String>,
+
     /// Optional file to extract
     file: Option<String>,
 }
 
+fn sha256(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn extract_image(
+    hubris: &HubrisArchive,
+    subargs: &ExtractArgs,
+    format: &str,
+) -> Result<()> {
+    let output = subargs.output.as_ref().unwrap();
+    let flash = hubris.load_flash_config()?;
+    let regions = image_regions(&flash.elf)?;
+
+    if regions.is_empty() {
+        bail!("flash image is empty");
+    }
+
+    if format != "bin" && (subargs.fill.is_some() || subargs.base.is_some()) {
+        bail!("--fill and --base are only valid for binary images");
+    }
+
+    let start = regions.iter().map(|(addr, _)| *addr).min().unwrap();
+
+    let end = regions
+        .iter()
+        .map(|(addr, contents)| *addr + contents.len() as u32)
+        .max()
+        .unwrap();
+
+    let fill = subargs.fill.unwrap_or(0xff);
+    let base = subargs.base.unwrap_or(start);
+
+    let contents = match format {
+        "srec" => generate_srec_from_elf(&flash.elf)?.into_bytes(),
+        "ihex" => generate_ihex_from_elf(&flash.elf)?.into_bytes(),
+        "bin" => {
+            if base > start {
+                bail!(
+                    "base address 0x{:08x} is above start of image (0x{:08x})",
+                    base,
+                    start
+                );
+            }
+
+            if end - base > MAX_BIN_SIZE {
+                bail!(
+                    "binary image would be {} bytes (0x{:08x} to 0x{:08x}); \
+                    use srec or ihex instead",
+                    end - base,
+                    base,
+                    end
+                );
+            }
+
+            let mut bin = vec![fill; (end - base) as usize];
+
+            for (addr, contents) in &regions {
+                let offset = (*addr - base) as usize;
+                bin[offset..offset + contents.len()].copy_from_slice(contents);
+            }
+
+            bin
+        }
+        _ => {
+            bail!(
+                "unknown image format \"{}\" (expected srec, ihex, or bin)",
+                format
+            );
+        }
+    };
+
+    let mut ofile = File::create(output)?;
+    ofile.write_all(&contents)?;
+
+    let nbytes = regions.iter().fold(0, |n, (_, c)| n + c.len());
+
+    humility::msg!(
+        "extracted {} image ({} region{}, {} bytes) to {}",
+        format,
+        regions.len(),
+        if regions.len() != 1 { "s" } else { "" },
+        nbytes,
+        output
+    );
+
+    let mut manifest = json!({
+        "file": output,
+        "format": format,
+        "size": contents.len(),
+        "sha256": sha256(&contents),
+        "regions": regions.iter().map(|(addr, contents)| json!({
+            "address": addr,
+            "size": contents.len(),
+            "sha256": sha256(contents),
+        })).collect::<Vec<_>>(),
+    });
+
+    if format == "bin" {
+        manifest["base"] = json!(base);
+        manifest["fill"] = json!(fill);
+    }
+
+    let filename = match &subargs.manifest {
+        Some(manifest) => manifest.clone(),
+        None => format!("{}.json", output),
+    };
+
+    let mut mfile = File::create(&filename)?;
+    serde_json::to_writer_pretty(&mut mfile, &manifest)?;
+    writeln!(mfile)?;
+
+    humility::msg!("wrote manifest to {}", filename);
+
+    Ok(())
+}
+
 fn extract(hubris: &mut HubrisArchive, subargs: &[String]) -> Result<()> {
     let archive = hubris.archive();
     let subargs = ExtractArgs::try_parse_from(subargs)?;
 
+    if let Some(ref format) = subargs.image {
+        return extract_image(hubris, &subargs, format);
+    }
+
     if subargs.list {
         let cursor = Cursor::new(archive);
         let mut archive = zip::ZipArchive::new(cursor)?;
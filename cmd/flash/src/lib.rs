@@ -26,15 +26,54 @@
 //! includes a task with the `AuxFlash` API, two slots of auxiliary flash
 //! will be programmed after the image is written.  See RFD 311 for more
 //! information about auxiliary flash management.
+//!
+//! To confirm that the image was written correctly, use the `--verify`
+//! flag.  After the image is written, every byte of it is read back from
+//! the part and compared against the archive; the part is then reset, and
+//! `humility flash` waits for the kernel to boot and checks that the image
+//! ID on the part matches the archive.  Any mismatch is an error:
+//!
+//! ```console
+//! % humility -a ./build-gimlet.zip flash --verify
+//! humility: attaching with chip set to "STM32H753ZITx"
+//! humility: verified 412.34KB in 2 seconds
+//! humility: image booted after 310 ms
+//! humility: flashing done
+//! ```
+//!
+//! The time to wait for the image to boot can be specified with
+//! `--verify-timeout`; it defaults to 5 seconds.
+//!
+//! When iterating on an image, much of it is typically unchanged from what
+//! is already on the part.  To only erase and program those sectors that
+//! have changed, use the `-D` (`--differential`) flag.  The contents of the
+//! part are read back and compared against the archive, and only the parts
+//! of the image that differ are written:
+//!
+//! ```console
+//! % humility -a ./build-gimlet.zip flash --differential
+//! humility: attaching with chip set to "STM32H753ZITx"
+//! humility: 3.00KB of 412.34KB differs; compared in 2 seconds
+//! humility: flashing done
+//! ```
+//!
+//! Differential flashing is only supported when flashing via probe-rs.
 
 use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
+use humility::image::{
+    generate_ihex, generate_ihex_from_elf, generate_srec_from_elf,
+    image_regions,
+};
 use humility::{core::Core, hubris::*};
 use humility_cmd::{Archive, Args, Command, RunUnattached};
+use indicatif::{HumanBytes, HumanDuration};
+use indicatif::{ProgressBar, ProgressStyle};
 use path_slash::PathExt;
 use std::io::Write;
 use std::process::ExitStatus;
+use std::time::{Duration, Instant};
 
 use serde::Deserialize;
 
@@ -58,6 +97,10 @@ struct FlashArgs {
     #[clap(long = "force-openocd", short = 'O')]
     force_openocd: bool,
 
+    /// only erase and program sectors that differ from the archive
+    #[clap(long, short = 'D', conflicts_with = "force_openocd")]
+    differential: bool,
+
     /// reset delay
     #[clap(
         long = "reset-delay", short = 'd',
@@ -65,8 +108,31 @@ struct FlashArgs {
         parse(try_from_str = parse_int::parse)
     )]
     reset_delay: u64,
+
+    /// read back the image after flashing and confirm that it boots
+    #[clap(long, conflicts_with = "dryrun")]
+    verify: bool,
+
+    /// time to wait for the image to boot when verifying
+    #[clap(
+        long = "verify-timeout", requires = "verify",
+        default_value = "5000", value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    verify_timeout: u64,
 }
 
+//
+// The size of each read when comparing the image against the part.
+//
+const READ_CHUNK: usize = 1024;
+
+//
+// The number of mismatched regions that we will report individually before
+// giving up on the verification.
+//
+const VERIFY_MAXERRS: usize = 10;
+
 //
 // This is the Hubris definition
 //
@@ -239,6 +305,14 @@ fn force_openocd(
         anyhow::bail!("flash command ({:?}) failed; see output", flash);
     }
 
+    if subargs.verify {
+        let mut c = humility::core::attach(probe, hubris)?;
+        let core = c.as_mut();
+
+        verify_image(core, elf)?;
+        verify_boot(hubris, core, subargs.verify_timeout)?;
+    }
+
     Ok(())
 }
 
@@ -366,17 +440,54 @@ fn flashcmd(
     }
 
     let ihex = tempfile::NamedTempFile::new()?;
-    std::fs::write(&ihex, generate_ihex_from_elf(&flash.elf)?)?;
-    let ihex_path = ihex.path();
 
     //
     // Load the flash image, and reset the part if that works.
     //
-    if let Err(err) = core.load(ihex_path) {
+    let rval = if subargs.differential {
+        //
+        // Determine what differs between the part and the image; if nothing
+        // does, we have nothing to do.  (Note that this is unlikely, as the
+        // image ID would have had to match.)
+        //
+        let deltas = match image_deltas(core, &flash.elf) {
+            Ok(deltas) => deltas,
+            Err(err) => {
+                core.run()?;
+                return Err(err);
+            }
+        };
+
+        if deltas.is_empty() {
+            core.run()?;
+            humility::msg!("no delta; image is already flashed");
+            return Ok(());
+        }
+
+        let chunks = deltas.iter().map(|(addr, buf)| (*addr, buf.as_slice()));
+        std::fs::write(&ihex, generate_ihex(chunks)?)?;
+        core.load_partial(ihex.path())
+    } else {
+        std::fs::write(&ihex, generate_ihex_from_elf(&flash.elf)?)?;
+        core.load(ihex.path())
+    };
+
+    if let Err(err) = rval {
         core.run()?;
         return Err(err);
     }
 
+    //
+    // If we've been asked to verify, read back the image before we reset
+    // the part; if it doesn't match, we want to fail before running it.
+    //
+    if subargs.verify {
+        if let Err(err) = verify_image(core, &flash.elf) {
+            core.run()?;
+            return Err(err);
+        }
+    }
+
     //
     // On Gimlet Rev B, the BOOT0 pin is unstrapped -- and during a flash,
     // it seems to float high enough to bounce the part onto the wrong
@@ -403,10 +514,193 @@ fn flashcmd(
     //
     // This is called out in RFD 311 as a weakness of our approach!
     try_program_auxflash(hubris, core)?;
+
+    if subargs.verify {
+        verify_boot(hubris, core, subargs.verify_timeout)?;
+    }
+
     humility::msg!("flashing done");
     Ok(())
 }
 
+///
+/// Reads the part's current contents for each region of the image, returning
+/// those pieces of the image that differ.  Each piece is at most `READ_CHUNK`
+/// bytes; when these are loaded, the flash loader will preserve the rest of
+/// any sector that it needs to erase.
+///
+fn image_deltas(
+    core: &mut dyn Core,
+    elf: &[u8],
+) -> Result<Vec<(u32, Vec<u8>)>> {
+    let regions = image_regions(elf)?;
+    let nbytes = regions.iter().fold(0, |n, (_, c)| n + c.len());
+    let started = Instant::now();
+    let bar = ProgressBar::new(nbytes as u64);
+
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("humility: comparing [{bar:30}] {bytes}/{total_bytes}"),
+    );
+
+    let mut ncompared = 0;
+    let mut ndeltas = 0;
+    let mut deltas = vec![];
+
+    for (base, contents) in &regions {
+        for (i, expected) in contents.chunks(READ_CHUNK).enumerate() {
+            let addr = base + (i * READ_CHUNK) as u32;
+            let mut actual = vec![0; expected.len()];
+
+            core.read_8(addr, &mut actual).with_context(|| {
+                format!("failed to read image at 0x{:08x}", addr)
+            })?;
+
+            ncompared += expected.len();
+            bar.set_position(ncompared as u64);
+
+            if actual != expected {
+                ndeltas += expected.len();
+                deltas.push((addr, expected.to_vec()));
+            }
+        }
+    }
+
+    bar.finish_and_clear();
+
+    humility::msg!(
+        "{} of {} differs; compared in {}",
+        HumanBytes(ndeltas as u64),
+        HumanBytes(nbytes as u64),
+        HumanDuration(started.elapsed())
+    );
+
+    Ok(deltas)
+}
+
+///
+/// Reads back every chunk of the image from the part, comparing it against
+/// the ELF in the archive.
+///
+fn verify_image(core: &mut dyn Core, elf: &[u8]) -> Result<()> {
+    let regions = image_regions(elf)?;
+    let nbytes = regions.iter().fold(0, |n, (_, c)| n + c.len());
+    let started = Instant::now();
+    let bar = ProgressBar::new(nbytes as u64);
+
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("humility: verifying [{bar:30}] {bytes}/{total_bytes}"),
+    );
+
+    let mut nverified = 0;
+    let mut nbad = 0;
+    let mut errs = vec![];
+    let mut truncated = false;
+
+    'regions: for (base, contents) in &regions {
+        for (i, expected) in contents.chunks(READ_CHUNK).enumerate() {
+            let addr = base + (i * READ_CHUNK) as u32;
+            let mut actual = vec![0; expected.len()];
+
+            core.read_8(addr, &mut actual).with_context(|| {
+                format!("failed to read back image at 0x{:08x}", addr)
+            })?;
+
+            nverified += expected.len();
+            bar.set_position(nverified as u64);
+
+            let bad = actual
+                .iter()
+                .zip(expected.iter())
+                .enumerate()
+                .filter(|(_, (lhs, rhs))| lhs != rhs)
+                .map(|(offs, _)| offs)
+                .collect::<Vec<_>>();
+
+            if bad.is_empty() {
+                continue;
+            }
+
+            //
+            // If we have already reported as many mismatches as we will,
+            // this one means that we are giving up on the verification.
+            //
+            if errs.len() == VERIFY_MAXERRS {
+                truncated = true;
+                break 'regions;
+            }
+
+            let offs = bad[0];
+
+            errs.push(format!(
+                "{} byte{} differ at 0x{:08x}..0x{:08x}; first at 0x{:08x} \
+                (expected 0x{:02x}, found 0x{:02x})",
+                bad.len(),
+                if bad.len() != 1 { "s" } else { "" },
+                addr,
+                addr + expected.len() as u32,
+                addr + offs as u32,
+                expected[offs],
+                actual[offs]
+            ));
+
+            nbad += bad.len();
+        }
+    }
+
+    bar.finish_and_clear();
+
+    for err in &errs {
+        humility::msg!("{}", err);
+    }
+
+    if truncated {
+        bail!("too many mismatches; image verification failed");
+    }
+
+    if nbad != 0 {
+        bail!("image verification failed: {} of {} bytes differ", nbad, nbytes);
+    }
+
+    humility::msg!(
+        "verified {} in {}",
+        HumanBytes(nbytes as u64),
+        HumanDuration(started.elapsed())
+    );
+
+    Ok(())
+}
+
+///
+/// Waits for the image that we've just flashed to boot, which also confirms
+/// that its image ID matches the archive.
+///
+fn verify_boot(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    timeout: u64,
+) -> Result<()> {
+    let started = Instant::now();
+
+    loop {
+        match hubris.validate(core, HubrisValidate::Booted) {
+            Ok(_) => break,
+            Err(err) => {
+                if started.elapsed().as_millis() > timeout.into() {
+                    bail!("image failed to boot after flashing: {}", err);
+                }
+            }
+        }
+
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    humility::msg!("image booted after {} ms", started.elapsed().as_millis());
+
+    Ok(())
+}
+
 fn try_program_auxflash(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
@@ -509,83 +803,3 @@ pub fn init() -> (Command, ClapCommand<'static>) {
         FlashArgs::command(),
     )
 }
-
-/// While it may sound like the impetus for an OSHA investigation at the North
-/// Pole, this function is _actually_ designed to generate small (32-byte)
-/// chunks describing the data in the PHDRs of an ELF file. Unless the file is
-/// missing PHDRs, because objcopy sometimes does that for whatever reason, in
-/// which case we do the section headers.
-///
-/// This is an implementation factor of both SREC and IHEX generation.
-fn elf_chunks(elf_data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
-    let elf = goblin::elf::Elf::parse(elf_data)?;
-
-    let mut addr_slices = vec![];
-
-    if elf.program_headers.is_empty() {
-        for sh in &elf.section_headers {
-            if sh.sh_type != goblin::elf::section_header::SHT_PROGBITS {
-                continue;
-            }
-
-            let addr = u32::try_from(sh.sh_addr)?;
-            let offset = usize::try_from(sh.sh_offset)?;
-            let size = usize::try_from(sh.sh_size)?;
-
-            for (i, chunk) in
-                elf_data[offset..offset + size].chunks(32).enumerate()
-            {
-                addr_slices.push((addr + i as u32 * 32, chunk));
-            }
-        }
-    } else {
-        for ph in &elf.program_headers {
-            if ph.p_type != goblin::elf::program_header::PT_LOAD {
-                continue;
-            }
-
-            let addr = u32::try_from(ph.p_vaddr)?;
-            let offset = usize::try_from(ph.p_offset)?;
-            let size = usize::try_from(ph.p_filesz)?;
-
-            for (i, chunk) in
-                elf_data[offset..offset + size].chunks(32).enumerate()
-            {
-                addr_slices.push((addr + i as u32 * 32, chunk));
-            }
-        }
-    }
-
-    Ok(addr_slices)
-}
-
-fn generate_srec_from_elf(data: &[u8]) -> Result<String> {
-    let mut records = vec![srec::Record::S0("humility!".into())];
-
-    for (addr, slice) in elf_chunks(data)? {
-        records.push(srec::Record::S3(srec::Data {
-            address: srec::Address32(addr),
-            data: slice.to_vec(),
-        }));
-    }
-    records.push(srec::Record::S7(srec::Address32(0))); // bogus entry point
-
-    Ok(srec::writer::generate_srec_file(&records))
-}
-
-fn generate_ihex_from_elf(data: &[u8]) -> Result<String> {
-    // Build up IHEX records from that information.
-    let mut records = vec![];
-
-    for (addr, slice) in elf_chunks(data)? {
-        records.push(ihex::Record::ExtendedLinearAddress((addr >> 16) as u16));
-        records.push(ihex::Record::Data {
-            offset: addr as u16,
-            value: slice.to_vec(),
-        });
-    }
-
-    records.push(ihex::Record::EndOfFile);
-
-    Ok(ihex::create_object_file_representation(&records)?)
-}
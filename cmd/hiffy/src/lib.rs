@@ -37,6 +37,61 @@
 //! UserLeds.led_toggle() = ()
 //! ```
 //!
+//! To see exactly what is being sent to the target -- the HIF operations,
+//! the encoded payload and the raw bytes of the reply -- add `--trace`:
+//!
+//! ```console
+//! % humility hiffy -c UserLeds.led_toggle -a index=0 --trace
+//! humility: attached via ST-Link
+//! humility: payload (4 bytes): [00, 00, 00, 00]
+//! humility: HIF operations (11):
+//! humility:     0: Push(4)
+//! humility:     1: Push(3)
+//! humility:     2: Push(0)
+//! humility:     3: Push(0)
+//! humility:     4: Push(0)
+//! humility:     5: Push(0)
+//! humility:     6: Push(4)
+//! humility:     7: Push(0)
+//! humility:     8: Call(TargetFunction(0))
+//! humility:     9: DropN(8)
+//! humility:    10: Done
+//! humility: reply (0 bytes): []
+//! UserLeds.led_toggle() => ()
+//! ```
+//!
+//! To measure the latency of an Idol server, use `--profile` to have the
+//! Hubris agent call an operation in a loop, using `-n` to denote the number
+//! of calls to make.  Calls are batched into HIF loops that fit within the
+//! target's return stack (the batch size can be set with `--batch`).  Note
+//! that the calls are not timed on the target:  rather, Humility times each
+//! batch by reading the kernel's `TICKS` over the debug probe once it has
+//! started the batch and again once it has noticed that the batch has
+//! completed, and then subtracts the time measured in the same way for an
+//! empty loop of the same size.  Each batch therefore yields an average
+//! per-call latency, and the minimum, average and maximum of these across
+//! batches are reported, along with the distribution of results:
+//!
+//! ```console
+//! % humility hiffy -c Sensor.get -a id=3 --profile -n 1000
+//! humility: attached via ST-Link
+//! humility: profiling Sensor.get: 1000 calls in 8 batches of 125
+//! OPERATION                 CALLS BATCHES      MIN      AVG      MAX
+//! Sensor.get                 1000       8    8.0us   11.0us   16.0us
+//! RESULT                    COUNT
+//! Ok                          991
+//! Err(NoReading)                9
+//! ```
+//!
+//! Because the kernel tick is generally 1 millisecond, the time for a batch
+//! is a whole number of milliseconds, so with batches of 125 calls, per-call
+//! latencies are multiples of 8 microseconds.  Further, the time for a batch
+//! includes the round trips over the debug probe to start it, to poll for
+//! its completion (which Humility does every millisecond) and to read
+//! `TICKS`; subtracting the empty loop removes much (but not necessarily
+//! all) of this overhead.  Larger batches (and more calls) will yield more
+//! accurate results; the outliers of individual calls are not visible.
+//!
 //! To view the raw HIF functions provided to programmatic HIF consumers
 //! within Humility, use `-L` (`--list-functions`).
 //!
@@ -52,7 +107,10 @@ use humility::warn;
 use humility_cmd::hiffy::*;
 use humility_cmd::idol;
 use humility_cmd::{Archive, Attach, Command, Run, Validate};
+use std::collections::BTreeMap;
 use std::io::Read;
+use std::thread;
+use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[clap(name = "hiffy", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -85,6 +143,7 @@ struct HiffyArgs {
     input: Option<String>,
 
     /// number of bytes to return, when a function has a write-only lease
+    /// (or, with --profile, number of calls to make)
     #[clap(long, short, requires = "call", conflicts_with = "input")]
     num: Option<usize>,
 
@@ -103,6 +162,24 @@ struct HiffyArgs {
     /// arguments
     #[clap(long, short, requires = "call", use_value_delimiter = true)]
     arguments: Vec<String>,
+
+    /// show HIF operations, payload and reply bytes for a call
+    #[clap(long, requires = "call")]
+    trace: bool,
+
+    /// profile a call by looping it in the target
+    #[clap(
+        long, requires = "call",
+        conflicts_with_all = &["input", "output", "hex", "trace"]
+    )]
+    profile: bool,
+
+    /// with --profile, number of calls to make in each HIF program
+    #[clap(
+        long, requires = "profile", value_name = "calls",
+        parse(try_from_str = parse_int::parse)
+    )]
+    batch: Option<u32>,
 }
 
 #[derive(Debug)]
@@ -264,6 +341,32 @@ pub fn hiffy_call(
     op: &idol::IdolOperation,
     args: &[(&str, idol::IdolArgument)],
     lease: Option<HiffyLease>,
+) -> Result<std::result::Result<humility::reflect::Value, String>> {
+    hiffy_call_traced(hubris, core, context, op, args, lease, false)
+}
+
+fn hiffy_trace_bytes(what: &str, bytes: &[u8]) {
+    humility::msg!("{} ({} bytes): {:02x?}", what, bytes.len(), bytes);
+}
+
+fn hiffy_trace_ops(ops: &[Op]) {
+    humility::msg!("HIF operations ({}):", ops.len());
+
+    for (i, op) in ops.iter().enumerate() {
+        humility::msg!("{:>5}: {:?}", i, op);
+    }
+}
+
+/// Identical to [hiffy_call], but optionally (if `trace` is set) displays
+/// the payload, the HIF operations and the raw reply.
+fn hiffy_call_traced(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    op: &idol::IdolOperation,
+    args: &[(&str, idol::IdolArgument)],
+    lease: Option<HiffyLease>,
+    trace: bool,
 ) -> Result<std::result::Result<humility::reflect::Value, String>> {
     check_lease(op, lease.as_ref())?;
 
@@ -294,6 +397,11 @@ pub fn hiffy_call(
     }
     ops.push(Op::Done);
 
+    if trace {
+        hiffy_trace_bytes("payload", &payload);
+        hiffy_trace_ops(&ops);
+    }
+
     let data = lease.as_ref().and_then(|lease| {
         if let HiffyLease::Write(d) = *lease {
             Some(d)
@@ -309,6 +417,13 @@ pub fn hiffy_call(
 
     let mut v: Result<Vec<u8>, u32> = results.pop().unwrap();
 
+    if trace {
+        match &v {
+            Ok(reply) => hiffy_trace_bytes("reply", reply),
+            Err(code) => humility::msg!("reply: error code {}", code),
+        }
+    }
+
     // If this is a Read operation, steal extra data from the returned stack
     // and copy it into the incoming HiffyLease::Read argument
     let out = match lease {
@@ -392,6 +507,221 @@ pub fn hiffy_print_result(
     Ok(())
 }
 
+fn read_ticks(core: &mut dyn Core, ticks: &HubrisVariable) -> Result<u64> {
+    core.op_start()?;
+
+    let rval = match ticks.size {
+        4 => core.read_word_32(ticks.addr).map(u64::from),
+        8 => core.read_word_64(ticks.addr),
+        size => Err(anyhow!("unexpected size for TICKS: {}", size)),
+    };
+
+    core.op_done()?;
+    rval
+}
+
+/// Runs the specified HIF program, returning the number of kernel ticks
+/// that elapsed over its execution along with its results.
+fn hiffy_run_timed(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    ticks: &HubrisVariable,
+    ops: &[Op],
+) -> Result<(u64, Vec<Result<Vec<u8>, u32>>)> {
+    context.start(core, ops, None)?;
+    let start = read_ticks(core, ticks)?;
+
+    while !context.done(core)? {
+        thread::sleep(Duration::from_millis(1));
+    }
+
+    let end = read_ticks(core, ticks)?;
+
+    Ok((end.saturating_sub(start), context.results(core)?))
+}
+
+/// Generates a HIF loop of `n` iterations that (if `call` is set) makes the
+/// specified Idol call on each iteration.
+fn hiffy_loop_ops(
+    context: &HiffyContext,
+    funcs: &HiffyFunctions,
+    op: &idol::IdolOperation,
+    payload: &[u8],
+    n: u32,
+    call: bool,
+) -> Result<Vec<Op>> {
+    let label = Target(0);
+    let mut ops = vec![
+        Op::Push32(n), // Limit
+        Op::Push32(0), // Current loop iteration
+        Op::Label(label),
+    ];
+
+    if call {
+        context.idol_call_ops(funcs, op, payload, &mut ops)?;
+    }
+
+    ops.push(Op::Push(1)); // Prepare to increment
+    ops.push(Op::Add); // i = i + 1
+    ops.push(Op::BranchLessThan(label));
+    ops.push(Op::DropN(2));
+    ops.push(Op::Done);
+
+    Ok(ops)
+}
+
+/// Profiles an Idol operation by calling it `count` times from within HIF
+/// loops of (at most) `batch` calls, timing each loop by reading the kernel's
+/// `TICKS` from the host.
+fn hiffy_profile(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    op: &idol::IdolOperation,
+    args: &[(&str, idol::IdolArgument)],
+    count: u32,
+    batch: Option<u32>,
+) -> Result<()> {
+    check_lease(op, None)?;
+
+    let ticks = hubris
+        .lookup_variable("TICKS")
+        .context("kernel TICKS variable not found")?;
+
+    let funcs = context.functions()?;
+    let payload = op.payload(args)?;
+
+    //
+    // Every call leaves a result on the return stack; we determine the
+    // number of calls we can make in a single HIF program based on the
+    // maximum size of a serialized result (which is the reply itself,
+    // plus a variant tag and a varint-encoded length), leaving room for
+    // the final result that marks the end of the stack.
+    //
+    let rsize = hubris.typesize(op.ok)? + 6;
+    let max = u32::try_from((context.rstack_size() - 1) / rsize)?;
+
+    if max == 0 {
+        bail!("reply size ({} bytes) exceeds return stack", rsize);
+    }
+
+    let batch = match batch {
+        Some(0) => bail!("batch size must be non-zero"),
+        Some(batch) if batch > max => {
+            bail!("batch size cannot exceed {} for {}", max, call_name(op));
+        }
+        Some(batch) => batch,
+        None => max,
+    }
+    .min(count);
+
+    let mut sizes = vec![batch; (count / batch) as usize];
+
+    if count % batch != 0 {
+        sizes.push(count % batch);
+    }
+
+    humility::msg!(
+        "profiling {}: {} calls in {} batch{} of {}",
+        call_name(op),
+        count,
+        sizes.len(),
+        if sizes.len() == 1 { "" } else { "es" },
+        batch
+    );
+
+    //
+    // To factor out the overhead of kicking off a HIF program and waiting
+    // for its completion, we time an empty loop for each size of batch.
+    //
+    let mut baseline = BTreeMap::new();
+
+    for &n in &sizes {
+        if !baseline.contains_key(&n) {
+            let ops = hiffy_loop_ops(context, &funcs, op, &payload, n, false)?;
+            let (elapsed, _) = hiffy_run_timed(core, context, ticks, &ops)?;
+            baseline.insert(n, elapsed);
+        }
+    }
+
+    let mut latencies = vec![];
+    let mut outcomes: BTreeMap<String, usize> = BTreeMap::new();
+
+    for &n in &sizes {
+        let ops = hiffy_loop_ops(context, &funcs, op, &payload, n, true)?;
+        let (elapsed, results) = hiffy_run_timed(core, context, ticks, &ops)?;
+
+        if results.len() != n as usize {
+            bail!("expected {} results, found {}", n, results.len());
+        }
+
+        let elapsed = elapsed.saturating_sub(baseline[&n]);
+        latencies.push(elapsed as f64 / n as f64);
+
+        for r in &results {
+            let outcome = match r {
+                Ok(_) => "Ok".to_string(),
+                Err(code) => {
+                    match op.error.and_then(|e| e.lookup_variant(*code as u64))
+                    {
+                        Some(variant) => format!("Err({})", variant.name),
+                        None => format!("Err({:x?})", code),
+                    }
+                }
+            };
+
+            *outcomes.entry(outcome).or_insert(0) += 1;
+        }
+    }
+
+    latencies.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    //
+    // Each latency is the average of a batch, so we don't pretend to know
+    // the distribution of individual calls beyond these.
+    //
+    let avg = latencies.iter().sum::<f64>() / latencies.len() as f64;
+
+    //
+    // Kernel ticks are milliseconds; we display per-call latency in
+    // microseconds unless it is large enough to merit milliseconds.
+    //
+    let fmt = |ticks: f64| {
+        if ticks >= 1.0 {
+            format!("{:.1}ms", ticks)
+        } else {
+            format!("{:.1}us", ticks * 1000.0)
+        }
+    };
+
+    println!(
+        "{:<25} {:>6} {:>7} {:>8} {:>8} {:>8}",
+        "OPERATION", "CALLS", "BATCHES", "MIN", "AVG", "MAX"
+    );
+
+    println!(
+        "{:<25} {:>6} {:>7} {:>8} {:>8} {:>8}",
+        call_name(op),
+        count,
+        latencies.len(),
+        fmt(latencies[0]),
+        fmt(avg),
+        fmt(latencies[latencies.len() - 1]),
+    );
+
+    println!("{:<25} {:>6}", "RESULT", "COUNT");
+
+    for (outcome, n) in &outcomes {
+        println!("{:<25} {:>6}", outcome, n);
+    }
+
+    Ok(())
+}
+
+fn call_name(op: &idol::IdolOperation) -> String {
+    format!("{}.{}", op.name.0, op.name.1)
+}
+
 fn hiffy(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
@@ -441,7 +771,8 @@ fn hiffy(
         // the input source.
         let input = if let Some(input) = subargs.input {
             Some(std::fs::read(input)?)
-        } else if op.operation.leases.len() == 1
+        } else if !subargs.profile
+            && op.operation.leases.len() == 1
             && op.operation.leases[0].read
             && !op.operation.leases[0].write
             && atty::isnt(atty::Stream::Stdin)
@@ -453,31 +784,64 @@ fn hiffy(
             None
         };
 
+        if subargs.profile {
+            let count = match subargs.num {
+                Some(0) => bail!("number of calls must be non-zero"),
+                Some(n) => u32::try_from(n)?,
+                None => 100,
+            };
+
+            return hiffy_profile(
+                hubris,
+                core,
+                &mut context,
+                &op,
+                &args,
+                count,
+                subargs.batch,
+            );
+        }
+
+        let trace = subargs.trace;
+
         let (return_code, data) = if let Some(input) = input {
             (
-                hiffy_call(
+                hiffy_call_traced(
                     hubris,
                     core,
                     &mut context,
                     &op,
                     &args,
                     Some(HiffyLease::Write(&input)),
+                    trace,
                 )?,
                 None,
             )
         } else if let Some(read_size) = subargs.num {
             let mut read = vec![0u8; read_size];
-            let r = hiffy_call(
+            let r = hiffy_call_traced(
                 hubris,
                 core,
                 &mut context,
                 &op,
                 &args,
                 Some(HiffyLease::Read(&mut read)),
+                trace,
             )?;
             (r, Some(read))
         } else {
-            (hiffy_call(hubris, core, &mut context, &op, &args, None)?, None)
+            (
+                hiffy_call_traced(
+                    hubris,
+                    core,
+                    &mut context,
+                    &op,
+                    &args,
+                    None,
+                    trace,
+                )?,
+                None,
+            )
         };
 
         hiffy_print_result(hubris, &op, return_code)?;
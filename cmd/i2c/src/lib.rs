@@ -90,6 +90,120 @@
 //! Controller I2C3, device 0x48, register 0x4 = 0x1f
 //! ```
 //!
+//! To scan every bus (and every mux segment on every bus) and compare the
+//! devices found against those that the archive expects, use `--topology`
+//! (`-T`).  Each device is reported as present, missing, absent (that is,
+//! missing but removable) or unexpected; the scan can be restricted to a
+//! single controller, port or bus with `-c`, `-p` or `-b`:
+//!
+//! ```console
+//! % humility i2c -T -c 2
+//! humility: attached via ST-Link
+//! I2C2, port F (m2)
+//!     +-- 0x48 present    tmp451        M.2 A temperature sensor
+//!     +-- 0x70 present    pca9545       mux 1
+//!     +-- mux 1, segment 1
+//!     |   +-- 0x50 present    at24csw080    M.2 A FRU ID
+//!     +-- mux 1, segment 2
+//!     |   +-- 0x50 absent     at24csw080    M.2 B FRU ID
+//!     +-- mux 1, segment 3
+//!     +-- mux 1, segment 4
+//!     |   +-- 0x1c UNEXPECTED -             -
+//!
+//! humility: 3 present, 0 missing, 1 absent (removable), 1 unexpected, no scan errors
+//! ```
+//!
+//! Every segment of each mux that the archive describes is scanned, whether
+//! or not the archive expects any devices on it; a mux that doesn't respond
+//! is reported as missing, as are the devices behind it.
+//!
+//! For devices that have a register map, registers can be specified by
+//! name rather than by number, and the value read from a register will be
+//! decoded into its constituent fields.  Register maps are built in for
+//! some devices (currently, `max5970`, `tmp117`, `pca9545` and `pca9548`)
+//! and are used when the device is specified by its name in the archive or,
+//! for a mux that the archive describes, by its address:
+//!
+//! ```console
+//! % humility i2c -d tmp117 -r configuration
+//! humility: attached via ST-Link
+//! Controller I2C2, device 0x48, register 0x1 = 0x02 0x20
+//! CONFIGURATION (Configuration)
+//!   bits |    value   | field
+//!     15 | 0x0        | HIGH_ALERT
+//!     14 | 0x0        | LOW_ALERT
+//!     13 | 0x0        | DATA_READY
+//!     12 | 0x0        | EEPROM_BUSY
+//!  11:10 | 0x0        | MOD (continuous)
+//!    9:7 | 0x4        | CONV
+//!    6:5 | 0x1        | AVG (8 samples)
+//!      4 | 0x0        | T_NA (alert mode)
+//!      3 | 0x0        | POL (active low)
+//!      2 | 0x0        | DR_ALERT (alert)
+//!      1 | 0x0        | SOFT_RESET
+//! ```
+//!
+//! Absent an explicit `-n`, a register is read at the width given by its
+//! map.  A built-in map can be specified explicitly by its device with
+//! `--regmap` (e.g., `--regmap pca9548`), as can a map for any other device
+//! by its file.  A register map is a TOML file that names the device, the
+//! default register width (in bytes) and byte order, and each register
+//! along with its fields, e.g.:
+//!
+//! ```toml
+//! device = "tmp117"
+//! width = 2
+//! endian = "big"
+//!
+//! [[register]]
+//! name = "CONFIGURATION"
+//! address = 0x01
+//! fields = [
+//!     { name = "MOD", bits = "11:10", values = { 0 = "continuous", 1 = "shutdown" } },
+//!     { name = "AVG", bits = "6:5" },
+//! ]
+//! ```
+//!
+//! A register without an `address` is accessed without a register address
+//! (that is, with a raw read or write), as with the control register of an
+//! I<sup>2</sup>C mux.
+//!
+//! To reproduce a sequence of transactions captured from another tool (e.g.,
+//! a vendor's configuration software), use `--replay` to replay the writes
+//! and reads of a capture on the specified bus, checking that each read
+//! returns what was captured.  The capture can be an Aardvark batch file,
+//! a CSV export of the Saleae I<sup>2</sup>C analyzer (from either Logic 1
+//! or Logic 2), or a CSV file with `op`, `address` and `data` columns:
+//!
+//! ```csv
+//! op,address,data
+//! write,0x48,0x01 0x02 0x20
+//! sleep,,10
+//! write,0x48,0x01
+//! read,0x48,0x02 xx
+//! ```
+//!
+//! In a read, `xx` denotes a byte that need not match; a single-byte write
+//! followed by a read of the same device is replayed as a register read.
+//! (Aardvark batch files don't record what was read, so their reads are
+//! performed but not checked.)  If a device is specified with `-d`, only
+//! transactions to that device are replayed:
+//!
+//! ```console
+//! % humility i2c -b northeast --replay tmp117.csv
+//! humility: attached via ST-Link
+//! humility: replaying 3 transactions on I2C2, port F
+//!     0 write 0x48 0x01 0x02 0x20                ok
+//!     1 sleep 10 ms                              ok
+//!     2 read  0x48 reg 0x01                      0x02 0x20
+//! humility: replayed 3 transactions; 1 read matched
+//! ```
+//!
+//! A transaction that fails stops the replay:  transactions are sent to the
+//! target in batches, but each write begins a new batch, so no write that
+//! follows a failed transaction is performed.  Reads that don't match are
+//! reported and cause the command to fail once the replay is complete.
+//!
 
 use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
@@ -154,11 +268,13 @@ pub struct I2cArgs {
     #[clap(long, short, value_name = "address")]
     device: Option<String>,
 
-    /// specifies register
-    #[clap(long, short, value_name = "register",
-        parse(try_from_str = parse_int::parse),
-    )]
-    register: Option<u8>,
+    /// specifies register, by number or (given a register map) by name
+    #[clap(long, short, value_name = "register")]
+    register: Option<String>,
+
+    /// specifies a register map for the device, by file or built-in device
+    #[clap(long, value_name = "file | device")]
+    regmap: Option<String>,
 
     /// indicates a raw operation
     #[clap(long, short = 'R', conflicts_with = "register")]
@@ -198,11 +314,38 @@ pub struct I2cArgs {
         requires = "device",
     )]
     flash: Option<String>,
+
+    /// scan every bus and mux segment, comparing the devices found against
+    /// those that are expected
+    #[clap(long, short = 'T',
+        conflicts_with_all = &[
+            "scan", "scanreg", "mux", "device", "register", "raw", "write",
+            "writeraw", "nbytes", "flash", "block"
+        ],
+    )]
+    topology: bool,
+
+    /// replays the writes and reads of an I2C capture
+    #[clap(long, value_name = "capture",
+        conflicts_with_all = &[
+            "scan", "scanreg", "register", "regmap", "raw", "write",
+            "writeraw", "nbytes", "flash", "block", "topology"
+        ],
+    )]
+    replay: Option<String>,
 }
 
+mod regmap;
+mod replay;
+mod topology;
+
+use regmap::{Register, RegisterMap};
+
 fn i2c_done(
     subargs: &I2cArgs,
     hargs: &humility_cmd::i2c::I2cArgs,
+    register: Option<u8>,
+    decode: Option<(&RegisterMap, &Register)>,
     results: &[Result<Vec<u8>, u32>],
     func: &HiffyFunction,
 ) -> Result<()> {
@@ -375,7 +518,7 @@ fn i2c_done(
             hargs.controller,
             hargs.address.unwrap(),
             if subargs.writeraw { "raw write to " } else { "" },
-            subargs.register.unwrap()
+            register.unwrap()
         );
 
         if results.is_empty() {
@@ -409,6 +552,10 @@ fn i2c_done(
         }
     }
 
+    if let (Some((map, reg)), Some(Ok(val))) = (decode, results.first()) {
+        reg.print(map.value(val));
+    }
+
     if !errs.is_empty() {
         println!("\nError summary:\n\n  COUNT ERROR");
 
@@ -427,22 +574,44 @@ fn i2c(
     core: &mut dyn Core,
     subargs: &[String],
 ) -> Result<()> {
-    let subargs = I2cArgs::try_parse_from(subargs)?;
+    let mut subargs = I2cArgs::try_parse_from(subargs)?;
 
     if !subargs.scan
         && subargs.scanreg.is_none()
         && subargs.register.is_none()
         && !subargs.raw
         && subargs.flash.is_none()
+        && !subargs.topology
+        && subargs.replay.is_none()
     {
         bail!(
             "must indicate a scan (-s/-S), specify a register (-r), \
-            indicate raw (-R) or flash (-f)"
+            indicate raw (-R), flash (-f), topology (-T) or replay (--replay)"
         );
     }
 
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
 
+    if subargs.topology {
+        let funcs = context.functions()?;
+        let func = funcs.get("I2cRead", 7)?;
+
+        return topology::topology(hubris, core, &mut context, func, &subargs);
+    }
+
+    if let Some(ref filename) = subargs.replay {
+        let hargs = humility_cmd::i2c::I2cArgs::parse(
+            hubris,
+            &subargs.bus,
+            subargs.controller,
+            &subargs.port,
+            &subargs.mux,
+            &subargs.device,
+        )?;
+
+        return replay::replay(core, &mut context, &hargs, filename);
+    }
+
     let (fname, args) = if subargs.flash.is_some() {
         ("I2cBulkWrite", 8)
     } else {
@@ -464,6 +633,75 @@ fn i2c(
         &subargs.device,
     )?;
 
+    //
+    // If we have a register map -- either one that we have been explicitly
+    // given or one that is built in for our device -- we can resolve
+    // registers by name and decode what we read.  Muxes aren't devices in
+    // the archive, so if we have been given the address of a mux on our
+    // bus, we use the map for its driver.
+    //
+    let regmap = match (&subargs.regmap, &hargs.device) {
+        (Some(regmap), _) => Some(RegisterMap::find(regmap)?),
+        (None, Some(device)) => RegisterMap::builtin(device)?,
+        (None, None) => match hubris
+            .manifest
+            .i2c_buses
+            .iter()
+            .filter(|b| b.controller == hargs.controller)
+            .filter(|b| b.port.index == hargs.port.index)
+            .flat_map(|b| b.muxes.iter())
+            .find(|m| Some(m.address) == hargs.address)
+        {
+            Some(mux) => RegisterMap::builtin(&mux.driver)?,
+            None => None,
+        },
+    };
+
+    let register = match &subargs.register {
+        None => None,
+        Some(r) => match (parse_int::parse::<u8>(r), &regmap) {
+            (Ok(register), _) => Some(register),
+            (Err(_), Some(map)) => {
+                let reg = map.lookup(r)?;
+
+                //
+                // A register without an address is accessed via raw
+                // operations.
+                //
+                if reg.address.is_none() {
+                    if subargs.writeraw {
+                        bail!("{} is accessed without a register address", r);
+                    }
+
+                    subargs.raw = true;
+                }
+
+                reg.address
+            }
+            (Err(_), None) => {
+                bail!("no register map for device; invalid register {}", r);
+            }
+        },
+    };
+
+    let reading = subargs.write.is_none() && !subargs.writeraw;
+
+    let decode = match &regmap {
+        Some(map) if reading && (subargs.raw || register.is_some()) => {
+            map.lookup_address(register).map(|reg| (map, reg))
+        }
+        _ => None,
+    };
+
+    //
+    // Absent an explicit size, we read the width of a register in our map.
+    //
+    if let Some((_, reg)) = decode {
+        if subargs.nbytes.is_none() && !subargs.block {
+            subargs.nbytes = Some(reg.width);
+        }
+    }
+
     let mut ops = vec![Op::Push(hargs.controller)];
 
     ops.push(Op::Push(hargs.port.index));
@@ -623,7 +861,7 @@ fn i2c(
         }
 
         if let Some(ref write) = subargs.write {
-            if let Some(register) = subargs.register {
+            if let Some(register) = register {
                 ops.push(Op::Push(register));
             } else {
                 ops.push(Op::PushNone);
@@ -651,10 +889,10 @@ fn i2c(
             // this as our 1-byte payload and set our register to None
             //
             ops.push(Op::PushNone);
-            ops.push(Op::Push(subargs.register.unwrap()));
+            ops.push(Op::Push(register.unwrap()));
             ops.push(Op::Push(1));
         } else {
-            if let Some(register) = subargs.register {
+            if let Some(register) = register {
                 ops.push(Op::Push(register));
             } else {
                 ops.push(Op::PushNone);
@@ -706,7 +944,7 @@ fn i2c(
 
     let results = context.run(core, ops.as_slice(), None)?;
 
-    i2c_done(&subargs, &hargs, &results, func)?;
+    i2c_done(&subargs, &hargs, register, decode, &results, func)?;
 
     Ok(())
 }
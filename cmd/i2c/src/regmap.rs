@@ -0,0 +1,364 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// I2C device register maps.  A register map is a TOML file that names the
+// registers of a particular kind of device (as identified by its `device`
+// in the archive) and the fields within them; maps for some devices are
+// built in (see the `regmaps` directory), and others can be specified
+// with `--regmap` (which can also name a built-in map explicitly).  A
+// register without an address is one that is accessed without a register
+// address, e.g. the control register of an I2C mux.
+//
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+const BUILTIN: &[&str] = &[
+    include_str!("../regmaps/max5970.toml"),
+    include_str!("../regmaps/pca9545.toml"),
+    include_str!("../regmaps/pca9548.toml"),
+    include_str!("../regmaps/tmp117.toml"),
+];
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Endian {
+    Big,
+    Little,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldSpec {
+    name: String,
+    bits: String,
+    values: Option<BTreeMap<String, String>>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegisterSpec {
+    name: String,
+    address: Option<u8>,
+    width: Option<u8>,
+    description: Option<String>,
+    #[serde(default)]
+    fields: Vec<FieldSpec>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegisterMapSpec {
+    device: String,
+    width: Option<u8>,
+    endian: Option<Endian>,
+    register: Vec<RegisterSpec>,
+}
+
+#[derive(Debug)]
+pub struct Field {
+    pub name: String,
+    pub lo: u32,
+    pub hi: u32,
+    pub values: BTreeMap<u32, String>,
+}
+
+#[derive(Debug)]
+pub struct Register {
+    pub name: String,
+    pub address: Option<u8>,
+    pub width: u8,
+    pub description: Option<String>,
+    pub fields: Vec<Field>,
+}
+
+#[derive(Debug)]
+pub struct RegisterMap {
+    pub device: String,
+    pub endian: Endian,
+    pub registers: Vec<Register>,
+}
+
+fn parse_bits(bits: &str) -> Result<(u32, u32)> {
+    let bit = |b: &str| {
+        b.trim()
+            .parse::<u32>()
+            .with_context(|| format!("invalid bit \"{}\"", b))
+    };
+
+    let (hi, lo) = match bits.split_once(':') {
+        Some((hi, lo)) => (bit(hi)?, bit(lo)?),
+        None => (bit(bits)?, bit(bits)?),
+    };
+
+    if hi < lo || hi >= 32 {
+        bail!("invalid bit range \"{}\"", bits);
+    }
+
+    Ok((hi, lo))
+}
+
+impl RegisterMap {
+    fn parse(contents: &str) -> Result<Self> {
+        let spec: RegisterMapSpec = toml::from_str(contents)?;
+        let mut registers = vec![];
+
+        for r in spec.register {
+            let width = r.width.or(spec.width).unwrap_or(1);
+
+            if width == 0 || width > 4 {
+                bail!("{}: invalid width {}", r.name, width);
+            }
+
+            let mut fields = vec![];
+
+            for f in r.fields {
+                let (hi, lo) = parse_bits(&f.bits)
+                    .with_context(|| format!("{}.{}", r.name, f.name))?;
+
+                if hi >= u32::from(width) * 8 {
+                    bail!("{}.{}: field exceeds register", r.name, f.name);
+                }
+
+                let mut values = BTreeMap::new();
+
+                for (val, name) in f.values.unwrap_or_default() {
+                    let val =
+                        parse_int::parse::<u32>(&val).with_context(|| {
+                            format!(
+                                "{}.{}: invalid value {}",
+                                r.name, f.name, val
+                            )
+                        })?;
+
+                    values.insert(val, name);
+                }
+
+                fields.push(Field { name: f.name, lo, hi, values });
+            }
+
+            //
+            // Fields are displayed from the most significant bit down.
+            //
+            fields.sort_by(|a, b| b.lo.cmp(&a.lo));
+
+            registers.push(Register {
+                name: r.name,
+                address: r.address,
+                width,
+                description: r.description,
+                fields,
+            });
+        }
+
+        Ok(Self {
+            device: spec.device,
+            endian: spec.endian.unwrap_or(Endian::Little),
+            registers,
+        })
+    }
+
+    pub fn load(filename: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(filename)
+            .with_context(|| format!("failed to read {}", filename))?;
+
+        Self::parse(&contents)
+            .with_context(|| format!("failed to parse {}", filename))
+    }
+
+    ///
+    /// Returns the built-in register map for the specified device, if any.
+    ///
+    pub fn builtin(device: &str) -> Result<Option<Self>> {
+        for contents in BUILTIN {
+            let map = Self::parse(contents)?;
+
+            if map.device == device {
+                return Ok(Some(map));
+            }
+        }
+
+        Ok(None)
+    }
+
+    ///
+    /// Returns the register map specified with `--regmap`:  either the name
+    /// of a file or the device of a built-in map.
+    ///
+    pub fn find(regmap: &str) -> Result<Self> {
+        if std::path::Path::new(regmap).is_file() {
+            return Self::load(regmap);
+        }
+
+        match Self::builtin(regmap)? {
+            Some(map) => Ok(map),
+            None => bail!(
+                "{} is neither a file nor a built-in register map \
+                (built-in maps: {})",
+                regmap,
+                Self::builtins()?.join(", ")
+            ),
+        }
+    }
+
+    ///
+    /// Returns the devices for which a register map is built in.
+    ///
+    pub fn builtins() -> Result<Vec<String>> {
+        BUILTIN
+            .iter()
+            .map(|contents| Ok(Self::parse(contents)?.device))
+            .collect()
+    }
+
+    ///
+    /// Looks up a register by name (without regard to case).
+    ///
+    pub fn lookup(&self, name: &str) -> Result<&Register> {
+        match self.registers.iter().find(|r| r.name.eq_ignore_ascii_case(name))
+        {
+            Some(r) => Ok(r),
+            None => bail!(
+                "{} has no register {} (registers: {})",
+                self.device,
+                name,
+                self.registers
+                    .iter()
+                    .map(|r| r.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+
+    ///
+    /// Looks up a register by its address; `None` denotes the register that
+    /// is accessed without an address.
+    ///
+    pub fn lookup_address(&self, address: Option<u8>) -> Option<&Register> {
+        self.registers.iter().find(|r| r.address == address)
+    }
+
+    ///
+    /// Assembles the value of a register from the bytes read from it.
+    ///
+    pub fn value(&self, bytes: &[u8]) -> u32 {
+        let fold = |val: u32, b: &u8| (val << 8) | u32::from(*b);
+
+        match self.endian {
+            Endian::Big => bytes.iter().take(4).fold(0, fold),
+            Endian::Little => bytes.iter().take(4).rev().fold(0, fold),
+        }
+    }
+}
+
+impl Field {
+    ///
+    /// Extracts the value of this field from the value of its register.
+    ///
+    pub fn value(&self, value: u32) -> u32 {
+        let mask = ((1u64 << (self.hi - self.lo + 1)) - 1) as u32;
+        (value >> self.lo) & mask
+    }
+}
+
+impl Register {
+    pub fn print(&self, value: u32) {
+        match self.description {
+            Some(ref description) => {
+                println!("{} ({})", self.name, description)
+            }
+            None => println!("{}", self.name),
+        }
+
+        if self.fields.is_empty() {
+            return;
+        }
+
+        println!("  bits |    value   | field");
+
+        for field in &self.fields {
+            let bits = field.value(value);
+
+            let range = if field.hi > field.lo {
+                format!("{}:{}", field.hi, field.lo)
+            } else {
+                format!("{}", field.lo)
+            };
+
+            match field.values.get(&bits) {
+                Some(name) => println!(
+                    "{:>6} | 0x{:<8x} | {} ({})",
+                    range, bits, field.name, name
+                ),
+                None => {
+                    println!("{:>6} | 0x{:<8x} | {}", range, bits, field.name)
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn validate_parse_bits() {
+    assert_eq!(parse_bits("7").unwrap(), (7, 7));
+    assert_eq!(parse_bits("11:10").unwrap(), (11, 10));
+    assert_eq!(parse_bits(" 31 : 0 ").unwrap(), (31, 0));
+    assert!(parse_bits("3:4").is_err());
+    assert!(parse_bits("32").is_err());
+    assert!(parse_bits("7:").is_err());
+    assert!(parse_bits("x").is_err());
+}
+
+#[test]
+fn validate_builtin() {
+    for device in RegisterMap::builtins().unwrap() {
+        let map = RegisterMap::builtin(&device).unwrap().unwrap();
+        assert_eq!(map.device, device);
+        assert_eq!(RegisterMap::find(&device).unwrap().device, device);
+    }
+
+    assert!(RegisterMap::builtin("nonexistent").unwrap().is_none());
+    assert!(RegisterMap::find("nonexistent").is_err());
+}
+
+#[test]
+fn validate_decode() {
+    let map = RegisterMap::builtin("tmp117").unwrap().unwrap();
+
+    //
+    // The TMP117 is big-endian:  0x02 0x20 is 0x0220.
+    //
+    let value = map.value(&[0x02, 0x20]);
+    assert_eq!(value, 0x0220);
+
+    let reg = map.lookup("configuration").unwrap();
+    assert_eq!(reg.address, Some(0x1));
+    assert_eq!(reg.width, 2);
+
+    let field = |name: &str| {
+        reg.fields.iter().find(|f| f.name == name).unwrap().value(value)
+    };
+
+    assert_eq!(field("MOD"), 0);
+    assert_eq!(field("CONV"), 4);
+    assert_eq!(field("AVG"), 1);
+    assert_eq!(field("SOFT_RESET"), 0);
+
+    let avg = reg.fields.iter().find(|f| f.name == "AVG").unwrap();
+    assert_eq!(avg.values[&1], "8 samples");
+
+    //
+    // Fields are sorted from the most significant bit down.
+    //
+    assert!(reg.fields.windows(2).all(|w| w[0].lo > w[1].lo));
+
+    let map = RegisterMap::builtin("pca9548").unwrap().unwrap();
+    assert_eq!(map.value(&[0x34, 0x12]), 0x1234);
+
+    let reg = map.lookup_address(None).unwrap();
+    assert_eq!(reg.name, "CONTROL");
+}
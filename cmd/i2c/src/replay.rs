@@ -0,0 +1,325 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Replay of I2C captures, as provided by `humility i2c --replay`.  (See
+// `humility_cmd::capture` for the formats that we accept.)  A register read
+// in a capture is replayed as such (that is, with a repeated start).
+//
+// To keep the number of round trips to the target down, transactions are
+// batched into as few HIF programs as will hold them -- but because a
+// failed transaction stops the replay, we never batch anything that can
+// change the state of a device after a transaction that might fail:  each
+// write begins a new batch, and only reads and sleeps follow it.
+//
+
+use anyhow::{bail, Result};
+use hif::*;
+use humility::core::Core;
+use humility_cmd::capture::{Capture, Transaction};
+use humility_cmd::hiffy::*;
+
+//
+// Hiffy limits the duration of any one sleep; longer sleeps are broken up
+// into sleeps of this length.
+//
+const MAX_SLEEP_MS: u32 = 100;
+
+//
+// A conservative estimate of the return stack consumed by a result, over
+// and above any data that it carries.
+//
+const RESULT_OVERHEAD: usize = 8;
+
+fn hex(bytes: impl Iterator<Item = Option<u8>>) -> String {
+    bytes
+        .map(|b| match b {
+            Some(b) => format!("0x{:02x}", b),
+            None => "xx".to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+//
+// Generates the HIF for a transaction, returning the ops along with the
+// number of results and the number of bytes of data that they will carry.
+//
+fn transaction_ops(
+    t: &Transaction,
+    read: &HiffyFunction,
+    write: &HiffyFunction,
+    sleep: &HiffyFunction,
+) -> Result<(Vec<Op>, usize, usize)> {
+    let mut ops = vec![];
+
+    match t {
+        Transaction::Write { address, data } => {
+            if data.len() > u8::MAX as usize - 3 {
+                bail!("write of {} bytes is too large", data.len());
+            }
+
+            ops.push(Op::Push(*address));
+            ops.push(Op::PushNone);
+
+            for byte in data {
+                ops.push(Op::Push(*byte));
+            }
+
+            ops.push(Op::Push32(data.len() as u32));
+            ops.push(Op::Call(write.id));
+            ops.push(Op::DropN(data.len() as u8 + 3));
+
+            Ok((ops, 1, 0))
+        }
+        Transaction::Read { address, register, expected } => {
+            let nbytes = match u8::try_from(expected.len()) {
+                Ok(nbytes) => nbytes,
+                Err(_) => {
+                    bail!("read of {} bytes is too large", expected.len())
+                }
+            };
+
+            ops.push(Op::Push(*address));
+
+            match register {
+                Some(register) => ops.push(Op::Push(*register)),
+                None => ops.push(Op::PushNone),
+            }
+
+            ops.push(Op::Push(nbytes));
+            ops.push(Op::Call(read.id));
+            ops.push(Op::DropN(3));
+
+            Ok((ops, 1, expected.len()))
+        }
+        Transaction::Sleep { ms } => {
+            let mut remaining = *ms;
+            let mut nresults = 0;
+
+            while remaining > 0 {
+                let ms = std::cmp::min(remaining, MAX_SLEEP_MS);
+                ops.push(Op::Push(ms as u8));
+                ops.push(Op::Call(sleep.id));
+                ops.push(Op::Drop);
+                remaining -= ms;
+                nresults += 1;
+            }
+
+            Ok((ops, nresults, 0))
+        }
+    }
+}
+
+//
+// Reports the result of a transaction, returning true if it succeeded (and,
+// for a read, if it read what was expected).
+//
+fn report(
+    ndx: usize,
+    t: &Transaction,
+    results: &[Result<Vec<u8>, u32>],
+    func: &HiffyFunction,
+) -> bool {
+    let (desc, expected) = match t {
+        Transaction::Write { address, data } => (
+            format!(
+                "write 0x{:02x} {}",
+                address,
+                hex(data.iter().map(|b| Some(*b)))
+            ),
+            None,
+        ),
+        Transaction::Read { address, register: Some(register), expected } => (
+            format!("read  0x{:02x} reg 0x{:02x}", address, register),
+            Some(expected),
+        ),
+        Transaction::Read { address, register: None, expected } => {
+            (format!("read  0x{:02x}", address), Some(expected))
+        }
+        Transaction::Sleep { ms } => (format!("sleep {} ms", ms), None),
+    };
+
+    print!("{:>5} {:<40} ", ndx, desc);
+
+    if let Some(Err(err)) = results.iter().find(|r| r.is_err()) {
+        println!("Err({})", func.strerror(*err));
+        return false;
+    }
+
+    match (expected, results.first()) {
+        (Some(expected), Some(Ok(val))) => {
+            let matches = expected.len() == val.len()
+                && expected.iter().zip(val.iter()).all(|(e, v)| match e {
+                    Some(e) => e == v,
+                    None => true,
+                });
+
+            let read = hex(val.iter().map(|b| Some(*b)));
+
+            if matches {
+                println!("{}", read);
+            } else {
+                println!(
+                    "{} MISMATCH (expected {})",
+                    read,
+                    hex(expected.iter().copied())
+                );
+            }
+
+            matches
+        }
+        _ => {
+            println!("ok");
+            true
+        }
+    }
+}
+
+pub fn replay(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    hargs: &humility_cmd::i2c::I2cArgs,
+    filename: &str,
+) -> Result<()> {
+    let transactions = Capture::load(filename)?.transactions;
+
+    //
+    // If we have been given a device, we replay only those transactions
+    // that are addressed to it.
+    //
+    let transactions = transactions
+        .into_iter()
+        .enumerate()
+        .filter(|(_, t)| match (hargs.address, t.address()) {
+            (Some(address), Some(taddr)) => address == taddr,
+            _ => true,
+        })
+        .collect::<Vec<_>>();
+
+    if transactions.is_empty() {
+        bail!("{} contains no transactions to replay", filename);
+    }
+
+    let funcs = context.functions()?;
+    let read = funcs.get("I2cRead", 7)?;
+    let write = funcs.get("I2cWrite", 8)?;
+    let sleep = funcs.get("Sleep", 1)?;
+
+    let mut base = vec![Op::Push(hargs.controller), Op::Push(hargs.port.index)];
+
+    if let Some((mux, segment)) = hargs.mux {
+        base.push(Op::Push(mux));
+        base.push(Op::Push(segment));
+    } else {
+        base.push(Op::PushNone);
+        base.push(Op::PushNone);
+    }
+
+    let text = context.text_size() - context.ops_size(&base)? - 1;
+    let rstack = context.rstack_size();
+
+    let mut mismatches = 0;
+    let mut nreads = 0;
+    let mut next = 0;
+
+    humility::msg!(
+        "replaying {} transaction{} on {}",
+        transactions.len(),
+        if transactions.len() != 1 { "s" } else { "" },
+        hargs
+    );
+
+    while next < transactions.len() {
+        //
+        // Gather as many transactions as will fit in both the program text
+        // and the return stack, ending the batch before any write that
+        // isn't its first transaction.
+        //
+        let mut ops = base.clone();
+        let mut chunk = vec![];
+        let (mut tsize, mut rsize) = (0, 0);
+
+        for (ndx, t) in &transactions[next..] {
+            if let Transaction::Write { .. } = t {
+                if !chunk.is_empty() {
+                    break;
+                }
+            }
+
+            let (tops, nresults, nbytes) =
+                transaction_ops(t, read, write, sleep)?;
+            let size = context.ops_size(&tops)?;
+            let rbytes = nresults * RESULT_OVERHEAD + nbytes;
+
+            if tsize + size > text || rsize + rbytes > rstack {
+                if chunk.is_empty() {
+                    bail!("transaction {} is too large to replay", ndx);
+                }
+
+                break;
+            }
+
+            tsize += size;
+            rsize += rbytes;
+            ops.extend(tops);
+            chunk.push((*ndx, t, nresults));
+        }
+
+        ops.push(Op::Done);
+
+        let results = context.run(core, ops.as_slice(), None)?;
+        let mut offs = 0;
+
+        for (ndx, t, nresults) in &chunk {
+            if offs + nresults > results.len() {
+                bail!("replay timed out at transaction {}", ndx);
+            }
+
+            let r = &results[offs..offs + nresults];
+            offs += nresults;
+
+            let func = match t {
+                Transaction::Write { .. } => write,
+                Transaction::Read { .. } => read,
+                Transaction::Sleep { .. } => sleep,
+            };
+
+            if let Transaction::Read { .. } = t {
+                nreads += 1;
+            }
+
+            if !report(*ndx, t, r, func) {
+                //
+                // A failed transaction leaves the device in an unknown
+                // state, so we don't go any further; a read that returns
+                // other than what we expected, on the other hand, may well
+                // be benign.  (Any transactions that remain in this batch
+                // are reads and sleeps, and are not reported.)
+                //
+                if r.iter().any(|r| r.is_err()) {
+                    bail!("transaction {} failed; stopping replay", ndx);
+                }
+
+                mismatches += 1;
+            }
+        }
+
+        next += chunk.len();
+    }
+
+    if mismatches != 0 {
+        bail!("{} of {} reads did not match the capture", mismatches, nreads);
+    }
+
+    humility::msg!(
+        "replayed {} transaction{}; {} read{} matched",
+        transactions.len(),
+        if transactions.len() != 1 { "s" } else { "" },
+        nreads,
+        if nreads != 1 { "s" } else { "" }
+    );
+
+    Ok(())
+}
@@ -0,0 +1,374 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// The I2C topology view, as provided by `humility i2c --topology`.  Every
+// segment of each mux that the archive describes (and that responds on its
+// bus) is scanned; for muxes that are only known by the devices behind them
+// (or whose driver we don't know), we scan only those segments that have
+// devices.  Because devices on the bus itself are visible regardless of
+// which mux segment is enabled, any address that responds on the bus itself
+// is disregarded when scanning a segment.
+//
+
+use super::I2cArgs;
+use anyhow::{bail, Result};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Status {
+    Present,
+    Missing,
+    Absent,
+    Unexpected,
+}
+
+impl Status {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Status::Present => "present",
+            Status::Missing => "MISSING",
+            Status::Absent => "absent",
+            Status::Unexpected => "UNEXPECTED",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Totals {
+    present: usize,
+    missing: usize,
+    absent: usize,
+    unexpected: usize,
+    failed: usize,
+}
+
+///
+/// The result of scanning a single segment (or a bus without a segment):
+/// the addresses that responded, and a count of any errors other than the
+/// absence of a device.
+///
+#[derive(Default)]
+struct Scan {
+    found: BTreeSet<u8>,
+    errors: BTreeMap<String, usize>,
+}
+
+///
+/// Returns the number of segments for the specified mux driver, if known.
+///
+fn nsegments(driver: &str) -> Option<u8> {
+    match driver {
+        "pca9545" => Some(4),
+        "pca9548" | "max7358" => Some(8),
+        _ => None,
+    }
+}
+
+fn scan(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    bus: &HubrisI2cBus,
+    mux: Option<(u8, u8)>,
+) -> Result<Scan> {
+    let mut ops = vec![Op::Push(bus.controller), Op::Push(bus.port.index)];
+
+    if let Some((mux, segment)) = mux {
+        ops.push(Op::Push(mux));
+        ops.push(Op::Push(segment));
+    } else {
+        ops.push(Op::PushNone);
+        ops.push(Op::PushNone);
+    }
+
+    //
+    // This is the same loop as a device scan: a one-byte raw read at each
+    // address.
+    //
+    ops.push(Op::PushNone);
+    ops.push(Op::Push(0));
+    ops.push(Op::PushNone);
+    ops.push(Op::Label(Target(0)));
+    ops.push(Op::Drop);
+    ops.push(Op::Swap);
+    ops.push(Op::Push(1));
+    ops.push(Op::Call(func.id));
+    ops.push(Op::Drop);
+    ops.push(Op::Swap);
+    ops.push(Op::Push(1));
+    ops.push(Op::Add);
+    ops.push(Op::Push(128));
+    ops.push(Op::BranchGreaterThanOrEqualTo(Target(0)));
+    ops.push(Op::Done);
+
+    let results = context.run(core, ops.as_slice(), None)?;
+
+    let mut rval = Scan { found: BTreeSet::new(), errors: BTreeMap::new() };
+
+    for addr in 0..128u8 {
+        match results.get(addr as usize) {
+            Some(Ok(_)) => {
+                rval.found.insert(addr);
+            }
+            Some(Err(err)) => match func.errmap.get(err) {
+                Some(name) if name == "NoDevice" => {}
+                Some(name) if name == "ReservedAddress" => {}
+                _ => {
+                    *rval.errors.entry(func.strerror(*err)).or_insert(0) += 1;
+                }
+            },
+            None => {
+                *rval.errors.entry("timed out".to_string()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    Ok(rval)
+}
+
+fn print_segment(
+    prefix: &str,
+    devices: &[&HubrisI2cDevice],
+    muxes: &[HubrisI2cMux],
+    scan: &Scan,
+    ignore: &BTreeSet<u8>,
+    totals: &mut Totals,
+) {
+    let mut rows = vec![];
+
+    for (ndx, m) in muxes.iter().enumerate() {
+        let status = if scan.found.contains(&m.address) {
+            Status::Present
+        } else {
+            Status::Missing
+        };
+
+        rows.push((
+            m.address,
+            status,
+            m.driver.as_str(),
+            format!("mux {}", ndx + 1),
+        ));
+    }
+
+    for d in devices {
+        let status = if scan.found.contains(&d.address) {
+            Status::Present
+        } else if d.removable {
+            Status::Absent
+        } else {
+            Status::Missing
+        };
+
+        rows.push((
+            d.address,
+            status,
+            d.device.as_str(),
+            d.description.clone(),
+        ));
+    }
+
+    for addr in scan.found.difference(ignore) {
+        if !rows.iter().any(|row| row.0 == *addr) {
+            rows.push((*addr, Status::Unexpected, "-", "-".to_string()));
+        }
+    }
+
+    rows.sort_by_key(|row| row.0);
+
+    for (addr, status, device, description) in rows {
+        match status {
+            Status::Present => totals.present += 1,
+            Status::Missing => totals.missing += 1,
+            Status::Absent => totals.absent += 1,
+            Status::Unexpected => totals.unexpected += 1,
+        }
+
+        println!(
+            "{}+-- 0x{:02x} {:<10} {:<13} {}",
+            prefix,
+            addr,
+            status.as_str(),
+            device,
+            description
+        );
+    }
+
+    for (err, count) in &scan.errors {
+        totals.failed += count;
+
+        println!(
+            "{}+-- {} address{} failed: {}",
+            prefix,
+            count,
+            if *count != 1 { "es" } else { "" },
+            err
+        );
+    }
+}
+
+pub fn topology(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    subargs: &I2cArgs,
+) -> Result<()> {
+    let buses = hubris.manifest.i2c_buses.iter().filter(|bus| {
+        if let Some(ref name) = subargs.bus {
+            if bus.name.as_ref() != Some(name) {
+                return false;
+            }
+        }
+
+        if let Some(controller) = subargs.controller {
+            if bus.controller != controller {
+                return false;
+            }
+        }
+
+        if let Some(ref port) = subargs.port {
+            if !bus.port.name.eq_ignore_ascii_case(port) {
+                return false;
+            }
+        }
+
+        true
+    });
+
+    let mut totals = Totals::default();
+    let mut nbuses = 0;
+
+    for bus in buses {
+        nbuses += 1;
+
+        println!(
+            "I2C{}, port {}{}{}",
+            bus.controller,
+            bus.port.name,
+            match bus.name {
+                Some(ref name) => format!(" ({})", name),
+                None => "".to_string(),
+            },
+            if bus.target { ": target mode, not scanned" } else { "" }
+        );
+
+        if bus.target {
+            println!();
+            continue;
+        }
+
+        //
+        // Group the devices on this bus by mux and segment; devices that
+        // aren't behind a mux have a key of None.
+        //
+        let mut segments: BTreeMap<Option<(u8, u8)>, Vec<&HubrisI2cDevice>> =
+            BTreeMap::new();
+
+        segments.insert(None, vec![]);
+
+        for d in &hubris.manifest.i2c_devices {
+            if d.controller != bus.controller || d.port.index != bus.port.index
+            {
+                continue;
+            }
+
+            let key = match (d.mux, d.segment) {
+                (Some(m), Some(s)) => Some((m, s)),
+                _ => None,
+            };
+
+            segments.entry(key).or_default().push(d);
+        }
+
+        let root = scan(core, context, func, bus, None)?;
+        print_segment(
+            "    ",
+            &segments[&None],
+            &bus.muxes,
+            &root,
+            &BTreeSet::new(),
+            &mut totals,
+        );
+
+        //
+        // Determine the segments to scan:  every segment of every mux that
+        // we know (and that is present), along with any segment that has
+        // devices on it.  (Muxes are numbered from 1, as are segments.)
+        //
+        let mut scans = BTreeSet::new();
+
+        for (ndx, m) in bus.muxes.iter().enumerate() {
+            if let Some(n) = nsegments(&m.driver) {
+                if root.found.contains(&m.address) {
+                    for segment in 1..=n {
+                        scans.insert((ndx as u8 + 1, segment));
+                    }
+                }
+            }
+        }
+
+        scans.extend(segments.keys().flatten());
+
+        for (mux, segment) in scans {
+            let devices = match segments.get(&Some((mux, segment))) {
+                Some(devices) => devices.as_slice(),
+                None => &[],
+            };
+
+            println!("    +-- mux {}, segment {}", mux, segment);
+
+            //
+            // If the mux that the archive describes isn't present, we don't
+            // bother scanning its segments:  any devices behind it are
+            // missing.
+            //
+            let present = match bus.muxes.get(mux as usize - 1) {
+                Some(m) => root.found.contains(&m.address),
+                None => true,
+            };
+
+            let s = if present {
+                scan(core, context, func, bus, Some((mux, segment)))?
+            } else {
+                Scan::default()
+            };
+
+            print_segment(
+                "    |   ",
+                devices,
+                &[],
+                &s,
+                &root.found,
+                &mut totals,
+            );
+        }
+
+        println!();
+    }
+
+    if nbuses == 0 {
+        bail!("no I2C buses match the specified criteria");
+    }
+
+    humility::msg!(
+        "{} present, {} missing, {} absent (removable), {} unexpected, {}",
+        totals.present,
+        totals.missing,
+        totals.absent,
+        totals.unexpected,
+        match totals.failed {
+            0 => "no scan errors".to_string(),
+            1 => "1 scan error".to_string(),
+            n => format!("{} scan errors", n),
+        }
+    );
+
+    Ok(())
+}
@@ -78,17 +78,66 @@
 //! To restart a task that has had a fault injected, again use the `-r` flag to
 //! change its disposition back to restart.
 //!
-//! Finally, to start a task that is not started by default, use the `-s` flag.
+//! To start a task that is not started by default, use the `-s` flag.
+//!
+//! Tasks may be specified by name, by a glob pattern (using `*` and `?`), or
+//! as `all` to denote every task other than the supervisor; a request is
+//! sent to each matching task in turn:
+//!
+//! ```console
+//! % humility jefe -H 'sensor*'
+//! humility: attached via ST-Link
+//! humility: successfully changed disposition for sensor
+//! humility: successfully changed disposition for sensor_polling
+//! ```
+//!
+//! If no operation is specified, `humility jefe` reports the current state
+//! of the specified tasks (or of all tasks if none are specified), as found
+//! in the kernel's task table.  (Note that `jefe` does not externally
+//! expose its restart disposition; a held task can be recognized as one that
+//! remains faulted.)
+//!
+//! ```console
+//! % humility jefe
+//! humility: attached via ST-Link
+//! ID TASK                 GEN STATE
+//!  0 jefe                   0 healthy
+//!  1 rcc_driver             0 healthy
+//! ...
+//!  7 pong                   0 faulted: Injected(#0/gen0)
+//!  8 ping                  41 healthy
+//! ```
+//!
+//! To script fault-recovery testing, a fault can be injected, a dump taken
+//! once all specified tasks have faulted, and the tasks then restarted with
+//! a single invocation by combining `-f` with `--dump` and `--restart`:
+//!
+//! ```console
+//! % humility jefe -f --dump --restart pong
+//! humility: attached via ST-Link
+//! humility: successfully changed disposition for pong
+//! humility: pong: faulted: Injected(#0/gen0)
+//! humility: core halted
+//! humility: dumping to hubris.core.3
+//! humility: dumped 1.12MB in 24 seconds
+//! humility: core resumed
+//! humility: successfully changed disposition for pong
+//! humility: pong: restarted (generation 1)
+//! ```
 //!
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{bail, Result};
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::doppel::{SchedState, Task, TaskState};
 use humility_cmd::jefe::{send_request, JefeRequest};
+use humility_cmd::tasks::read_tasks;
 use humility_cmd::{Archive, Attach, Command, Run, Validate};
 use std::num::NonZeroU32;
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Parser, Debug)]
 #[clap(name = "jefe", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -100,23 +149,209 @@ struct JefeArgs {
     )]
     timeout: u32,
 
-    /// fault the specified task
+    /// fault the specified task(s)
     #[clap(long, short, conflicts_with_all = &["start", "release", "hold"])]
     fault: bool,
 
-    /// start the specified task
+    /// start the specified task(s)
     #[clap(long, short, conflicts_with_all = &["release", "hold"])]
     start: bool,
 
-    /// hold the specified task
+    /// hold the specified task(s)
     #[clap(long, short = 'H', conflicts_with = "release")]
     hold: bool,
 
-    /// release the specified task
+    /// release the specified task(s)
     #[clap(long, short)]
     release: bool,
 
-    task: String,
+    /// after faulting, take a dump once all specified tasks have faulted
+    #[clap(long, requires = "fault")]
+    dump: bool,
+
+    /// after faulting (and dumping), restart the specified task(s)
+    #[clap(long, requires = "fault")]
+    restart: bool,
+
+    /// task name, glob pattern, or "all"
+    task: Option<String>,
+}
+
+///
+/// Simple glob matching, supporting `*` (any sequence of characters) and
+/// `?` (any single character).
+///
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<char>, Vec<char>) =
+        (pattern.chars().collect(), name.chars().collect());
+
+    let (mut pi, mut ni) = (0, 0);
+    let mut backtrack = None;
+
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ni));
+            pi += 1;
+        } else if let Some((bp, bn)) = backtrack {
+            pi = bp + 1;
+            ni = bn + 1;
+            backtrack = Some((bp, bn + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+///
+/// Returns the indices and names of the tasks that match the specified name,
+/// glob pattern or "all".  If `supervisor` is false, the supervisor (task 0)
+/// is excluded from the match -- though it is an error to explicitly name it.
+///
+fn match_tasks<'a>(
+    hubris: &'a HubrisArchive,
+    spec: &str,
+    supervisor: bool,
+) -> Result<Vec<(u32, &'a str)>> {
+    let mut rval = vec![];
+
+    for i in 0..hubris.ntasks() {
+        let name = match hubris.task_name(i) {
+            Some(name) => name,
+            None => continue,
+        };
+
+        let matched = if spec == "all" {
+            true
+        } else if spec.contains(&['*', '?'][..]) {
+            glob_match(spec, name)
+        } else if spec == name {
+            if i == 0 && !supervisor {
+                bail!("cannot change disposition of supervisor task");
+            }
+
+            true
+        } else {
+            false
+        };
+
+        if matched && (i != 0 || supervisor) {
+            rval.push((i as u32, name));
+        }
+    }
+
+    if rval.is_empty() {
+        bail!("no tasks match \"{}\"", spec);
+    }
+
+    Ok(rval)
+}
+
+fn explain(task: &Task) -> String {
+    match task.state {
+        TaskState::Healthy(SchedState::Stopped) => "not started".to_string(),
+        TaskState::Healthy(_) => "healthy".to_string(),
+        TaskState::Faulted { fault, .. } => format!("faulted: {:?}", fault),
+    }
+}
+
+///
+/// Polls the task table until `check` is true for every specified task,
+/// returning the tasks as last read.
+///
+fn wait_for(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    tasks: &[(u32, &str)],
+    timeout: u32,
+    what: &str,
+    check: impl Fn(usize, &Task) -> bool,
+) -> Result<Vec<Task>> {
+    let start = Instant::now();
+
+    loop {
+        let table = read_tasks(hubris, core)?;
+
+        if tasks.iter().all(|&(i, _)| check(i as usize, &table[i as usize])) {
+            return Ok(table);
+        }
+
+        if start.elapsed().as_millis() > timeout.into() {
+            bail!("timed out waiting for task(s) to {}", what);
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn jefe_status(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    spec: Option<&str>,
+) -> Result<()> {
+    let tasks = match spec {
+        Some(spec) => match_tasks(hubris, spec, true)?,
+        None => match_tasks(hubris, "all", true)?,
+    };
+
+    let table = read_tasks(hubris, core)?;
+
+    println!("{:2} {:15} {:>8} STATE", "ID", "TASK", "GEN");
+
+    for (i, name) in tasks {
+        let task = &table[i as usize];
+
+        println!(
+            "{:2} {:15} {:>8} {}",
+            i,
+            name,
+            u32::from(task.generation),
+            explain(task)
+        );
+    }
+
+    Ok(())
+}
+
+fn jefe_request(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    tasks: &[(u32, &str)],
+    request: JefeRequest,
+    timeout: u32,
+) -> Result<()> {
+    let mut failed = 0;
+
+    for &(i, name) in tasks {
+        //
+        // This unwrap is safe: the supervisor is never matched.
+        //
+        let id = NonZeroU32::new(i).unwrap();
+
+        match send_request(hubris, core, request, id, timeout) {
+            Ok(_) => {
+                humility::msg!("successfully changed disposition for {}", name);
+            }
+            Err(err) => {
+                humility::warn!(
+                    "failed to change disposition for {}: {}",
+                    name,
+                    err
+                );
+                failed += 1;
+            }
+        }
+    }
+
+    if failed != 0 {
+        bail!("failed to change disposition for {} task(s)", failed);
+    }
+
+    Ok(())
 }
 
 fn jefe(
@@ -135,29 +370,62 @@ fn jefe(
     } else if subargs.release {
         JefeRequest::Release
     } else {
-        bail!("one of fault, start, hold, or release must be specified");
+        return jefe_status(hubris, core, subargs.task.as_deref());
     };
 
-    let task = hubris
-        .lookup_task(&subargs.task)
-        .ok_or_else(|| anyhow!("couldn't find task {}", subargs.task))?;
-
-    let id = match task {
-        HubrisTask::Kernel => {
-            bail!("cannot change disposition of kernel");
-        }
-        HubrisTask::Task(id) => {
-            if let Some(id) = NonZeroU32::new(*id) {
-                id
-            } else {
-                bail!("cannot change disposition of supervisor task");
-            }
+    let spec = match subargs.task {
+        Some(ref task) => task,
+        None => {
+            bail!("must specify a task, glob pattern, or \"all\"");
         }
     };
 
-    send_request(hubris, core, request, id, subargs.timeout)?;
+    let tasks = match_tasks(hubris, spec, false)?;
+    let timeout = subargs.timeout;
+
+    jefe_request(hubris, core, &tasks, request, timeout)?;
+
+    if !subargs.dump && !subargs.restart {
+        return Ok(());
+    }
+
+    let table = wait_for(hubris, core, &tasks, timeout, "fault", |_, t| {
+        matches!(t.state, TaskState::Faulted { .. })
+    })?;
 
-    humility::msg!("successfully changed disposition for {}", subargs.task);
+    for &(i, name) in &tasks {
+        humility::msg!("{}: {}", name, explain(&table[i as usize]));
+    }
+
+    if subargs.dump {
+        core.halt()?;
+        humility::msg!("core halted");
+
+        let rval = hubris.dump(core, None);
+
+        core.run()?;
+        humility::msg!("core resumed");
+
+        rval?;
+    }
+
+    if subargs.restart {
+        jefe_request(hubris, core, &tasks, JefeRequest::Release, timeout)?;
+
+        let restarted =
+            wait_for(hubris, core, &tasks, timeout, "restart", |i, t| {
+                matches!(t.state, TaskState::Healthy(_))
+                    && t.generation != table[i].generation
+            })?;
+
+        for &(i, name) in &tasks {
+            humility::msg!(
+                "{}: restarted (generation {})",
+                name,
+                u32::from(restarted[i as usize].generation)
+            );
+        }
+    }
 
     Ok(())
 }
@@ -174,3 +442,23 @@ pub fn init() -> (Command, ClapCommand<'static>) {
         JefeArgs::command(),
     )
 }
+
+#[test]
+fn validate_glob_match() {
+    assert!(glob_match("*", "sensor"));
+    assert!(glob_match("*", ""));
+    assert!(glob_match("sensor", "sensor"));
+    assert!(glob_match("sens*", "sensor"));
+    assert!(glob_match("*sor", "sensor"));
+    assert!(glob_match("s*s*r", "sensor"));
+    assert!(glob_match("sens?r", "sensor"));
+    assert!(glob_match("i2c_driver*", "i2c_driver"));
+    assert!(glob_match("*_*", "i2c_driver"));
+
+    assert!(!glob_match("sens", "sensor"));
+    assert!(!glob_match("sens?", "sensor"));
+    assert!(!glob_match("?sensor", "sensor"));
+    assert!(!glob_match("*x*", "sensor"));
+    assert!(!glob_match("s*n*q", "sensor"));
+    assert!(!glob_match("", "sensor"));
+}
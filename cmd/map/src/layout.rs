@@ -0,0 +1,496 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Rendering of the memory layout of an archive, without a target.  Region
+// descriptors are read from the archive's flash image; flash usage comes
+// from the regions that are actually loaded, and RAM usage from the loaded
+// data and BSS (plus the stack, as configured in the app.toml).
+//
+
+use anyhow::{bail, Result};
+use humility::hubris::*;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+
+//
+// The width (in characters) of the usage bar in our ASCII chart.
+//
+const BAR_WIDTH: usize = 20;
+
+//
+// The dimensions of each row of our SVG, in pixels.
+//
+const SVG_ROW_HEIGHT: usize = 20;
+const SVG_BAR_WIDTH: usize = 400;
+const SVG_LABEL_WIDTH: usize = 380;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum Kind {
+    Flash,
+    Ram,
+    Device,
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Flash => "FLASH",
+            Kind::Ram => "RAM",
+            Kind::Device => "DEVICE",
+        }
+    }
+}
+
+struct Region {
+    base: u32,
+    size: u32,
+    used: Option<u32>,
+    kind: Kind,
+    kernel: bool,
+    stack: Option<u32>,
+    name: String,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.base as u64 + self.size as u64
+    }
+
+    fn padding(&self) -> Option<u32> {
+        self.used.map(|used| self.size.saturating_sub(used))
+    }
+}
+
+enum Row<'a> {
+    Region(&'a Region),
+    Gap { base: u32, size: u32, alignment: bool },
+}
+
+fn size(nbytes: u32) -> String {
+    if nbytes >= 1024 && nbytes % 1024 == 0 {
+        format!("{}KiB", nbytes >> 10)
+    } else if nbytes >= 1024 {
+        format!("{:.1}KiB", nbytes as f64 / 1024_f64)
+    } else {
+        format!("{}", nbytes)
+    }
+}
+
+//
+// Determines how much of the specified region is occupied by memory that
+// has been loaded.
+//
+fn loaded(hubris: &HubrisArchive, base: u32, size: u32) -> u32 {
+    let end = base as u64 + size as u64;
+
+    hubris
+        .loaded_regions()
+        .range(base..)
+        .take_while(|(&b, _)| (b as u64) < end)
+        .fold(0, |ttl, (&b, r)| {
+            ttl + (r.size as u64).min(end - b as u64) as u32
+        })
+}
+
+fn regions(hubris: &HubrisArchive) -> Result<Vec<Region>> {
+    let mut core = humility::core::attach_archive(hubris)?;
+    let mut rval = vec![];
+
+    for region in hubris.regions(core.as_mut())?.values() {
+        let kernel = region.daddr.is_none();
+
+        let kind = if region.attr.device {
+            Kind::Device
+        } else if region.attr.write {
+            Kind::Ram
+        } else {
+            Kind::Flash
+        };
+
+        let mut names = vec![];
+
+        for t in &region.tasks {
+            names.push(hubris.lookup_module(*t)?.name.clone());
+        }
+
+        let name = names.join(", ");
+
+        //
+        // For flash regions, the size of the region is the memory in use,
+        // and its mapsize is the memory allocated to it.  For RAM regions,
+        // we determine the usage from the loaded data and BSS -- and the
+        // stack, which Hubris places at the base of a task's RAM, and which
+        // we account for explicitly if it isn't itself a loaded region.
+        //
+        let (stack, used) = match kind {
+            Kind::Device => (None, None),
+            Kind::Flash => (None, Some(region.size)),
+            Kind::Ram if kernel => (None, Some(region.mapsize)),
+            Kind::Ram => {
+                let stack = hubris.manifest.task_stacksize.get(&name).cloned();
+                let mut used = loaded(hubris, region.base, region.mapsize);
+
+                if !hubris.loaded_regions().contains_key(&region.base) {
+                    used += stack.unwrap_or(0);
+                }
+
+                (stack, Some(used))
+            }
+        };
+
+        rval.push(Region {
+            base: region.base,
+            size: region.mapsize,
+            used,
+            kind,
+            kernel,
+            stack,
+            name,
+        });
+    }
+
+    Ok(rval)
+}
+
+//
+// Returns the rows for the specified kind of memory, interleaving gaps.  We
+// consider a gap to be due to alignment if it is smaller than the region
+// that follows it:  as regions must be naturally aligned, such a gap could
+// not have been occupied by that region.
+//
+fn rows(regions: &[Region], kind: Kind) -> Vec<Row> {
+    let mut rows = vec![];
+    let mut end: Option<u64> = None;
+
+    for region in regions.iter().filter(|r| r.kind == kind) {
+        if let Some(end) = end {
+            if (region.base as u64) > end {
+                let size = (region.base as u64 - end) as u32;
+
+                rows.push(Row::Gap {
+                    base: end as u32,
+                    size,
+                    alignment: !region.kernel && size < region.size,
+                });
+            }
+        }
+
+        end = Some(end.unwrap_or(0).max(region.end()));
+        rows.push(Row::Region(region));
+    }
+
+    rows
+}
+
+fn bar(region: &Region) -> String {
+    match region.used {
+        Some(used) if region.size != 0 => {
+            let n = ((used.min(region.size) as u64 * BAR_WIDTH as u64)
+                / region.size as u64) as usize;
+
+            format!("[{}{}]", "#".repeat(n), ".".repeat(BAR_WIDTH - n))
+        }
+        _ => format!("[{}]", " ".repeat(BAR_WIDTH)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn print_row(
+    low: u32,
+    high: u32,
+    size: String,
+    used: String,
+    pad: String,
+    stack: String,
+    usage: String,
+    name: &str,
+) {
+    println!(
+        "  0x{:08x} - 0x{:08x} {:>8} {:>8} {:>8} {:>6} {:22} {}",
+        low, high, size, used, pad, stack, usage, name
+    );
+}
+
+fn print_chart(regions: &[Region]) {
+    for kind in [Kind::Flash, Kind::Ram, Kind::Device] {
+        let rows = rows(regions, kind);
+
+        if rows.is_empty() {
+            continue;
+        }
+
+        println!("{}", kind.name());
+        println!(
+            "  {:10}   {:10} {:>8} {:>8} {:>8} {:>6} {:22} TASK",
+            "LOW", "HIGH", "SIZE", "USED", "PAD", "STACK", "USAGE"
+        );
+
+        let (mut ttl, mut used, mut pad, mut gaps) = (0u64, 0u64, 0u64, 0u64);
+
+        for row in &rows {
+            match row {
+                Row::Region(r) => {
+                    let opt = |val: Option<u32>| match val {
+                        Some(val) => size(val),
+                        None => "-".to_string(),
+                    };
+
+                    print_row(
+                        r.base,
+                        (r.end() as u32).wrapping_sub(1),
+                        size(r.size),
+                        opt(r.used),
+                        opt(r.padding()),
+                        opt(r.stack),
+                        match kind {
+                            Kind::Device => "-".to_string(),
+                            _ => bar(r),
+                        },
+                        &r.name,
+                    );
+
+                    ttl += r.size as u64;
+                    used += r.used.unwrap_or(0) as u64;
+                    pad += r.padding().unwrap_or(0) as u64;
+                }
+                Row::Gap { base, size: gsize, alignment } => {
+                    let dash = || "-".to_string();
+
+                    print_row(
+                        *base,
+                        base + gsize - 1,
+                        size(*gsize),
+                        dash(),
+                        dash(),
+                        dash(),
+                        dash(),
+                        if *alignment { "<alignment gap>" } else { "<gap>" },
+                    );
+
+                    if *alignment {
+                        gaps += *gsize as u64;
+                    }
+                }
+            }
+        }
+
+        if kind != Kind::Device {
+            println!(
+                "  total: {} allocated, {} used, {} padding, \
+                {} in alignment gaps",
+                size(ttl as u32),
+                size(used as u32),
+                size(pad as u32),
+                size(gaps as u32)
+            );
+        }
+
+        println!();
+    }
+}
+
+fn svg(regions: &[Region], filename: &str) -> Result<()> {
+    let mut body = String::new();
+    let mut y = 0;
+
+    for kind in [Kind::Flash, Kind::Ram, Kind::Device] {
+        let rows = rows(regions, kind);
+
+        if rows.is_empty() {
+            continue;
+        }
+
+        let max = rows
+            .iter()
+            .map(|row| match row {
+                Row::Region(r) => r.size,
+                Row::Gap { size, .. } => *size,
+            })
+            .max()
+            .unwrap_or(1)
+            .max(1) as u64;
+
+        y += SVG_ROW_HEIGHT;
+
+        writeln!(
+            body,
+            "<text x=\"0\" y=\"{}\" font-weight=\"bold\">{}</text>",
+            y - 5,
+            kind.name()
+        )?;
+
+        for row in &rows {
+            let (base, rsize, used, label, fill) = match row {
+                Row::Region(r) => (
+                    r.base,
+                    r.size,
+                    r.used,
+                    &r.name[..],
+                    match kind {
+                        Kind::Flash => "#4e79a7",
+                        Kind::Ram => "#59a14f",
+                        Kind::Device => "#9c755f",
+                    },
+                ),
+                Row::Gap { base, size, alignment } => (
+                    *base,
+                    *size,
+                    None,
+                    if *alignment { "<alignment gap>" } else { "<gap>" },
+                    "#ffffff",
+                ),
+            };
+
+            let width = ((rsize as u64 * SVG_BAR_WIDTH as u64) / max).max(1);
+
+            writeln!(
+                body,
+                "<text x=\"0\" y=\"{}\">0x{:08x} {:>8} {}</text>",
+                y + SVG_ROW_HEIGHT - 5,
+                base,
+                size(rsize),
+                label.replace('&', "&amp;").replace('<', "&lt;"),
+            )?;
+
+            writeln!(
+                body,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" \
+                fill=\"{}\" fill-opacity=\"0.3\" stroke=\"black\"/>",
+                SVG_LABEL_WIDTH,
+                y + 2,
+                width,
+                SVG_ROW_HEIGHT - 4,
+                fill
+            )?;
+
+            if let Some(used) = used {
+                let uwidth = (used.min(rsize) as u64 * width) / rsize as u64;
+
+                writeln!(
+                    body,
+                    "<rect x=\"{}\" y=\"{}\" width=\"{}\" \
+                    height=\"{}\" fill=\"{}\"/>",
+                    SVG_LABEL_WIDTH,
+                    y + 2,
+                    uwidth,
+                    SVG_ROW_HEIGHT - 4,
+                    fill
+                )?;
+            }
+
+            y += SVG_ROW_HEIGHT;
+        }
+
+        y += SVG_ROW_HEIGHT;
+    }
+
+    let mut file = File::create(filename)?;
+
+    writeln!(
+        file,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" \
+        height=\"{}\" font-family=\"monospace\" font-size=\"12\">",
+        SVG_LABEL_WIDTH + SVG_BAR_WIDTH + 10,
+        y
+    )?;
+
+    file.write_all(body.as_bytes())?;
+    writeln!(file, "</svg>")?;
+
+    humility::msg!("wrote memory layout to {}", filename);
+
+    Ok(())
+}
+
+//
+// Checks the layout for overlapping regions and for regions that are near
+// exhaustion, returning the number of each found.
+//
+fn check(regions: &[Region], threshold: u8) -> (usize, usize) {
+    let mut overlaps = 0;
+    let mut full = 0;
+    let mut sorted = regions.iter().collect::<Vec<_>>();
+    sorted.sort_by_key(|r| r.base);
+
+    let mut prev: Option<&Region> = None;
+
+    for region in sorted {
+        if let Some(p) = prev {
+            if (region.base as u64) < p.end() {
+                humility::warn!(
+                    "region 0x{:08x} ({}) overlaps region 0x{:08x} ({})",
+                    region.base,
+                    region.name,
+                    p.base,
+                    p.name
+                );
+
+                overlaps += 1;
+            }
+        }
+
+        if prev.map_or(true, |p| region.end() > p.end()) {
+            prev = Some(region);
+        }
+
+        if region.kernel || region.size == 0 {
+            continue;
+        }
+
+        if let Some(used) = region.used {
+            let pct = (used as u64 * 100) / region.size as u64;
+
+            if pct >= threshold as u64 {
+                humility::warn!(
+                    "{} region 0x{:08x} ({}) is {}% full ({} of {})",
+                    region.kind.name(),
+                    region.base,
+                    region.name,
+                    pct,
+                    size(used),
+                    size(region.size)
+                );
+
+                full += 1;
+            }
+        }
+    }
+
+    (overlaps, full)
+}
+
+pub fn layout(
+    hubris: &HubrisArchive,
+    svgfile: Option<&str>,
+    threshold: u8,
+    strict: bool,
+) -> Result<()> {
+    let regions = regions(hubris)?;
+
+    print_chart(&regions);
+
+    if let Some(filename) = svgfile {
+        svg(&regions, filename)?;
+    }
+
+    let (overlaps, full) = check(&regions, threshold);
+
+    //
+    // Overlapping regions are always an error, but a region near exhaustion
+    // is only an error if we have been asked to be strict.
+    //
+    let problems = overlaps + if strict { full } else { 0 };
+
+    if problems != 0 {
+        bail!(
+            "{} problem{} found in memory layout",
+            problems,
+            if problems != 1 { "s" } else { "" }
+        );
+    }
+
+    Ok(())
+}
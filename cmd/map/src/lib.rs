@@ -60,23 +60,94 @@
 //!
 //! (In this case, task 7, `oh_no`, has overflowed its stack -- which
 //! we can see from the `map` output has been sized to only 256 bytes.)
+//!
+//! To see the memory layout of an archive without attaching to a target, use
+//! the `--layout` (`-l`) option.  This reads the region descriptors from the
+//! archive's flash image, and charts flash, RAM and device regions along with
+//! how much of each region is used, how much is padding (e.g., due to the
+//! power-of-two sizing and alignment required by the ARMv7-M MPU), and any
+//! gaps between regions.  For RAM regions, usage includes each task's
+//! configured stack:
+//!
+//! ```console
+//! % humility -a ~/hubris/target/demo/dist/build-demo.zip map --layout
+//! FLASH
+//!   LOW          HIGH           SIZE     USED      PAD  STACK USAGE                  TASK
+//!   0x08000000 - 0x080001ff      512      512        0      - [####################] kernel
+//!   0x08000200 - 0x08004c5f  18.1KiB  18.1KiB        0      - [####################] kernel
+//!   0x08004c60 - 0x0800ffff  44.9KiB        -        -      - -                      <gap>
+//!   0x08010000 - 0x08017fff    32KiB  19.3KiB  12.7KiB      - [############........] jefe
+//!   0x08018000 - 0x08019fff     8KiB   5.1KiB   2.9KiB      - [############........] rcc_driver
+//! ...
+//!   total: 188.8KiB allocated, 107.3KiB used, 81.5KiB padding, 0 in alignment gaps
+//!
+//! RAM
+//!   LOW          HIGH           SIZE     USED      PAD  STACK USAGE                  TASK
+//!   0x20001000 - 0x200013ff     1KiB      928       96    800 [##################..] jefe
+//! ...
+//! humility: WARNING: RAM region 0x20001000 (jefe) is 90% full (928 of 1KiB)
+//! ```
+//!
+//! The layout is checked for overlapping regions, which are reported and
+//! cause the command to fail, allowing it to be used to check archives as
+//! part of continuous integration.  Regions that are nearly exhausted (by
+//! default, 90% or more used; this can be changed with `--threshold`) are
+//! reported as warnings, and cause the command to fail only if `--strict` is
+//! also specified.  To also render the layout as an SVG, specify a file with
+//! `--svg`.
 
 use anyhow::Result;
 use clap::Command as ClapCommand;
 use clap::{CommandFactory, Parser};
 use humility::core::Core;
 use humility::hubris::*;
-use humility_cmd::{Archive, Attach, Command, Run, Validate};
+use humility_cmd::{Archive, Args, Attach, Command, RunUnattached, Validate};
+
+mod layout;
 
 #[derive(Parser, Debug)]
 #[clap(name = "map", about = env!("CARGO_PKG_DESCRIPTION"))]
-struct MapArgs {}
+struct MapArgs {
+    /// display memory layout of the archive without attaching
+    #[clap(long, short)]
+    layout: bool,
+
+    /// also render the memory layout as SVG to the specified file
+    #[clap(long, value_name = "file", requires = "layout")]
+    svg: Option<String>,
+
+    /// percentage of a region in use at which to report it as near exhaustion
+    #[clap(
+        long, value_name = "percent", default_value = "90", requires = "layout",
+        parse(try_from_str = parse_int::parse)
+    )]
+    threshold: u8,
+
+    /// fail if any region is near exhaustion
+    #[clap(long, requires = "layout")]
+    strict: bool,
+}
 
 fn mapcmd(
-    hubris: &HubrisArchive,
-    core: &mut dyn Core,
-    _subargs: &[String],
+    hubris: &mut HubrisArchive,
+    args: &Args,
+    subargs: &[String],
 ) -> Result<()> {
+    let subargs = MapArgs::try_parse_from(subargs)?;
+
+    if subargs.layout {
+        return layout::layout(
+            hubris,
+            subargs.svg.as_deref(),
+            subargs.threshold,
+            subargs.strict,
+        );
+    }
+
+    humility_cmd::attach(hubris, args, Attach::Any, Validate::Booted, map)
+}
+
+fn map(hubris: &HubrisArchive, core: &mut dyn Core) -> Result<()> {
     core.op_start()?;
     let regions = hubris.regions(core)?;
     core.op_done()?;
@@ -134,12 +205,10 @@ fn mapcmd(
 /// This is some init right here
 pub fn init() -> (Command, ClapCommand<'static>) {
     (
-        Command::Attached {
+        Command::Unattached {
             name: "map",
             archive: Archive::Required,
-            attach: Attach::Any,
-            validate: Validate::Booted,
-            run: Run::Subargs(mapcmd),
+            run: RunUnattached::Args(mapcmd),
         },
         MapArgs::command(),
     )
@@ -0,0 +1,393 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// The PMBus fault report, as provided by `humility pmbus --faults`.  For
+// every rail of every PMBus device, we read the full hierarchy of status
+// registers and break down any bits that are set.  Many devices also keep
+// fault records (a "black box") in manufacturer-specific commands; where a
+// device's driver knows of such commands, we read and display them as well.
+//
+
+use super::{all_commands, print_result, PmbusArgs};
+use anyhow::{bail, Result};
+use colored::Colorize;
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::i2c::I2cArgs;
+use pmbus::commands::*;
+use pmbus::*;
+
+//
+// The status registers, from the summary in STATUS_WORD down.
+//
+const STATUS_COMMANDS: &[&str] = &[
+    "STATUS_WORD",
+    "STATUS_VOUT",
+    "STATUS_IOUT",
+    "STATUS_INPUT",
+    "STATUS_TEMPERATURE",
+    "STATUS_CML",
+    "STATUS_OTHER",
+    "STATUS_MFR_SPECIFIC",
+    "STATUS_FANS_1_2",
+    "STATUS_FANS_3_4",
+];
+
+//
+// Drivers don't identify their fault records as such, so we identify them
+// by name: a manufacturer-specific command is taken to be a fault record if
+// its name contains one of the `RECORD` patterns and none of the `EXCLUDE`
+// patterns (which denote fault configuration rather than fault state).  If
+// these change, the documentation of `--faults` should change with them.
+//
+const RECORD: &[&str] = &["BLACKBOX", "BLACK_BOX", "FAULT", "STATUS", "_LOG"];
+const EXCLUDE: &[&str] = &["LIMIT", "RESPONSE", "CLEAR", "CONFIG", "MASK"];
+
+fn read_op(cmd: &dyn pmbus::Command) -> Option<Op> {
+    match cmd.read_op() {
+        pmbus::Operation::ReadByte => Some(Op::Push(1)),
+        pmbus::Operation::ReadWord => Some(Op::Push(2)),
+        pmbus::Operation::ReadWord32 => Some(Op::Push(4)),
+        pmbus::Operation::ReadBlock => Some(Op::PushNone),
+        _ => None,
+    }
+}
+
+fn fault_commands(driver: pmbus::Device) -> (Vec<u8>, Vec<u8>) {
+    let (common, _) = all_commands(pmbus::Device::Common);
+
+    let status = STATUS_COMMANDS
+        .iter()
+        .filter_map(|name| common.get(*name).copied())
+        .collect::<Vec<_>>();
+
+    let mut records = vec![];
+
+    for code in 0..=255u8 {
+        driver.command(code, |cmd| {
+            let name = cmd.name();
+
+            if common.contains_key(name) || read_op(cmd).is_none() {
+                return;
+            }
+
+            if RECORD.iter().any(|p| name.contains(p))
+                && !EXCLUDE.iter().any(|p| name.contains(p))
+            {
+                records.push(code);
+            }
+        });
+    }
+
+    (status, records)
+}
+
+//
+// Prints the breakdown of a status register, returning true if any bit
+// that denotes a fault (or warning) is set.
+//
+fn print_status(
+    subargs: &PmbusArgs,
+    driver: pmbus::Device,
+    code: u8,
+    mode: impl Fn() -> VOutModeCommandData,
+    result: &Result<Vec<u8>, u32>,
+    func: &HiffyFunction,
+) -> bool {
+    let mut name = String::new();
+
+    driver.command(code, |cmd| name = cmd.name().to_string());
+
+    let cmdstr = format!("0x{:02x} {:<25}", code, name);
+
+    let val = match result {
+        Err(err) => {
+            if subargs.errors {
+                println!("{} Err({})", cmdstr, func.strerror(*err));
+            }
+
+            return false;
+        }
+        Ok(val) if val.is_empty() => {
+            if subargs.errors {
+                println!("{} Timed out", cmdstr);
+            }
+
+            return false;
+        }
+        Ok(val) => val,
+    };
+
+    let mut raw = String::from("0x");
+
+    for b in val.iter().rev() {
+        raw.push_str(&format!("{:02x}", b));
+    }
+
+    let mut bits = vec![];
+    let mut faulted = false;
+
+    let _ = driver.interpret(code, val, mode, |field, value| {
+        if !field.bitfield() || value.raw() == 0 {
+            return;
+        }
+
+        //
+        // STATUS_WORD is a summary of the other status registers, some of
+        // whose bits (e.g., the absence of power good) are not themselves
+        // faults; we count only its fault bits, relying on the other
+        // registers to indicate warnings.
+        //
+        if code != CommandCode::STATUS_WORD as u8
+            || field.name().contains("Fault")
+        {
+            faulted = true;
+        }
+
+        let (pos, width) = field.bits();
+
+        let b = if width.0 == 1 {
+            format!("b{}", pos.0)
+        } else {
+            format!("b{}:{}", pos.0 + width.0 - 1, pos.0)
+        };
+
+        bits.push((b, format!("{}", value), field.desc().to_string()));
+    });
+
+    if bits.is_empty() {
+        println!("{} {}", cmdstr, raw);
+        return false;
+    }
+
+    println!("{} {}", cmdstr, raw.red());
+    println!("     |");
+
+    for (b, value, desc) in &bits {
+        println!("     | {:6} {:<30} <= {}", b, value, desc);
+    }
+
+    println!(
+        "     +------------------------------------------\
+        -----------------------------\n"
+    );
+
+    faulted
+}
+
+pub fn faults(
+    subargs: &PmbusArgs,
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    write_func: &HiffyFunction,
+) -> Result<()> {
+    let page = CommandCode::PAGE as u8;
+    let vout_mode = CommandCode::VOUT_MODE as u8;
+
+    let mut nrails = 0;
+    let mut nfaulted = 0;
+
+    for device in &hubris.manifest.i2c_devices {
+        let rails = match &device.class {
+            HubrisI2cDeviceClass::Pmbus { rails } => rails,
+            _ => continue,
+        };
+
+        let driver = match pmbus::Device::from_str(&device.device) {
+            Some(device) => device,
+            None => pmbus::Device::Common,
+        };
+
+        let (status, records) = fault_commands(driver);
+        let harg = I2cArgs::from_device(device);
+
+        let mut ops =
+            vec![Op::Push(harg.controller), Op::Push(harg.port.index)];
+
+        if let Some(mux) = harg.mux {
+            ops.push(Op::Push(mux.0));
+            ops.push(Op::Push(mux.1));
+        } else {
+            ops.push(Op::PushNone);
+            ops.push(Op::PushNone);
+        }
+
+        ops.push(Op::Push(harg.address.unwrap()));
+
+        let mut work = vec![];
+
+        for (rnum, rail) in rails.iter().enumerate() {
+            if let Some(ref only) = subargs.rail {
+                if !only.iter().any(|r| r == rail) {
+                    continue;
+                }
+            }
+
+            let mut calls = vec![];
+
+            if rails.len() > 1 {
+                ops.push(Op::Push(page));
+                ops.push(Op::Push(rnum as u8));
+                ops.push(Op::Push(1));
+                ops.push(Op::Call(write_func.id));
+                ops.push(Op::DropN(3));
+                calls.push(page);
+            }
+
+            for code in std::iter::once(&vout_mode)
+                .chain(status.iter())
+                .chain(records.iter())
+            {
+                driver.command(*code, |cmd| {
+                    if let Some(op) = read_op(cmd) {
+                        ops.push(Op::Push(*code));
+                        ops.push(op);
+                        ops.push(Op::Call(func.id));
+                        ops.push(Op::DropN(2));
+                        calls.push(*code);
+                    }
+                });
+            }
+
+            work.push((rail, calls));
+        }
+
+        if work.is_empty() {
+            continue;
+        }
+
+        ops.push(Op::DropN(5));
+        ops.push(Op::Done);
+
+        let results = context.run(core, ops.as_slice(), None)?;
+        let mut base = 0;
+
+        for (rail, calls) in &work {
+            let results = &results[base..base + calls.len()];
+            base += calls.len();
+            nrails += 1;
+
+            println!("{} {} ({}):\n", device.device.bold(), rail.bold(), harg);
+
+            let mut ndx = 0;
+
+            if calls[ndx] == page {
+                if let Err(code) = results[ndx] {
+                    humility::warn!(
+                        "rail selection failed: {}",
+                        func.strerror(code)
+                    );
+                    continue;
+                }
+
+                ndx += 1;
+            }
+
+            //
+            // Without VOUT_MODE we can't interpret the rail's output voltage
+            // commands; warn and move on to the next rail.
+            //
+            let mode = match (calls.get(ndx), results.get(ndx)) {
+                (Some(code), Some(Ok(val))) if *code == vout_mode => {
+                    ndx += 1;
+
+                    match VOUT_MODE::CommandData::from_slice(val) {
+                        Some(mode) => Some(mode),
+                        None => {
+                            humility::warn!("bad VOUT_MODE: {:x?}", val);
+                            continue;
+                        }
+                    }
+                }
+                (Some(code), Some(Err(err))) if *code == vout_mode => {
+                    humility::warn!(
+                        "can't read VOUT_MODE: {}",
+                        func.strerror(*err)
+                    );
+                    continue;
+                }
+                _ => None,
+            };
+
+            let getmode = || match mode {
+                Some(mode) => mode,
+                None => {
+                    panic!("unexpected call to VOutMode");
+                }
+            };
+
+            let mut faulted = false;
+
+            for (code, result) in calls[ndx..].iter().zip(results[ndx..].iter())
+            {
+                if status.contains(code) {
+                    faulted |= print_status(
+                        subargs, driver, *code, getmode, result, func,
+                    );
+                    continue;
+                }
+
+                let mut rval = Ok(());
+
+                driver.command(*code, |cmd| {
+                    rval = print_result(
+                        subargs,
+                        driver,
+                        *code,
+                        getmode,
+                        cmd,
+                        result,
+                        &func.errmap,
+                    );
+                });
+
+                //
+                // A record that we can't make sense of shouldn't deprive us
+                // of the rest of the report.
+                //
+                if let Err(err) = rval {
+                    humility::warn!(
+                        "{} {}: failed to display 0x{:02x}: {}",
+                        device.device,
+                        rail,
+                        code,
+                        err
+                    );
+                }
+            }
+
+            if faulted {
+                nfaulted += 1;
+            }
+
+            println!();
+        }
+    }
+
+    if nrails == 0 {
+        bail!("no PMBus rails found");
+    }
+
+    if nfaulted == 0 {
+        humility::msg!(
+            "no faults on {} rail{}",
+            nrails,
+            if nrails != 1 { "s" } else { "" }
+        );
+    } else {
+        humility::warn!(
+            "{} of {} rail{} indicate faults",
+            nfaulted,
+            nrails,
+            if nrails != 1 { "s" } else { "" }
+        );
+    }
+
+    Ok(())
+}
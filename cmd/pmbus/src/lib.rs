@@ -2,6 +2,112 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+//! ## `humility pmbus`
+//!
+//! `humility pmbus` operates on PMBus devices in the system.  To list all
+//! PMBus devices, use `-l` (`--list`); to summarize all PMBus rails, use
+//! `-s` (`--summarize`).  To run specific commands on a device, specify the
+//! device by rail (`-r`) or by address (`-d`) along with the commands
+//! (`-C`); to perform writes, use `-w`.
+//!
+//! When a rail faults, use `-f` (`--faults`) to read the status registers
+//! of every PMBus rail (optionally constrained to the rails specified with
+//! `-r`).  Any bits that are set are broken down, and if the driver for a
+//! device has manufacturer-specific fault records (e.g., a black box), they
+//! are read and displayed as well:
+//!
+//! ```console
+//! % humility pmbus -f -r VDD_VCORE
+//! humility: attached via ST-Link
+//! raa229618 VDD_VCORE (I2C3, port H, dev 0x60):
+//!
+//! 0x79 STATUS_WORD               0x0840
+//!      |
+//!      | b11    NoPowerGood                    <= Power Good Status
+//!      | b6     Off                            <= Unit Off
+//!      +-----------------------------------------------------------------------
+//!
+//! 0x7a STATUS_VOUT               0x00
+//! 0x7b STATUS_IOUT               0x00
+//! 0x7c STATUS_INPUT              0x00
+//! 0x7d STATUS_TEMPERATURE        0x00
+//! 0x7e STATUS_CML                0x00
+//! 0x80 STATUS_MFR_SPECIFIC       0x00
+//!
+//! humility: no faults on 1 rail
+//! ```
+//!
+//! If any rail indicates a fault, a warning is emitted at the end of the
+//! report.  Note that drivers don't identify their fault records as such:
+//! a manufacturer-specific command is taken to be a fault record if its name
+//! contains `BLACKBOX`, `BLACK_BOX`, `FAULT`, `STATUS` or `_LOG` -- and
+//! doesn't contain `LIMIT`, `RESPONSE`, `CLEAR`, `CONFIG` or `MASK` (which
+//! denote the configuration of faults rather than their state).  A record
+//! that can't be displayed is reported, and the rest of the report
+//! continues.
+//!
+//! To capture the configuration of PMBus rails, use `--snapshot` to write
+//! every command that can be both read and written to a TOML file; the
+//! rails to snapshot can be specified with `-r` or (for all rails of a
+//! device) `-d`, and otherwise all PMBus rails are captured:
+//!
+//! ```console
+//! % humility pmbus -r VDD_VCORE --snapshot golden.toml
+//! humility: attached via ST-Link
+//! humility: wrote 47 commands from 1 rail to golden.toml
+//! ```
+//!
+//! A snapshot has an entry for each rail, with the raw value of each
+//! command:
+//!
+//! ```toml
+//! [[rail]]
+//! device = "raa229618"
+//! rail = "VDD_VCORE"
+//!
+//! [rail.commands]
+//! IOUT_OC_FAULT_LIMIT = "0x00dc"
+//! ON_OFF_CONFIG = "0x16"
+//! VOUT_COMMAND = "0x0384"
+//! ```
+//!
+//! To compare the configuration of a board against a snapshot, use
+//! `--diff`; each command that differs is displayed, and the command fails
+//! if there are any differences:
+//!
+//! ```console
+//! % humility pmbus --diff golden.toml
+//! humility: attached via ST-Link
+//! raa229618 VDD_VCORE (I2C3, port H, dev 0x60):
+//!     0x21 VOUT_COMMAND              snapshot: 0x0384 0.900V
+//!                                    board:    0x0398 0.920V
+//!
+//! humility: 1 command on 1 of 1 rails differ from golden.toml
+//! ```
+//!
+//! To restore a configuration from a snapshot, use `--restore`: the
+//! commands that differ are converted into writes (as if specified with
+//! `-w`), which are validated before any are performed.  Use `-n` to see
+//! the writes that would be performed without performing them.  A command
+//! that differs but can't be written (e.g., because a field has a value that
+//! has no name to write it by) is reported, and causes the restore to fail
+//! once the other commands have been restored:
+//!
+//! ```console
+//! % humility pmbus --restore golden.toml
+//! humility: attached via ST-Link
+//! raa229618 VDD_VCORE (I2C3, port H, dev 0x60):
+//!     0x21 VOUT_COMMAND              snapshot: 0x0384 0.900V
+//!                                    board:    0x0398 0.920V
+//!
+//! humility: I2C3, port H, dev 0x60, rail 0: successfully wrote VOUT_COMMAND
+//! humility: restored 1 command on 1 rail from golden.toml
+//! ```
+//!
+//! Note that a restore changes only the operating configuration; to persist
+//! it, the device's configuration must be stored (e.g., via
+//! `STORE_USER_ALL`).
+
 use colored::Colorize;
 use humility::core::Core;
 use humility::hubris::*;
@@ -43,6 +149,40 @@ struct PmbusArgs {
     )]
     summarize: bool,
 
+    /// report faults and fault records on all PMBus rails
+    #[clap(
+        long, short = 'f', conflicts_with_all = &[
+            "driver", "controller", "port", "bus", "summarize", "list",
+            "commands", "writes", "device"
+        ]
+    )]
+    faults: bool,
+
+    /// snapshot the configuration of PMBus rails to the specified file
+    #[clap(
+        long, value_name = "file", conflicts_with_all = &[
+            "summarize", "list", "faults", "commands", "writes", "diff",
+            "restore"
+        ]
+    )]
+    snapshot: Option<String>,
+
+    /// compare the configuration of PMBus rails to the specified snapshot
+    #[clap(
+        long, value_name = "file", conflicts_with_all = &[
+            "summarize", "list", "faults", "commands", "writes", "restore"
+        ]
+    )]
+    diff: Option<String>,
+
+    /// restore the configuration of PMBus rails from the specified snapshot
+    #[clap(
+        long, value_name = "file", conflicts_with_all = &[
+            "summarize", "list", "faults", "commands", "writes"
+        ]
+    )]
+    restore: Option<String>,
+
     /// command-specific help
     #[clap(long, short = 'H', value_name = "command")]
     commandhelp: Option<Vec<String>>,
@@ -109,6 +249,9 @@ struct PmbusArgs {
     rail: Option<Vec<String>>,
 }
 
+mod faults;
+mod snapshot;
+
 fn all_commands(
     device: pmbus::Device,
 ) -> (HashMap<String, u8>, HashMap<u8, String>) {
@@ -1008,6 +1151,19 @@ fn writes(
     let writecmds = subargs.writes.as_ref().unwrap();
     let writes = validate_writes(writecmds, device)?;
 
+    apply_writes(core, context, func, write_func, &hargs, device, &writes)
+}
+
+#[rustfmt::skip::macros(bail)]
+fn apply_writes(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    write_func: &HiffyFunction,
+    hargs: &[(I2cArgs, Option<u8>)],
+    device: pmbus::Device,
+    writes: &IndexMap<u8, (String, WriteOp)>,
+) -> Result<()> {
     let mut ops = vec![];
 
     //
@@ -1015,7 +1171,7 @@ fn writes(
     // with any operations to set a command (SendByte) as well as set an
     // entire block (WriteBlock).
     //
-    for (harg, rail) in &hargs {
+    for (harg, rail) in hargs {
         ops.push(Op::Push(harg.controller));
         ops.push(Op::Push(harg.port.index));
 
@@ -1048,7 +1204,7 @@ fn writes(
         ops.push(Op::Call(func.id));
         ops.push(Op::DropN(2));
 
-        for (&code, (_cmd, op)) in &writes {
+        for (&code, (_cmd, op)) in writes {
             match op {
                 WriteOp::Modify(size, _) => {
                     //
@@ -1117,7 +1273,7 @@ fn writes(
         }
     };
 
-    for (harg, rail) in &hargs {
+    for (harg, rail) in hargs {
         if let Some(rnum) = rail {
             if let Err(code) = results[ndx] {
                 bail!("{}: failed to set rail {}: {}", harg, rnum, code);
@@ -1131,7 +1287,7 @@ fn writes(
         //
         ndx += 1;
 
-        for (&_code, (cmd, op)) in &writes {
+        for (&_code, (cmd, op)) in writes {
             match op {
                 WriteOp::Modify(_, _) => {
                     additional = true;
@@ -1162,7 +1318,7 @@ fn writes(
     let mut ops = vec![];
     let mut ndx = 0;
 
-    for (harg, rail) in &hargs {
+    for (harg, rail) in hargs {
         ops.push(Op::Push(harg.controller));
         ops.push(Op::Push(harg.port.index));
 
@@ -1199,7 +1355,7 @@ fn writes(
 
         let getmode = || mode;
 
-        for (&code, (cmd, op)) in &writes {
+        for (&code, (cmd, op)) in writes {
             if let WriteOp::Modify(size, set) = op {
                 let payload = match results[ndx] {
                     Err(code) => {
@@ -1254,7 +1410,7 @@ fn writes(
     // Now take one final lap through our results, reporting any errors
     // that we find.
     //
-    for (harg, rail) in &hargs {
+    for (harg, rail) in hargs {
         if let Some(rnum) = rail {
             if let Err(code) = results[ndx] {
                 bail!("failed to set rail {} on {}: Err({})", rnum, harg, code);
@@ -1263,7 +1419,7 @@ fn writes(
             ndx += 1;
         }
 
-        for (&_code, (cmd, op)) in &writes {
+        for (&_code, (cmd, op)) in writes {
             if let WriteOp::Modify(_, _) = op {
                 if let Err(code) = results[ndx] {
                     bail!(
@@ -1333,6 +1489,47 @@ fn pmbus(
         return Ok(());
     }
 
+    if subargs.faults {
+        faults::faults(&subargs, hubris, core, &mut context, func, write_func)?;
+        return Ok(());
+    }
+
+    if let Some(ref filename) = subargs.snapshot {
+        return snapshot::snapshot(
+            &subargs,
+            hubris,
+            core,
+            &mut context,
+            func,
+            write_func,
+            filename,
+        );
+    }
+
+    if let Some(ref filename) = subargs.diff {
+        return snapshot::diff(
+            &subargs,
+            hubris,
+            core,
+            &mut context,
+            func,
+            write_func,
+            filename,
+        );
+    }
+
+    if let Some(ref filename) = subargs.restore {
+        return snapshot::restore(
+            &subargs,
+            hubris,
+            core,
+            &mut context,
+            func,
+            write_func,
+            filename,
+        );
+    }
+
     if subargs.writes.is_some() {
         writes(&subargs, hubris, core, &mut context, func, write_func)?;
         return Ok(());
@@ -0,0 +1,879 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// PMBus configuration snapshots, as provided by `humility pmbus --snapshot`,
+// `--diff` and `--restore`.  A snapshot is a TOML file that contains, for
+// each rail, the raw value of every command that can be both read and
+// written.  Values are recorded as they are read:  byte, word and 32-bit
+// word commands are recorded as hexadecimal integers, and block commands as
+// comma-separated bytes (that is, in the same form as they are specified
+// to `--writes`).  A restore is performed by converting each value that
+// differs from that on the board into writes of its fields, which are
+// then validated and applied in the same manner as writes given with `-w`.
+//
+
+use super::{
+    all_commands, apply_writes, find_rail, validate_writes, PmbusArgs,
+};
+use anyhow::{bail, Context, Result};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use humility_cmd::i2c::I2cArgs;
+use pmbus::commands::*;
+use pmbus::*;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::fs;
+
+//
+// Commands that can be written but that don't constitute configuration:
+// writing them selects a page or phase, clears status, or changes the
+// operational state of the rail.
+//
+const EXCLUDE: &[&str] = &["PAGE", "PHASE", "OPERATION"];
+
+//
+// A conservative estimate of the return stack consumed by a result, over
+// and above any data that it carries.
+//
+const RESULT_OVERHEAD: usize = 8;
+
+//
+// The largest payload that a block read can return.
+//
+const BLOCK_MAX: usize = 255;
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Snapshot {
+    #[serde(default)]
+    rail: Vec<RailSnapshot>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RailSnapshot {
+    device: String,
+    rail: String,
+    commands: BTreeMap<String, String>,
+}
+
+struct Target<'a> {
+    harg: I2cArgs<'a>,
+    page: Option<u8>,
+    rail: String,
+    driver: pmbus::Device,
+}
+
+struct ConfigCommand {
+    code: u8,
+    name: String,
+    read: Op,
+    block: bool,
+}
+
+fn driver(device: &Option<String>) -> pmbus::Device {
+    match device {
+        Some(device) => match pmbus::Device::from_str(device) {
+            Some(device) => device,
+            None => pmbus::Device::Common,
+        },
+        None => pmbus::Device::Common,
+    }
+}
+
+fn config_commands(driver: pmbus::Device) -> Vec<ConfigCommand> {
+    let mut rval = vec![];
+
+    for code in 0..=255u8 {
+        driver.command(code, |cmd| {
+            let name = cmd.name();
+
+            if EXCLUDE.contains(&name) || name.starts_with("STATUS_") {
+                return;
+            }
+
+            let block = match cmd.write_op() {
+                pmbus::Operation::WriteByte
+                | pmbus::Operation::WriteWord
+                | pmbus::Operation::WriteWord32 => false,
+                pmbus::Operation::WriteBlock => true,
+                _ => return,
+            };
+
+            let read = match cmd.read_op() {
+                pmbus::Operation::ReadByte => Op::Push(1),
+                pmbus::Operation::ReadWord => Op::Push(2),
+                pmbus::Operation::ReadWord32 => Op::Push(4),
+                pmbus::Operation::ReadBlock => Op::PushNone,
+                _ => return,
+            };
+
+            rval.push(ConfigCommand {
+                code,
+                name: name.to_string(),
+                read,
+                block,
+            });
+        });
+    }
+
+    rval
+}
+
+fn encode(block: bool, val: &[u8]) -> String {
+    if block {
+        val.iter().map(|b| format!("0x{:x}", b)).collect::<Vec<_>>().join(",")
+    } else {
+        let mut rval = String::from("0x");
+
+        for b in val.iter().rev() {
+            rval.push_str(&format!("{:02x}", b));
+        }
+
+        rval
+    }
+}
+
+//
+// Converts a value from a snapshot back into bytes:  block commands are
+// comma-separated bytes, and other commands are integers of `nbytes` bytes.
+//
+fn decode(block: bool, nbytes: usize, val: &str) -> Result<Vec<u8>> {
+    if block {
+        return val
+            .split(',')
+            .map(|b| Ok(parse_int::parse::<u8>(b.trim())?))
+            .collect();
+    }
+
+    let v = parse_int::parse::<u32>(val)?;
+
+    if nbytes < 4 && v >> (nbytes * 8) != 0 {
+        bail!("0x{:x} is too large for a {}-byte command", v, nbytes);
+    }
+
+    Ok(v.to_le_bytes()[..nbytes].to_vec())
+}
+
+//
+// Determines the rails that we are to operate upon:  those that have been
+// specified by name, those of the specified device, or (absent either) all
+// PMBus rails.
+//
+fn targets<'a>(
+    subargs: &PmbusArgs,
+    hubris: &'a HubrisArchive,
+) -> Result<Vec<Target<'a>>> {
+    let mut rval = vec![];
+
+    if let Some(ref rails) = subargs.rail {
+        for rail in rails {
+            let (harg, page) = find_rail(hubris, rail)?;
+            let driver = driver(&harg.device);
+            rval.push(Target { harg, page, rail: rail.clone(), driver });
+        }
+    } else if subargs.device.is_some() {
+        let harg = I2cArgs::parse(
+            hubris,
+            &subargs.bus,
+            subargs.controller,
+            &subargs.port,
+            &subargs.mux,
+            &subargs.device,
+        )?;
+
+        let device =
+            hubris.manifest.i2c_devices.iter().find(|d| harg.matches_device(d));
+
+        let (device, rails) = match device {
+            Some(d) => match &d.class {
+                HubrisI2cDeviceClass::Pmbus { rails } if !rails.is_empty() => {
+                    (d, rails)
+                }
+                _ => bail!("{} does not have PMBus rails", harg),
+            },
+            None => bail!("{} is not a known device", harg),
+        };
+
+        for (rnum, rail) in rails.iter().enumerate() {
+            let harg = I2cArgs::from_device(device);
+            let page = if rails.len() > 1 { Some(rnum as u8) } else { None };
+            let driver = driver(&harg.device);
+
+            rval.push(Target { harg, page, rail: rail.clone(), driver });
+        }
+    } else {
+        for device in &hubris.manifest.i2c_devices {
+            if let HubrisI2cDeviceClass::Pmbus { rails } = &device.class {
+                for (rnum, rail) in rails.iter().enumerate() {
+                    let harg = I2cArgs::from_device(device);
+                    let page =
+                        if rails.len() > 1 { Some(rnum as u8) } else { None };
+                    let driver = driver(&harg.device);
+
+                    rval.push(Target {
+                        harg,
+                        page,
+                        rail: rail.clone(),
+                        driver,
+                    });
+                }
+            }
+        }
+    }
+
+    if rval.is_empty() {
+        bail!("no PMBus rails found");
+    }
+
+    Ok(rval)
+}
+
+//
+// Reads the configuration of a rail, returning its VOUT_MODE (if it could
+// be read) along with the value of each configuration command that could
+// be read.  The reads are performed in as many HIF programs as are needed
+// to fit within both the program text and the return stack.
+//
+fn read_config(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    write_func: &HiffyFunction,
+    target: &Target,
+) -> Result<(Option<VOutModeCommandData>, BTreeMap<String, Vec<u8>>)> {
+    let harg = &target.harg;
+    let commands = config_commands(target.driver);
+
+    let mut base = vec![Op::Push(harg.controller), Op::Push(harg.port.index)];
+
+    if let Some(mux) = harg.mux {
+        base.push(Op::Push(mux.0));
+        base.push(Op::Push(mux.1));
+    } else {
+        base.push(Op::PushNone);
+        base.push(Op::PushNone);
+    }
+
+    base.push(Op::Push(harg.address.unwrap()));
+
+    //
+    // We select the page anew in each program, as we can't know what the
+    // device was last left on.
+    //
+    let mut select = vec![];
+
+    if let Some(page) = target.page {
+        select.push(Op::Push(CommandCode::PAGE as u8));
+        select.push(Op::Push(page));
+        select.push(Op::Push(1));
+        select.push(Op::Call(write_func.id));
+        select.push(Op::DropN(3));
+    }
+
+    //
+    // We read VOUT_MODE first, followed by each configuration command.
+    //
+    let reads = std::iter::once((CommandCode::VOUT_MODE as u8, Op::Push(1)))
+        .chain(commands.iter().map(|cmd| (cmd.code, cmd.read)))
+        .collect::<Vec<_>>();
+
+    let text = context.text_size()
+        - context.ops_size(&base)?
+        - context.ops_size(&select)?
+        - 1;
+    let rstack = context.rstack_size()
+        - if select.is_empty() { 0 } else { RESULT_OVERHEAD };
+
+    let mut results = vec![];
+
+    while results.len() < reads.len() {
+        let mut ops = base.clone();
+        ops.extend_from_slice(&select);
+
+        let (mut tsize, mut rsize, mut nreads) = (0, 0, 0);
+
+        for (code, read) in &reads[results.len()..] {
+            let rops =
+                [Op::Push(*code), *read, Op::Call(func.id), Op::DropN(2)];
+            let size = context.ops_size(&rops)?;
+            let rbytes = RESULT_OVERHEAD
+                + match read {
+                    Op::Push(n) => *n as usize,
+                    _ => BLOCK_MAX,
+                };
+
+            if tsize + size > text || rsize + rbytes > rstack {
+                if nreads == 0 {
+                    bail!("PMBus read is too large for HIF program");
+                }
+
+                break;
+            }
+
+            tsize += size;
+            rsize += rbytes;
+            nreads += 1;
+            ops.extend_from_slice(&rops);
+        }
+
+        ops.push(Op::Done);
+
+        let mut r = context.run(core, ops.as_slice(), None)?.into_iter();
+
+        if let Some(page) = target.page {
+            if let Some(Err(code)) = r.next() {
+                bail!(
+                    "{}: failed to set rail {}: {}",
+                    harg,
+                    page,
+                    write_func.strerror(code)
+                );
+            }
+        }
+
+        let r = r.collect::<Vec<_>>();
+
+        if r.len() < nreads {
+            bail!("{}: reading configuration timed out", harg);
+        }
+
+        results.extend(r.into_iter().take(nreads));
+    }
+
+    let mode = match results[0] {
+        Ok(ref val) => VOUT_MODE::CommandData::from_slice(val),
+        Err(_) => None,
+    };
+
+    let mut config = BTreeMap::new();
+
+    for (cmd, result) in commands.iter().zip(results[1..].iter()) {
+        //
+        // Commands that the device doesn't support will generally fail; we
+        // simply leave these out of the configuration.
+        //
+        if let Ok(val) = result {
+            if !val.is_empty() {
+                config.insert(cmd.name.clone(), val.clone());
+            }
+        }
+    }
+
+    Ok((mode, config))
+}
+
+//
+// Returns a VOUT_MODE for interpreting a value.  If the rail's VOUT_MODE
+// couldn't be read, an arbitrary one is returned and `missing` is set, in
+// which case the interpretation should be discarded.
+//
+fn vout_mode(
+    mode: Option<VOutModeCommandData>,
+    missing: &Cell<bool>,
+) -> VOutModeCommandData {
+    match mode {
+        Some(mode) => mode,
+        None => {
+            missing.set(true);
+            VOUT_MODE::CommandData::from_slice(&[0]).unwrap()
+        }
+    }
+}
+
+//
+// Returns a description of a value for display.  Values that have a
+// single (non-bitfield) interpretation are described by it; bitfields are
+// described by the fields that are non-zero.
+//
+fn describe(
+    driver: pmbus::Device,
+    code: u8,
+    mode: Option<VOutModeCommandData>,
+    val: &[u8],
+) -> String {
+    let missing = Cell::new(false);
+    let getmode = || vout_mode(mode, &missing);
+
+    let mut value = None;
+    let mut bits = vec![];
+
+    let _ = driver.interpret(code, val, getmode, |field, v| {
+        if !field.bitfield() {
+            value = Some(format!("{}", v));
+        } else if v.raw() != 0 {
+            bits.push(format!("{}={}", field.name(), v));
+        }
+    });
+
+    if missing.get() {
+        return "(VOUT_MODE unknown)".to_string();
+    }
+
+    match value {
+        Some(value) => value,
+        None => bits.join(", "),
+    }
+}
+
+//
+// Converts a value from a snapshot into the writes that will restore it:
+// block commands are written in their entirety, while other commands are
+// written field by field.
+//
+fn restore_writes(
+    driver: pmbus::Device,
+    code: u8,
+    name: &str,
+    block: bool,
+    mode: Option<VOutModeCommandData>,
+    val: &[u8],
+) -> Result<Vec<String>> {
+    if block {
+        return Ok(vec![format!("{}={}", name, encode(true, val))]);
+    }
+
+    let missing = Cell::new(false);
+    let getmode = || vout_mode(mode, &missing);
+
+    let mut fields = vec![];
+
+    let err = driver.interpret(code, val, getmode, |field, v| {
+        fields.push((
+            field.name().to_string(),
+            field.bitfield(),
+            field.bits().0,
+            v.raw(),
+        ));
+    });
+
+    if missing.get() {
+        bail!("can't interpret {} without VOUT_MODE", name);
+    }
+
+    if err.is_err() {
+        bail!("failed to interpret {}: {:?}", name, err);
+    }
+
+    let mut rval = vec![];
+
+    for (field, bitfield, pos, raw) in fields {
+        if !bitfield {
+            rval.push(format!("{}=0x{:x}", name, raw));
+            continue;
+        }
+
+        let mut sentinel = None;
+
+        let _ = driver.sentinels(code, pos, |s| {
+            if s.raw() == raw {
+                sentinel = Some(s.name().to_string());
+            }
+        });
+
+        match sentinel {
+            Some(sentinel) => {
+                rval.push(format!("{}.{}={}", name, field, sentinel));
+            }
+            None => {
+                humility::warn!(
+                    "{}.{} has value 0x{:x}, which cannot be written; \
+                    leaving it unmodified",
+                    name,
+                    field,
+                    raw
+                );
+            }
+        }
+    }
+
+    Ok(rval)
+}
+
+fn load(filename: &str) -> Result<Snapshot> {
+    let contents = fs::read_to_string(filename)
+        .with_context(|| format!("failed to read {}", filename))?;
+
+    toml::from_str(&contents)
+        .with_context(|| format!("failed to parse {}", filename))
+}
+
+//
+// Resolves each rail in a snapshot to its target on this system, checking
+// that it is the same kind of device.
+//
+fn snapshot_targets<'a>(
+    hubris: &'a HubrisArchive,
+    snapshot: &Snapshot,
+    subargs: &PmbusArgs,
+) -> Result<Vec<Target<'a>>> {
+    let mut rval = vec![];
+
+    for r in &snapshot.rail {
+        if let Some(ref rails) = subargs.rail {
+            if !rails.contains(&r.rail) {
+                continue;
+            }
+        }
+
+        let (harg, page) = find_rail(hubris, &r.rail)?;
+
+        match harg.device {
+            Some(ref device) if *device == r.device => {}
+            _ => {
+                bail!(
+                    "rail {} in snapshot is on a {}, but is on {} here",
+                    r.rail,
+                    r.device,
+                    harg
+                );
+            }
+        }
+
+        let driver = driver(&harg.device);
+        rval.push(Target { harg, page, rail: r.rail.clone(), driver });
+    }
+
+    if rval.is_empty() {
+        bail!("no rails in snapshot to operate upon");
+    }
+
+    Ok(rval)
+}
+
+pub fn snapshot(
+    subargs: &PmbusArgs,
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    write_func: &HiffyFunction,
+    filename: &str,
+) -> Result<()> {
+    let mut snapshot = Snapshot::default();
+    let mut ncommands = 0;
+
+    for target in targets(subargs, hubris)? {
+        let (_, config) =
+            read_config(core, context, func, write_func, &target)?;
+        let blocks = config_commands(target.driver)
+            .into_iter()
+            .filter(|cmd| cmd.block)
+            .map(|cmd| cmd.name)
+            .collect::<Vec<_>>();
+
+        let commands = config
+            .iter()
+            .map(|(name, val)| {
+                (name.clone(), encode(blocks.contains(name), val))
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        ncommands += commands.len();
+
+        snapshot.rail.push(RailSnapshot {
+            device: target.harg.device.clone().unwrap_or_default(),
+            rail: target.rail.clone(),
+            commands,
+        });
+    }
+
+    fs::write(filename, toml::to_string(&snapshot)?)
+        .with_context(|| format!("failed to write {}", filename))?;
+
+    humility::msg!(
+        "wrote {} commands from {} rail{} to {}",
+        ncommands,
+        snapshot.rail.len(),
+        if snapshot.rail.len() != 1 { "s" } else { "" },
+        filename
+    );
+
+    Ok(())
+}
+
+//
+// Compares a snapshot against the board, returning for each rail the
+// commands that differ (with their values in the snapshot) along with the
+// rail's VOUT_MODE.
+//
+#[allow(clippy::type_complexity)]
+fn compare<'a>(
+    subargs: &PmbusArgs,
+    hubris: &'a HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    write_func: &HiffyFunction,
+    filename: &str,
+) -> Result<Vec<(Target<'a>, Option<VOutModeCommandData>, Vec<(u8, Vec<u8>)>)>>
+{
+    let snapshot = load(filename)?;
+    let mut rval = vec![];
+
+    for target in snapshot_targets(hubris, &snapshot, subargs)? {
+        let r = snapshot.rail.iter().find(|r| r.rail == target.rail).unwrap();
+        let (mode, config) =
+            read_config(core, context, func, write_func, &target)?;
+        let (all, _) = all_commands(target.driver);
+        let commands = config_commands(target.driver);
+        let mut differences = vec![];
+
+        println!("{} {} ({}):", r.device, r.rail, target.harg);
+
+        for (name, expected) in &r.commands {
+            let cmd = match commands.iter().find(|c| c.name == *name) {
+                Some(cmd) => cmd,
+                None if all.contains_key(name) => {
+                    bail!("{} is not a configuration command", name);
+                }
+                None => bail!("unrecognized PMBus command {}", name),
+            };
+
+            let found = config.get(name);
+
+            if found.map(|val| encode(cmd.block, val)).as_ref()
+                == Some(expected)
+            {
+                continue;
+            }
+
+            //
+            // To interpret (and later write) the value in the snapshot, we
+            // convert it back into bytes.
+            //
+            let nbytes = match cmd.read {
+                Op::Push(n) => n as usize,
+                _ => 0,
+            };
+
+            let val = decode(cmd.block, nbytes, expected)
+                .with_context(|| format!("invalid value for {}", name))?;
+
+            let board = match found {
+                Some(found) => format!(
+                    "{} {}",
+                    encode(cmd.block, found),
+                    describe(target.driver, cmd.code, mode, found)
+                ),
+                None => "unreadable".to_string(),
+            };
+
+            println!(
+                "    0x{:02x} {:<25} snapshot: {} {}",
+                cmd.code,
+                name,
+                expected,
+                describe(target.driver, cmd.code, mode, &val)
+            );
+            println!("    {:30} board:    {}", "", board);
+
+            differences.push((cmd.code, val));
+        }
+
+        if differences.is_empty() {
+            println!("    matches snapshot");
+        }
+
+        println!();
+
+        rval.push((target, mode, differences));
+    }
+
+    Ok(rval)
+}
+
+pub fn diff(
+    subargs: &PmbusArgs,
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    write_func: &HiffyFunction,
+    filename: &str,
+) -> Result<()> {
+    let rails =
+        compare(subargs, hubris, core, context, func, write_func, filename)?;
+
+    let differ = rails.iter().filter(|(_, _, d)| !d.is_empty()).count();
+    let ndiffs: usize = rails.iter().map(|(_, _, d)| d.len()).sum();
+
+    if differ != 0 {
+        bail!(
+            "{} command{} on {} of {} rails differ from {}",
+            ndiffs,
+            if ndiffs != 1 { "s" } else { "" },
+            differ,
+            rails.len(),
+            filename
+        );
+    }
+
+    humility::msg!("{} rails match {}", rails.len(), filename);
+
+    Ok(())
+}
+
+pub fn restore(
+    subargs: &PmbusArgs,
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    func: &HiffyFunction,
+    write_func: &HiffyFunction,
+    filename: &str,
+) -> Result<()> {
+    let rails =
+        compare(subargs, hubris, core, context, func, write_func, filename)?;
+
+    //
+    // Validate all of our writes before we perform any of them.  A
+    // difference that can't be converted into any writes is reported, but
+    // doesn't prevent us from restoring the others.
+    //
+    let mut work = vec![];
+    let mut nrestored = 0;
+    let mut nskipped = 0;
+
+    for (target, mode, differences) in rails {
+        let commands = config_commands(target.driver);
+        let mut writecmds = vec![];
+
+        for (code, val) in &differences {
+            let cmd = commands.iter().find(|c| c.code == *code).unwrap();
+
+            let w = restore_writes(
+                target.driver,
+                *code,
+                &cmd.name,
+                cmd.block,
+                mode,
+                val,
+            )
+            .with_context(|| format!("rail {}", target.rail))?;
+
+            if w.is_empty() {
+                humility::warn!(
+                    "{}: {} differs from snapshot but cannot be restored",
+                    target.rail,
+                    cmd.name
+                );
+                nskipped += 1;
+                continue;
+            }
+
+            writecmds.extend(w);
+            nrestored += 1;
+        }
+
+        if writecmds.is_empty() {
+            continue;
+        }
+
+        let writes = validate_writes(&writecmds, target.driver)
+            .with_context(|| format!("rail {}", target.rail))?;
+
+        work.push((target, writecmds, writes));
+    }
+
+    if work.is_empty() && nskipped == 0 {
+        humility::msg!("all rails match {}; nothing to restore", filename);
+        return Ok(());
+    }
+
+    let nrails = work.len();
+
+    for (target, writecmds, writes) in work {
+        if subargs.dryrun {
+            for w in &writecmds {
+                humility::msg!("{}: would write {}", target.rail, w);
+            }
+
+            continue;
+        }
+
+        apply_writes(
+            core,
+            context,
+            func,
+            write_func,
+            &[(target.harg, target.page)],
+            target.driver,
+            &writes,
+        )?;
+    }
+
+    humility::msg!(
+        "{} {} command{} on {} rail{} from {}",
+        if subargs.dryrun { "would restore" } else { "restored" },
+        nrestored,
+        if nrestored != 1 { "s" } else { "" },
+        nrails,
+        if nrails != 1 { "s" } else { "" },
+        filename
+    );
+
+    if nskipped != 0 {
+        bail!(
+            "{} differing command{} could not be restored",
+            nskipped,
+            if nskipped != 1 { "s" } else { "" }
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn validate_encode() {
+    assert_eq!(encode(false, &[0x2a]), "0x2a");
+    assert_eq!(encode(false, &[0x01, 0x02]), "0x0201");
+    assert_eq!(encode(false, &[0x78, 0x56, 0x34, 0x12]), "0x12345678");
+    assert_eq!(encode(true, &[0x1, 0x2a, 0xff]), "0x1,0x2a,0xff");
+}
+
+#[test]
+fn validate_decode() {
+    assert_eq!(decode(false, 1, "0x2a").unwrap(), vec![0x2a]);
+    assert_eq!(decode(false, 2, "0x0201").unwrap(), vec![0x01, 0x02]);
+    assert_eq!(decode(false, 2, "513").unwrap(), vec![0x01, 0x02]);
+    assert_eq!(decode(true, 0, "0x1, 0x2a,0xff").unwrap(), vec![1, 0x2a, 0xff]);
+
+    assert!(decode(false, 1, "0x100").is_err());
+    assert!(decode(false, 2, "0x10000").is_err());
+    assert!(decode(false, 4, "0x100000000").is_err());
+    assert!(decode(false, 2, "bogus").is_err());
+    assert!(decode(true, 0, "0x1,0x100").is_err());
+    assert!(decode(true, 0, "").is_err());
+}
+
+#[test]
+fn validate_roundtrip() {
+    let values: &[(bool, &[u8])] = &[
+        (false, &[0x00]),
+        (false, &[0xff]),
+        (false, &[0x00, 0x80]),
+        (false, &[0x34, 0x12]),
+        (false, &[0xef, 0xbe, 0xad, 0xde]),
+        (true, &[0x00]),
+        (true, &[0x4f, 0x58, 0x43]),
+    ];
+
+    for (block, val) in values {
+        let nbytes = if *block { 0 } else { val.len() };
+        let encoded = encode(*block, val);
+
+        assert_eq!(
+            decode(*block, nbytes, &encoded).unwrap(),
+            *val,
+            "{}",
+            encoded
+        );
+    }
+}
@@ -0,0 +1,419 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility profile`
+//!
+//! `humility profile` is a statistical profiler:  it samples the program
+//! counter of a running system at a specified rate (in samples per second,
+//! via `--rate`) for a specified number of samples (via `--samples`), and
+//! attributes each sample to the task and function that contains it.  The
+//! result is displayed as a flat profile, with the most frequently sampled
+//! functions first:
+//!
+//! ```console
+//! % humility profile --samples 500
+//! humility: attached via ST-Link V3
+//! humility: sampling via DWT_PCSR at 100 Hz
+//! humility: 500 samples in 5.03s (99.4 Hz)
+//!  SAMPLES     PCT TASK            FUNCTION
+//!      391  78.20% idle            main
+//!       41   8.20% kernel          pend_context_switch_from_isr
+//!       22   4.40% net             ksz8463::Ksz8463::read
+//!       17   3.40% i2c_driver      drv_stm32xx_i2c::I2cController::wait_until
+//!       11   2.20% kernel          DefaultHandler
+//! ...
+//! ```
+//!
+//! Where the core implements the DWT program counter sample register
+//! (`DWT_PCSR`), samples are taken without perturbing the target.  On cores
+//! that lack it, or if `--halt` is specified, each sample is instead taken
+//! by briefly halting the core and reading the PC; this is more intrusive
+//! (and slower), but has the advantage that the stack of the running task
+//! can be unwound at each sample by specifying `--unwind` (which implies
+//! `--halt`).  To limit the flat profile to a number of functions, use
+//! `--limit`.
+//!
+//! To generate a flame graph, use `--output` to specify a file to which
+//! stacks should be written in the folded format understood by
+//! `flamegraph.pl` and `inferno-flamegraph`.  Each stack is rooted at its
+//! task; absent `--unwind`, stacks consist only of the sampled function (and
+//! any functions that have been inlined into it at the sampled PC):
+//!
+//! ```console
+//! % humility profile --samples 1000 --unwind --output net.folded
+//! humility: attached via ST-Link V3
+//! humility: sampling via halting at 100 Hz
+//! ...
+//! humility: wrote 38 stacks to net.folded
+//! % inferno-flamegraph < net.folded > net.svg
+//! ```
+//!
+//! Note that samples that find the core halted (or otherwise unable to
+//! provide a sample) are counted as `<no sample>`, and samples in memory
+//! that isn't in the archive (e.g., ROM) are attributed to `<unknown>`.
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::tasks::read_tasks;
+use humility_cmd::{Archive, Attach, Command, Run, Validate};
+use humility_cortex::debug::DEMCR;
+use humility_cortex::dwt::DWT_PCSR;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "profile", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct ProfileArgs {
+    /// number of samples to take
+    #[clap(
+        long, short, value_name = "count", default_value = "1000",
+        parse(try_from_str = parse_int::parse)
+    )]
+    samples: u32,
+
+    /// sampling rate, in samples per second
+    #[clap(
+        long, short, value_name = "hz", default_value = "100",
+        parse(try_from_str = parse_int::parse)
+    )]
+    rate: u32,
+
+    /// halt the core to take each sample, even if DWT_PCSR is present
+    #[clap(long)]
+    halt: bool,
+
+    /// unwind the stack of the running task at each sample (implies --halt)
+    #[clap(long, short)]
+    unwind: bool,
+
+    /// write folded stacks to the specified file
+    #[clap(long, short, value_name = "file")]
+    output: Option<String>,
+
+    /// limit the flat profile to the specified number of functions
+    #[clap(long, short, value_name = "count")]
+    limit: Option<usize>,
+}
+
+/// Value read from `DWT_PCSR` when no sample is available
+const PCSR_NOSAMPLE: u32 = 0xffff_ffff;
+
+/// Number of times we read `DWT_PCSR` when determining if it's present
+const PCSR_PROBES: usize = 16;
+
+const NOSAMPLE: &str = "<no sample>";
+const UNKNOWN: &str = "<unknown>";
+
+#[derive(Default)]
+struct Profile {
+    nsamples: u64,
+    flat: HashMap<(String, String), u64>,
+    folded: HashMap<String, u64>,
+}
+
+impl Profile {
+    fn record(&mut self, task: &str, func: &str, stack: &[String]) {
+        self.nsamples += 1;
+
+        *self.flat.entry((task.to_string(), func.to_string())).or_insert(0) +=
+            1;
+
+        let folded = std::iter::once(task.to_string())
+            .chain(stack.iter().cloned())
+            .collect::<Vec<_>>()
+            .join(";");
+
+        *self.folded.entry(folded).or_insert(0) += 1;
+    }
+}
+
+///
+/// Determines if `DWT_PCSR` is implemented.  On cores that lack it, it reads
+/// as zero; on cores that have it, we expect to see a valid PC after a small
+/// number of reads.
+///
+fn pcsr_present(core: &mut dyn Core) -> Result<bool> {
+    for _ in 0..PCSR_PROBES {
+        let pc = DWT_PCSR::read(core)?.eiasample();
+
+        if pc != 0 && pc != PCSR_NOSAMPLE {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn sym_name(hubris: &HubrisArchive, pc: u32) -> (String, Option<u32>) {
+    match hubris.instr_sym(pc) {
+        Some((name, addr)) => (name.to_string(), Some(addr)),
+        None => (format!("0x{:08x}", pc), None),
+    }
+}
+
+///
+/// Attributes a PC to a task and function.  The stack consists of the
+/// function and anything inlined into it, outermost first.
+///
+fn attribute(hubris: &HubrisArchive, pc: u32) -> (String, String, Vec<String>) {
+    let task = hubris.instr_mod(pc).unwrap_or(UNKNOWN).to_string();
+    let (func, base) = sym_name(hubris, pc);
+    let mut stack = vec![func.clone()];
+
+    if let Some(base) = base {
+        for inline in hubris.instr_inlined(pc, base) {
+            stack.push(inline.name.to_string());
+        }
+    }
+
+    (task, func, stack)
+}
+
+///
+/// Unwinds the stack of the task containing the PC, returning it outermost
+/// frame first.  This can only be called with the core halted.
+///
+fn unwind(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    task: &str,
+    stacks: &[u32],
+) -> Result<Option<Vec<String>>> {
+    let ndx = match hubris.lookup_task(task) {
+        Some(HubrisTask::Task(ndx)) => *ndx,
+        _ => return Ok(None),
+    };
+
+    let limit = match stacks.get(ndx as usize) {
+        Some(limit) => *limit,
+        None => return Ok(None),
+    };
+
+    let t = HubrisTask::Task(ndx);
+    let regs = hubris.registers(core, t)?;
+    let frames = hubris.stack(core, t, limit, &regs)?;
+
+    let mut rval = vec![];
+
+    for frame in frames.iter().rev() {
+        match frame.sym {
+            Some(sym) => rval.push(sym.name.clone()),
+            None => {
+                let pc = frame.registers.get(&ARMRegister::PC).unwrap();
+                rval.push(format!("0x{:08x}", pc));
+            }
+        }
+
+        //
+        // Our inlined functions are innermost first.
+        //
+        if let Some(ref inlined) = frame.inlined {
+            for inline in inlined.iter().rev() {
+                rval.push(inline.name.to_string());
+            }
+        }
+    }
+
+    Ok(Some(rval))
+}
+
+fn sample_halted(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    stacks: Option<&[u32]>,
+    profile: &mut Profile,
+) -> Result<()> {
+    let pc = core.read_reg(ARMRegister::PC)?;
+    let (task, func, mut stack) = attribute(hubris, pc);
+
+    if let Some(stacks) = stacks {
+        match unwind(hubris, core, &task, stacks) {
+            Ok(Some(unwound)) => stack = unwound,
+            Ok(None) => {}
+            Err(e) => {
+                log::trace!("unwind failed at 0x{:08x}: {:?}", pc, e);
+            }
+        }
+    }
+
+    profile.record(&task, &func, &stack);
+
+    Ok(())
+}
+
+#[rustfmt::skip::macros(println)]
+fn print_profile(profile: &Profile, limit: Option<usize>) {
+    let mut flat = profile.flat.iter().collect::<Vec<_>>();
+    flat.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+
+    println!("{:>8} {:>7} {:15} FUNCTION", "SAMPLES", "PCT", "TASK");
+
+    for ((task, func), count) in flat.iter().take(limit.unwrap_or(usize::MAX)) {
+        let pct = (**count as f64 * 100.0) / profile.nsamples as f64;
+        println!("{:>8} {:>6.2}% {:15} {}", count, pct, task, func);
+    }
+}
+
+fn write_folded(profile: &Profile, filename: &str) -> Result<()> {
+    let mut file = File::create(filename)
+        .with_context(|| format!("failed to create {}", filename))?;
+
+    let mut folded = profile.folded.iter().collect::<Vec<_>>();
+    folded.sort();
+
+    for (stack, count) in &folded {
+        writeln!(file, "{} {}", stack, count)?;
+    }
+
+    humility::msg!("wrote {} stacks to {}", folded.len(), filename);
+
+    Ok(())
+}
+
+fn sample(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &ProfileArgs,
+    profile: &mut Profile,
+) -> Result<()> {
+    let halt = if subargs.halt || subargs.unwind {
+        true
+    } else if pcsr_present(core)? {
+        false
+    } else {
+        humility::msg!("DWT_PCSR not present; sampling will halt the core");
+        true
+    };
+
+    //
+    // If we're going to unwind, we need the initial stack for each task,
+    // which we get from the task descriptors.
+    //
+    let stacks = if subargs.unwind {
+        let mut stacks = vec![];
+
+        for task in read_tasks(hubris, core)? {
+            let desc: humility_cmd::doppel::TaskDesc =
+                task.descriptor.load_from(hubris, core)?;
+            stacks.push(desc.initial_stack);
+        }
+
+        Some(stacks)
+    } else {
+        None
+    };
+
+    humility::msg!(
+        "sampling via {} at {} Hz",
+        if halt { "halting" } else { "DWT_PCSR" },
+        subargs.rate
+    );
+
+    let interval = Duration::from_secs(1) / subargs.rate;
+    let mut next = Instant::now();
+
+    for _ in 0..subargs.samples {
+        if halt {
+            core.halt()?;
+            let rval = sample_halted(hubris, core, stacks.as_deref(), profile);
+            core.run()?;
+            rval?;
+        } else {
+            let pc = DWT_PCSR::read(core)?.eiasample();
+
+            if pc == PCSR_NOSAMPLE {
+                profile.record(NOSAMPLE, NOSAMPLE, &[]);
+            } else {
+                let (task, func, stack) = attribute(hubris, pc);
+                profile.record(&task, &func, &stack);
+            }
+        }
+
+        //
+        // If we have fallen behind, we don't try to catch up.
+        //
+        next += interval;
+        let now = Instant::now();
+
+        if next > now {
+            thread::sleep(next - now);
+        } else {
+            next = now;
+        }
+    }
+
+    Ok(())
+}
+
+fn profile(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = ProfileArgs::try_parse_from(subargs)?;
+
+    if subargs.rate == 0 {
+        bail!("sampling rate must be non-zero");
+    }
+
+    //
+    // DWT_PCSR is only functional if trace is enabled; if it isn't already,
+    // we enable it for the duration of profiling -- and we are sure to
+    // restore it regardless of how sampling concludes.
+    //
+    let demcr = DEMCR::read(core)?;
+
+    if !demcr.trcena() {
+        let mut val = demcr;
+        val.set_trcena(true);
+        val.write(core)?;
+    }
+
+    let mut profile = Profile::default();
+    let start = Instant::now();
+    let rval = sample(hubris, core, &subargs, &mut profile);
+    let elapsed = start.elapsed().as_secs_f64();
+
+    if !demcr.trcena() {
+        demcr.write(core)?;
+    }
+
+    rval?;
+
+    humility::msg!(
+        "{} samples in {:.2}s ({:.1} Hz)",
+        profile.nsamples,
+        elapsed,
+        profile.nsamples as f64 / elapsed
+    );
+
+    print_profile(&profile, subargs.limit);
+
+    if let Some(ref output) = subargs.output {
+        write_folded(&profile, output)?;
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "profile",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: Run::Subargs(profile),
+        },
+        ProfileArgs::command(),
+    )
+}
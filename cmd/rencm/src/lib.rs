@@ -10,6 +10,7 @@
 
 use humility::core::Core;
 use humility::hubris::*;
+use humility_cmd::capture::{Capture, Format, Transaction};
 use humility_cmd::hiffy::*;
 use humility_cmd::i2c::I2cArgs;
 use humility_cmd::{
@@ -25,10 +26,6 @@ use hif::*;
 use idt8a3xxxx::*;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
-use std::fs;
-
-use serde_derive::Deserialize;
-use serde_xml_rs::from_str;
 
 #[derive(Parser, Debug)]
 #[clap(name = "rencm", about = env!("CARGO_PKG_DESCRIPTION"))]
@@ -80,7 +77,7 @@ struct RencmArgs {
     #[clap(long, short = 'd', value_name = "address")]
     device: Option<String>,
 
-    /// ingest an Aardvark data file or Saleae trace
+    /// ingest an Aardvark data file, Saleae trace or other I2C capture
     #[clap(
         long,
         short = 'i',
@@ -464,7 +461,7 @@ fn rencm_attached(
 }
 
 fn rencm_dump(
-    input: Input,
+    format: Format,
     subargs: &RencmArgs,
     ops: &[Vec<u8>],
     modules: &[Module],
@@ -490,9 +487,12 @@ pub fn idt8a3xxxx_payload<E>(
 ) -> Result<(), E> {{
 
     const PAYLOAD: &[&[u8]] = &["##,
-            match input {
-                Input::Aardvark => "Aardvark output generated by",
-                Input::Saleae => "a Salea trace gathered while",
+            match format {
+                Format::Aardvark => "Aardvark output generated by",
+                Format::Logic1 | Format::Logic2 => {
+                    "a Saleae trace gathered while"
+                }
+                Format::Native => "an I2C capture gathered while",
             }
         );
     }
@@ -674,129 +674,24 @@ pub fn idt8a3xxxx_payload<E>(
     Ok(())
 }
 
-enum Input {
-    Aardvark,
-    Saleae,
-}
-
-#[derive(Debug, Deserialize, PartialEq)]
-#[serde(rename_all = "kebab-case")]
-struct Aardvark {
-    #[serde(rename = "i2c_write")]
-    writes: Vec<AardvarkWrite>,
-}
-
-#[derive(Debug, Deserialize, PartialEq)]
-#[serde(rename_all = "kebab-case")]
-struct AardvarkWrite {
-    #[serde(rename = "$value")]
-    value: String,
-}
-
-fn rencm_aardvark(subargs: &RencmArgs, modules: &[Module]) -> Result<()> {
-    let contents =
-        String::from_utf8(fs::read(subargs.ingest.as_ref().unwrap())?)?;
-    let mut ops = vec![];
-
-    let payload = match contents.find("<aardvark>") {
-        Some(offset) => &contents[offset..],
-        None => {
-            bail!("input is not an Aardvark file");
-        }
-    };
-
-    let aardvark: Aardvark = from_str(payload)?;
-
-    for (windex, w) in aardvark.writes.iter().enumerate() {
-        let mut bytes = vec![];
-
-        for v in w.value.split(' ') {
-            if let Ok(val) = u8::from_str_radix(v, 16) {
-                bytes.push(val);
-            } else {
-                bail!("{}: bad write line: {:?}", windex, w);
-            }
-        }
-
-        ops.push(bytes)
-    }
-
-    rencm_dump(Input::Aardvark, subargs, &ops, modules)?;
-
-    Ok(())
-}
-
-#[derive(Debug, Deserialize)]
-#[allow(dead_code)]
-struct SaleaeTraceRecord {
-    #[serde(rename = "Time [s]")]
-    time: f64,
-
-    #[serde(rename = "Packet ID")]
-    id: u8,
-
-    #[serde(rename = "Address")]
-    address: String,
-
-    #[serde(rename = "Data")]
-    data: String,
-
-    #[serde(rename = "Read/Write")]
-    rw: String,
-
-    #[serde(rename = "ACK/NAK")]
-    acknak: String,
-}
-
 fn rencm_ingest(subargs: &RencmArgs, modules: &[Module]) -> Result<()> {
-    let file = fs::File::open(subargs.ingest.as_ref().unwrap())?;
-
-    let mut rdr = csv::Reader::from_reader(file);
-
-    match rdr.headers() {
-        Ok(hdr) if hdr.len() == 6 => {
-            let mut register = None;
-
-            let mut ops = vec![];
-            let mut bytes = vec![];
+    let capture = Capture::load(subargs.ingest.as_ref().unwrap())?;
 
-            for result in rdr.deserialize() {
-                let record: SaleaeTraceRecord = result?;
-
-                if record.rw == "Read" {
-                    if !bytes.is_empty() {
-                        ops.push(bytes);
-                        bytes = vec![];
-                    }
-
-                    register = None;
-                }
-
-                if record.rw == "Write" {
-                    let datum = match parse_int::parse::<u8>(&record.data) {
-                        Ok(val) => val,
-                        Err(_) => {
-                            bail!("invalid record {:?}", record)
-                        }
-                    };
-
-                    if let Some(val) = register {
-                        bytes.push(val);
-                    }
-
-                    register = Some(datum);
-                }
-            }
-
-            rencm_dump(Input::Saleae, subargs, &ops, modules)?;
-        }
-        _ => {
-            humility::msg!("not a Saleae trace file; assuming Aardvark input");
-            rencm_aardvark(subargs, modules)?;
-        }
-    }
-
-    Ok(())
+    //
+    // We are only interested in the writes in the capture -- and of those,
+    // not in the single-byte writes that set the register to be read (which
+    // the capture has already folded into the reads themselves).
+    //
+    let ops = capture
+        .transactions
+        .into_iter()
+        .filter_map(|t| match t {
+            Transaction::Write { data, .. } => Some(data),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    rencm_dump(capture.format, subargs, &ops, modules)
 }
 
 fn rencm(
@@ -61,6 +61,26 @@
 //! humility: image CRC (0x841f35a5) matches OTP CRC
 //! ```
 //!
+//! When CRCs don't match, it can be useful to know which registers differ.
+//! To verify the live register values against a Power Navigator text file,
+//! use the `--verify` option.  Each PMBus command and DMA register written
+//! by the configuration is read back (DMA registers via `DMAADDR` and
+//! `DMASEQ`), and any that differ are displayed:
+//!
+//! ```console
+//! % humility rendmp -b mid -d 0x5c --verify ./isl68224-0x5c.txt
+//! humility: attached via ST-Link V3
+//! humility: verifying 306 registers on I2C3, port H, dev 0x5c
+//! REGISTER                                     EXPECTED       ACTUAL
+//! 0x21 VOUT_COMMAND (page 1)                     0x0384       0x0398
+//! DMA 0xe60c                                 0x00000012   0x00000010
+//! humility rendmp failed: 2 of 306 registers differ from ./isl68224-0x5c.txt
+//! ```
+//!
+//! Note that the only writes performed are to `PAGE` (to select the rail)
+//! and to `DMAADDR` (to select the DMA register); the configuration itself
+//! is not altered.
+//!
 
 use humility::core::Core;
 use humility::hubris::*;
@@ -76,6 +96,7 @@ use indicatif::{HumanBytes, HumanDuration};
 use indicatif::{ProgressBar, ProgressStyle};
 use num_derive::FromPrimitive;
 use num_traits::FromPrimitive;
+use pmbus::commands::CommandCode;
 use std::collections::HashMap;
 use std::fs::{self, OpenOptions};
 use std::io::prelude::*;
@@ -175,6 +196,15 @@ struct RendmpArgs {
     /// check the OTP CRC against the image CRC
     #[clap(long, short = 'C', requires = "flash")]
     check: bool,
+
+    /// verify live registers against a Power Navigator text file
+    #[clap(
+        long,
+        short = 'V',
+        value_name = "filename",
+        conflicts_with_all = &["ingest", "dump", "flash", "slots", "crc"],
+    )]
+    verify: Option<String>,
 }
 
 #[derive(Copy, Clone, Debug, FromPrimitive)]
@@ -553,6 +583,12 @@ fn all_commands(
     all
 }
 
+//
+// A conservative estimate of the return stack consumed by a result, over
+// and above any data that it carries.
+//
+const RESULT_OVERHEAD: usize = 8;
+
 #[derive(Copy, Clone, Debug)]
 enum Address<'a> {
     Dma(u16),
@@ -657,24 +693,8 @@ pub fn {}_payload<E>(
     Ok(())
 }
 
-fn rendmp_ingest(subargs: &RendmpArgs) -> Result<()> {
-    let filename = subargs.ingest.as_ref().unwrap();
-    let file = fs::File::open(filename)?;
-    let lines = BufReader::new(file).lines();
-
+fn pmbus_commands(device: pmbus::Device) -> HashMap<u8, &'static str> {
     let mut allcmds = HashMap::new();
-    let mut packets = vec![];
-
-    let device = if let Some(driver) = &subargs.driver {
-        match pmbus::Device::from_str(driver) {
-            Some(device) => device,
-            None => {
-                bail!("unknown device \"{}\"", driver);
-            }
-        }
-    } else {
-        bail!("must specify device driver");
-    };
 
     for code in 0..0xffu8 {
         device.command(code, |cmd| {
@@ -682,6 +702,21 @@ fn rendmp_ingest(subargs: &RendmpArgs) -> Result<()> {
         });
     }
 
+    allcmds
+}
+
+//
+// Parses a Power Navigator text file into its constituent packets, each of
+// which is either a PMBus write or a DMA write.
+//
+fn rendmp_packets<'a>(
+    filename: &str,
+    allcmds: &'a HashMap<u8, &'static str>,
+) -> Result<Vec<Packet<'a>>> {
+    let file = fs::File::open(filename)?;
+    let lines = BufReader::new(file).lines();
+    let mut packets = vec![];
+
     for (ndx, line) in lines.enumerate() {
         let line = line?;
         let lineno = ndx + 1;
@@ -761,6 +796,26 @@ fn rendmp_ingest(subargs: &RendmpArgs) -> Result<()> {
         packets.push(Packet { address, payload });
     }
 
+    Ok(packets)
+}
+
+fn rendmp_ingest(subargs: &RendmpArgs) -> Result<()> {
+    let filename = subargs.ingest.as_ref().unwrap();
+
+    let device = if let Some(driver) = &subargs.driver {
+        match pmbus::Device::from_str(driver) {
+            Some(device) => device,
+            None => {
+                bail!("unknown device \"{}\"", driver);
+            }
+        }
+    } else {
+        bail!("must specify device driver");
+    };
+
+    let allcmds = pmbus_commands(device);
+    let mut packets = rendmp_packets(filename, &allcmds)?;
+
     packets.push(Packet {
         address: Address::Pmbus(0xe7, allcmds.get(&0xe7).unwrap()),
         payload: vec![1, 0],
@@ -956,6 +1011,253 @@ fn rendmp(
         return Ok(());
     }
 
+    if let Some(ref filename) = subargs.verify {
+        let allcmds = pmbus_commands(device);
+        let packets = rendmp_packets(filename, &allcmds)?;
+        let page = CommandCode::PAGE as u8;
+
+        //
+        // A configuration may write a register more than once; it's only
+        // the last write (to a given page, in the case of a PMBus command)
+        // that we expect to find in the device.  DMA addresses are not
+        // paged.
+        //
+        let mut registers: Vec<(Option<u8>, Address, &[u8])> = vec![];
+        let mut index = HashMap::new();
+        let mut current = None;
+
+        for packet in &packets {
+            let key = match packet.address {
+                Address::Pmbus(code, _) if code == page => {
+                    current = packet.payload.first().copied();
+                    continue;
+                }
+                Address::Pmbus(code, _) => (current, Some(code), 0),
+                Address::Dma(addr) => (None, None, addr),
+            };
+
+            match index.get(&key) {
+                Some(&ndx) => registers[ndx].2 = packet.payload.as_slice(),
+                None => {
+                    index.insert(key, registers.len());
+                    registers.push((
+                        key.0,
+                        packet.address,
+                        packet.payload.as_slice(),
+                    ));
+                }
+            }
+        }
+
+        let nbytes = |address: &Address| -> Option<u8> {
+            match address {
+                Address::Dma(_) => Some(4),
+                Address::Pmbus(code, _) => {
+                    let mut nbytes = None;
+
+                    device.command(*code, |cmd| {
+                        nbytes = match cmd.read_op() {
+                            pmbus::Operation::ReadByte => Some(1),
+                            pmbus::Operation::ReadWord => Some(2),
+                            pmbus::Operation::ReadWord32 => Some(4),
+                            _ => None,
+                        };
+                    });
+
+                    nbytes
+                }
+            }
+        };
+
+        let (readable, unreadable): (Vec<_>, Vec<_>) = registers
+            .iter()
+            .partition(|(_, address, _)| nbytes(address).is_some());
+
+        for (_, address, _) in &unreadable {
+            if let Address::Pmbus(code, name) = address {
+                humility::msg!(
+                    "0x{:02x} {} is not readable; skipping",
+                    code,
+                    name
+                );
+            }
+        }
+
+        humility::msg!(
+            "verifying {} register{} on {}",
+            readable.len(),
+            if readable.len() != 1 { "s" } else { "" },
+            hargs
+        );
+
+        let text = context.text_size() - context.ops_size(&base)? - 1;
+        let rstack = context.rstack_size();
+
+        let mut nmismatches = 0;
+        let mut next = 0;
+        let mut header = false;
+
+        let hex = |bytes: &[u8]| {
+            let mut s = String::from("0x");
+
+            for b in bytes.iter().rev() {
+                s.push_str(&format!("{:02x}", b));
+            }
+
+            s
+        };
+
+        while next < readable.len() {
+            //
+            // Gather as many register reads as will fit in both the program
+            // text and the return stack.  We select the page anew for each
+            // batch, as we can't know what the device was last left on.
+            //
+            let mut ops = base.clone();
+            let mut chunk = vec![];
+            let mut selected = None;
+            let (mut tsize, mut rsize) = (0, 0);
+
+            for (rpage, address, expected) in &readable[next..] {
+                let mut rops = vec![];
+                let mut nresults = 0;
+                let n = nbytes(address).unwrap();
+
+                if rpage.is_some() && *rpage != selected {
+                    rops.push(Op::Push(page));
+                    rops.push(Op::Push(rpage.unwrap()));
+                    rops.push(Op::Push(1));
+                    rops.push(Op::Call(i2c_write.id));
+                    rops.push(Op::DropN(3));
+                    nresults += 1;
+                }
+
+                match address {
+                    Address::Dma(addr) => {
+                        dmaread_ops(&mut rops, addr.to_le_bytes(), n);
+                        nresults += 2;
+                    }
+                    Address::Pmbus(code, _) => {
+                        rops.push(Op::Push(*code));
+                        rops.push(Op::Push(n));
+                        rops.push(Op::Call(i2c_read.id));
+                        rops.push(Op::DropN(2));
+                        nresults += 1;
+                    }
+                }
+
+                let size = context.ops_size(&rops)?;
+                let rbytes = nresults * RESULT_OVERHEAD + n as usize;
+
+                if tsize + size > text || rsize + rbytes > rstack {
+                    if chunk.is_empty() {
+                        bail!("register read is too large for HIF program");
+                    }
+
+                    break;
+                }
+
+                if rpage.is_some() {
+                    selected = *rpage;
+                }
+
+                tsize += size;
+                rsize += rbytes;
+                ops.extend(rops);
+                chunk.push((*rpage, *address, *expected, nresults));
+            }
+
+            ops.push(Op::Done);
+
+            let results = context.run(core, ops.as_slice(), None)?;
+            let mut offs = 0;
+
+            for (rpage, address, expected, nresults) in &chunk {
+                if offs + nresults > results.len() {
+                    bail!("verification timed out");
+                }
+
+                let r = &results[offs..offs + nresults];
+                offs += nresults;
+
+                let name = match address {
+                    Address::Dma(addr) => format!("DMA 0x{:04x}", addr),
+                    Address::Pmbus(code, name) => match rpage {
+                        Some(p) => {
+                            format!("0x{:02x} {} (page {})", code, name, p)
+                        }
+                        None => format!("0x{:02x} {}", code, name),
+                    },
+                };
+
+                //
+                // Every result but the last is a write (to either PAGE or
+                // DMAADDR); the last is our read.
+                //
+                for (ndx, result) in r.iter().enumerate() {
+                    if let Err(err) = result {
+                        let func = if ndx == r.len() - 1 {
+                            &i2c_read
+                        } else {
+                            &i2c_write
+                        };
+
+                        bail!(
+                            "failed to read {}: {}",
+                            name,
+                            func.strerror(*err)
+                        );
+                    }
+                }
+
+                let actual = r[r.len() - 1].as_ref().unwrap();
+                let len = expected.len().min(actual.len());
+
+                if actual[..len] == expected[..] {
+                    continue;
+                }
+
+                if !header {
+                    println!(
+                        "{:<40} {:>12} {:>12}",
+                        "REGISTER", "EXPECTED", "ACTUAL"
+                    );
+                    header = true;
+                }
+
+                println!(
+                    "{:<40} {:>12} {:>12}",
+                    name,
+                    hex(expected),
+                    hex(&actual[..len])
+                );
+
+                nmismatches += 1;
+            }
+
+            next += chunk.len();
+        }
+
+        if nmismatches != 0 {
+            bail!(
+                "{} of {} register{} differ from {}",
+                nmismatches,
+                readable.len(),
+                if readable.len() != 1 { "s" } else { "" },
+                filename
+            );
+        }
+
+        humility::msg!(
+            "all {} register{} match {}",
+            readable.len(),
+            if readable.len() != 1 { "s" } else { "" },
+            filename
+        );
+
+        return Ok(());
+    }
+
     if let Some(ref flash) = subargs.flash {
         let hex = RendmpHex::from_file(flash, address)?;
 
@@ -17,6 +17,80 @@
 //! either device), but if multiple kinds of specifications are present, they
 //! serve as a logical AND (e.g., `-t thermal -d raa229618,tmp117` would yield
 //! all thermal sensors from either device).
+//!
+//! To check sensor values against expected ranges (e.g., as part of board
+//! acceptance), use `--thresholds` to specify a TOML file of thresholds,
+//! specified either by sensor kind or by sensor name (with any bound for a
+//! name overriding the corresponding bound for its kind).  As a device may
+//! have several sensors of the same name (e.g., the voltage, current and
+//! power of a rail), a sensor name can be qualified with its kind -- and
+//! must be if sensors of that name are of more than one kind:
+//!
+//! ```toml
+//! [kind.temp]
+//! max = 70.0
+//! critical = 85.0
+//!
+//! [sensor.V3P3_SYS_A0.voltage]
+//! min = 3.2
+//! max = 3.4
+//! ```
+//!
+//! A sensor is out of range if it is below `min` or above `max`, and is
+//! critically out of range if it is at or above `critical`; a sensor that
+//! has a threshold but cannot be read is also considered out of range.  Each
+//! violation is reported, and if there are any, `humility sensors` exits
+//! with a non-zero status:
+//!
+//! ```console
+//! % humility sensors --thresholds board.toml
+//!    SOUTHWEST        SOUTH    SOUTHEAST  V3P3_SYS_A0 ...
+//!         TEMP         TEMP         TEMP      VOLTAGE ...
+//!        28.75        88.12        30.25         3.31 ...
+//! humility: WARNING: South (temp) is 88.12, above critical of 85.00
+//! humility sensors failed: 1 of 22 sensors out of range (1 critical)
+//! ```
+//!
+//! With `--sleep`, violations are reported at each sample but do not cause
+//! `humility sensors` to exit.
+//!
+//! To monitor sensors without a human in the loop, use `--serve` to specify
+//! an address on which to serve sensor values in the OpenMetrics text format
+//! (e.g., for scraping by Prometheus).  Sensors are polled at the interval
+//! specified via `--interval` (in milliseconds; defaults to 1000), and each
+//! value is labelled with the sensor's ID, name and kind, and the name and
+//! description of the device that it is on:
+//!
+//! ```console
+//! % humility sensors --serve 0.0.0.0:9100 --types temp &
+//! humility: serving 12 sensors at http://0.0.0.0:9100/metrics every 1000ms
+//! % curl -s localhost:9100/metrics
+//! # TYPE humility_sensor gauge
+//! # HELP humility_sensor Most recent sensor reading.
+//! humility_sensor{id="0",name="Southwest",kind="temp",device="tmp117",description="Southwest temperature sensor"} 24.6875
+//! humility_sensor{id="1",name="South",kind="temp",device="tmp117",description="South temperature sensor"} 25.5
+//! ...
+//! # TYPE humility_sensor_errors counter
+//! # HELP humility_sensor_errors Failed sensor reads.
+//! humility_sensor_errors_total{id="0",name="Southwest",kind="temp",device="tmp117",description="Southwest temperature sensor"} 0
+//! ...
+//! # TYPE humility_sensor_polls counter
+//! # HELP humility_sensor_polls Polls of all sensors.
+//! humility_sensor_polls_total 3627
+//! # EOF
+//! ```
+//!
+//! Sensors that cannot be read are omitted from the `humility_sensor`
+//! family, and are counted in `humility_sensor_errors`; if a poll fails
+//! entirely (e.g., because the HIF program timed out), every sensor is
+//! counted as an error, and the HIF context is reinitialized before the
+//! next poll.  Clients are served one at a time, and a client that does not
+//! send its request (or read its response) within five seconds is
+//! disconnected.  With `--serve`,
+//! sensor values can additionally be logged as CSV to the file specified
+//! via `--log`; once the log reaches the size specified via `--log-size`
+//! (16 MiB by default), it is rotated, with the number of old logs retained
+//! being specified via `--log-count` (8 by default).
 
 use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
@@ -26,11 +100,14 @@ use humility::core::Core;
 use humility::hubris::*;
 use humility_cmd::hiffy::*;
 use humility_cmd::idol;
+use humility_cmd::thresholds::Thresholds;
 use humility_cmd::{Archive, Attach, Command, Run, Validate};
 use std::collections::HashSet;
 use std::thread;
 use std::time::Duration;
 
+mod serve;
+
 #[derive(Parser, Debug)]
 #[clap(name = "sensors", about = env!("CARGO_PKG_DESCRIPTION"))]
 struct SensorsArgs {
@@ -49,6 +126,41 @@ struct SensorsArgs {
     #[clap(long, short, conflicts_with = "list")]
     sleep: bool,
 
+    /// check sensor values against thresholds in the specified file
+    #[clap(
+        long, value_name = "file", conflicts_with_all = &["list", "serve"]
+    )]
+    thresholds: Option<String>,
+
+    /// serve sensor values as OpenMetrics on the specified address
+    #[clap(long, value_name = "addr", conflicts_with_all = &["list", "sleep"])]
+    serve: Option<String>,
+
+    /// with --serve, interval at which to poll sensors
+    #[clap(
+        long, short, value_name = "ms", requires = "serve",
+        default_value = "1000", parse(try_from_str = parse_interval)
+    )]
+    interval: u64,
+
+    /// with --serve, log sensor values to the specified file
+    #[clap(long, value_name = "file", requires = "serve")]
+    log: Option<String>,
+
+    /// with --log, size at which the log is rotated
+    #[clap(
+        long, value_name = "bytes", requires = "log",
+        default_value = "16777216", parse(try_from_str = parse_int::parse)
+    )]
+    log_size: u64,
+
+    /// with --log, number of rotated logs to keep
+    #[clap(
+        long, value_name = "count", requires = "log",
+        default_value = "8", parse(try_from_str = parse_int::parse)
+    )]
+    log_count: u32,
+
     /// restrict sensors by type of sensor
     #[clap(
         long,
@@ -72,6 +184,17 @@ struct SensorsArgs {
     named: Option<Vec<String>>,
 }
 
+/// Parses a polling interval in milliseconds, which must be non-zero.
+fn parse_interval(s: &str) -> Result<u64> {
+    let interval = parse_int::parse(s)?;
+
+    if interval == 0 {
+        bail!("interval must be non-zero");
+    }
+
+    Ok(interval)
+}
+
 fn list(
     hubris: &HubrisArchive,
     types: &Option<HashSet<HubrisSensorKind>>,
@@ -126,15 +249,17 @@ fn list(
     Ok(())
 }
 
-fn print(
-    hubris: &HubrisArchive,
-    core: &mut dyn Core,
-    subargs: &SensorsArgs,
+///
+/// Builds the HIF ops to read the sensors that match the specified
+/// constraints, returning the sensors (and their IDs) along with the ops.
+///
+fn sensor_ops<'a>(
+    hubris: &'a HubrisArchive,
     context: &mut HiffyContext,
     types: &Option<HashSet<HubrisSensorKind>>,
     devices: &Option<HashSet<&String>>,
     named: &Option<HashSet<&String>>,
-) -> Result<()> {
+) -> Result<(Vec<(usize, &'a HubrisSensor)>, Vec<Op>)> {
     let mut ops = vec![];
     let funcs = context.functions()?;
     let op = idol::IdolOperation::new(hubris, "Sensor", "get", None)
@@ -177,7 +302,7 @@ fn print(
             }
         }
 
-        rvals.push(s);
+        rvals.push((i, s));
 
         let payload =
             op.payload(&[("id", idol::IdolArgument::Scalar(i as u64))])?;
@@ -186,32 +311,96 @@ fn print(
 
     ops.push(Op::Done);
 
-    for r in &rvals {
-        print!(" {:>12}", r.name.to_uppercase());
+    Ok((rvals, ops))
+}
+
+///
+/// Runs the ops built by [`sensor_ops`], returning a value for each sensor
+/// that could be read.
+///
+fn read_sensors(
+    core: &mut dyn Core,
+    context: &mut HiffyContext,
+    ops: &[Op],
+) -> Result<Vec<Option<f32>>> {
+    let results = context.run(core, ops, None)?;
+    let mut rval = vec![];
+
+    for r in results {
+        if let Ok(val) = r {
+            rval.push(Some(f32::from_le_bytes(val[0..4].try_into()?)));
+        } else {
+            rval.push(None);
+        }
     }
 
-    println!();
+    Ok(rval)
+}
+
+///
+/// Checks sensor values against any thresholds, emitting a warning for each
+/// violation.  Returns the number of sensors checked, the number of
+/// violations, and the number of critical violations.
+///
+fn check(
+    sensors: &[(usize, &HubrisSensor)],
+    values: &[Option<f32>],
+    thresholds: &Thresholds,
+) -> (usize, usize, usize) {
+    let mut checked = 0;
+    let mut violations = 0;
+    let mut critical = 0;
+
+    for ((_, s), val) in sensors.iter().zip(values.iter()) {
+        let threshold = match thresholds.lookup(s) {
+            Some(threshold) => threshold,
+            None => continue,
+        };
 
-    for r in &rvals {
-        print!(" {:>12}", r.kind.to_string().to_uppercase());
+        checked += 1;
+
+        if let Some(violation) = threshold.check(*val) {
+            humility::warn!(
+                "{} ({}) {}",
+                s.name,
+                s.kind.to_string(),
+                violation
+            );
+            violations += 1;
+
+            if violation.is_critical() {
+                critical += 1;
+            }
+        }
+    }
+
+    (checked, violations, critical)
+}
+
+fn print(
+    core: &mut dyn Core,
+    subargs: &SensorsArgs,
+    context: &mut HiffyContext,
+    sensors: &[(usize, &HubrisSensor)],
+    ops: &[Op],
+    thresholds: Option<&Thresholds>,
+) -> Result<()> {
+    for (_, s) in sensors {
+        print!(" {:>12}", s.name.to_uppercase());
     }
 
     println!();
 
-    loop {
-        let results = context.run(core, ops.as_slice(), None)?;
+    for (_, s) in sensors {
+        print!(" {:>12}", s.kind.to_string().to_uppercase());
+    }
 
-        let mut rval = vec![];
+    println!();
 
-        for r in results {
-            if let Ok(val) = r {
-                rval.push(Some(f32::from_le_bytes(val[0..4].try_into()?)));
-            } else {
-                rval.push(None);
-            }
-        }
+    loop {
+        let values = read_sensors(core, context, ops)?;
 
-        for val in rval {
+        for val in &values {
             if let Some(val) = val {
                 print!(" {:>12.2}", val);
             } else {
@@ -221,6 +410,24 @@ fn print(
 
         println!();
 
+        if let Some(thresholds) = thresholds {
+            let (checked, violations, critical) =
+                check(sensors, &values, thresholds);
+
+            if !subargs.sleep {
+                if violations != 0 {
+                    bail!(
+                        "{} of {} sensors out of range ({} critical)",
+                        violations,
+                        checked,
+                        critical
+                    );
+                }
+
+                humility::msg!("{} sensors within thresholds", checked);
+            }
+        }
+
         if !subargs.sleep {
             break;
         }
@@ -311,8 +518,43 @@ fn sensors(
     }
 
     let mut context = HiffyContext::new(hubris, core, subargs.timeout)?;
+    let (sensors, ops) =
+        sensor_ops(hubris, &mut context, &types, &devices, &named)?;
+
+    if let Some(ref addr) = subargs.serve {
+        let log = match subargs.log {
+            Some(ref log) => Some(serve::RotatingLog::new(
+                log,
+                &sensors,
+                subargs.log_size,
+                subargs.log_count,
+            )?),
+            None => None,
+        };
+
+        let config = serve::ServeConfig {
+            addr: addr.as_str(),
+            interval: Duration::from_millis(subargs.interval),
+            timeout: subargs.timeout,
+            log,
+        };
 
-    print(hubris, core, &subargs, &mut context, &types, &devices, &named)?;
+        serve::serve(hubris, core, &mut context, &sensors, &ops, config)?;
+    } else {
+        let thresholds = match subargs.thresholds {
+            Some(ref filename) => Some(Thresholds::load(hubris, filename)?),
+            None => None,
+        };
+
+        print(
+            core,
+            &subargs,
+            &mut context,
+            &sensors,
+            &ops,
+            thresholds.as_ref(),
+        )?;
+    }
 
     Ok(())
 }
@@ -0,0 +1,308 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Headless sensor monitoring, as provided by `humility sensors --serve`.
+// Sensors are polled on an interval from the calling thread (which owns the
+// core), and the most recent exposition is handed to a listener thread that
+// serves it to any HTTP client that asks; values can additionally be logged
+// to a CSV file that is rotated when it reaches a specified size.
+//
+
+use anyhow::{Context, Result};
+use hif::*;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::hiffy::*;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Time we allow a client to send its request or receive our response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct ServeConfig<'a> {
+    pub addr: &'a str,
+    pub interval: Duration,
+    pub timeout: u32,
+    pub log: Option<RotatingLog>,
+}
+
+///
+/// A CSV log of sensor values that is rotated once it exceeds a specified
+/// size:  the current log is renamed with a `.1` suffix, any `.1` is renamed
+/// to `.2`, and so on, with the oldest log being discarded.
+///
+pub struct RotatingLog {
+    path: String,
+    header: String,
+    max: u64,
+    count: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingLog {
+    pub fn new(
+        path: &str,
+        sensors: &[(usize, &HubrisSensor)],
+        max: u64,
+        count: u32,
+    ) -> Result<Self> {
+        let header = std::iter::once("time".to_string())
+            .chain(sensors.iter().map(|(_, s)| s.name.clone()))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        let (file, size) = Self::create(path, &header)?;
+
+        Ok(Self { path: path.to_string(), header, max, count, file, size })
+    }
+
+    fn create(path: &str, header: &str) -> Result<(File, u64)> {
+        let mut file = File::create(path)
+            .with_context(|| format!("failed to create {}", path))?;
+
+        writeln!(file, "{}", header)?;
+
+        Ok((file, header.len() as u64 + 1))
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        for i in (1..self.count).rev() {
+            let from = format!("{}.{}", self.path, i);
+
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", self.path, i + 1))?;
+            }
+        }
+
+        if self.count > 0 {
+            fs::rename(&self.path, format!("{}.1", self.path))?;
+        }
+
+        let (file, size) = Self::create(&self.path, &self.header)?;
+        self.file = file;
+        self.size = size;
+
+        Ok(())
+    }
+
+    fn log(&mut self, time: f64, values: &[Option<f32>]) -> Result<()> {
+        let mut line = format!("{:.3}", time);
+
+        for val in values {
+            match val {
+                Some(val) => line.push_str(&format!(",{:.2}", val)),
+                None => line.push(','),
+            }
+        }
+
+        if self.size + line.len() as u64 + 1 > self.max {
+            self.rotate()?;
+        }
+
+        writeln!(self.file, "{}", line)?;
+        self.file.flush()?;
+        self.size += line.len() as u64 + 1;
+
+        Ok(())
+    }
+}
+
+///
+/// Escapes a label value as required by the OpenMetrics text format.
+///
+fn escape(val: &str) -> String {
+    val.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn sensor_labels(
+    hubris: &HubrisArchive,
+    id: usize,
+    s: &HubrisSensor,
+) -> String {
+    let device = &hubris.manifest.i2c_devices[s.device];
+
+    format!(
+        "id=\"{}\",name=\"{}\",kind=\"{}\",device=\"{}\",description=\"{}\"",
+        id,
+        escape(&s.name),
+        s.kind.to_string(),
+        escape(&device.device),
+        escape(&device.description)
+    )
+}
+
+fn exposition(
+    labels: &[String],
+    values: &[Option<f32>],
+    errors: &[u64],
+    polls: u64,
+) -> String {
+    let mut out = String::new();
+
+    out.push_str("# TYPE humility_sensor gauge\n");
+    out.push_str("# HELP humility_sensor Most recent sensor reading.\n");
+
+    for (l, val) in labels.iter().zip(values.iter()) {
+        if let Some(val) = val {
+            out.push_str(&format!("humility_sensor{{{}}} {}\n", l, val));
+        }
+    }
+
+    out.push_str("# TYPE humility_sensor_errors counter\n");
+    out.push_str("# HELP humility_sensor_errors Failed sensor reads.\n");
+
+    for (l, errors) in labels.iter().zip(errors.iter()) {
+        out.push_str(&format!(
+            "humility_sensor_errors_total{{{}}} {}\n",
+            l, errors
+        ));
+    }
+
+    out.push_str("# TYPE humility_sensor_polls counter\n");
+    out.push_str("# HELP humility_sensor_polls Polls of all sensors.\n");
+    out.push_str(&format!("humility_sensor_polls_total {}\n", polls));
+    out.push_str("# EOF\n");
+
+    out
+}
+
+fn respond(mut stream: TcpStream, metrics: &Mutex<String>) -> Result<()> {
+    //
+    // We serve clients one at a time, so we don't want a client that stalls
+    // to prevent any other client from being served.
+    //
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+
+    //
+    // Consume (and ignore) any headers.
+    //
+    loop {
+        let mut line = String::new();
+
+        if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let path = request.split_whitespace().nth(1).unwrap_or("");
+
+    if path == "/metrics" || path == "/" {
+        let body = metrics.lock().unwrap().clone();
+
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Type: {}\r\n\
+            Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+            CONTENT_TYPE,
+            body.len(),
+            body
+        )?;
+    } else {
+        write!(
+            stream,
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\
+            Connection: close\r\n\r\n"
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn serve<'h>(
+    hubris: &'h HubrisArchive,
+    core: &mut dyn Core,
+    context: &mut HiffyContext<'h>,
+    sensors: &[(usize, &HubrisSensor)],
+    ops: &[Op],
+    mut config: ServeConfig,
+) -> Result<()> {
+    let listener = TcpListener::bind(config.addr)
+        .with_context(|| format!("failed to listen on {}", config.addr))?;
+
+    let labels = sensors
+        .iter()
+        .map(|(id, s)| sensor_labels(hubris, *id, s))
+        .collect::<Vec<_>>();
+
+    let metrics = Arc::new(Mutex::new("# EOF\n".to_string()));
+    let m = metrics.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let rval = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| respond(stream, &m));
+
+            if let Err(e) = rval {
+                log::warn!("failed to serve metrics: {:?}", e);
+            }
+        }
+    });
+
+    humility::msg!(
+        "serving {} sensors at http://{}/metrics every {}ms",
+        sensors.len(),
+        config.addr,
+        config.interval.as_millis()
+    );
+
+    let mut errors = vec![0u64; sensors.len()];
+    let mut polls = 0;
+
+    loop {
+        let start = Instant::now();
+
+        //
+        // We don't want a transient failure (e.g., a HIF timeout) to take
+        // down a long-running monitor, so we treat a failed poll as a
+        // failure to read every sensor.  A failed poll can leave the HIF
+        // context mid-execution, so we create a new one for the next poll;
+        // if we can't do that, something is more seriously amiss.
+        //
+        let values = match super::read_sensors(core, context, ops) {
+            Ok(values) => values,
+            Err(e) => {
+                humility::warn!("failed to read sensors: {:?}", e);
+
+                *context = HiffyContext::new(hubris, core, config.timeout)
+                    .context("failed to reinitialize HIF context")?;
+
+                vec![None; sensors.len()]
+            }
+        };
+
+        polls += 1;
+
+        for (i, val) in values.iter().enumerate() {
+            if val.is_none() {
+                errors[i] += 1;
+            }
+        }
+
+        *metrics.lock().unwrap() = exposition(&labels, &values, &errors, polls);
+
+        if let Some(ref mut log) = config.log {
+            let time = SystemTime::now().duration_since(UNIX_EPOCH)?;
+            log.log(time.as_secs_f64(), &values)?;
+        }
+
+        if let Some(remaining) = config.interval.checked_sub(start.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+}
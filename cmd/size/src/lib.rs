@@ -0,0 +1,688 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility size`
+//!
+//! `humility size` breaks down the size of the kernel and each task in a
+//! Hubris archive.  It does not connect to a Hubris target.  By default, the
+//! size of each task is displayed by section type -- text, read-only data,
+//! initialized data, and BSS:
+//!
+//! ```console
+//! % humility -a ./build-gimlet.zip size
+//! TASK                   TEXT   RODATA     DATA      BSS    TOTAL
+//! kernel                24292     2944       40     1804    29080
+//! jefe                   5436      852        0      172     6460
+//! net                   60928    13700     1208    31216   107052
+//! ...
+//! ```
+//!
+//! To see where a task's size is going, specify the task with `--task`
+//! (`-t`).  Each symbol is attributed to the crate from which it came --
+//! using the DWARF compilation units in the task's ELF object if possible,
+//! and the demangled name of the symbol if not -- and the crates and symbols
+//! that contribute the most to the task's size are displayed.  (By default,
+//! the top 10 are displayed; this can be changed with `--count` (`-n`).)
+//! Space in a section that can't be attributed to any symbol (e.g., padding
+//! or anonymous constants) is listed as unattributed:
+//!
+//! ```console
+//! % humility -a ./build-gimlet.zip size --task net
+//! TASK                   TEXT   RODATA     DATA      BSS    TOTAL
+//! net                   60928    13700     1208    31216   107052
+//!
+//! CRATE                  TEXT   RODATA     DATA      BSS    TOTAL
+//! task_net              20088     2312        0    31216    53616
+//! smoltcp               23744     1024        0        0    24768
+//! core                   8824     6412        0        0    15236
+//! ...
+//!
+//! SYMBOL                                           SECTION     SIZE
+//! task_net::bsp::...::TxRing                       bss        24576
+//! smoltcp::iface::interface::InterfaceInner::...   text        4180
+//! ...
+//! ```
+//!
+//! To compare sizes against another archive, specify it with `--compare`
+//! (`-c`).  The change in size of each task is displayed by section type,
+//! along with its total size in each archive; if a task is specified, the
+//! crates and symbols whose size changed the most are displayed:
+//!
+//! ```console
+//! % humility -a ./build-b.zip size --compare ./build-a.zip
+//! TASK                   TEXT   RODATA     DATA      BSS   BEFORE    AFTER    DELTA
+//! kernel                    0        0        0        0    29080    29080        0
+//! jefe                    +48      +16        0        0     6460     6524      +64
+//! ...
+//! ```
+//!
+//! Note that sizes are those of the sections within each ELF object, not of
+//! the regions allocated to each task; to see those, use `humility map`.
+//!
+
+use anyhow::{anyhow, bail, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use goblin::elf::section_header::{SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE};
+use goblin::elf::Elf;
+use humility::hubris::*;
+use humility_cmd::{Archive, Command, RunUnattached};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+#[derive(Parser, Debug)]
+#[clap(name = "size", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct SizeArgs {
+    /// break down the size of the specified task by crate and symbol
+    #[clap(long, short)]
+    task: Option<String>,
+
+    /// number of crates and symbols to display
+    #[clap(long = "count", short = 'n', default_value = "10")]
+    count: usize,
+
+    /// archive to compare sizes against
+    #[clap(long, short, value_name = "archive")]
+    compare: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+enum Section {
+    Text,
+    Rodata,
+    Data,
+    Bss,
+}
+
+impl Section {
+    fn name(&self) -> &'static str {
+        match self {
+            Section::Text => "text",
+            Section::Rodata => "rodata",
+            Section::Data => "data",
+            Section::Bss => "bss",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+struct Sizes {
+    text: u64,
+    rodata: u64,
+    data: u64,
+    bss: u64,
+}
+
+impl Sizes {
+    fn add(&mut self, section: Section, nbytes: u64) {
+        match section {
+            Section::Text => self.text += nbytes,
+            Section::Rodata => self.rodata += nbytes,
+            Section::Data => self.data += nbytes,
+            Section::Bss => self.bss += nbytes,
+        }
+    }
+
+    fn total(&self) -> u64 {
+        self.text + self.rodata + self.data + self.bss
+    }
+
+    fn delta(&self, before: &Sizes) -> [i64; 4] {
+        [
+            self.text as i64 - before.text as i64,
+            self.rodata as i64 - before.rodata as i64,
+            self.data as i64 - before.data as i64,
+            self.bss as i64 - before.bss as i64,
+        ]
+    }
+}
+
+#[derive(Debug, Default)]
+struct ObjectSizes {
+    sizes: Sizes,
+    crates: BTreeMap<String, Sizes>,
+    symbols: BTreeMap<String, (Section, u64)>,
+}
+
+const UNKNOWN: &str = "<unknown>";
+
+//
+// Rust compilation units are named by the source file, followed by "/@/"
+// and the name of the codegen unit -- which itself begins with the name of
+// the crate, e.g. "src/main.rs/@/task_jefe.9c1a3bd2-cgu.0".
+//
+fn crate_from_unit(name: &str) -> Option<String> {
+    let (_, cgu) = name.split_once("/@/")?;
+    let krate = cgu.split('.').next()?;
+
+    if krate.is_empty() {
+        None
+    } else {
+        Some(krate.to_string())
+    }
+}
+
+//
+// Splits the contents of a qualified path (e.g., the `X as Y` of `<X as
+// Y>::f`) into its self type and its trait (if any).
+//
+fn split_qualified(inner: &str) -> (&str, Option<&str>) {
+    let mut depth = 0;
+    let mut prev = None;
+
+    for (i, c) in inner.char_indices() {
+        match c {
+            '<' | '(' | '[' => depth += 1,
+            '>' if prev == Some('-') => {}
+            '>' | ')' | ']' => depth -= 1,
+            ' ' if depth == 0 && inner[i..].starts_with(" as ") => {
+                return (&inner[..i], Some(&inner[i + 4..]));
+            }
+            _ => {}
+        }
+
+        prev = Some(c);
+    }
+
+    (inner, None)
+}
+
+//
+// Failing that, we take the first element of the demangled path, stripping
+// any references.  For a qualified path (e.g., `<X as Y>::f` or `<X>::f`),
+// we use the crate of the self type if it has one, and the crate of the
+// trait if it doesn't (e.g., `<[u8] as core::fmt::Debug>::fmt`).
+//
+fn crate_from_symbol(name: &str) -> Option<String> {
+    let mut name = name.trim_start();
+
+    loop {
+        let stripped = name
+            .trim_start_matches(|c| c == '&' || c == '*')
+            .trim_start_matches("mut ")
+            .trim_start_matches("const ")
+            .trim_start_matches("dyn ");
+
+        if stripped == name {
+            break;
+        }
+
+        name = stripped;
+    }
+
+    if let Some(rest) = name.strip_prefix('<') {
+        let mut depth = 1;
+        let mut prev = None;
+
+        for (i, c) in rest.char_indices() {
+            match c {
+                '<' => depth += 1,
+                '>' if prev == Some('-') => {}
+                '>' => {
+                    depth -= 1;
+
+                    if depth == 0 {
+                        let (ty, tr) = split_qualified(&rest[..i]);
+                        return crate_from_symbol(ty)
+                            .or_else(|| tr.and_then(crate_from_symbol));
+                    }
+                }
+                _ => {}
+            }
+
+            prev = Some(c);
+        }
+
+        return None;
+    }
+
+    match name.split_once("::") {
+        Some((krate, _))
+            if !krate.is_empty()
+                && krate.chars().all(|c| c.is_alphanumeric() || c == '_') =>
+        {
+            Some(krate.to_string())
+        }
+        _ => None,
+    }
+}
+
+//
+// Returns the address ranges of each compilation unit with the name of its
+// crate, by base address.
+//
+fn compile_units(
+    elf: &Elf,
+    buffer: &[u8],
+) -> Result<BTreeMap<u64, (u64, String)>> {
+    let dwarf = gimli::Dwarf::<&[u8]>::load(
+        |id| {
+            let sec = elf.section_headers.iter().find(|sh| {
+                if let Some(Ok(name)) = elf.shdr_strtab.get(sh.sh_name) {
+                    name == id.name()
+                } else {
+                    false
+                }
+            });
+
+            match sec {
+                Some(sec) => {
+                    let offset = sec.sh_offset as usize;
+                    let size = sec.sh_size as usize;
+
+                    buffer.get(offset..offset + size).ok_or_else(|| {
+                        anyhow!("bad offset/size for ELF section {}", id.name())
+                    })
+                }
+                None => Ok(&[]),
+            }
+        },
+        |_| Ok(&[]),
+    )?;
+
+    let dwarf = dwarf.borrow(|section| {
+        gimli::EndianSlice::new(section, gimli::LittleEndian)
+    });
+
+    let mut rval = BTreeMap::new();
+    let mut iter = dwarf.units();
+
+    while let Some(header) = iter.next()? {
+        let unit = dwarf.unit(header)?;
+
+        let krate = match unit.name {
+            Some(name) => match crate_from_unit(&name.to_string_lossy()) {
+                Some(krate) => krate,
+                None => continue,
+            },
+            None => continue,
+        };
+
+        let mut ranges = dwarf.unit_ranges(&unit)?;
+
+        while let Some(range) = ranges.next()? {
+            if range.begin < range.end {
+                rval.insert(range.begin, (range.end, krate.clone()));
+            }
+        }
+    }
+
+    Ok(rval)
+}
+
+fn object_sizes(name: &str, buffer: &[u8]) -> Result<ObjectSizes> {
+    let elf = Elf::parse(buffer)
+        .map_err(|e| anyhow!("unrecognized ELF object: {}: {}", name, e))?;
+
+    let units = compile_units(&elf, buffer)?;
+    let mut rval = ObjectSizes::default();
+    let mut sections = HashMap::new();
+
+    for (ndx, sh) in elf.section_headers.iter().enumerate() {
+        let flags = sh.sh_flags as u32;
+
+        if flags & SHF_ALLOC == 0 || sh.sh_size == 0 {
+            continue;
+        }
+
+        let section = if flags & SHF_EXECINSTR != 0 {
+            Section::Text
+        } else if sh.sh_type == goblin::elf::section_header::SHT_NOBITS {
+            Section::Bss
+        } else if flags & SHF_WRITE != 0 {
+            Section::Data
+        } else {
+            Section::Rodata
+        };
+
+        sections.insert(ndx, (section, sh.sh_size));
+        rval.sizes.add(section, sh.sh_size);
+    }
+
+    //
+    // Multiple symbols can refer to the same object; we only want to count
+    // each object once.
+    //
+    let mut seen = HashSet::new();
+    let mut attributed: HashMap<usize, u64> = HashMap::new();
+
+    for sym in elf.syms.iter() {
+        if sym.st_size == 0 || sym.st_name == 0 {
+            continue;
+        }
+
+        let section = match sections.get(&sym.st_shndx) {
+            Some((section, _)) => *section,
+            None => continue,
+        };
+
+        //
+        // As with loading the archive, we need to clear the Thumb bit.
+        //
+        let addr =
+            if sym.is_function() { sym.st_value & !1 } else { sym.st_value };
+
+        if !seen.insert((addr, sym.st_size)) {
+            continue;
+        }
+
+        let name = match elf.strtab.get(sym.st_name) {
+            Some(Ok(name)) => format!("{:#}", rustc_demangle::demangle(name)),
+            _ => bail!("bad symbol in {}: {}", name, sym.st_name),
+        };
+
+        let unit = match units.range(..=addr).next_back() {
+            Some((_, (end, krate))) if addr < *end => Some(krate.clone()),
+            _ => None,
+        };
+
+        let krate = unit
+            .or_else(|| crate_from_symbol(&name))
+            .unwrap_or_else(|| UNKNOWN.to_string());
+
+        rval.crates.entry(krate).or_default().add(section, sym.st_size);
+        rval.symbols.entry(name).or_insert((section, 0)).1 += sym.st_size;
+        *attributed.entry(sym.st_shndx).or_default() += sym.st_size;
+    }
+
+    for (ndx, (section, size)) in &sections {
+        let used = attributed.get(ndx).cloned().unwrap_or(0);
+
+        if used >= *size {
+            continue;
+        }
+
+        let name = match elf.shdr_strtab.get(elf.section_headers[*ndx].sh_name)
+        {
+            Some(Ok(name)) => name,
+            _ => section.name(),
+        };
+
+        rval.crates
+            .entry(UNKNOWN.to_string())
+            .or_default()
+            .add(*section, size - used);
+
+        rval.symbols
+            .entry(format!("<unattributed {}>", name))
+            .or_insert((*section, 0))
+            .1 += size - used;
+    }
+
+    Ok(rval)
+}
+
+fn archive_sizes(hubris: &HubrisArchive) -> Result<Vec<(String, ObjectSizes)>> {
+    let mut rval = vec![];
+
+    hubris.for_each_object(|name, _, buffer| {
+        rval.push((name.to_string(), object_sizes(name, buffer)?));
+        Ok(())
+    })?;
+
+    Ok(rval)
+}
+
+fn lookup<'a>(
+    sizes: &'a [(String, ObjectSizes)],
+    name: &str,
+) -> Option<&'a ObjectSizes> {
+    sizes.iter().find(|(n, _)| n == name).map(|(_, s)| s)
+}
+
+fn print_sizes_header(what: &str) {
+    println!(
+        "{:16} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        what, "TEXT", "RODATA", "DATA", "BSS", "TOTAL"
+    );
+}
+
+fn print_sizes(name: &str, sizes: &Sizes) {
+    println!(
+        "{:16} {:8} {:8} {:8} {:8} {:8}",
+        name,
+        sizes.text,
+        sizes.rodata,
+        sizes.data,
+        sizes.bss,
+        sizes.total()
+    );
+}
+
+fn print_delta_header(what: &str) {
+    println!(
+        "{:16} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        what, "TEXT", "RODATA", "DATA", "BSS", "BEFORE", "AFTER", "DELTA"
+    );
+}
+
+fn delta(val: i64) -> String {
+    if val == 0 {
+        "0".to_string()
+    } else {
+        format!("{:+}", val)
+    }
+}
+
+fn print_delta(name: &str, before: Option<&Sizes>, after: Option<&Sizes>) {
+    let zero = Sizes::default();
+    let d = after.unwrap_or(&zero).delta(before.unwrap_or(&zero));
+    let total = |sizes: Option<&Sizes>| match sizes {
+        Some(sizes) => sizes.total().to_string(),
+        None => "-".to_string(),
+    };
+
+    println!(
+        "{:16} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8} {:>8}",
+        name,
+        delta(d[0]),
+        delta(d[1]),
+        delta(d[2]),
+        delta(d[3]),
+        total(before),
+        total(after),
+        delta(d.iter().sum())
+    );
+}
+
+fn print_symbols(symbols: &BTreeMap<String, (Section, u64)>, count: usize) {
+    let mut sorted = symbols.iter().collect::<Vec<_>>();
+    sorted.sort_by(|a, b| b.1 .1.cmp(&a.1 .1).then(a.0.cmp(b.0)));
+
+    println!("{:48} {:7} {:>8}", "SYMBOL", "SECTION", "SIZE");
+
+    for (name, (section, size)) in sorted.iter().take(count) {
+        println!("{:48} {:7} {:8}", name, section.name(), size);
+    }
+}
+
+fn breakdown(sizes: &ObjectSizes, count: usize) {
+    let mut crates = sizes.crates.iter().collect::<Vec<_>>();
+    crates.sort_by(|a, b| b.1.total().cmp(&a.1.total()).then(a.0.cmp(b.0)));
+
+    println!();
+    print_sizes_header("CRATE");
+
+    for (krate, sizes) in crates.iter().take(count) {
+        print_sizes(krate, sizes);
+    }
+
+    println!();
+    print_symbols(&sizes.symbols, count);
+}
+
+fn breakdown_delta(before: &ObjectSizes, after: &ObjectSizes, count: usize) {
+    let keys = before
+        .crates
+        .keys()
+        .chain(after.crates.keys())
+        .collect::<BTreeSet<_>>();
+
+    let mut crates = keys
+        .into_iter()
+        .map(|k| (k, before.crates.get(k), after.crates.get(k)))
+        .filter(|(_, b, a)| b != a)
+        .collect::<Vec<_>>();
+
+    let change = |b: Option<&Sizes>, a: Option<&Sizes>| {
+        let total = |s: Option<&Sizes>| s.map_or(0, |s| s.total() as i64);
+        (total(a) - total(b)).abs()
+    };
+
+    crates.sort_by(|x, y| change(y.1, y.2).cmp(&change(x.1, x.2)));
+
+    println!();
+    print_delta_header("CRATE");
+
+    for (krate, b, a) in crates.iter().take(count) {
+        print_delta(krate, *b, *a);
+    }
+
+    let keys = before
+        .symbols
+        .keys()
+        .chain(after.symbols.keys())
+        .collect::<BTreeSet<_>>();
+
+    let size = |s: Option<&(Section, u64)>| s.map_or(0, |s| s.1 as i64);
+
+    let mut symbols = keys
+        .into_iter()
+        .map(|k| (k, size(before.symbols.get(k)), size(after.symbols.get(k))))
+        .filter(|(_, b, a)| b != a)
+        .collect::<Vec<_>>();
+
+    symbols.sort_by(|x, y| (y.2 - y.1).abs().cmp(&(x.2 - x.1).abs()));
+
+    println!();
+    println!("{:48} {:>8} {:>8} {:>8}", "SYMBOL", "BEFORE", "AFTER", "DELTA");
+
+    for (name, b, a) in symbols.iter().take(count) {
+        println!("{:48} {:8} {:8} {:>8}", name, b, a, delta(a - b));
+    }
+}
+
+fn size(hubris: &mut HubrisArchive, subargs: &[String]) -> Result<()> {
+    let subargs = SizeArgs::try_parse_from(subargs)?;
+    let sizes = archive_sizes(hubris)?;
+
+    if let Some(ref task) = subargs.task {
+        if !sizes.iter().any(|(name, _)| name == task) {
+            bail!("no such task \"{}\"", task);
+        }
+    }
+
+    let selected =
+        |name: &str| subargs.task.as_ref().map_or(true, |task| task == name);
+
+    if let Some(ref compare) = subargs.compare {
+        let mut other = HubrisArchive::new()?;
+        other.load(compare, HubrisArchiveDoneness::Raw)?;
+
+        let before = archive_sizes(&other)?;
+
+        print_delta_header("TASK");
+
+        for (name, after) in sizes.iter().filter(|(n, _)| selected(n)) {
+            let before = lookup(&before, name).map(|s| &s.sizes);
+            print_delta(name, before, Some(&after.sizes));
+        }
+
+        for (name, before) in before.iter().filter(|(n, _)| selected(n)) {
+            if lookup(&sizes, name).is_none() {
+                print_delta(name, Some(&before.sizes), None);
+            }
+        }
+
+        if let Some(ref task) = subargs.task {
+            match (lookup(&before, task), lookup(&sizes, task)) {
+                (Some(b), Some(a)) => breakdown_delta(b, a, subargs.count),
+                _ => humility::msg!("{} is not in {}", task, compare),
+            }
+        }
+
+        return Ok(());
+    }
+
+    print_sizes_header("TASK");
+
+    for (name, sizes) in sizes.iter().filter(|(n, _)| selected(n)) {
+        print_sizes(name, &sizes.sizes);
+    }
+
+    if let Some(ref task) = subargs.task {
+        if let Some(sizes) = lookup(&sizes, task) {
+            breakdown(sizes, subargs.count);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Unattached {
+            name: "size",
+            archive: Archive::Required,
+            run: RunUnattached::Subargs(size),
+        },
+        SizeArgs::command(),
+    )
+}
+
+#[test]
+fn validate_crate_from_unit() {
+    assert_eq!(
+        crate_from_unit("src/main.rs/@/task_jefe.9c1a3bd2-cgu.0"),
+        Some("task_jefe".to_string())
+    );
+    assert_eq!(crate_from_unit("src/main.rs"), None);
+    assert_eq!(crate_from_unit("src/main.rs/@/.cgu.0"), None);
+}
+
+#[test]
+fn validate_crate_from_symbol() {
+    let some = |krate: &str| Some(krate.to_string());
+
+    assert_eq!(crate_from_symbol("task_jefe::main"), some("task_jefe"));
+    assert_eq!(crate_from_symbol("&mut userlib::sys_send"), some("userlib"));
+    assert_eq!(
+        crate_from_symbol("<core::fmt::Arguments as core::fmt::Display>::fmt"),
+        some("core")
+    );
+    assert_eq!(
+        crate_from_symbol("<drv_i2c_api::I2cDevice as ringbuf::Count>::count"),
+        some("drv_i2c_api")
+    );
+    assert_eq!(
+        crate_from_symbol("<[u8] as core::fmt::Debug>::fmt"),
+        some("core")
+    );
+    assert_eq!(
+        crate_from_symbol("<&T as core::fmt::Debug>::fmt"),
+        some("core")
+    );
+    assert_eq!(
+        crate_from_symbol("<u32 as task_net::Encode>::encode"),
+        some("task_net")
+    );
+    assert_eq!(
+        crate_from_symbol("<alloc::vec::Vec<T> as core::ops::Drop>::drop"),
+        some("alloc")
+    );
+    assert_eq!(
+        crate_from_symbol("<heapless::Vec<u8, 32>>::push"),
+        some("heapless")
+    );
+    assert_eq!(
+        crate_from_symbol("<fn() -> u32 as userlib::Callback>::call"),
+        some("userlib")
+    );
+    assert_eq!(
+        crate_from_symbol("<dyn core::any::Any>::type_id"),
+        some("core")
+    );
+    assert_eq!(crate_from_symbol("<u32 as Foo>::bar"), None);
+    assert_eq!(crate_from_symbol("main"), None);
+    assert_eq!(crate_from_symbol("<u32"), None);
+}
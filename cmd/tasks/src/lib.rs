@@ -110,6 +110,41 @@
 //!
 //! These options can naturally be combined, e.g. `humility tasks -slvr`.
 //!
+//! To record how task state changes over time, use `--record` to sample the
+//! task table at an interval (specified in milliseconds with `--interval`)
+//! and write the samples to a timeline file.  Recording continues until the
+//! number of samples specified with `--samples` have been taken (or until
+//! interrupted):
+//!
+//! ```console
+//! % humility tasks --record ping.timeline --interval 50
+//! humility: attached via ST-Link
+//! humility: recording to ping.timeline every 50ms; ^C to stop
+//! ^C
+//! ```
+//!
+//! A timeline does not require a probe or an archive to be examined.  It
+//! can be replayed with `--replay`, which displays each sample in turn
+//! (marking with an asterisk any task that has changed since the previous
+//! sample); summarized with `--summarize`, which displays the number of
+//! restarts (only a lower bound on older kernels, whose 6-bit generation
+//! wraps) and the proportion of time spent in each state for each task; or
+//! exported with `--export` as JSON in the Trace Event Format that can be
+//! loaded into a trace viewer like Perfetto:
+//!
+//! ```console
+//! % humility tasks --summarize ping.timeline
+//! humility: 2112 samples over 107421 ticks (from 8023411 to 8130832)
+//! ID TASK            RESTARTS   READY    SEND   REPLY    RECV STOPPED   FAULT
+//!  0 jefe                   0    0.0%    0.0%    0.0%  100.0%    0.0%    0.0%
+//!  1 rcc_driver             0    0.0%    0.0%    0.0%  100.0%    0.0%    0.0%
+//! ...
+//!  7 pong                   0    0.3%    0.0%    0.0%   99.7%    0.0%    0.0%
+//!  8 ping                 211    8.2%    0.0%   90.1%    0.0%    0.0%    1.7%
+//! ...
+//! % humility tasks --export ping.timeline > ping.json
+//! ```
+//!
 
 use anyhow::{bail, Context, Result};
 use clap::Command as ClapCommand;
@@ -119,10 +154,12 @@ use humility::core::Core;
 use humility::hubris::*;
 use humility::reflect::{self, Format, Load};
 use humility_cmd::doppel::{self, Task, TaskDesc, TaskId, TaskState};
-use humility_cmd::{Archive, Attach, Command, Run, Validate};
+use humility_cmd::{Archive, Args, Attach, Command, RunUnattached, Validate};
 use num_traits::FromPrimitive;
 use std::collections::{BTreeMap, HashMap};
 
+mod timeline;
+
 #[derive(Parser, Debug)]
 #[clap(name = "tasks", about = env!("CARGO_PKG_DESCRIPTION"))]
 struct TasksArgs {
@@ -146,6 +183,51 @@ struct TasksArgs {
     #[clap(long, short)]
     verbose: bool,
 
+    /// record a timeline of task state to the specified file
+    #[clap(
+        long, value_name = "file",
+        conflicts_with_all = &["registers", "stack", "spin", "verbose"]
+    )]
+    record: Option<String>,
+
+    /// with --record, interval between samples
+    #[clap(
+        long, value_name = "ms", requires = "record", default_value = "100",
+        parse(try_from_str = parse_int::parse)
+    )]
+    interval: u64,
+
+    /// with --record, number of samples to take
+    #[clap(
+        long, value_name = "count", requires = "record",
+        parse(try_from_str = parse_int::parse)
+    )]
+    samples: Option<u64>,
+
+    /// replay a recorded timeline
+    #[clap(
+        long, value_name = "file",
+        conflicts_with_all = &["registers", "stack", "spin", "verbose",
+            "record"]
+    )]
+    replay: Option<String>,
+
+    /// summarize a recorded timeline
+    #[clap(
+        long, value_name = "file",
+        conflicts_with_all = &["task", "replay", "record", "registers",
+            "stack", "spin", "verbose"]
+    )]
+    summarize: Option<String>,
+
+    /// export a recorded timeline as a trace
+    #[clap(
+        long, value_name = "file",
+        conflicts_with_all = &["task", "replay", "summarize", "record",
+            "registers", "stack", "spin", "verbose"]
+    )]
+    export: Option<String>,
+
     /// single task to display
     task: Option<String>,
 }
@@ -176,10 +258,8 @@ fn print_regs(regs: &BTreeMap<ARMRegister, u32>, additional: bool) {
 fn tasks(
     hubris: &HubrisArchive,
     core: &mut dyn Core,
-    subargs: &[String],
+    subargs: &TasksArgs,
 ) -> Result<()> {
-    let subargs = TasksArgs::try_parse_from(subargs)?;
-
     let (base, task_count) = hubris.task_table(core)?;
     log::debug!("task table: {:#x?}, count: {}", base, task_count);
     let ticks = core.read_word_64(hubris.lookup_variable("TICKS")?.addr)?;
@@ -363,7 +443,7 @@ fn tasks(
         }
 
         if subargs.task.is_some() && !found {
-            bail!("\"{}\" is not a valid task", subargs.task.unwrap());
+            bail!("\"{}\" is not a valid task", subargs.task.as_ref().unwrap());
         }
 
         if !subargs.spin {
@@ -639,14 +719,56 @@ fn explain_recv(
     }
 }
 
+fn taskscmd(
+    hubris: &mut HubrisArchive,
+    args: &Args,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = TasksArgs::try_parse_from(subargs)?;
+
+    //
+    // Operating on a recorded timeline requires neither an archive nor an
+    // attached system.
+    //
+    if let Some(ref replay) = subargs.replay {
+        return timeline::replay(replay, subargs.task.as_deref());
+    }
+
+    if let Some(ref summarize) = subargs.summarize {
+        return timeline::summarize(summarize);
+    }
+
+    if let Some(ref export) = subargs.export {
+        return timeline::export(export);
+    }
+
+    if !hubris.loaded() {
+        bail!("must provide a Hubris archive or dump");
+    }
+
+    let attach =
+        if subargs.record.is_some() { Attach::LiveOnly } else { Attach::Any };
+
+    humility_cmd::attach(hubris, args, attach, Validate::Booted, |h, core| {
+        match subargs.record {
+            Some(ref record) => timeline::record(
+                h,
+                core,
+                record,
+                subargs.interval,
+                subargs.samples,
+            ),
+            None => tasks(h, core, &subargs),
+        }
+    })
+}
+
 pub fn init() -> (Command, ClapCommand<'static>) {
     (
-        Command::Attached {
+        Command::Unattached {
             name: "tasks",
-            archive: Archive::Required,
-            attach: Attach::Any,
-            validate: Validate::Booted,
-            run: Run::Subargs(tasks),
+            archive: Archive::Optional,
+            run: RunUnattached::Args(taskscmd),
         },
         TasksArgs::command(),
     )
@@ -0,0 +1,478 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Task state timelines, as recorded by `humility tasks --record`.  A
+// timeline is a file of JSON lines, each of which is a single sample of
+// the task table; because each sample is written (and flushed) as it is
+// taken, a recording that is interrupted is still a valid timeline.
+//
+
+use anyhow::{bail, Context, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::doppel::{
+    GenOrRestartCount, Generation, SchedState, Task, TaskId, TaskState,
+};
+use humility_cmd::tasks::read_tasks;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::thread;
+use std::time::Duration;
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimelineTask {
+    pub name: String,
+    pub state: String,
+    pub detail: String,
+    pub generation: u32,
+    /// true if `generation` is a wrapping 6-bit generation rather than a
+    /// restart count (as it is on older kernels)
+    pub narrow: bool,
+    pub notifications: u32,
+    pub timer: Option<u64>,
+}
+
+impl TimelineTask {
+    fn restart_count(&self) -> GenOrRestartCount {
+        if self.narrow {
+            GenOrRestartCount::Gen(Generation(self.generation as u8))
+        } else {
+            GenOrRestartCount::RestartCount(self.generation)
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimelineSample {
+    pub ticks: u64,
+    pub tasks: Vec<TimelineTask>,
+}
+
+/// The states into which we bucket tasks for the purposes of summarizing.
+const STATES: [&str; 6] =
+    ["ready", "send", "reply", "recv", "stopped", "fault"];
+
+fn task_name(hubris: &HubrisArchive, id: TaskId) -> String {
+    match hubris.task_name(id.index()) {
+        Some(name) => format!("{}/gen{}", name, id.generation()),
+        None => format!("unknown#{}/gen{}", id.index(), id.generation()),
+    }
+}
+
+fn sched_state(hubris: &HubrisArchive, ss: SchedState) -> (&str, String) {
+    match ss {
+        SchedState::Stopped => ("stopped", "not started".to_string()),
+        SchedState::Runnable => ("ready", "ready".to_string()),
+        SchedState::InSend(TaskId::KERNEL) => {
+            ("send", "HALT: send to kernel".to_string())
+        }
+        SchedState::InSend(tid) => {
+            ("send", format!("send to {}", task_name(hubris, tid)))
+        }
+        SchedState::InReply(tid) => {
+            ("reply", format!("reply from {}", task_name(hubris, tid)))
+        }
+        SchedState::InRecv(None) => ("recv", "recv".to_string()),
+        SchedState::InRecv(Some(TaskId::KERNEL)) => {
+            ("recv", "notif".to_string())
+        }
+        SchedState::InRecv(Some(tid)) => {
+            ("recv", format!("recv({} only)", task_name(hubris, tid)))
+        }
+    }
+}
+
+fn sample_task(
+    hubris: &HubrisArchive,
+    name: &str,
+    task: &Task,
+) -> TimelineTask {
+    let (state, detail) = match task.state {
+        TaskState::Healthy(ss) => {
+            let (state, detail) = sched_state(hubris, ss);
+            (state.to_string(), detail)
+        }
+        TaskState::Faulted { fault, .. } => {
+            ("fault".to_string(), format!("FAULT: {:?}", fault))
+        }
+    };
+
+    TimelineTask {
+        name: name.to_string(),
+        state,
+        detail,
+        generation: u32::from(task.generation),
+        narrow: matches!(task.generation, GenOrRestartCount::Gen(_)),
+        notifications: task.notifications,
+        timer: task.timer.deadline.map(|d| d.0),
+    }
+}
+
+pub fn record(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    filename: &str,
+    interval: u64,
+    samples: Option<u64>,
+) -> Result<()> {
+    let ticks = hubris.lookup_variable("TICKS")?.addr;
+
+    let names = (0..hubris.ntasks())
+        .map(|i| hubris.task_name(i).unwrap_or("<unknown>").to_string())
+        .collect::<Vec<_>>();
+
+    let mut file = File::create(filename)
+        .with_context(|| format!("failed to create {}", filename))?;
+
+    humility::msg!(
+        "recording to {} every {}ms{}",
+        filename,
+        interval,
+        if samples.is_none() { "; ^C to stop" } else { "" }
+    );
+
+    let mut nsamples = 0;
+
+    loop {
+        let now = core.read_word_64(ticks)?;
+        let tasks = read_tasks(hubris, core)?;
+
+        let sample = TimelineSample {
+            ticks: now,
+            tasks: tasks
+                .iter()
+                .enumerate()
+                .map(|(i, task)| {
+                    let name = names.get(i).map_or("<unknown>", |n| n.as_str());
+                    sample_task(hubris, name, task)
+                })
+                .collect(),
+        };
+
+        serde_json::to_writer(&mut file, &sample)?;
+        writeln!(file)?;
+        file.flush()?;
+
+        nsamples += 1;
+
+        if let Some(samples) = samples {
+            if nsamples >= samples {
+                break;
+            }
+        }
+
+        thread::sleep(Duration::from_millis(interval));
+    }
+
+    humility::msg!("recorded {} samples to {}", nsamples, filename);
+
+    Ok(())
+}
+
+fn load(filename: &str) -> Result<Vec<TimelineSample>> {
+    let file = File::open(filename)
+        .with_context(|| format!("failed to open {}", filename))?;
+
+    let mut samples = vec![];
+
+    for (lineno, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let sample: TimelineSample =
+            serde_json::from_str(&line).with_context(|| {
+                format!("{}: malformed sample on line {}", filename, lineno + 1)
+            })?;
+
+        samples.push(sample);
+    }
+
+    if samples.is_empty() {
+        bail!("{}: timeline contains no samples", filename);
+    }
+
+    Ok(samples)
+}
+
+#[rustfmt::skip::macros(println)]
+pub fn replay(filename: &str, task: Option<&str>) -> Result<()> {
+    let samples = load(filename)?;
+    let mut last: Option<&TimelineSample> = None;
+
+    for sample in &samples {
+        println!("system time = {}", sample.ticks);
+
+        println!("{:2} {:15} {:>8} {:9}", "ID", "TASK", "GEN", "STATE");
+
+        for (i, t) in sample.tasks.iter().enumerate() {
+            if let Some(task) = task {
+                if t.name != task {
+                    continue;
+                }
+            }
+
+            //
+            // Flag any task that has changed since the last sample.
+            //
+            let changed = match last {
+                Some(last) => last.tasks.get(i) != Some(t),
+                None => false,
+            };
+
+            println!("{:2} {:15} {:>8} {}{}", i, t.name, t.generation,
+                t.detail, if changed { " *" } else { "" });
+        }
+
+        println!();
+        last = Some(sample);
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+struct TaskSummary {
+    name: String,
+    ticks: BTreeMap<String, u64>,
+    restarts: u32,
+}
+
+fn summary(samples: &[TimelineSample]) -> Vec<TaskSummary> {
+    let mut summary: Vec<TaskSummary> = vec![];
+
+    //
+    // We attribute the time between each sample and its successor to the
+    // state that each task was in at the time of the first sample.
+    //
+    for pair in samples.windows(2) {
+        let elapsed = pair[1].ticks.saturating_sub(pair[0].ticks);
+
+        for (i, t) in pair[0].tasks.iter().enumerate() {
+            if i >= summary.len() {
+                summary.push(TaskSummary {
+                    name: t.name.clone(),
+                    ..Default::default()
+                });
+            }
+
+            let s = &mut summary[i];
+            *s.ticks.entry(t.state.clone()).or_insert(0) += elapsed;
+
+            if let Some(next) = pair[1].tasks.get(i) {
+                s.restarts += next
+                    .restart_count()
+                    .restarts_since(t.restart_count())
+                    .unwrap_or(0);
+            }
+        }
+    }
+
+    summary
+}
+
+#[rustfmt::skip::macros(println)]
+pub fn summarize(filename: &str) -> Result<()> {
+    let samples = load(filename)?;
+    let summary = summary(&samples);
+
+    let first = samples[0].ticks;
+    let last = samples[samples.len() - 1].ticks;
+
+    humility::msg!(
+        "{} samples over {} ticks (from {} to {})",
+        samples.len(),
+        last.saturating_sub(first),
+        first,
+        last
+    );
+
+    print!("{:2} {:15} {:>8}", "ID", "TASK", "RESTARTS");
+
+    for state in STATES {
+        print!(" {:>7}", state.to_uppercase());
+    }
+
+    println!();
+
+    for (i, s) in summary.iter().enumerate() {
+        let total: u64 = s.ticks.values().sum();
+
+        print!("{:2} {:15} {:>8}", i, s.name, s.restarts);
+
+        for state in STATES {
+            let ticks = s.ticks.get(state).copied().unwrap_or(0);
+
+            if total == 0 {
+                print!(" {:>7}", "-");
+            } else {
+                let pct = (ticks as f64 * 100.0) / total as f64;
+                print!(" {:>6.1}%", pct);
+            }
+        }
+
+        println!();
+    }
+
+    Ok(())
+}
+
+///
+/// Exports a timeline in the Trace Event Format understood by (among others)
+/// Perfetto and `chrome://tracing`: each task is a thread, and each run of
+/// samples in a given state is a complete event.  Kernel ticks are taken to
+/// be milliseconds.
+///
+pub fn export(filename: &str) -> Result<()> {
+    let samples = load(filename)?;
+    let mut events = vec![];
+
+    let ntasks = samples.iter().map(|s| s.tasks.len()).max().unwrap_or(0);
+    let end = samples[samples.len() - 1].ticks;
+
+    for i in 0..ntasks {
+        let mut current: Option<(u64, &TimelineTask)> = None;
+
+        for sample in &samples {
+            let t = match sample.tasks.get(i) {
+                Some(t) => t,
+                None => continue,
+            };
+
+            match current {
+                Some((_, c))
+                    if c.detail == t.detail && c.generation == t.generation => {
+                }
+                Some((start, c)) => {
+                    events.push(event(i, c, start, sample.ticks));
+                    current = Some((sample.ticks, t));
+                }
+                None => {
+                    events.push(json!({
+                        "name": "thread_name",
+                        "ph": "M",
+                        "pid": 0,
+                        "tid": i,
+                        "args": { "name": t.name },
+                    }));
+
+                    current = Some((sample.ticks, t));
+                }
+            }
+        }
+
+        if let Some((start, c)) = current {
+            events.push(event(i, c, start, end));
+        }
+    }
+
+    println!("{}", serde_json::to_string_pretty(&events)?);
+
+    Ok(())
+}
+
+fn event(
+    tid: usize,
+    t: &TimelineTask,
+    start: u64,
+    end: u64,
+) -> serde_json::Value {
+    json!({
+        "name": t.detail,
+        "cat": t.state,
+        "ph": "X",
+        "ts": start * 1000,
+        "dur": end.saturating_sub(start) * 1000,
+        "pid": 0,
+        "tid": tid,
+        "args": { "generation": t.generation },
+    })
+}
+
+#[cfg(test)]
+fn sample(ticks: u64, tasks: &[(&str, &str, u32)]) -> TimelineSample {
+    TimelineSample {
+        ticks,
+        tasks: tasks
+            .iter()
+            .map(|&(name, state, generation)| TimelineTask {
+                name: name.to_string(),
+                state: state.to_string(),
+                detail: state.to_string(),
+                generation,
+                narrow: true,
+                notifications: 0,
+                timer: None,
+            })
+            .collect(),
+    }
+}
+
+#[test]
+fn validate_summary() {
+    let samples = vec![
+        sample(100, &[("jefe", "recv", 0), ("sensor", "ready", 0)]),
+        sample(110, &[("jefe", "recv", 0), ("sensor", "send", 0)]),
+        sample(140, &[("jefe", "ready", 0), ("sensor", "fault", 0)]),
+        sample(150, &[("jefe", "recv", 0), ("sensor", "ready", 2)]),
+    ];
+
+    let summary = summary(&samples);
+    assert_eq!(summary.len(), 2);
+
+    assert_eq!(summary[0].name, "jefe");
+    assert_eq!(summary[0].restarts, 0);
+    assert_eq!(summary[0].ticks.get("recv"), Some(&40));
+    assert_eq!(summary[0].ticks.get("ready"), Some(&10));
+
+    assert_eq!(summary[1].name, "sensor");
+    assert_eq!(summary[1].restarts, 2);
+    assert_eq!(summary[1].ticks.get("ready"), Some(&10));
+    assert_eq!(summary[1].ticks.get("send"), Some(&30));
+    assert_eq!(summary[1].ticks.get("fault"), Some(&10));
+}
+
+#[test]
+fn validate_summary_wrap() {
+    //
+    // A generation that goes backwards has wrapped past 63.
+    //
+    let samples = vec![
+        sample(0, &[("sensor", "ready", 62)]),
+        sample(5, &[("sensor", "ready", 63)]),
+        sample(10, &[("sensor", "ready", 1)]),
+    ];
+
+    let summary = summary(&samples);
+    assert_eq!(summary[0].restarts, 3);
+    assert_eq!(summary[0].ticks.get("ready"), Some(&10));
+}
+
+#[test]
+fn validate_summary_restart_count() {
+    //
+    // Restart counts don't wrap at 64.
+    //
+    let mut samples = vec![
+        sample(0, &[("sensor", "ready", 62)]),
+        sample(5, &[("sensor", "ready", 130)]),
+    ];
+
+    samples.iter_mut().for_each(|s| s.tasks[0].narrow = false);
+
+    let summary = summary(&samples);
+    assert_eq!(summary[0].restarts, 68);
+}
+
+#[test]
+fn validate_summary_single() {
+    let samples = vec![sample(0, &[("jefe", "recv", 0)])];
+    assert!(summary(&samples).is_empty());
+}
@@ -0,0 +1,604 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! ## `humility watch`
+//!
+//! `humility watch` sets a watchpoint on a variable (or a field within a
+//! structure, specified with `.`-delimited field names) by programming a
+//! DWT comparator, and then waits for the variable to be written.  When the
+//! watchpoint is hit, the core halts, and the task and function that
+//! accessed the variable are displayed, along with the task's stack
+//! backtrace:
+//!
+//! ```console
+//! % humility watch TICKS
+//! humility: attached via ST-Link V3
+//! humility: watching TICKS (8 bytes at 0x20000098) for writes; ^C to stop
+//! humility: watchpoint hit: TICKS written
+//! humility: value was [3f, 42, 0f, 00, 00, 00, 00, 00]
+//! humility: value now [40, 42, 0f, 00, 00, 00, 00, 00]
+//! humility: PC 0x08001e3a is in kernel (SysTick+0x12)
+//! humility: current task is idle
+//!    |
+//!    +--->  0x20012bf0 0x08031ca2 main
+//!           0x20012c00 0x0803101c _start
+//! ```
+//!
+//! By default, only writes trigger the watchpoint; to also trigger on reads,
+//! use `--read`.  A field within a structure can be watched by name:
+//!
+//! ```console
+//! % humility watch TASK_JEFE_RINGBUF.last
+//! ```
+//!
+//! Note that data watchpoints are imprecise:  the core halts after the
+//! accessing instruction has executed, and the reported PC may be one or
+//! more instructions beyond it.  Also note that on ARMv6-M and ARMv7-M,
+//! comparators can only watch naturally aligned, power-of-two sized regions;
+//! if the variable can't be watched exactly, a larger region is watched
+//! (and a warning is emitted), so accesses to neighbouring data can also
+//! trigger the watchpoint.
+//!
+//! Once the watchpoint has been hit (or if the watch is interrupted, or if
+//! the timeout specified with `--timeout` expires), the DWT state is
+//! restored to what it was prior to `humility watch`, and the core is
+//! resumed.  To leave the core halted after the watchpoint has been hit
+//! (e.g., for further inspection with `humility tasks`), use `--halt`.  If
+//! the core halts for some reason other than the watchpoint (e.g., another
+//! debugger halting it), the DWT state is restored, an error is reported,
+//! and the core is left halted.
+//!
+
+use anyhow::{bail, Context, Result};
+use clap::Command as ClapCommand;
+use clap::{CommandFactory, Parser};
+use humility::arch::ARMRegister;
+use humility::core::Core;
+use humility::hubris::*;
+use humility_cmd::tasks::read_tasks;
+use humility_cmd::{Archive, Attach, Command, Run, Validate};
+use humility_cortex::debug::*;
+use humility_cortex::dwt::*;
+use humility_cortex::scs::CoreInfo;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Parser, Debug)]
+#[clap(name = "watch", about = env!("CARGO_PKG_DESCRIPTION"))]
+struct WatchArgs {
+    /// trigger on reads as well as writes
+    #[clap(long, short)]
+    read: bool,
+
+    /// leave the core halted after the watchpoint is hit
+    #[clap(long)]
+    halt: bool,
+
+    /// timeout to wait for the watchpoint to be hit
+    #[clap(
+        long, short = 'T', value_name = "timeout_ms",
+        parse(try_from_str = parse_int::parse)
+    )]
+    timeout: Option<u64>,
+
+    /// variable to watch, with any fields delimited by `.`
+    variable: String,
+}
+
+///
+/// DWT_FUNCTION values for ARMv6-M and ARMv7-M
+///
+const V7_FUNCTION_WRITE: u32 = 0b0110;
+const V7_FUNCTION_READWRITE: u32 = 0b0111;
+
+///
+/// DWT_FUNCTION MATCH and ACTION values for ARMv8-M
+///
+const V8_MATCH_READWRITE: u32 = 0b0100;
+const V8_MATCH_WRITE: u32 = 0b0101;
+const V8_MATCH_LIMIT: u32 = 0b0111;
+const V8_ACTION_DEBUG: u32 = 0b01;
+
+/// Interval at which we check to see if the core has halted
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+///
+/// Resolves a variable (and any fields) to an address and a size.
+///
+fn resolve(hubris: &HubrisArchive, spec: &str) -> Result<(u32, usize)> {
+    let mut fields = spec.split('.');
+    let name = fields.next().unwrap();
+    let var = hubris.lookup_variable(name)?;
+
+    let mut addr = var.addr;
+    let mut size = var.size;
+    let mut goff = var.goff;
+    let mut path = name.to_string();
+
+    for field in fields {
+        let s = hubris
+            .lookup_struct(goff)
+            .with_context(|| format!("{} is not a structure", path))?;
+
+        let m = s.lookup_member(field)?;
+
+        addr += m.offset as u32;
+        goff = m.goff;
+        size = hubris.typesize(goff)?;
+        path = format!("{}.{}", path, field);
+    }
+
+    if size == 0 {
+        bail!("{} is zero-sized and cannot be watched", spec);
+    }
+
+    Ok((addr, size))
+}
+
+///
+/// The state of a comparator that we are going to program, saved so that
+/// it can be restored.
+///
+struct SavedComparator {
+    comp: DWT_COMP,
+    mask: Option<DWT_MASK>,
+    function: DWT_FUNCTION,
+}
+
+impl SavedComparator {
+    fn save(core: &mut dyn Core, n: u32, v8: bool) -> Result<Self> {
+        let base = dwt_comparator(n);
+
+        Ok(Self {
+            comp: DWT_COMP::read(core, base)?,
+            mask: if v8 { None } else { Some(DWT_MASK::read(core, base)?) },
+            function: DWT_FUNCTION::read(core, base)?,
+        })
+    }
+
+    fn restore(&self, core: &mut dyn Core) -> Result<()> {
+        //
+        // Disable the comparator before restoring its other registers.
+        //
+        let mut function = self.function;
+        function.register.set_function(0);
+        function.write(core)?;
+
+        self.comp.write(core)?;
+
+        if let Some(mask) = self.mask {
+            mask.write(core)?;
+        }
+
+        self.function.write(core)
+    }
+}
+
+///
+/// Finds a run of `count` free comparators, starting at a multiple of
+/// `count`.
+///
+fn find_comparators(
+    core: &mut dyn Core,
+    ncomparators: u32,
+    count: u32,
+) -> Result<u32> {
+    let mut n = 0;
+
+    while n + count <= ncomparators {
+        let mut free = true;
+
+        for i in n..n + count {
+            let function = DWT_FUNCTION::read(core, dwt_comparator(i))?;
+
+            if function.register.function() != 0 {
+                free = false;
+            }
+        }
+
+        if free {
+            return Ok(n);
+        }
+
+        n += count;
+    }
+
+    bail!("no free DWT comparators; is another debugger using them?");
+}
+
+///
+/// Programs an ARMv6-M/ARMv7-M comparator, which can only watch a naturally
+/// aligned power-of-two region.  Returns the size of the region watched.
+///
+fn program_v7(
+    core: &mut dyn Core,
+    n: u32,
+    addr: u32,
+    size: usize,
+    read: bool,
+) -> Result<u32> {
+    let base = dwt_comparator(n);
+    let last = addr + size as u32 - 1;
+    let mut bits = 0;
+
+    while bits < 32
+        && ((1u64 << bits) < size as u64 || (addr >> bits) != (last >> bits))
+    {
+        bits += 1;
+    }
+
+    //
+    // The number of mask bits supported is implementation defined; we
+    // determine it by writing all ones and seeing what sticks.
+    //
+    let mut mask = DWT_MASK::read(core, base)?;
+    mask.register.set_mask(0x1f);
+    mask.write(core)?;
+    let max = DWT_MASK::read(core, base)?.register.mask();
+
+    if bits > max {
+        bail!("region is too large to watch (maximum is {} bytes)", 1 << max);
+    }
+
+    let mut comp = DWT_COMP::read(core, base)?;
+    comp.register.set_comp(addr & !((1u32 << bits) - 1));
+    comp.write(core)?;
+
+    mask.register.set_mask(bits);
+    mask.write(core)?;
+
+    let mut function = DWT_FUNCTION::read(core, base)?;
+    function.register.set_function(if read {
+        V7_FUNCTION_READWRITE
+    } else {
+        V7_FUNCTION_WRITE
+    });
+    function.write(core)?;
+
+    Ok(1 << bits)
+}
+
+///
+/// Programs ARMv8-M comparators.  Naturally aligned variables of 1, 2 or 4
+/// bytes can be watched with a single comparator; anything else requires a
+/// second comparator to serve as the limit of an address range.
+///
+fn program_v8(
+    core: &mut dyn Core,
+    n: u32,
+    addr: u32,
+    size: usize,
+    read: bool,
+) -> Result<()> {
+    let base = dwt_comparator(n);
+    let single = matches!(size, 1 | 2 | 4) && addr % size as u32 == 0;
+
+    let mut comp = DWT_COMP::read(core, base)?;
+    comp.register.set_comp(addr);
+    comp.write(core)?;
+
+    let mut function = DWT_FUNCTION::read(core, base)?;
+    function.register.set_action(V8_ACTION_DEBUG);
+    function.register.set_function(if read {
+        V8_MATCH_READWRITE
+    } else {
+        V8_MATCH_WRITE
+    });
+
+    if single {
+        function.register.set_datavsize(size.trailing_zeros());
+    } else {
+        let limit = dwt_comparator(n + 1);
+
+        let mut comp = DWT_COMP::read(core, limit)?;
+        comp.register.set_comp(addr + size as u32 - 1);
+        comp.write(core)?;
+
+        let mut lfunction = DWT_FUNCTION::read(core, limit)?;
+        lfunction.register.set_action(0);
+        lfunction.register.set_datavsize(0);
+        lfunction.register.set_function(V8_MATCH_LIMIT);
+        lfunction.write(core)?;
+
+        function.register.set_datavsize(0);
+    }
+
+    function.write(core)
+}
+
+fn print_stack(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    ndx: u32,
+    stacks: &[u32],
+) -> Result<()> {
+    let t = HubrisTask::Task(ndx);
+    let regs = hubris.registers(core, t)?;
+
+    let limit = match stacks.get(ndx as usize) {
+        Some(limit) => *limit,
+        None => bail!("no descriptor for task {}", ndx),
+    };
+
+    let printer =
+        humility_cmd::stack::StackPrinter { indent: 3, ..Default::default() };
+
+    match hubris.stack(core, t, limit, &regs) {
+        Ok(stack) => printer.print(hubris, &stack),
+        Err(e) => println!("   stack unwind failed: {:?} ", e),
+    }
+
+    Ok(())
+}
+
+///
+/// Reports on a watchpoint hit.  This is called with the core halted.
+///
+fn report(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &WatchArgs,
+    addr: u32,
+    before: &[u8],
+    stacks: &[u32],
+) -> Result<()> {
+    humility::msg!(
+        "watchpoint hit: {} {}",
+        subargs.variable,
+        if subargs.read { "accessed" } else { "written" }
+    );
+
+    let mut after = vec![0u8; before.len()];
+    core.read_8(addr, &mut after)?;
+
+    if after != before {
+        humility::msg!("value was {:02x?}", before);
+        humility::msg!("value now {:02x?}", after);
+    }
+
+    let pc = core.read_reg(ARMRegister::PC)?;
+
+    let sym = match hubris.instr_sym(pc) {
+        Some((name, addr)) => format!("{}+0x{:x}", name, pc - addr),
+        None => "<unknown>".to_string(),
+    };
+
+    let module = hubris.instr_mod(pc);
+
+    humility::msg!(
+        "PC 0x{:08x} is in {} ({})",
+        pc,
+        module.unwrap_or("<unknown>"),
+        sym
+    );
+
+    //
+    // If we're in a task, that's the stack we want; if we're in the kernel,
+    // we display the stack of the current task, as it may well have been
+    // the kernel acting on its behalf.
+    //
+    let ndx = match module.and_then(|m| hubris.lookup_task(m)) {
+        Some(HubrisTask::Task(ndx)) => *ndx,
+        _ => {
+            let (base, _) = hubris.task_table(core)?;
+            let task_t = hubris.lookup_struct_byname("Task")?;
+            let cur =
+                core.read_word_32(hubris.lookup_symword("CURRENT_TASK_PTR")?)?;
+            let ndx = (cur - base) / task_t.size as u32;
+
+            humility::msg!(
+                "current task is {}",
+                hubris.task_name(ndx as usize).unwrap_or("<unknown>")
+            );
+
+            ndx
+        }
+    };
+
+    print_stack(hubris, core, ndx, stacks)
+}
+
+fn watch(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &[String],
+) -> Result<()> {
+    let subargs = WatchArgs::try_parse_from(subargs)?;
+    let (addr, size) = resolve(hubris, &subargs.variable)?;
+
+    let coreinfo = CoreInfo::read(core)?;
+    let v8 = matches!(coreinfo.part, ARMCore::CortexM23 | ARMCore::CortexM33);
+
+    //
+    // We need the initial stack of each task to be able to unwind it, which
+    // we get now rather than when the core is halted at the watchpoint.
+    //
+    let mut stacks = vec![];
+
+    for task in read_tasks(hubris, core)? {
+        let desc: humility_cmd::doppel::TaskDesc =
+            task.descriptor.load_from(hubris, core)?;
+        stacks.push(desc.initial_stack);
+    }
+
+    let demcr = DEMCR::read(core)?;
+
+    if !demcr.trcena() {
+        let mut val = demcr;
+        val.set_trcena(true);
+        val.write(core)?;
+    }
+
+    let mut hit = false;
+    let rval =
+        watchpoint(hubris, core, &subargs, addr, size, v8, &stacks, &mut hit);
+
+    //
+    // Restore DEMCR to the state we found it in regardless of how the watch
+    // concluded, and resume the core if we halted it.
+    //
+    let restored = demcr.write(core);
+
+    if hit && !subargs.halt {
+        core.run()?;
+    }
+
+    rval.and(restored)
+}
+
+///
+/// Programs a watchpoint, waits for it to be hit and reports on it, setting
+/// `hit` if the core was halted by the watchpoint.  Any comparators that we
+/// program are restored before returning.
+///
+#[allow(clippy::too_many_arguments)]
+fn watchpoint(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+    subargs: &WatchArgs,
+    addr: u32,
+    size: usize,
+    v8: bool,
+    stacks: &[u32],
+    hit: &mut bool,
+) -> Result<()> {
+    let ncomparators = DWT_CTRL::read(core)?.num_comparators();
+
+    if ncomparators == 0 {
+        bail!("core has no DWT comparators");
+    }
+
+    //
+    // On ARMv8-M, anything other than a naturally aligned 1, 2 or 4 byte
+    // variable needs a pair of comparators.
+    //
+    let exact = matches!(size, 1 | 2 | 4) && addr % size as u32 == 0;
+    let count = if v8 && !exact { 2 } else { 1 };
+
+    let n = find_comparators(core, ncomparators, count)?;
+
+    let saved = (n..n + count)
+        .map(|i| SavedComparator::save(core, i, v8))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut before = vec![0u8; size];
+    core.read_8(addr, &mut before)?;
+
+    let rval = if v8 {
+        program_v8(core, n, addr, size, subargs.read)
+    } else {
+        program_v7(core, n, addr, size, subargs.read).map(|watched| {
+            if watched as usize != size {
+                humility::warn!(
+                    "watching {} bytes at 0x{:08x} to cover {}",
+                    watched,
+                    addr & !(watched - 1),
+                    subargs.variable
+                );
+            }
+        })
+    };
+
+    let rval = rval.and_then(|_| {
+        humility::msg!(
+            "watching {} ({} bytes at 0x{:08x}) for {}; ^C to stop",
+            subargs.variable,
+            size,
+            addr,
+            if subargs.read { "reads and writes" } else { "writes" }
+        );
+
+        wait(core, n, count, subargs.timeout)
+    });
+
+    let rval = match rval {
+        Ok(true) => {
+            *hit = true;
+            report(hubris, core, subargs, addr, &before, stacks)
+        }
+        Ok(false) => Ok(()),
+        Err(e) => Err(e),
+    };
+
+    //
+    // Restore each comparator to the state we found it in -- even if
+    // restoring one of them fails -- and return the first error, if any.
+    //
+    let restored = saved
+        .iter()
+        .map(|s| s.restore(core))
+        .fold(Ok(()), |rval: Result<()>, r| rval.and(r));
+
+    rval.and(restored)
+}
+
+///
+/// Waits for the core to be halted by the watchpoint programmed into the
+/// `count` comparators starting at `n` (returning true) or for us to be
+/// interrupted (returning false).  If the core halts for any other reason,
+/// or if a timeout is specified and expires, an error is returned.
+///
+fn wait(
+    core: &mut dyn Core,
+    n: u32,
+    count: u32,
+    timeout: Option<u64>,
+) -> Result<bool> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let i = interrupted.clone();
+
+    ctrlc::set_handler(move || i.store(true, Ordering::SeqCst))
+        .context("failed to set ^C handler")?;
+
+    let start = Instant::now();
+
+    loop {
+        if DHCSR::read(core)?.halted() {
+            //
+            // Reading DWT_FUNCTION clears MATCHED, so we read every
+            // comparator rather than stopping at the first match.
+            //
+            let mut matched = false;
+
+            for i in n..n + count {
+                let function = DWT_FUNCTION::read(core, dwt_comparator(i))?;
+                matched |= function.register.matched();
+            }
+
+            if !matched {
+                bail!("core halted, but not by watchpoint");
+            }
+
+            return Ok(true);
+        }
+
+        if interrupted.load(Ordering::SeqCst) {
+            humility::msg!("interrupted; watchpoint not hit");
+            return Ok(false);
+        }
+
+        if let Some(timeout) = timeout {
+            if start.elapsed() > Duration::from_millis(timeout) {
+                bail!("timed out waiting for watchpoint");
+            }
+        }
+
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+pub fn init() -> (Command, ClapCommand<'static>) {
+    (
+        Command::Attached {
+            name: "watch",
+            archive: Archive::Required,
+            attach: Attach::LiveOnly,
+            validate: Validate::Booted,
+            run: Run::Subargs(watch),
+        },
+        WatchArgs::command(),
+    )
+}
@@ -4,6 +4,7 @@
 
 use crate::debug::Register;
 use crate::register;
+use crate::register_offs;
 use bitfield::bitfield;
 use humility::core::Core;
 
@@ -35,6 +36,44 @@ register!(DWT_CTRL, 0xe000_1000,
     pub cyccnt_enabled, set_cyccnt_enabled: 0;
 );
 
+/*
+ * DWT Program Counter Sample Register.  Reads as 0xffff_ffff if the core is
+ * halted or if no sample is available.
+ */
+register!(DWT_PCSR, 0xe000_101c,
+    #[derive(Copy, Clone)]
+    #[allow(non_camel_case_types)]
+    pub struct DWT_PCSR(u32);
+    impl Debug;
+    pub eiasample, _: 31, 0;
+);
+
+//
+// DWT comparator registers.  Each comparator has a block of registers
+// starting at the address returned by `dwt_comparator`; the layout of
+// DWT_FUNCTION differs between ARMv7-M (where `function` selects the
+// comparison and the action) and ARMv8-M (where `function` is the MATCH
+// field and the action is selected separately).  ARMv8-M lacks DWT_MASK.
+//
+pub fn dwt_comparator(n: u32) -> u32 {
+    0xe000_1020 + n * 0x10
+}
+
+register_offs!(DWT_COMP, 0x0,
+    pub comp, set_comp: 31, 0;
+);
+
+register_offs!(DWT_MASK, 0x4,
+    pub mask, set_mask: 4, 0;
+);
+
+register_offs!(DWT_FUNCTION, 0x8,
+    pub matched, _: 24;
+    pub datavsize, set_datavsize: 11, 10;
+    pub action, set_action: 5, 4;
+    pub function, set_function: 3, 0;
+);
+
 pub enum DWTSyncTapFrequency {
     Disabled,
     CycCnt8M,   // Every 2^23rd (8M) cycles
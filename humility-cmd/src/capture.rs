@@ -0,0 +1,528 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// I2C captures.  We accept Aardvark batch files (as written by Total Phase's
+// Control Center), CSV exports of the Saleae I2C analyzer (from either
+// Logic 1 or Logic 2) and a simple CSV format of our own.  Whatever its
+// origin, a capture is reduced to a sequence of writes, reads and sleeps; a
+// single-byte write that is immediately followed by a read of the same
+// device is taken to be a read of a register.
+//
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_xml_rs::from_str;
+use std::fs;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Format {
+    Aardvark,
+    Logic1,
+    Logic2,
+    Native,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Transaction {
+    Write { address: u8, data: Vec<u8> },
+    Read { address: u8, register: Option<u8>, expected: Vec<Option<u8>> },
+    Sleep { ms: u32 },
+}
+
+impl Transaction {
+    pub fn address(&self) -> Option<u8> {
+        match self {
+            Transaction::Write { address, .. }
+            | Transaction::Read { address, .. } => Some(*address),
+            Transaction::Sleep { .. } => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Capture {
+    pub format: Format,
+    pub transactions: Vec<Transaction>,
+}
+
+fn parse_address(address: &str) -> Result<u8> {
+    let address = parse_int::parse::<u8>(address)
+        .with_context(|| format!("invalid address \"{}\"", address))?;
+
+    if address > 0x7f {
+        bail!(
+            "address 0x{:x} is not a 7-bit address; \
+            was the capture exported with 8-bit addresses?",
+            address
+        );
+    }
+
+    Ok(address)
+}
+
+fn parse_byte(byte: &str) -> Result<u8> {
+    parse_int::parse::<u8>(byte)
+        .with_context(|| format!("invalid byte \"{}\"", byte))
+}
+
+//
+// Folds a single-byte write followed by a read from the same device into a
+// register read, and drops any transfers that moved no data (e.g., an
+// address that was NAK'd).
+//
+fn fuse(transfers: Vec<Transaction>) -> Vec<Transaction> {
+    let mut rval: Vec<Transaction> = vec![];
+
+    for t in transfers {
+        match &t {
+            Transaction::Write { data, .. } if data.is_empty() => continue,
+            Transaction::Read { expected, .. } if expected.is_empty() => {
+                continue
+            }
+            Transaction::Read { address, register: None, expected } => {
+                if let Some(Transaction::Write { address: waddr, data }) =
+                    rval.last()
+                {
+                    if waddr == address && data.len() == 1 {
+                        let read = Transaction::Read {
+                            address: *address,
+                            register: Some(data[0]),
+                            expected: expected.clone(),
+                        };
+
+                        rval.pop();
+                        rval.push(read);
+                        continue;
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        rval.push(t);
+    }
+
+    rval
+}
+
+#[derive(Debug, Deserialize)]
+struct AardvarkBatch {
+    #[serde(rename = "$value", default)]
+    elements: Vec<AardvarkElement>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum AardvarkElement {
+    Configure {},
+    I2cBitrate {},
+    I2cFreeBus {},
+    Sleep {
+        ms: String,
+    },
+    I2cWrite {
+        addr: String,
+        radix: Option<String>,
+        #[serde(rename = "$value", default)]
+        value: String,
+    },
+    I2cRead {
+        addr: String,
+        count: String,
+    },
+}
+
+fn load_aardvark(payload: &str) -> Result<Vec<Transaction>> {
+    let batch: AardvarkBatch = from_str(payload)?;
+    let mut rval = vec![];
+
+    for element in batch.elements {
+        match element {
+            AardvarkElement::Configure {}
+            | AardvarkElement::I2cBitrate {}
+            | AardvarkElement::I2cFreeBus {} => {}
+            AardvarkElement::Sleep { ms } => {
+                let ms = parse_int::parse::<u32>(&ms)
+                    .with_context(|| format!("invalid sleep \"{}\"", ms))?;
+                rval.push(Transaction::Sleep { ms });
+            }
+            AardvarkElement::I2cWrite { addr, radix, value } => {
+                let radix = match radix {
+                    Some(radix) => parse_int::parse::<u32>(&radix)?,
+                    None => 16,
+                };
+
+                let data = value
+                    .split_whitespace()
+                    .map(|v| {
+                        u8::from_str_radix(v, radix)
+                            .with_context(|| format!("invalid byte \"{}\"", v))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+
+                rval.push(Transaction::Write {
+                    address: parse_address(&addr)?,
+                    data,
+                });
+            }
+            AardvarkElement::I2cRead { addr, count } => {
+                //
+                // An Aardvark batch file doesn't record what was read, so
+                // there is nothing to check the read against.
+                //
+                let count = parse_int::parse::<usize>(&count)
+                    .with_context(|| format!("invalid count \"{}\"", count))?;
+
+                rval.push(Transaction::Read {
+                    address: parse_address(&addr)?,
+                    register: None,
+                    expected: vec![None; count],
+                });
+            }
+        }
+    }
+
+    Ok(rval)
+}
+
+fn column(headers: &csv::StringRecord, name: &str) -> Option<usize> {
+    headers.iter().position(|h| h.eq_ignore_ascii_case(name))
+}
+
+fn columns(headers: &csv::StringRecord, names: &[&str]) -> Result<Vec<usize>> {
+    names
+        .iter()
+        .map(|name| match column(headers, name) {
+            Some(ndx) => Ok(ndx),
+            None => bail!("capture is missing \"{}\" column", name),
+        })
+        .collect()
+}
+
+fn field<'a>(record: &'a csv::StringRecord, ndx: usize) -> Result<&'a str> {
+    match record.get(ndx) {
+        Some(field) => Ok(field),
+        None => bail!("short record {:?}", record),
+    }
+}
+
+//
+// A Logic 1 export has a row for each byte, with the bytes of a transfer
+// sharing a packet ID (and the bytes on either side of a repeated start
+// differing in direction).
+//
+fn load_logic1(
+    rdr: &mut csv::Reader<&[u8]>,
+    headers: &csv::StringRecord,
+) -> Result<Vec<Transaction>> {
+    let cols =
+        columns(headers, &["Packet ID", "Address", "Data", "Read/Write"])?;
+
+    let mut rval = vec![];
+    let mut current = None;
+
+    for record in rdr.records() {
+        let record = record?;
+        let id = field(&record, cols[0])?;
+        let address = parse_address(field(&record, cols[1])?)?;
+        let datum = parse_byte(field(&record, cols[2])?)?;
+        let read = field(&record, cols[3])? == "Read";
+
+        let key = (id.to_string(), address, read);
+
+        if current.as_ref() != Some(&key) {
+            rval.push(if read {
+                Transaction::Read { address, register: None, expected: vec![] }
+            } else {
+                Transaction::Write { address, data: vec![] }
+            });
+
+            current = Some(key);
+        }
+
+        match rval.last_mut() {
+            Some(Transaction::Read { expected, .. }) => {
+                expected.push(Some(datum))
+            }
+            Some(Transaction::Write { data, .. }) => data.push(datum),
+            _ => unreachable!(),
+        }
+    }
+
+    Ok(rval)
+}
+
+//
+// A Logic 2 export has a row for each frame: a start (or repeated start),
+// the address (with its direction), each byte of data, and a stop.
+//
+fn load_logic2(
+    rdr: &mut csv::Reader<&[u8]>,
+    headers: &csv::StringRecord,
+) -> Result<Vec<Transaction>> {
+    let cols = columns(headers, &["type", "address", "read", "data"])?;
+
+    let mut rval = vec![];
+
+    for record in rdr.records() {
+        let record = record?;
+
+        match field(&record, cols[0])? {
+            "address" => {
+                let address = parse_address(field(&record, cols[1])?)?;
+
+                rval.push(match field(&record, cols[2])? {
+                    "true" => Transaction::Read {
+                        address,
+                        register: None,
+                        expected: vec![],
+                    },
+                    _ => Transaction::Write { address, data: vec![] },
+                });
+            }
+            "data" => {
+                let datum = parse_byte(field(&record, cols[3])?)?;
+
+                match rval.last_mut() {
+                    Some(Transaction::Read { expected, .. }) => {
+                        expected.push(Some(datum))
+                    }
+                    Some(Transaction::Write { data, .. }) => data.push(datum),
+                    _ => bail!("data without address: {:?}", record),
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(rval)
+}
+
+//
+// Our own format has an operation, an address and data for each row:
+//
+//   op,address,data
+//   write,0x48,0x01 0x60 0xa0
+//   read,0x48,0x60 xx
+//   sleep,,10
+//
+// The data of a read is what we expect to read, with any byte that need
+// not match denoted as `xx`; the data of a sleep is its duration in
+// milliseconds.
+//
+fn load_native(
+    rdr: &mut csv::Reader<&[u8]>,
+    headers: &csv::StringRecord,
+) -> Result<Vec<Transaction>> {
+    let cols = columns(headers, &["op", "address", "data"])?;
+
+    let mut rval = vec![];
+
+    for record in rdr.records() {
+        let record = record?;
+        let data = field(&record, cols[2])?;
+
+        match field(&record, cols[0])? {
+            "write" => rval.push(Transaction::Write {
+                address: parse_address(field(&record, cols[1])?)?,
+                data: data
+                    .split_whitespace()
+                    .map(parse_byte)
+                    .collect::<Result<Vec<_>>>()?,
+            }),
+            "read" => rval.push(Transaction::Read {
+                address: parse_address(field(&record, cols[1])?)?,
+                register: None,
+                expected: data
+                    .split_whitespace()
+                    .map(|b| match b {
+                        "xx" => Ok(None),
+                        _ => parse_byte(b).map(Some),
+                    })
+                    .collect::<Result<Vec<_>>>()?,
+            }),
+            "sleep" => rval.push(Transaction::Sleep {
+                ms: parse_int::parse::<u32>(data)
+                    .with_context(|| format!("invalid sleep \"{}\"", data))?,
+            }),
+            op => bail!("unrecognized operation \"{}\"", op),
+        }
+    }
+
+    Ok(rval)
+}
+
+fn load_csv(contents: &str) -> Result<(Format, Vec<Transaction>)> {
+    let mut rdr = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'))
+        .from_reader(contents.as_bytes());
+
+    let headers = rdr.headers()?.clone();
+
+    if column(&headers, "Packet ID").is_some() {
+        Ok((Format::Logic1, load_logic1(&mut rdr, &headers)?))
+    } else if column(&headers, "type").is_some() {
+        Ok((Format::Logic2, load_logic2(&mut rdr, &headers)?))
+    } else if column(&headers, "op").is_some() {
+        Ok((Format::Native, load_native(&mut rdr, &headers)?))
+    } else {
+        bail!("unrecognized capture format");
+    }
+}
+
+impl Capture {
+    pub fn parse(contents: &str) -> Result<Self> {
+        let (format, transfers) = match contents.find("<aardvark>") {
+            Some(offset) => {
+                (Format::Aardvark, load_aardvark(&contents[offset..])?)
+            }
+            None => load_csv(contents)?,
+        };
+
+        Ok(Self { format, transactions: fuse(transfers) })
+    }
+
+    pub fn load(filename: &str) -> Result<Self> {
+        let contents = fs::read_to_string(filename)
+            .with_context(|| format!("failed to read {}", filename))?;
+
+        Self::parse(&contents)
+            .with_context(|| format!("failed to parse {}", filename))
+    }
+}
+
+#[test]
+fn validate_aardvark() {
+    let capture = Capture::parse(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<aardvark>
+    <configure i2c="1" spi="1" gpio="0" tpower="1" pullups="0" />
+    <i2c_bitrate khz="400" />
+    <i2c_write addr="0x5b" count="2" radix="16">FC 00</i2c_write>
+    <sleep ms="10" />
+    <i2c_write addr="0x5b" count="1" radix="16">10</i2c_write>
+    <i2c_read addr="0x5b" count="2" />
+</aardvark>"#,
+    )
+    .unwrap();
+
+    assert_eq!(capture.format, Format::Aardvark);
+    assert_eq!(
+        capture.transactions,
+        vec![
+            Transaction::Write { address: 0x5b, data: vec![0xfc, 0x00] },
+            Transaction::Sleep { ms: 10 },
+            Transaction::Read {
+                address: 0x5b,
+                register: Some(0x10),
+                expected: vec![None, None],
+            },
+        ]
+    );
+}
+
+#[test]
+fn validate_logic1() {
+    let capture = Capture::parse(
+        "Time [s],Packet ID,Address,Data,Read/Write,ACK/NAK
+0.100,0,0x48,0x01,Write,ACK
+0.101,0,0x48,0x02,Write,ACK
+0.102,0,0x48,0x20,Write,ACK
+0.200,1,0x48,0x01,Write,ACK
+0.201,1,0x48,0x02,Read,ACK
+0.202,1,0x48,0x20,Read,NAK
+",
+    )
+    .unwrap();
+
+    assert_eq!(capture.format, Format::Logic1);
+    assert_eq!(
+        capture.transactions,
+        vec![
+            Transaction::Write { address: 0x48, data: vec![0x01, 0x02, 0x20] },
+            Transaction::Read {
+                address: 0x48,
+                register: Some(0x01),
+                expected: vec![Some(0x02), Some(0x20)],
+            },
+        ]
+    );
+}
+
+#[test]
+fn validate_logic2() {
+    let capture = Capture::parse(
+        "name,type,start_time,duration,ack,address,read,data
+I2C,start,0.100,1e-06,,,,
+I2C,address,0.100,9e-05,true,0x48,false,
+I2C,data,0.101,9e-05,true,,,0x01
+I2C,start,0.102,1e-06,,,,
+I2C,address,0.102,9e-05,true,0x48,true,
+I2C,data,0.103,9e-05,true,,,0x02
+I2C,data,0.104,9e-05,false,,,0x20
+I2C,stop,0.105,1e-06,,,,
+I2C,start,0.200,1e-06,,,,
+I2C,address,0.200,9e-05,false,0x49,false,
+I2C,stop,0.201,1e-06,,,,
+",
+    )
+    .unwrap();
+
+    //
+    // The NAK'd address moved no data, and is dropped.
+    //
+    assert_eq!(capture.format, Format::Logic2);
+    assert_eq!(
+        capture.transactions,
+        vec![Transaction::Read {
+            address: 0x48,
+            register: Some(0x01),
+            expected: vec![Some(0x02), Some(0x20)],
+        }]
+    );
+}
+
+#[test]
+fn validate_native() {
+    let capture = Capture::parse(
+        "op,address,data
+# set the configuration, and read it back
+write,0x48,0x01 0x02 0x20
+sleep,,10
+write,0x48,0x01
+read,0x48,0x02 xx
+read,0x49,0x7f
+",
+    )
+    .unwrap();
+
+    assert_eq!(capture.format, Format::Native);
+    assert_eq!(
+        capture.transactions,
+        vec![
+            Transaction::Write { address: 0x48, data: vec![0x01, 0x02, 0x20] },
+            Transaction::Sleep { ms: 10 },
+            Transaction::Read {
+                address: 0x48,
+                register: Some(0x01),
+                expected: vec![Some(0x02), None],
+            },
+            Transaction::Read {
+                address: 0x49,
+                register: None,
+                expected: vec![Some(0x7f)],
+            },
+        ]
+    );
+}
+
+#[test]
+fn validate_invalid() {
+    assert!(Capture::parse("op,address,data\nwrite,0x90,0x01\n").is_err());
+    assert!(Capture::parse("op,address,data\nerase,0x48,0x01\n").is_err());
+    assert!(Capture::parse("op,address,data\nread,0x48,0x100\n").is_err());
+    assert!(Capture::parse("a,b,c\n1,2,3\n").is_err());
+}
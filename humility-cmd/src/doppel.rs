@@ -51,6 +51,7 @@ pub struct Task {
     pub priority: Priority,
     pub descriptor: Ptr,
     pub timer: TimerState,
+    pub notifications: u32,
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Load)]
@@ -206,6 +207,30 @@ pub enum GenOrRestartCount {
     RestartCount(u32),
 }
 
+impl GenOrRestartCount {
+    /// Number of distinct generations in kernels that only have a generation
+    pub const GENERATIONS: u8 = 64;
+
+    ///
+    /// Returns the number of times a task has restarted between an `earlier`
+    /// reading and this one, or `None` if the two readings are of different
+    /// shapes.  For kernels that only have a 6-bit generation, this can only
+    /// be a lower bound:  the generation wraps, and any multiple of
+    /// [`Self::GENERATIONS`] restarts between the readings are invisible.
+    ///
+    pub fn restarts_since(self, earlier: Self) -> Option<u32> {
+        match (earlier, self) {
+            (Self::Gen(e), Self::Gen(c)) => {
+                Some(u32::from(c.0.wrapping_sub(e.0) % Self::GENERATIONS))
+            }
+            (Self::RestartCount(e), Self::RestartCount(c)) => {
+                Some(c.wrapping_sub(e))
+            }
+            _ => None,
+        }
+    }
+}
+
 impl From<GenOrRestartCount> for u32 {
     fn from(g: GenOrRestartCount) -> Self {
         match g {
@@ -320,3 +345,15 @@ pub struct RpcHeader {
     pub nreply: U16<LittleEndian>,
     pub nbytes: U16<LittleEndian>,
 }
+
+#[test]
+fn validate_restarts_since() {
+    use GenOrRestartCount::*;
+
+    assert_eq!(Gen(Generation(3)).restarts_since(Gen(Generation(1))), Some(2));
+    assert_eq!(Gen(Generation(1)).restarts_since(Gen(Generation(1))), Some(0));
+    assert_eq!(Gen(Generation(1)).restarts_since(Gen(Generation(63))), Some(2));
+    assert_eq!(RestartCount(70).restarts_since(RestartCount(5)), Some(65));
+    assert_eq!(RestartCount(1).restarts_since(RestartCount(u32::MAX)), Some(2));
+    assert_eq!(Gen(Generation(1)).restarts_since(RestartCount(1)), None);
+}
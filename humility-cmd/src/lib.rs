@@ -2,6 +2,7 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
+pub mod capture;
 pub mod doppel;
 pub mod env;
 pub mod hiffy;
@@ -9,7 +10,9 @@ pub mod i2c;
 pub mod idol;
 pub mod jefe;
 pub mod stack;
+pub mod tasks;
 pub mod test;
+pub mod thresholds;
 
 use anyhow::{bail, Result};
 use clap::{AppSettings, Parser};
@@ -80,6 +83,19 @@ pub struct Args {
         conflicts_with_all = &["dump", "probe", "target"])]
     pub list_targets: bool,
 
+    /// comma-separated targets within an environment on which to run the
+    /// command in parallel
+    #[clap(long, requires = "environment", use_value_delimiter = true,
+        value_name = "targets",
+        conflicts_with_all = &["dump", "probe", "target", "list_targets"])]
+    pub targets: Option<Vec<String>>,
+
+    /// run the command in parallel on all targets within an environment
+    #[clap(long = "all-targets", requires = "environment",
+        conflicts_with_all = &["dump", "probe", "target", "list_targets",
+        "targets"])]
+    pub all_targets: bool,
+
     #[clap(subcommand)]
     pub cmd: Option<Subcommand>,
 }
@@ -0,0 +1,43 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+use crate::doppel::Task;
+use anyhow::{Context, Result};
+use humility::core::Core;
+use humility::hubris::*;
+use humility::reflect;
+
+///
+/// Reads the kernel's task table, returning the state of each task in task
+/// index order.  The target is halted while the table is read to assure a
+/// consistent snapshot.
+///
+pub fn read_tasks(
+    hubris: &HubrisArchive,
+    core: &mut dyn Core,
+) -> Result<Vec<Task>> {
+    let (base, task_count) = hubris.task_table(core)?;
+    let task_t = hubris.lookup_struct_byname("Task")?;
+
+    let mut taskblock = vec![0; task_t.size * task_count as usize];
+
+    core.halt()?;
+    let rval = core.read_8(base, &mut taskblock);
+    core.run()?;
+    rval?;
+
+    let mut tasks = vec![];
+
+    for i in 0..task_count as usize {
+        let task: Task =
+            reflect::load(hubris, &taskblock, task_t, i * task_t.size)
+                .with_context(|| {
+                    format!("loading task control block for task {}", i)
+                })?;
+
+        tasks.push(task);
+    }
+
+    Ok(tasks)
+}
@@ -0,0 +1,335 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Sensor thresholds.
+//!
+//! Thresholds are specified in a TOML file, either by sensor kind or by
+//! sensor name; where both are present, any bound specified for the sensor
+//! name overrides the corresponding bound for its kind:
+//!
+//! ```toml
+//! [kind.temp]
+//! max = 70.0
+//! critical = 85.0
+//!
+//! [sensor.Southwest]
+//! min = 10.0
+//! max = 60.0
+//!
+//! [sensor.V3P3_SYS_A0.voltage]
+//! min = 3.2
+//! max = 3.4
+//! ```
+//!
+//! Because a single device can have several sensors with the same name
+//! (e.g., the voltage, current and power of a rail), a sensor name may be
+//! qualified with its kind; a name that is not qualified with a kind must
+//! denote sensors of only one kind.
+//!
+//! A value below `min` or above `max` is out of range; a value at or above
+//! `critical` is critically out of range.
+
+use anyhow::{bail, Context, Result};
+use humility::hubris::*;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Threshold {
+    pub min: Option<f32>,
+    pub max: Option<f32>,
+    pub critical: Option<f32>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Violation {
+    Unreadable,
+    Low { value: f32, limit: f32 },
+    High { value: f32, limit: f32 },
+    Critical { value: f32, limit: f32 },
+}
+
+impl Violation {
+    pub fn is_critical(&self) -> bool {
+        matches!(self, Violation::Critical { .. })
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::Unreadable => write!(f, "could not be read"),
+            Violation::Low { value, limit } => {
+                write!(f, "is {:.2}, below minimum of {:.2}", value, limit)
+            }
+            Violation::High { value, limit } => {
+                write!(f, "is {:.2}, above maximum of {:.2}", value, limit)
+            }
+            Violation::Critical { value, limit } => {
+                write!(f, "is {:.2}, above critical of {:.2}", value, limit)
+            }
+        }
+    }
+}
+
+impl Threshold {
+    fn merge(&self, over: &Threshold) -> Threshold {
+        Threshold {
+            min: over.min.or(self.min),
+            max: over.max.or(self.max),
+            critical: over.critical.or(self.critical),
+        }
+    }
+
+    ///
+    /// Checks a sensor value (or the absence of one) against the threshold.
+    ///
+    pub fn check(&self, value: Option<f32>) -> Option<Violation> {
+        let value = match value {
+            Some(value) => value,
+            None => return Some(Violation::Unreadable),
+        };
+
+        if let Some(limit) = self.critical {
+            if value >= limit {
+                return Some(Violation::Critical { value, limit });
+            }
+        }
+
+        if let Some(limit) = self.max {
+            if value > limit {
+                return Some(Violation::High { value, limit });
+            }
+        }
+
+        if let Some(limit) = self.min {
+            if value < limit {
+                return Some(Violation::Low { value, limit });
+            }
+        }
+
+        None
+    }
+}
+
+//
+// A threshold for a sensor name is either a threshold (which applies to
+// the sensors of that name, which must all be of one kind) or a table of
+// thresholds by kind.
+//
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum SensorThreshold {
+    Any(Threshold),
+    Kinds(BTreeMap<String, Threshold>),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThresholdFile {
+    #[serde(default)]
+    kind: BTreeMap<String, Threshold>,
+    #[serde(default)]
+    sensor: BTreeMap<String, SensorThreshold>,
+}
+
+#[derive(Debug, Default)]
+pub struct Thresholds {
+    kinds: HashMap<HubrisSensorKind, Threshold>,
+    sensors: HashMap<(String, HubrisSensorKind), Threshold>,
+}
+
+fn sensor_kind(kind: &str) -> Result<HubrisSensorKind> {
+    match HubrisSensorKind::from_string(kind) {
+        Some(k) => Ok(k),
+        None => bail!("unrecognized sensor kind \"{}\"", kind),
+    }
+}
+
+impl Thresholds {
+    pub fn load(hubris: &HubrisArchive, filename: &str) -> Result<Self> {
+        let contents = std::fs::read_to_string(filename)
+            .with_context(|| format!("failed to read {}", filename))?;
+
+        Self::parse(&hubris.manifest.sensors, &contents)
+            .with_context(|| format!("failed to parse {}", filename))
+    }
+
+    ///
+    /// Parses thresholds, validating them against the specified sensors.
+    ///
+    pub fn parse(sensors: &[HubrisSensor], contents: &str) -> Result<Self> {
+        let file: ThresholdFile = toml::from_str(contents)?;
+        let mut rval = Thresholds::default();
+
+        for (kind, threshold) in file.kind {
+            rval.kinds.insert(sensor_kind(&kind)?, threshold);
+        }
+
+        for (name, threshold) in file.sensor {
+            let mut kinds = vec![];
+
+            for s in sensors.iter().filter(|s| s.name == name) {
+                if !kinds.contains(&s.kind) {
+                    kinds.push(s.kind);
+                }
+            }
+
+            if kinds.is_empty() {
+                bail!("unrecognized sensor name {}", name);
+            }
+
+            match threshold {
+                SensorThreshold::Any(threshold) => {
+                    if kinds.len() > 1 {
+                        bail!(
+                            "sensor name {} is ambiguous; qualify it with \
+                            one of its kinds (e.g., [sensor.{}.{}])",
+                            name,
+                            name,
+                            kinds[0].to_string()
+                        );
+                    }
+
+                    rval.sensors.insert((name, kinds[0]), threshold);
+                }
+
+                SensorThreshold::Kinds(map) => {
+                    for (kind, threshold) in map {
+                        let k = sensor_kind(&kind)?;
+
+                        if !kinds.contains(&k) {
+                            bail!("there is no {} sensor named {}", kind, name);
+                        }
+
+                        rval.sensors.insert((name.clone(), k), threshold);
+                    }
+                }
+            }
+        }
+
+        Ok(rval)
+    }
+
+    ///
+    /// Returns the threshold for the specified sensor, if any.
+    ///
+    pub fn lookup(&self, sensor: &HubrisSensor) -> Option<Threshold> {
+        let key = (sensor.name.clone(), sensor.kind);
+
+        match (self.kinds.get(&sensor.kind), self.sensors.get(&key)) {
+            (Some(kind), Some(named)) => Some(kind.merge(named)),
+            (Some(kind), None) => Some(*kind),
+            (None, Some(named)) => Some(*named),
+            (None, None) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+fn sensors() -> Vec<HubrisSensor> {
+    let sensor = |name: &str, kind, device| HubrisSensor {
+        name: name.to_string(),
+        kind,
+        device,
+    };
+
+    vec![
+        sensor("Southwest", HubrisSensorKind::Temperature, 0),
+        sensor("South", HubrisSensorKind::Temperature, 1),
+        sensor("V3P3_SYS_A0", HubrisSensorKind::Voltage, 2),
+        sensor("V3P3_SYS_A0", HubrisSensorKind::Current, 2),
+        sensor("V3P3_SYS_A0", HubrisSensorKind::Power, 2),
+        sensor("V3P3_SYS_A0", HubrisSensorKind::Temperature, 2),
+    ]
+}
+
+#[test]
+fn validate_thresholds() {
+    let sensors = sensors();
+    let thresholds = Thresholds::parse(
+        &sensors,
+        r#"
+        [kind.temp]
+        max = 70.0
+        critical = 85.0
+
+        [sensor.Southwest]
+        min = 10.0
+        max = 60.0
+
+        [sensor.V3P3_SYS_A0.voltage]
+        min = 3.2
+        max = 3.4
+    "#,
+    )
+    .unwrap();
+
+    //
+    // A bound for a sensor name overrides the bound for its kind.
+    //
+    let t = thresholds.lookup(&sensors[0]).unwrap();
+    assert_eq!(t.min, Some(10.0));
+    assert_eq!(t.max, Some(60.0));
+    assert_eq!(t.critical, Some(85.0));
+
+    let t = thresholds.lookup(&sensors[1]).unwrap();
+    assert_eq!(
+        t,
+        Threshold { min: None, max: Some(70.0), critical: Some(85.0) }
+    );
+
+    let t = thresholds.lookup(&sensors[2]).unwrap();
+    assert_eq!(t, Threshold { min: Some(3.2), max: Some(3.4), critical: None });
+
+    //
+    // The voltage threshold must not apply to other sensors of the rail.
+    //
+    assert_eq!(thresholds.lookup(&sensors[3]), None);
+    assert_eq!(thresholds.lookup(&sensors[4]), None);
+    assert_eq!(thresholds.lookup(&sensors[5]).unwrap().max, Some(70.0));
+}
+
+#[test]
+fn validate_thresholds_errors() {
+    let sensors = sensors();
+
+    let bad = [
+        "[sensor.V3P3_SYS_A0]\nmin = 3.2",
+        "[sensor.V3P3_SYS_A0.speed]\nmax = 1.0",
+        "[sensor.V3P3_SYS_A0.volts]\nmax = 1.0",
+        "[sensor.Nonexistent]\nmax = 1.0",
+        "[kind.volts]\nmax = 1.0",
+        "[kind.temp]\nmaximum = 1.0",
+        "[sensors.South]\nmax = 1.0",
+    ];
+
+    for contents in bad {
+        assert!(Thresholds::parse(&sensors, contents).is_err(), "{}", contents);
+    }
+}
+
+#[test]
+fn validate_threshold_check() {
+    let t = Threshold { min: Some(3.2), max: Some(3.4), critical: Some(3.6) };
+
+    assert_eq!(t.check(Some(3.3)), None);
+    assert_eq!(t.check(Some(3.2)), None);
+    assert_eq!(t.check(Some(3.4)), None);
+    assert_eq!(t.check(None), Some(Violation::Unreadable));
+    assert_eq!(
+        t.check(Some(3.1)),
+        Some(Violation::Low { value: 3.1, limit: 3.2 })
+    );
+    assert_eq!(
+        t.check(Some(3.5)),
+        Some(Violation::High { value: 3.5, limit: 3.4 })
+    );
+    assert_eq!(
+        t.check(Some(3.6)),
+        Some(Violation::Critical { value: 3.6, limit: 3.6 })
+    );
+}
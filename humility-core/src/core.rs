@@ -5,7 +5,7 @@
 use probe_rs::MemoryInterface;
 use probe_rs::{flashing, Probe};
 
-use anyhow::{anyhow, bail, ensure, Result};
+use anyhow::{anyhow, bail, ensure, Context, Result};
 
 use crate::arch::ARMRegister;
 use crate::hubris::*;
@@ -55,6 +55,15 @@ pub trait Core {
     ///
     fn load(&mut self, path: &Path) -> Result<()>;
 
+    ///
+    /// Called to load a flash image that specifies only part of the flash.
+    /// Any bytes in an erased sector that aren't specified by the image are
+    /// preserved.
+    ///
+    fn load_partial(&mut self, _path: &Path) -> Result<()> {
+        bail!("partial flash loading is not supported");
+    }
+
     /// Reset the chip
     fn reset(&mut self) -> Result<()>;
 
@@ -204,6 +213,96 @@ impl ProbeCore {
         }
     }
 
+    fn download(&mut self, path: &Path, keep_unwritten: bool) -> Result<()> {
+        #[derive(Debug, Default)]
+        struct LoadProgress {
+            /// total bytes that need to be erased
+            total_erase: usize,
+
+            /// bytes that have been erased
+            erased: usize,
+
+            /// total bytes that need to be written
+            total_write: usize,
+
+            /// number of bytes that have been written
+            written: usize,
+        }
+
+        use indicatif::{ProgressBar, ProgressStyle};
+
+        if !self.can_flash {
+            bail!("cannot flash without explicitly attaching to flash");
+        }
+
+        let progress =
+            Rc::new(RefCell::new(LoadProgress { ..Default::default() }));
+
+        let bar = ProgressBar::new(0);
+
+        let progress = flashing::FlashProgress::new(move |event| match event {
+            flashing::ProgressEvent::Initialized { flash_layout } => {
+                progress.borrow_mut().total_erase = flash_layout
+                    .sectors()
+                    .iter()
+                    .map(|s| s.size() as usize)
+                    .sum();
+
+                progress.borrow_mut().total_write = flash_layout
+                    .pages()
+                    .iter()
+                    .map(|s| s.size() as usize)
+                    .sum();
+
+                bar.set_style(ProgressStyle::default_bar().template(
+                    "humility: erasing [{bar:30}] {bytes}/{total_bytes}",
+                ));
+                bar.set_length(progress.borrow().total_erase as u64);
+            }
+
+            flashing::ProgressEvent::SectorErased { size, .. } => {
+                progress.borrow_mut().erased += size as usize;
+                bar.set_position(progress.borrow().erased as u64);
+            }
+
+            flashing::ProgressEvent::PageProgrammed { size, .. } => {
+                let mut progress = progress.borrow_mut();
+
+                if progress.written == 0 {
+                    progress.erased = progress.total_erase;
+                    bar.set_style(ProgressStyle::default_bar().template(
+                        "humility: flashing [{bar:30}] {bytes}/{total_bytes}",
+                    ));
+                    bar.set_length(progress.total_write as u64);
+                }
+
+                progress.written += size as usize;
+                bar.set_position(progress.written as u64);
+            }
+
+            flashing::ProgressEvent::FinishedProgramming => {
+                bar.finish_and_clear();
+            }
+
+            _ => {}
+        });
+
+        let mut options = flashing::DownloadOptions::default();
+        options.progress = Some(&progress);
+        options.keep_unwritten_bytes = keep_unwritten;
+
+        if let Err(e) = flashing::download_file_with_options(
+            &mut self.session,
+            path,
+            flashing::Format::Hex,
+            options,
+        ) {
+            bail!("Flash loading failed {:?}", e);
+        };
+
+        Ok(())
+    }
+
     fn halt_and_read(
         &mut self,
         mut func: impl FnMut(&mut probe_rs::Core) -> Result<()>,
@@ -361,92 +460,11 @@ impl Core for ProbeCore {
     }
 
     fn load(&mut self, path: &Path) -> Result<()> {
-        #[derive(Debug, Default)]
-        struct LoadProgress {
-            /// total bytes that need to be erased
-            total_erase: usize,
-
-            /// bytes that have been erased
-            erased: usize,
-
-            /// total bytes that need to be written
-            total_write: usize,
-
-            /// number of bytes that have been written
-            written: usize,
-        }
-
-        use indicatif::{ProgressBar, ProgressStyle};
-
-        if !self.can_flash {
-            bail!("cannot flash without explicitly attaching to flash");
-        }
-
-        let progress =
-            Rc::new(RefCell::new(LoadProgress { ..Default::default() }));
-
-        let bar = ProgressBar::new(0);
-
-        let progress = flashing::FlashProgress::new(move |event| match event {
-            flashing::ProgressEvent::Initialized { flash_layout } => {
-                progress.borrow_mut().total_erase = flash_layout
-                    .sectors()
-                    .iter()
-                    .map(|s| s.size() as usize)
-                    .sum();
-
-                progress.borrow_mut().total_write = flash_layout
-                    .pages()
-                    .iter()
-                    .map(|s| s.size() as usize)
-                    .sum();
-
-                bar.set_style(ProgressStyle::default_bar().template(
-                    "humility: erasing [{bar:30}] {bytes}/{total_bytes}",
-                ));
-                bar.set_length(progress.borrow().total_erase as u64);
-            }
-
-            flashing::ProgressEvent::SectorErased { size, .. } => {
-                progress.borrow_mut().erased += size as usize;
-                bar.set_position(progress.borrow().erased as u64);
-            }
-
-            flashing::ProgressEvent::PageProgrammed { size, .. } => {
-                let mut progress = progress.borrow_mut();
-
-                if progress.written == 0 {
-                    progress.erased = progress.total_erase;
-                    bar.set_style(ProgressStyle::default_bar().template(
-                        "humility: flashing [{bar:30}] {bytes}/{total_bytes}",
-                    ));
-                    bar.set_length(progress.total_write as u64);
-                }
-
-                progress.written += size as usize;
-                bar.set_position(progress.written as u64);
-            }
-
-            flashing::ProgressEvent::FinishedProgramming => {
-                bar.finish_and_clear();
-            }
-
-            _ => {}
-        });
-
-        let mut options = flashing::DownloadOptions::default();
-        options.progress = Some(&progress);
-
-        if let Err(e) = flashing::download_file_with_options(
-            &mut self.session,
-            path,
-            flashing::Format::Hex,
-            options,
-        ) {
-            bail!("Flash loading failed {:?}", e);
-        };
+        self.download(path, false)
+    }
 
-        Ok(())
+    fn load_partial(&mut self, path: &Path) -> Result<()> {
+        self.download(path, true)
     }
 
     fn reset(&mut self) -> Result<()> {
@@ -1258,6 +1276,102 @@ impl Core for DumpCore {
     }
 }
 
+///
+/// A core that reflects the flash image in an archive, allowing static
+/// structures (e.g., task and region descriptors) to be read without a
+/// target.
+///
+pub struct ArchiveCore {
+    regions: BTreeMap<u32, Vec<u8>>,
+}
+
+impl ArchiveCore {
+    fn new(hubris: &HubrisArchive) -> Result<ArchiveCore> {
+        let flash = hubris.load_flash_config()?;
+
+        let regions = crate::image::image_regions(&flash.elf)
+            .context("failed to load flash image")?
+            .into_iter()
+            .collect();
+
+        Ok(Self { regions })
+    }
+}
+
+#[rustfmt::skip::macros(bail)]
+impl Core for ArchiveCore {
+    fn info(&self) -> (String, Option<String>) {
+        ("archive".to_string(), None)
+    }
+
+    fn read_word_32(&mut self, addr: u32) -> Result<u32> {
+        let mut buf = [0u8; 4];
+        self.read_8(addr, &mut buf)?;
+        Ok(u32::from_le_bytes(buf))
+    }
+
+    fn read_8(&mut self, addr: u32, data: &mut [u8]) -> Result<()> {
+        if let Some((&base, contents)) = self.regions.range(..=addr).next_back()
+        {
+            let offs = (addr - base) as usize;
+
+            if offs + data.len() <= contents.len() {
+                data.copy_from_slice(&contents[offs..offs + data.len()]);
+                return Ok(());
+            }
+        }
+
+        bail!(
+            "read of {} bytes from 0x{:x} is not within archive image",
+            data.len(), addr
+        );
+    }
+
+    fn read_reg(&mut self, _reg: ARMRegister) -> Result<u32> {
+        bail!("cannot read register from an archive");
+    }
+
+    fn write_reg(&mut self, _reg: ARMRegister, _value: u32) -> Result<()> {
+        bail!("cannot write register on an archive");
+    }
+
+    fn write_word_32(&mut self, _addr: u32, _data: u32) -> Result<()> {
+        bail!("cannot write a word on an archive");
+    }
+
+    fn write_8(&mut self, _addr: u32, _data: &[u8]) -> Result<()> {
+        bail!("cannot write a byte on an archive");
+    }
+
+    fn halt(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn step(&mut self) -> Result<()> {
+        bail!("can't step an archive");
+    }
+
+    fn init_swv(&mut self) -> Result<()> {
+        bail!("cannot enable SWV on an archive");
+    }
+
+    fn read_swv(&mut self) -> Result<Vec<u8>> {
+        bail!("cannot read SWV on an archive");
+    }
+
+    fn load(&mut self, _path: &Path) -> Result<()> {
+        bail!("Flash loading is not supported on an archive");
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        bail!("Reset is not supported on an archive");
+    }
+}
+
 fn parse_probe(probe: &str) -> (&str, Option<usize>) {
     if probe.contains('-') {
         let str = probe.to_owned();
@@ -1508,3 +1622,10 @@ pub fn attach_dump(
     crate::msg!("attached to dump");
     Ok(Box::new(core))
 }
+
+///
+/// Returns a core that reflects the flash image of the specified archive.
+///
+pub fn attach_archive(hubris: &HubrisArchive) -> Result<Box<dyn Core>> {
+    Ok(Box::new(ArchiveCore::new(hubris)?))
+}
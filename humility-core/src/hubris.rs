@@ -40,15 +40,16 @@ const MAX_HUBRIS_VERSION: u32 = 3;
 
 #[derive(Default, Debug)]
 pub struct HubrisManifest {
-    version: Option<String>,
-    gitrev: Option<String>,
-    features: Vec<String>,
-    board: Option<String>,
+    pub version: Option<String>,
+    pub gitrev: Option<String>,
+    pub features: Vec<String>,
+    pub board: Option<String>,
     pub name: Option<String>,
-    target: Option<String>,
-    task_features: HashMap<String, Vec<String>>,
+    pub target: Option<String>,
+    pub task_features: HashMap<String, Vec<String>>,
     pub task_irqs: HashMap<String, Vec<(u32, u32)>>,
-    peripherals: BTreeMap<String, u32>,
+    pub task_stacksize: HashMap<String, u32>,
+    pub peripherals: BTreeMap<String, u32>,
     peripherals_byaddr: BTreeMap<u32, String>,
     pub i2c_devices: Vec<HubrisI2cDevice>,
     pub i2c_buses: Vec<HubrisI2cBus>,
@@ -71,6 +72,7 @@ struct HubrisConfig {
     tasks: IndexMap<String, HubrisConfigTask>,
     peripherals: Option<IndexMap<String, HubrisConfigPeripheral>>,
     chip: Option<String>,
+    stacksize: Option<u32>,
     config: Option<HubrisConfigConfig>,
 }
 
@@ -83,6 +85,7 @@ struct HubrisConfigKernel {
 struct HubrisConfigTask {
     features: Option<Vec<String>>,
     interrupts: Option<IndexMap<String, u32>>,
+    stacksize: Option<u32>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -93,10 +96,17 @@ struct HubrisConfigPeripheral {
     interrupts: Option<IndexMap<String, u32>>,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+struct HubrisConfigI2cMux {
+    driver: String,
+    address: u8,
+}
+
 #[derive(Clone, Debug, Deserialize)]
 struct HubrisConfigI2cPort {
     name: Option<String>,
     description: Option<String>,
+    muxes: Option<Vec<HubrisConfigI2cMux>>,
 }
 
 #[derive(Clone, Debug, Deserialize)]
@@ -164,6 +174,12 @@ pub struct HubrisI2cPort {
     pub index: u8,
 }
 
+#[derive(Clone, Debug)]
+pub struct HubrisI2cMux {
+    pub driver: String,
+    pub address: u8,
+}
+
 #[derive(Clone, Debug)]
 pub struct HubrisI2cBus {
     pub controller: u8,
@@ -171,6 +187,7 @@ pub struct HubrisI2cBus {
     pub name: Option<String>,
     pub description: Option<String>,
     pub target: bool,
+    pub muxes: Vec<HubrisI2cMux>,
 }
 
 #[derive(Clone, Debug)]
@@ -2192,6 +2209,15 @@ impl HubrisArchive {
                         name: port.name.as_ref().cloned(),
                         description: port.description.as_ref().cloned(),
                         target: controller.target.unwrap_or(false),
+                        muxes: port
+                            .muxes
+                            .iter()
+                            .flatten()
+                            .map(|m| HubrisI2cMux {
+                                driver: m.driver.clone(),
+                                address: m.address,
+                            })
+                            .collect(),
                     });
                 }
             }
@@ -2373,6 +2399,10 @@ impl HubrisArchive {
                     .insert(name.clone(), features.clone());
             }
 
+            if let Some(stacksize) = task.stacksize.or(config.stacksize) {
+                self.manifest.task_stacksize.insert(name.clone(), stacksize);
+            }
+
             if let Some(ref interrupts) = task.interrupts {
                 let mut task_irqs = vec![];
 
@@ -2575,7 +2605,11 @@ impl HubrisArchive {
         Self::check_version(comment)
     }
 
-    fn check_version(comment: &str) -> Result<()> {
+    ///
+    /// Checks that the version in the specified archive comment is one that
+    /// we support.
+    ///
+    pub fn check_version(comment: &str) -> Result<()> {
         match comment.strip_prefix("hubris build archive v") {
             Some(v) => {
                 let archive_version = match v {
@@ -3354,6 +3388,15 @@ impl HubrisArchive {
         Ok(regions)
     }
 
+    ///
+    /// Returns the regions loaded from the ELF objects in the archive (that
+    /// is, the memory that is actually occupied by the kernel and tasks), by
+    /// base address.
+    ///
+    pub fn loaded_regions(&self) -> &BTreeMap<u32, HubrisRegion> {
+        &self.loaded
+    }
+
     pub fn dump_registers(&self) -> HashMap<ARMRegister, u32> {
         self.registers.clone()
     }
@@ -4194,6 +4237,35 @@ impl HubrisArchive {
         std::fs::write(target, &buffer).map_err(Into::into)
     }
 
+    ///
+    /// Calls the specified closure with the name, task, and ELF object of
+    /// the kernel and of every task (in task ID order).
+    ///
+    pub fn for_each_object<F: FnMut(&str, HubrisTask, &[u8]) -> Result<()>>(
+        &self,
+        mut f: F,
+    ) -> Result<()> {
+        let cursor = Cursor::new(self.archive.as_slice());
+        let mut archive = zip::ZipArchive::new(cursor)?;
+        let mut buffer = Vec::new();
+
+        archive
+            .by_name("elf/kernel")
+            .map_err(|e| anyhow!("failed to find 'elf/kernel': {}", e))?
+            .read_to_end(&mut buffer)?;
+
+        f("kernel", HubrisTask::Kernel, &buffer)?;
+
+        let mut id = 0;
+
+        Self::for_each_task(archive, |path, buffer| {
+            let name = path.file_name().unwrap().to_str().unwrap();
+            f(name, HubrisTask::Task(id), buffer)?;
+            id += 1;
+            Ok(())
+        })
+    }
+
     /// Copies the kernel and every task ELF file to the given directory.
     pub fn extract_elfs_to(&self, p: &Path) -> Result<()> {
         self.extract_file_to("elf/kernel", &p.join("kernel"))?;
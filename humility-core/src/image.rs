@@ -0,0 +1,148 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Hubris images as loaded onto a part:  the contiguous regions of an ELF
+// image, and the SREC and IHEX files that describe them.
+//
+
+use anyhow::{anyhow, Result};
+
+///
+/// Coalesces the chunks of an ELF image into contiguous regions, to minimize
+/// the number of reads that we need to perform when comparing it against
+/// the part (and to describe the image when extracting it).
+///
+pub fn image_regions(elf: &[u8]) -> Result<Vec<(u32, Vec<u8>)>> {
+    let mut regions: Vec<(u32, Vec<u8>)> = vec![];
+
+    for (addr, chunk) in elf_chunks(elf)? {
+        match regions.last_mut() {
+            Some((base, contents)) if *base + contents.len() as u32 == addr => {
+                contents.extend_from_slice(chunk);
+            }
+            _ => regions.push((addr, chunk.to_vec())),
+        }
+    }
+
+    Ok(regions)
+}
+
+/// Returns the contents of a section or segment, failing if the ELF file is
+/// too short to contain it.
+fn contents(elf_data: &[u8], offset: u64, size: u64) -> Result<&[u8]> {
+    let offset = usize::try_from(offset)?;
+    let size = usize::try_from(size)?;
+
+    offset
+        .checked_add(size)
+        .and_then(|end| elf_data.get(offset..end))
+        .ok_or_else(|| {
+            anyhow!(
+                "ELF contents at offset 0x{:x} (0x{:x} bytes) are beyond the \
+                end of the file",
+                offset,
+                size
+            )
+        })
+}
+
+/// While it may sound like the impetus for an OSHA investigation at the North
+/// Pole, this function is _actually_ designed to generate small (32-byte)
+/// chunks describing the data in the PHDRs of an ELF file. Unless the file is
+/// missing PHDRs, because objcopy sometimes does that for whatever reason, in
+/// which case we do the section headers.
+///
+/// This is an implementation factor of both SREC and IHEX generation.
+fn elf_chunks(elf_data: &[u8]) -> Result<Vec<(u32, &[u8])>> {
+    let elf = goblin::elf::Elf::parse(elf_data)?;
+
+    let mut addr_slices = vec![];
+
+    if elf.program_headers.is_empty() {
+        for sh in &elf.section_headers {
+            if sh.sh_type != goblin::elf::section_header::SHT_PROGBITS {
+                continue;
+            }
+
+            let addr = u32::try_from(sh.sh_addr)?;
+            let data = contents(elf_data, sh.sh_offset, sh.sh_size)?;
+
+            for (i, chunk) in data.chunks(32).enumerate() {
+                addr_slices.push((addr + i as u32 * 32, chunk));
+            }
+        }
+    } else {
+        for ph in &elf.program_headers {
+            if ph.p_type != goblin::elf::program_header::PT_LOAD {
+                continue;
+            }
+
+            let addr = u32::try_from(ph.p_vaddr)?;
+            let data = contents(elf_data, ph.p_offset, ph.p_filesz)?;
+
+            for (i, chunk) in data.chunks(32).enumerate() {
+                addr_slices.push((addr + i as u32 * 32, chunk));
+            }
+        }
+    }
+
+    Ok(addr_slices)
+}
+
+pub fn generate_srec_from_elf(data: &[u8]) -> Result<String> {
+    let mut records = vec![srec::Record::S0("humility!".into())];
+
+    for (addr, slice) in elf_chunks(data)? {
+        records.push(srec::Record::S3(srec::Data {
+            address: srec::Address32(addr),
+            data: slice.to_vec(),
+        }));
+    }
+    records.push(srec::Record::S7(srec::Address32(0))); // bogus entry point
+
+    Ok(srec::writer::generate_srec_file(&records))
+}
+
+pub fn generate_ihex_from_elf(data: &[u8]) -> Result<String> {
+    generate_ihex(elf_chunks(data)?.into_iter())
+}
+
+/// Generates IHEX from the specified chunks of address and data, which are
+/// broken into records of (at most) 32 bytes.
+pub fn generate_ihex<'a>(
+    chunks: impl Iterator<Item = (u32, &'a [u8])>,
+) -> Result<String> {
+    // Build up IHEX records from that information.
+    let mut records = vec![];
+
+    for (base, data) in chunks {
+        for (i, slice) in data.chunks(32).enumerate() {
+            let addr = base + i as u32 * 32;
+
+            records
+                .push(ihex::Record::ExtendedLinearAddress((addr >> 16) as u16));
+            records.push(ihex::Record::Data {
+                offset: addr as u16,
+                value: slice.to_vec(),
+            });
+        }
+    }
+
+    records.push(ihex::Record::EndOfFile);
+
+    Ok(ihex::create_object_file_representation(&records)?)
+}
+
+#[test]
+fn validate_contents() {
+    let data = [0u8, 1, 2, 3, 4, 5, 6, 7];
+
+    assert_eq!(contents(&data, 2, 3).unwrap(), &[2, 3, 4]);
+    assert_eq!(contents(&data, 0, 8).unwrap(), &data);
+    assert!(contents(&data, 8, 0).unwrap().is_empty());
+    assert!(contents(&data, 6, 3).is_err());
+    assert!(contents(&data, 9, 0).is_err());
+    assert!(contents(&data, u64::MAX, 2).is_err());
+}
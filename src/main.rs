@@ -10,6 +10,7 @@ use clap::FromArgMatches;
 use clap::Parser;
 
 mod cmd;
+mod targets;
 
 fn main() {
     //
@@ -148,6 +149,27 @@ fn main() {
         std::process::exit(1);
     }
 
+    //
+    // If we have been asked to run on multiple targets, we run ourselves
+    // on each of them; each will take its probe and archive from the
+    // environment, so an archive on the command-line is an error.
+    //
+    if args.targets.is_some() || args.all_targets {
+        if m.occurrences_of("archive") == 1 {
+            msg!("cannot specify an archive when running on multiple targets");
+            std::process::exit(1);
+        }
+
+        let Subcommand::Other(subargs) = args.cmd.as_ref().unwrap();
+
+        if let Err(err) = targets::run(&args, subargs) {
+            eprintln!("humility {} failed: {:?}", subargs[0], err);
+            std::process::exit(1);
+        }
+
+        std::process::exit(0);
+    }
+
     //
     // Check to see if we have both a dump and an archive.  Because these
     // conflict with one another but because we allow both of them to be
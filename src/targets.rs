@@ -0,0 +1,178 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//
+// Support for running a command on several targets within an environment,
+// as specified via `--targets` or `--all-targets`.  Each target is run as a
+// child Humility process (with its own probe and archive, as dictated by the
+// environment) and all targets run in parallel.  The output of each child is
+// prefixed with the name of its target, and a summary is printed once all
+// targets have completed.
+//
+
+use anyhow::{bail, Context, Result};
+use humility_cmd::{env::Environment, Args};
+use indicatif::HumanDuration;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+//
+// Environment variables that would override the probe and archive that the
+// environment specifies for each target, and must therefore not be passed
+// to our children.
+//
+const OVERRIDES: &[&str] =
+    &["HUMILITY_PROBE", "HUMILITY_ARCHIVE", "HUMILITY_DUMP", "HUMILITY_TARGET"];
+
+fn run_target(
+    args: &Args,
+    environment: &str,
+    target: &str,
+    subargs: &[String],
+    width: usize,
+) -> Result<thread::JoinHandle<Result<(ExitStatus, Duration)>>> {
+    let exe = std::env::current_exe()?;
+    let mut cmd = Command::new(exe);
+
+    for var in OVERRIDES {
+        cmd.env_remove(var);
+    }
+
+    cmd.arg("--environment").arg(environment).arg("--target").arg(target);
+
+    if let Some(ref name) = args.archive_name {
+        cmd.arg("--archive-name").arg(name);
+    }
+
+    if args.verbose {
+        cmd.arg("--verbose");
+    }
+
+    cmd.args(subargs);
+    cmd.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped());
+
+    let started = Instant::now();
+
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("failed to run command on {}", target))?;
+
+    let prefix = format!("{:>width$} |", target, width = width);
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let stderr = BufReader::new(child.stderr.take().unwrap());
+
+    let out = prefix.clone();
+
+    let stdout = thread::spawn(move || {
+        for line in stdout.lines().map_while(|line| line.ok()) {
+            println!("{} {}", out, line);
+        }
+    });
+
+    let stderr = thread::spawn(move || {
+        for line in stderr.lines().map_while(|line| line.ok()) {
+            eprintln!("{} {}", prefix, line);
+        }
+    });
+
+    Ok(thread::spawn(move || {
+        let status = child.wait()?;
+        let _ = stdout.join();
+        let _ = stderr.join();
+
+        Ok((status, started.elapsed()))
+    }))
+}
+
+pub fn run(args: &Args, subargs: &[String]) -> Result<()> {
+    let environment = args.environment.as_ref().unwrap();
+    let all = Environment::targets(environment)?;
+
+    let targets = match &args.targets {
+        Some(targets) => {
+            for target in targets {
+                if !all.iter().any(|(t, _)| t == target) {
+                    let keys = all
+                        .iter()
+                        .map(|(t, _)| &**t)
+                        .collect::<Vec<_>>()
+                        .join(", ");
+
+                    bail!(
+                        "invalid target \"{}\" (expected one of: {})",
+                        target,
+                        keys
+                    );
+                }
+            }
+
+            targets.clone()
+        }
+        None => all.into_iter().map(|(t, _)| t).collect(),
+    };
+
+    if targets.is_empty() {
+        bail!("no targets found in environment");
+    }
+
+    let width = targets.iter().map(|t| t.len()).max().unwrap().max(6);
+
+    humility::msg!(
+        "running {} on {} target{}",
+        subargs[0],
+        targets.len(),
+        if targets.len() != 1 { "s" } else { "" }
+    );
+
+    let mut children = vec![];
+
+    for target in &targets {
+        children.push(run_target(args, environment, target, subargs, width)?);
+    }
+
+    let mut results = vec![];
+
+    for child in children {
+        results.push(match child.join() {
+            Ok(result) => result,
+            Err(_) => Err(anyhow::anyhow!("failed to wait for command")),
+        });
+    }
+
+    let mut nfailed = 0;
+
+    println!("\n{:width$} {:20} {}", "TARGET", "RESULT", "TIME", width = width);
+
+    for (target, result) in targets.iter().zip(results.iter()) {
+        let (status, elapsed) = match result {
+            Ok((status, elapsed)) if status.success() => {
+                ("ok".to_string(), HumanDuration(*elapsed).to_string())
+            }
+            Ok((status, elapsed)) => {
+                nfailed += 1;
+
+                let status = match status.code() {
+                    Some(code) => format!("failed (exit {})", code),
+                    None => "failed (killed)".to_string(),
+                };
+
+                (status, HumanDuration(*elapsed).to_string())
+            }
+            Err(err) => {
+                nfailed += 1;
+                (format!("failed ({})", err), "-".to_string())
+            }
+        };
+
+        println!("{:width$} {:20} {}", target, status, elapsed, width = width);
+    }
+
+    if nfailed != 0 {
+        bail!("{} of {} targets failed", nfailed, targets.len());
+    }
+
+    Ok(())
+}
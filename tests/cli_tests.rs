@@ -29,17 +29,25 @@ impl Test {
 
 fn make_tests() -> Result<()> {
     let postmortem = [
+        Test::witharg("archive-check", "archive", "check"),
         Test::witharg("extract", "extract", "app.toml"),
         Test::witharg("extract-list", "extract", "--list"),
+        Test::witharg(
+            "extract-image",
+            "extract",
+            "--image bin -o /dev/null --manifest /dev/stdout",
+        ),
         Test::basic("manifest"),
         Test::basic("spd"),
         Test::basic("map"),
+        Test::witharg("map-layout", "map", "--layout"),
         Test::basic("registers"),
         Test::witharg("registers-s", "registers", "-s"),
         Test::basic("ringbuf"),
         Test::witharg("ringbuf-arg", "ringbuf", "i2c"),
         Test::witharg("readvar-list", "readvar", "-l"),
         Test::witharg("readvar-ticks", "readvar", "TICKS"),
+        Test::basic("size"),
         Test::basic("stackmargin"),
         Test::basic("tasks"),
         Test::witharg("tasks-slvr", "tasks", "-slvr"),